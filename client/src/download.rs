@@ -0,0 +1,325 @@
+use std::{
+  fs,
+  io::{self, SeekFrom},
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+};
+
+use futures_util::{future::try_join_all, StreamExt};
+use reqwest::{header, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use crate::{check, Client, Error, Result};
+
+// Smaller files aren't worth more than one connection per this many bytes.
+const MIN_SEGMENT: u64 = 8 * 1024 * 1024;
+// How much a segment writes between saves of the progress, which is what a resume starts from.
+const SAVE_EVERY: u64 = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct Options {
+  // How many ranges of a file are fetched at once.
+  pub segments: usize,
+  // Whether the result is checked against the server's SHA-256.
+  pub verify: bool,
+}
+
+impl Default for Options {
+  fn default() -> Options {
+    Options {
+      segments: 4,
+      verify: true,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Segment {
+  start: u64,
+  end: u64,
+  // Bytes from `start` on that are already written.
+  done: u64,
+}
+
+impl Segment {
+  fn is_done(&self) -> bool {
+    self.start + self.done >= self.end
+  }
+}
+
+// Kept next to the partial file as `<name>.part.json`, so an interrupted download carries on where
+// it stopped, as long as the file on the server is still the same.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct State {
+  size: u64,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  sha256: Option<String>,
+  segments: Vec<Segment>,
+}
+
+impl State {
+  fn new(size: u64, sha256: Option<String>, segments: usize) -> State {
+    let count = (size / MIN_SEGMENT).clamp(1, segments.max(1) as u64);
+    let length = size.div_ceil(count).max(1);
+    let segments = (0..count)
+      .map(|index| Segment {
+        start: index * length,
+        end: ((index + 1) * length).min(size),
+        done: 0,
+      })
+      .filter(|segment| segment.start < segment.end)
+      .collect();
+    State {
+      size,
+      sha256,
+      segments,
+    }
+  }
+
+  fn load(path: &Path) -> Option<State> {
+    serde_json::from_slice(&fs::read(path).ok()?).ok()
+  }
+
+  fn save(&self, path: &Path) -> io::Result<()> {
+    fs::write(path, serde_json::to_vec(self)?)
+  }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+  let mut name = path.file_name().unwrap_or_default().to_os_string();
+  name.push(suffix);
+  path.with_file_name(name)
+}
+
+struct Progress {
+  state: Mutex<State>,
+  path: PathBuf,
+}
+
+impl Progress {
+  fn advance(&self, index: usize, written: u64, save: bool) -> io::Result<()> {
+    let mut state = self.state.lock().unwrap();
+    state.segments[index].done += written;
+    match save {
+      true => state.save(&self.path),
+      false => Ok(()),
+    }
+  }
+}
+
+impl Client {
+  // Fetches `id` into `dest`, picking up a previous attempt and splitting the rest into ranges
+  // fetched side by side. Returns the size of the file.
+  pub async fn download(&self, id: &str, dest: &Path, options: &Options) -> Result<u64> {
+    let expected = match options.verify {
+      true => Some(self.sha256(id).await?),
+      false => None,
+    };
+    self.download_expecting(id, dest, options, expected).await
+  }
+
+  pub(crate) async fn download_expecting(
+    &self,
+    id: &str,
+    dest: &Path,
+    options: &Options,
+    expected: Option<String>,
+  ) -> Result<u64> {
+    let (size, ranges) = self.stat(id).await?;
+    let (part, state_path) = (with_suffix(dest, ".part"), with_suffix(dest, ".part.json"));
+    if let Some(parent) = dest.parent() {
+      fs::create_dir_all(parent)?;
+    }
+
+    // Without ranges there is nothing to resume from, nor to split.
+    let previous = match ranges && part.exists() {
+      true => State::load(&state_path)
+        .filter(|state| state.size == size && (expected.is_none() || state.sha256 == expected)),
+      false => None,
+    };
+    let state = match previous {
+      Some(state) => state,
+      None => {
+        let segments = if ranges { options.segments } else { 1 };
+        let state = State::new(size, expected.clone(), segments);
+        fs::File::create(&part)?.set_len(size)?;
+        state.save(&state_path)?;
+        state
+      }
+    };
+
+    let pending: Vec<(usize, Segment)> = state
+      .segments
+      .iter()
+      .cloned()
+      .enumerate()
+      .filter(|(_, segment)| !segment.is_done())
+      .collect();
+    let progress = Arc::new(Progress {
+      state: Mutex::new(state),
+      path: state_path.clone(),
+    });
+    let fetches = pending.into_iter().map(|(index, segment)| {
+      let (client, part, progress) = (self.clone(), part.clone(), progress.clone());
+      let id = id.to_string();
+      tokio::spawn(async move {
+        client
+          .fetch(&id, &part, index, segment, size, ranges, &progress)
+          .await
+      })
+    });
+    let results = try_join_all(fetches)
+      .await
+      .map_err(|error| Error::Io(io::Error::other(error)))?;
+    for result in results {
+      result?;
+    }
+
+    if let Some(expected) = &expected {
+      if let Err(error) = check(id, &part, expected).await {
+        // Whatever got corrupted, starting over is the only way to be sure of the next attempt.
+        let _ = fs::remove_file(&part);
+        let _ = fs::remove_file(&state_path);
+        return Err(error);
+      }
+    }
+    fs::rename(&part, dest)?;
+    let _ = fs::remove_file(&state_path);
+    Ok(size)
+  }
+
+  #[allow(clippy::too_many_arguments)]
+  async fn fetch(
+    &self,
+    id: &str,
+    part: &Path,
+    index: usize,
+    segment: Segment,
+    size: u64,
+    ranges: bool,
+    progress: &Progress,
+  ) -> Result<()> {
+    let from = segment.start + segment.done;
+    // Nobody is watching these, so the server can hold them back for those who are (RFC 9218).
+    let mut request = self
+      .request(Method::GET, self.url(&["dr-who", id]))
+      .header("Priority", "u=7");
+    if ranges {
+      request = request.header(header::RANGE, format!("bytes={}-{}", from, segment.end - 1));
+    }
+    let response = Client::send(request).await?;
+    let expected = match ranges {
+      true => StatusCode::PARTIAL_CONTENT,
+      false => StatusCode::OK,
+    };
+    if response.status() != expected && !(from == 0 && segment.end == size) {
+      return Err(Error::Protocol(format!(
+        "Asked for part of {}, got {}",
+        id,
+        response.status()
+      )));
+    }
+
+    let mut file = tokio::fs::OpenOptions::new().write(true).open(part).await?;
+    file.seek(SeekFrom::Start(from)).await?;
+    let (mut position, mut unsaved) = (from, 0);
+    let mut body = response.bytes_stream();
+    while let Some(chunk) = body.next().await {
+      let chunk = chunk?;
+      let chunk = &chunk[..chunk.len().min((segment.end - position) as usize)];
+      file.write_all(chunk).await?;
+      position += chunk.len() as u64;
+      unsaved += chunk.len() as u64;
+      if unsaved >= SAVE_EVERY {
+        file.flush().await?;
+        progress.advance(index, unsaved, true)?;
+        unsaved = 0;
+      }
+      if position >= segment.end {
+        break;
+      }
+    }
+    file.flush().await?;
+    progress.advance(index, unsaved, true)?;
+    if position < segment.end {
+      return Err(Error::Protocol(format!(
+        "{} ended early, at {} of {}",
+        id, position, segment.end
+      )));
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MIB: u64 = 1024 * 1024;
+
+  fn ranges(state: &State) -> Vec<(u64, u64)> {
+    state
+      .segments
+      .iter()
+      .map(|segment| (segment.start, segment.end))
+      .collect()
+  }
+
+  #[test]
+  fn splits_big_files_evenly() {
+    let state = State::new(40 * MIB, None, 4);
+    assert_eq!(
+      ranges(&state),
+      [
+        (0, 10 * MIB),
+        (10 * MIB, 20 * MIB),
+        (20 * MIB, 30 * MIB),
+        (30 * MIB, 40 * MIB)
+      ]
+    );
+    assert!(state.segments.iter().all(|segment| segment.done == 0));
+  }
+
+  #[test]
+  fn keeps_segments_above_the_minimum() {
+    let size = 20 * MIB + 1;
+    assert_eq!(
+      ranges(&State::new(size, None, 4)),
+      [(0, 10 * MIB + 1), (10 * MIB + 1, size)]
+    );
+    assert_eq!(ranges(&State::new(1000, None, 4)), [(0, 1000)]);
+    assert_eq!(ranges(&State::new(100 * MIB, None, 0)), [(0, 100 * MIB)]);
+    assert!(State::new(0, None, 4).segments.is_empty());
+  }
+
+  #[test]
+  fn covers_the_whole_file() {
+    for (size, segments) in [(33 * MIB + 3, 4), (1 << 40, 7), (64 * MIB - 1, 16)] {
+      let state = State::new(size, None, segments);
+      let ranges = ranges(&state);
+      assert!(ranges.len() <= segments);
+      assert_eq!(ranges.first().unwrap().0, 0);
+      assert_eq!(ranges.last().unwrap().1, size);
+      assert!(ranges.windows(2).all(|pair| pair[0].1 == pair[1].0));
+      assert!(ranges.iter().all(|(start, end)| start < end));
+    }
+  }
+
+  #[test]
+  fn tracks_finished_segments() {
+    let mut state = State::new(20 * MIB, None, 2);
+    assert!(!state.segments[0].is_done());
+    state.segments[0].done = 10 * MIB;
+    assert!(state.segments[0].is_done());
+    assert!(!state.segments[1].is_done());
+  }
+
+  #[test]
+  fn names_files_next_to_the_download() {
+    assert_eq!(
+      with_suffix(Path::new("out/s04e01.mkv"), ".part.json"),
+      Path::new("out/s04e01.mkv.part.json")
+    );
+  }
+}
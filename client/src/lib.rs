@@ -0,0 +1,223 @@
+use std::{fmt, fs::File, io, path::Path};
+
+use reqwest::{header, RequestBuilder, StatusCode, Url};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+mod download;
+mod mirror;
+
+pub use download::Options;
+pub use mirror::Report;
+
+#[derive(Debug)]
+pub enum Error {
+  Http(reqwest::Error),
+  Io(io::Error),
+  Status(StatusCode, Url),
+  Checksum {
+    id: String,
+    expected: String,
+    actual: String,
+  },
+  Protocol(String),
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::Http(error) => write!(f, "{}", error),
+      Error::Io(error) => write!(f, "{}", error),
+      Error::Status(status, url) => write!(f, "{} for {}", status, url),
+      Error::Checksum {
+        id,
+        expected,
+        actual,
+      } => write!(f, "{} has sha256 {}, expected {}", id, actual, expected),
+      Error::Protocol(message) => write!(f, "{}", message),
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+  fn from(error: reqwest::Error) -> Error {
+    Error::Http(error)
+  }
+}
+
+impl From<io::Error> for Error {
+  fn from(error: io::Error) -> Error {
+    Error::Io(error)
+  }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Collection {
+  pub name: String,
+  pub title: String,
+  pub count: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Metadata {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub title: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub airdate: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub summary: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub runtime: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+  pub id: String,
+  pub url: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub season: Option<u32>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub episode: Option<u32>,
+  #[serde(flatten)]
+  pub metadata: Metadata,
+}
+
+// Imported details, plus whatever the server could read from the file's container.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Info {
+  pub id: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub metadata: Option<Metadata>,
+  #[serde(flatten)]
+  pub media: Map<String, Value>,
+}
+
+// Talks to a file-share server, authenticating with a token if it needs one.
+#[derive(Clone)]
+pub struct Client {
+  base: Url,
+  token: Option<String>,
+  http: reqwest::Client,
+}
+
+impl Client {
+  pub fn new(base: &str, token: Option<String>) -> Result<Client> {
+    let base = Url::parse(base).map_err(|error| Error::Protocol(format!("{}: {}", base, error)))?;
+    if base.cannot_be_a_base() {
+      return Err(Error::Protocol(format!("{} is not a server", base)));
+    }
+    let http = reqwest::Client::builder()
+      .user_agent(concat!("file-share-client/", env!("CARGO_PKG_VERSION")))
+      .build()?;
+    Ok(Client { base, token, http })
+  }
+
+  // `segments` appended to the server's address, each percent-encoded.
+  fn url(&self, segments: &[&str]) -> Url {
+    let mut url = self.base.clone();
+    url
+      .path_segments_mut()
+      .unwrap()
+      .pop_if_empty()
+      .extend(segments);
+    url
+  }
+
+  fn request(&self, method: reqwest::Method, url: Url) -> RequestBuilder {
+    let request = self.http.request(method, url);
+    match &self.token {
+      Some(token) => request.bearer_auth(token),
+      None => request,
+    }
+  }
+
+  // Sends `request`, treating anything but a success as an error.
+  async fn send(request: RequestBuilder) -> Result<reqwest::Response> {
+    let response = request.send().await?;
+    match response.status().is_success() {
+      true => Ok(response),
+      false => Err(Error::Status(response.status(), response.url().clone())),
+    }
+  }
+
+  async fn json<T: DeserializeOwned>(&self, url: Url) -> Result<T> {
+    let response = Client::send(self.request(reqwest::Method::GET, url)).await?;
+    Ok(response.json().await?)
+  }
+
+  pub async fn collections(&self) -> Result<Vec<Collection>> {
+    self.json(self.url(&["collections"])).await
+  }
+
+  // The ids of `collection`, or of the default one.
+  pub async fn list(&self, collection: Option<&str>) -> Result<Vec<Entry>> {
+    let mut url = self.url(&["dr-who"]);
+    if let Some(collection) = collection {
+      url.query_pairs_mut().append_pair("collection", collection);
+    }
+    self.json(url).await
+  }
+
+  pub async fn info(&self, id: &str) -> Result<Info> {
+    self.json(self.url(&["dr-who", id, "info"])).await
+  }
+
+  // The hex SHA-256 of `id`'s file, as the server computed it.
+  pub async fn sha256(&self, id: &str) -> Result<String> {
+    let url = self.url(&["dr-who", &format!("{}.sha256", id)]);
+    let response = Client::send(self.request(reqwest::Method::GET, url)).await?;
+    let text = response.text().await?;
+    match text.split_whitespace().next() {
+      Some(hash) if hash.len() == 64 => Ok(hash.to_lowercase()),
+      _ => Err(Error::Protocol(format!("No checksum for {}", id))),
+    }
+  }
+
+  // The size of `id`'s file, and whether parts of it can be asked for.
+  pub async fn stat(&self, id: &str) -> Result<(u64, bool)> {
+    let url = self.url(&["dr-who", id]);
+    let response = Client::send(self.request(reqwest::Method::HEAD, url)).await?;
+    let headers = response.headers();
+    let size = headers
+      .get(header::CONTENT_LENGTH)
+      .and_then(|value| value.to_str().ok())
+      .and_then(|value| value.parse().ok())
+      .ok_or_else(|| Error::Protocol(format!("No size for {}", id)))?;
+    let ranges = headers
+      .get(header::ACCEPT_RANGES)
+      .is_some_and(|value| value == "bytes");
+    Ok((size, ranges))
+  }
+
+  // Checks a local copy of `id` against the server's checksum.
+  pub async fn verify(&self, id: &str, path: &Path) -> Result<()> {
+    let expected = self.sha256(id).await?;
+    check(id, path, &expected).await
+  }
+}
+
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+  let mut hasher = Sha256::new();
+  io::copy(&mut File::open(path)?, &mut hasher)?;
+  Ok(format!("{:x}", hasher.finalize()))
+}
+
+async fn check(id: &str, path: &Path, expected: &str) -> Result<()> {
+  let path = path.to_path_buf();
+  let actual = tokio::task::spawn_blocking(move || sha256_file(&path))
+    .await
+    .map_err(io::Error::other)??;
+  match actual == expected {
+    true => Ok(()),
+    false => Err(Error::Checksum {
+      id: id.to_string(),
+      expected: expected.to_string(),
+      actual,
+    }),
+  }
+}
@@ -0,0 +1,127 @@
+use std::path::PathBuf;
+
+use file_share_client::{Client, Options};
+
+const USAGE: &str = "Usage: file-share-client [--server <url>] [--token <token>] <command>
+
+  collections                   List collections
+  ls [<collection>]             List the ids of a collection, the default one if none is given
+  info <id>                     Show what is known about an id
+  download <id> [<file>] [--segments <n>] [--no-verify]
+                                Fetch an id, resuming an earlier attempt
+  verify <id> <file>            Check a file against the server's checksum
+  mirror <collection> <dir> [--delete] [--segments <n>] [--no-verify]
+                                Keep a directory up to date with a collection
+
+The server and token can also be given as FILE_SHARE_SERVER and FILE_SHARE_TOKEN.";
+
+fn usage() -> ! {
+  eprintln!("{}", USAGE);
+  std::process::exit(2)
+}
+
+// Flags taking a value, which is then not positional either.
+const VALUED: &[&str] = &["--server", "--token", "--segments"];
+
+fn option(args: &[String], flag: &str) -> Option<String> {
+  args
+    .iter()
+    .position(|arg| arg == flag)
+    .and_then(|index| args.get(index + 1))
+    .cloned()
+}
+
+fn positional(args: &[String]) -> Vec<&str> {
+  args
+    .iter()
+    .enumerate()
+    .filter(|(index, arg)| {
+      !arg.starts_with("--") && (*index == 0 || !VALUED.contains(&args[index - 1].as_str()))
+    })
+    .map(|(_, arg)| arg.as_str())
+    .collect()
+}
+
+fn options(args: &[String]) -> Options {
+  let mut options = Options::default();
+  if let Some(segments) = option(args, "--segments") {
+    options.segments = segments.parse().unwrap_or_else(|_| usage());
+  }
+  options.verify = !args.iter().any(|arg| arg == "--no-verify");
+  options
+}
+
+fn print_json(value: &impl serde::Serialize) {
+  println!("{}", serde_json::to_string_pretty(value).unwrap());
+}
+
+async fn run(client: Client, args: &[String]) -> file_share_client::Result<()> {
+  match positional(args)[..] {
+    ["collections"] => {
+      for collection in client.collections().await? {
+        println!(
+          "{}\t{}\t{}",
+          collection.name, collection.count, collection.title
+        );
+      }
+    }
+    ["ls", ref collection @ ..] if collection.len() <= 1 => {
+      for entry in client.list(collection.first().copied()).await? {
+        let title = entry.metadata.title.unwrap_or_default();
+        println!("{}\t{}", entry.id, title);
+      }
+    }
+    ["info", id] => print_json(&client.info(id).await?),
+    ["download", id, ref file @ ..] if file.len() <= 1 => {
+      let dest = PathBuf::from(file.first().copied().unwrap_or(id));
+      let size = client.download(id, &dest, &options(args)).await?;
+      println!("{} -> {:?} ({} bytes)", id, dest, size);
+    }
+    ["verify", id, file] => {
+      client.verify(id, &PathBuf::from(file)).await?;
+      println!("{}: OK", file);
+    }
+    ["mirror", collection, dir] => {
+      let delete = args.iter().any(|arg| arg == "--delete");
+      let report = client
+        .mirror(collection, &PathBuf::from(dir), &options(args), delete)
+        .await?;
+      for id in &report.downloaded {
+        println!("downloaded: {}", id);
+      }
+      for id in &report.removed {
+        println!("removed: {}", id);
+      }
+      println!(
+        "{} downloaded, {} unchanged, {} removed",
+        report.downloaded.len(),
+        report.unchanged,
+        report.removed.len()
+      );
+    }
+    _ => usage(),
+  }
+  Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  if args.is_empty() || args.iter().any(|arg| arg == "--help" || arg == "-h") {
+    println!("{}", USAGE);
+    return;
+  }
+  let server = option(&args, "--server")
+    .or_else(|| std::env::var("FILE_SHARE_SERVER").ok())
+    .unwrap_or_else(|| "http://localhost:8000".to_string());
+  let token = option(&args, "--token").or_else(|| std::env::var("FILE_SHARE_TOKEN").ok());
+
+  let result = match Client::new(&server, token) {
+    Ok(client) => run(client, &args).await,
+    Err(error) => Err(error),
+  };
+  if let Err(error) = result {
+    eprintln!("{}", error);
+    std::process::exit(1);
+  }
+}
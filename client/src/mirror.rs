@@ -0,0 +1,132 @@
+use std::{
+  collections::BTreeMap,
+  fs, io,
+  path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Client, Entry, Options, Result};
+
+// What a mirror holds, kept in the mirrored directory so the next run only fetches what changed.
+const MANIFEST: &str = ".file-share-mirror.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Mirrored {
+  sha256: String,
+  size: u64,
+  #[serde(flatten)]
+  entry: Entry,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+  collection: String,
+  ids: BTreeMap<String, Mirrored>,
+}
+
+impl Manifest {
+  fn load(dir: &Path) -> Option<Manifest> {
+    serde_json::from_slice(&fs::read(dir.join(MANIFEST)).ok()?).ok()
+  }
+
+  fn save(&self, dir: &Path) -> io::Result<()> {
+    let (path, partial) = (
+      dir.join(MANIFEST),
+      dir.join(format!("{}.partial", MANIFEST)),
+    );
+    fs::write(&partial, serde_json::to_vec_pretty(self)?)?;
+    fs::rename(partial, path)
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+  pub downloaded: Vec<String>,
+  pub unchanged: usize,
+  pub removed: Vec<String>,
+}
+
+// Ids become file names, so they must not reach outside the mirror.
+fn local_path(dir: &Path, id: &str) -> PathBuf {
+  let name: String = id
+    .chars()
+    .map(|c| match c {
+      '/' | '\\' => '_',
+      c => c,
+    })
+    .collect();
+  match name.starts_with('.') {
+    true => dir.join(format!("_{}", name)),
+    false => dir.join(name),
+  }
+}
+
+impl Client {
+  // Brings `dir` up to date with `collection`: new and changed files are fetched, ones whose
+  // checksum still matches are left alone, and with `delete` ids gone from the server are removed.
+  pub async fn mirror(
+    &self,
+    collection: &str,
+    dir: &Path,
+    options: &Options,
+    delete: bool,
+  ) -> Result<Report> {
+    fs::create_dir_all(dir)?;
+    let mut manifest = Manifest::load(dir)
+      .filter(|manifest| manifest.collection == collection)
+      .unwrap_or_else(|| Manifest {
+        collection: collection.to_string(),
+        ..Manifest::default()
+      });
+    let entries = self.list(Some(collection)).await?;
+    let mut report = Report::default();
+
+    for entry in &entries {
+      let sha256 = self.sha256(&entry.id).await?;
+      let path = local_path(dir, &entry.id);
+      let unchanged = manifest.ids.get(&entry.id).is_some_and(|mirrored| {
+        mirrored.sha256 == sha256
+          && fs::metadata(&path).is_ok_and(|metadata| metadata.len() == mirrored.size)
+      });
+      if unchanged {
+        report.unchanged += 1;
+        continue;
+      }
+      let expected = options.verify.then(|| sha256.clone());
+      let size = self
+        .download_expecting(&entry.id, &path, options, expected)
+        .await?;
+      manifest.ids.insert(
+        entry.id.clone(),
+        Mirrored {
+          sha256,
+          size,
+          entry: entry.clone(),
+        },
+      );
+      // Saved as it goes, so an interrupted mirror doesn't fetch everything again.
+      manifest.save(dir)?;
+      report.downloaded.push(entry.id.clone());
+    }
+
+    let gone: Vec<String> = manifest
+      .ids
+      .keys()
+      .filter(|id| !entries.iter().any(|entry| entry.id == **id))
+      .cloned()
+      .collect();
+    if delete {
+      for id in gone {
+        match fs::remove_file(local_path(dir, &id)) {
+          Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error.into()),
+          _ => {}
+        }
+        manifest.ids.remove(&id);
+        report.removed.push(id);
+      }
+    }
+    manifest.save(dir)?;
+    Ok(report)
+  }
+}
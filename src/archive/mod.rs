@@ -0,0 +1,79 @@
+pub mod tar;
+pub mod zip;
+
+use std::{io, ops::Range, sync::Arc};
+
+use futures_util::{stream, StreamExt, TryStreamExt};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::{
+  download::{Body, ByteRange, Download},
+  storage::Storage,
+};
+
+// Archives are generated as a fixed sequence of byte ranges, so their length is known up front and
+// any range of them can be produced without generating what comes before it.
+pub enum Segment {
+  Bytes(Vec<u8>),
+  Object {
+    storage: Arc<dyn Storage>,
+    key: String,
+    size: u64,
+  },
+}
+
+impl Segment {
+  fn len(&self) -> u64 {
+    match self {
+      Segment::Bytes(bytes) => bytes.len() as u64,
+      Segment::Object { size, .. } => *size,
+    }
+  }
+
+  async fn open(self, range: Range<u64>) -> io::Result<Body> {
+    match self {
+      Segment::Bytes(bytes) => Ok(Box::pin(io::Cursor::new(
+        bytes[range.start as usize..range.end as usize].to_vec(),
+      ))),
+      Segment::Object { storage, key, .. } => Ok(storage.open(&key, Some(range)).await?),
+    }
+  }
+}
+
+#[derive(Default)]
+pub struct Segments(Vec<Segment>);
+
+impl Segments {
+  pub fn push(&mut self, segment: Segment) {
+    self.0.push(segment);
+  }
+
+  pub fn size(&self) -> u64 {
+    self.0.iter().map(Segment::len).sum()
+  }
+
+  pub fn open(self, range: Option<Range<u64>>) -> Body {
+    let range = range.unwrap_or(0..self.size());
+    let mut pieces = Vec::new();
+    let mut offset = 0;
+    for segment in self.0 {
+      let len = segment.len();
+      let start = range.start.max(offset);
+      let end = range.end.min(offset + len);
+      if start < end {
+        pieces.push((segment, start - offset..end - offset));
+      }
+      offset += len;
+    }
+
+    let chunks = stream::iter(pieces)
+      .then(|(segment, range)| segment.open(range))
+      .map_ok(ReaderStream::new)
+      .try_flatten();
+    Box::pin(StreamReader::new(chunks))
+  }
+
+  pub fn download(self, range: &ByteRange) -> Download {
+    Download::new(self.size(), range, |range| self.open(range))
+  }
+}
@@ -0,0 +1,295 @@
+use std::{
+  collections::{HashMap, HashSet},
+  sync::Arc,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_compression::tokio::bufread::ZstdEncoder;
+use log::warn;
+use rocket::{
+  http::{ContentType, Header},
+  request::FromParam,
+  tokio::io::BufReader,
+  State,
+};
+
+use super::{Segment, Segments};
+use crate::{
+  auth::User,
+  database::Db,
+  download::{file_name, ByteRange, Download},
+  storage::{Backends, Storage},
+  throttle::{Throttles, Traffic},
+};
+
+pub struct Entry {
+  pub path: String,
+  pub storage: Arc<dyn Storage>,
+  pub key: String,
+  pub size: u64,
+  pub modified: Option<SystemTime>,
+}
+
+const BLOCK: u64 = 512;
+const MAX_OCTAL_SIZE: u64 = 0o77777777777;
+
+fn octal(field: &mut [u8], value: u64) {
+  let width = field.len() - 1;
+  field[..width].copy_from_slice(format!("{:0width$o}", value, width = width).as_bytes());
+}
+
+fn header(name: &str, size: u64, mtime: u64, typeflag: u8) -> [u8; BLOCK as usize] {
+  let mut header = [0; BLOCK as usize];
+  let name = name.as_bytes();
+  header[..name.len().min(100)].copy_from_slice(&name[..name.len().min(100)]);
+  octal(&mut header[100..108], 0o644);
+  octal(&mut header[108..116], 0);
+  octal(&mut header[116..124], 0);
+  octal(&mut header[124..136], size.min(MAX_OCTAL_SIZE));
+  octal(&mut header[136..148], mtime);
+  header[148..156].fill(b' ');
+  header[156] = typeflag;
+  header[257..263].copy_from_slice(b"ustar\0");
+  header[263..265].copy_from_slice(b"00");
+
+  let checksum: u32 = header.iter().map(|byte| *byte as u32).sum();
+  header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+  header
+}
+
+// A pax record is prefixed with its own length in decimal, including the digits of that length.
+fn pax_record(key: &str, value: &str) -> String {
+  let body = format!(" {}={}\n", key, value);
+  let mut len = body.len() + 1;
+  while len != body.len() + len.to_string().len() {
+    len = body.len() + len.to_string().len();
+  }
+  format!("{}{}", len, body)
+}
+
+fn padding(size: u64) -> Vec<u8> {
+  vec![0; ((BLOCK - size % BLOCK) % BLOCK) as usize]
+}
+
+// POSIX pax tar, with an extended header for entries whose path or size does not fit in ustar.
+pub fn archive(entries: Vec<Entry>) -> Segments {
+  let mut segments = Segments::default();
+  for entry in entries {
+    let mtime = entry
+      .modified
+      .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+      .map_or(0, |duration| duration.as_secs());
+
+    let mut records = String::new();
+    if entry.path.len() > 100 {
+      records.push_str(&pax_record("path", &entry.path));
+    }
+    if entry.size > MAX_OCTAL_SIZE {
+      records.push_str(&pax_record("size", &entry.size.to_string()));
+    }
+
+    let mut headers = Vec::new();
+    if !records.is_empty() {
+      headers.extend_from_slice(&header("././@PaxHeader", records.len() as u64, mtime, b'x'));
+      headers.extend_from_slice(records.as_bytes());
+      headers.extend(padding(records.len() as u64));
+    }
+    headers.extend_from_slice(&header(&entry.path, entry.size, mtime, b'0'));
+
+    segments.push(Segment::Bytes(headers));
+    segments.push(Segment::Object {
+      storage: entry.storage,
+      key: entry.key,
+      size: entry.size,
+    });
+    segments.push(Segment::Bytes(padding(entry.size)));
+  }
+  segments.push(Segment::Bytes(vec![0; 2 * BLOCK as usize]));
+  segments
+}
+
+pub struct TarName {
+  collection: String,
+  zstd: bool,
+}
+
+impl<'a> FromParam<'a> for TarName {
+  type Error = &'a str;
+
+  fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+    let (collection, zstd) = match param.strip_suffix(".tar.zst") {
+      Some(collection) => (collection, true),
+      None => (param.strip_suffix(".tar").ok_or(param)?, false),
+    };
+    Ok(TarName {
+      collection: collection.to_string(),
+      zstd,
+    })
+  }
+}
+
+#[derive(FromFormField, PartialEq, Eq, Clone, Copy)]
+pub enum Names {
+  Release,
+  Ids,
+}
+
+// Either the files behind the collection's ids (all of them, or those in `ids`), or those of them
+// stored under the directory `path`. `names` picks between the stored paths and the ids as
+// names inside the archive.
+#[allow(clippy::too_many_arguments)]
+#[get("/archive/<name>?<ids>&<path>&<names>")]
+pub async fn tar(
+  user: User,
+  db: Db,
+  backends: &State<Backends>,
+  throttles: &State<Throttles>,
+  traffic: Traffic,
+  range: ByteRange,
+  name: TarName,
+  ids: Vec<String>,
+  path: Option<String>,
+  names: Option<Names>,
+) -> Option<Download> {
+  if !db.has_collection(&name.collection) {
+    return None;
+  }
+  let names = names.unwrap_or(Names::Release);
+  let collection_ids = db.collection_ids(&name.collection);
+  let storage = backends.for_collection(&name.collection);
+
+  let mut files: Vec<(String, String)> = match path {
+    Some(path) => {
+      if path.split('/').any(|part| part == "..") {
+        return None;
+      }
+      let ids_by_key: HashMap<&str, &str> = collection_ids
+        .iter()
+        .map(|id| (db.id_to_path[id].as_str(), id.as_str()))
+        .collect();
+      // Other collections, uploads and objects share the store, and `a/b` isn't under `a/bc`.
+      let directory = path.trim_matches('/');
+      let under = |key: &str| {
+        directory.is_empty()
+          || key
+            .strip_prefix(directory)
+            .is_some_and(|rest| rest.starts_with('/'))
+      };
+      storage
+        .list(directory)
+        .await
+        .ok()?
+        .into_iter()
+        .filter(|key| under(key))
+        .filter_map(|key| {
+          let id = ids_by_key.get(key.as_str())?;
+          Some(match names {
+            Names::Ids => (file_name(id, &key), key),
+            Names::Release => (key.clone(), key),
+          })
+        })
+        .collect()
+    }
+    None => {
+      let wanted: HashSet<&str> = ids.iter().flat_map(|ids| ids.split(',')).collect();
+      collection_ids
+        .iter()
+        .filter(|id| wanted.is_empty() || wanted.contains(id.as_str()))
+        .map(|id| {
+          let key = db.id_to_path[id].clone();
+          if names == Names::Release && !key.starts_with("sha256:") {
+            (key.clone(), key)
+          } else {
+            (file_name(id, &key), key)
+          }
+        })
+        .collect()
+    }
+  };
+  files.sort();
+  files.dedup_by(|a, b| a.0 == b.0);
+
+  let mut entries = Vec::new();
+  for (path, key) in files {
+    match storage.stat(&key).await {
+      Ok(stat) => entries.push(Entry {
+        path,
+        storage: storage.clone(),
+        key,
+        size: stat.size,
+        modified: stat.modified,
+      }),
+      Err(error) => warn!("Leaving {} out of the archive: {}", key, error),
+    }
+  }
+  if entries.is_empty() {
+    return None;
+  }
+
+  let segments = archive(entries);
+  let (mut download, file_name) = if name.zstd {
+    let encoder = ZstdEncoder::new(BufReader::new(segments.open(None)));
+    let mut download = Download::streamed(Box::pin(encoder));
+    download.content_type = Some(ContentType::new("application", "zstd"));
+    (download, format!("{}.tar.zst", name.collection))
+  } else {
+    let mut download = segments.download(&range);
+    download.content_type = Some(ContentType::TAR);
+    (download, format!("{}.tar", name.collection))
+  };
+  download.headers.push(Header::new(
+    "Content-Disposition",
+    format!("attachment; filename=\"{}\"", file_name),
+  ));
+  throttles.apply(
+    &db,
+    &mut download,
+    &traffic.bulk(),
+    user.name.as_deref(),
+    Some(&name.collection),
+  );
+  Some(download)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn counts_the_length_into_pax_records() {
+    assert_eq!(pax_record("path", "a"), "9 path=a\n");
+    // 97 bytes and two digits make 99.
+    let path = "p".repeat(90);
+    assert_eq!(pax_record("path", &path), format!("99 path={}\n", path));
+    // 98 bytes and two digits would make 100, which needs a third digit.
+    let path = "p".repeat(91);
+    assert_eq!(pax_record("path", &path), format!("101 path={}\n", path));
+    for len in 0..2000 {
+      let record = pax_record("path", &"p".repeat(len));
+      let (length, _) = record.split_once(' ').unwrap();
+      assert_eq!(length.parse::<usize>().unwrap(), record.len());
+    }
+  }
+
+  #[test]
+  fn checksums_ustar_headers() {
+    let header = header("a.txt", 1234, 0o17, b'0');
+    assert_eq!(&header[..5], b"a.txt");
+    assert_eq!(&header[124..136], b"00000002322\0");
+    assert_eq!(&header[136..148], b"00000000017\0");
+    assert_eq!(header[156], b'0');
+    assert_eq!(&header[257..265], b"ustar\x0000");
+    let mut blank = header;
+    blank[148..156].fill(b' ');
+    let sum: u32 = blank.iter().map(|byte| *byte as u32).sum();
+    assert_eq!(&header[148..156], format!("{:06o}\0 ", sum).as_bytes());
+  }
+
+  #[test]
+  fn pads_to_whole_blocks() {
+    assert_eq!(padding(0).len(), 0);
+    assert_eq!(padding(1).len(), 511);
+    assert_eq!(padding(512).len(), 0);
+    assert_eq!(padding(1000).len(), 24);
+  }
+}
@@ -0,0 +1,465 @@
+use std::{
+  sync::Arc,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use rocket::{
+  http::{ContentType, Header, Status},
+  request::FromParam,
+  response::{self, Responder},
+  Request, Response, State,
+};
+use time::OffsetDateTime;
+
+use super::{Segment, Segments};
+use crate::{
+  auth::User,
+  checksum::Checksums,
+  database::{Database, Db},
+  download::{file_name, ByteRange, Download},
+  episode,
+  storage::{Backends, Storage},
+  throttle::{Throttles, Traffic},
+};
+
+pub struct Entry {
+  pub name: String,
+  pub storage: Arc<dyn Storage>,
+  pub key: String,
+  pub size: u64,
+  pub crc32: u32,
+  pub modified: Option<SystemTime>,
+}
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+  fn u16(&mut self, value: u16) -> &mut Self {
+    self.0.extend_from_slice(&value.to_le_bytes());
+    self
+  }
+
+  fn u32(&mut self, value: u32) -> &mut Self {
+    self.0.extend_from_slice(&value.to_le_bytes());
+    self
+  }
+
+  fn u64(&mut self, value: u64) -> &mut Self {
+    self.0.extend_from_slice(&value.to_le_bytes());
+    self
+  }
+
+  fn bytes(&mut self, value: &[u8]) -> &mut Self {
+    self.0.extend_from_slice(value);
+    self
+  }
+}
+
+const VERSION: u16 = 45;
+const UTF8_NAMES: u16 = 1 << 11;
+const UNIX: u16 = 3 << 8;
+
+fn dos_time(modified: Option<SystemTime>) -> (u16, u16) {
+  match modified.map(OffsetDateTime::from) {
+    Some(time) if time.year() >= 1980 => (
+      ((time.hour() as u16) << 11) | ((time.minute() as u16) << 5) | (time.second() as u16 / 2),
+      (((time.year() - 1980) as u16) << 9) | ((time.month() as u16) << 5) | time.day() as u16,
+    ),
+    _ => (0, (1 << 5) | 1),
+  }
+}
+
+// Every entry is stored uncompressed with ZIP64 sizes, so the layout only depends on the names
+// and sizes of the files.
+pub fn archive(entries: Vec<Entry>) -> Segments {
+  let mut segments = Segments::default();
+  let mut central = Writer::default();
+  let mut offset = 0;
+  let count = entries.len() as u64;
+
+  for entry in entries {
+    let (time, date) = dos_time(entry.modified);
+    let name = entry.name.as_bytes();
+
+    let mut local = Writer::default();
+    local
+      .u32(0x04034b50)
+      .u16(VERSION)
+      .u16(UTF8_NAMES)
+      .u16(0)
+      .u16(time)
+      .u16(date)
+      .u32(entry.crc32)
+      .u32(u32::MAX)
+      .u32(u32::MAX)
+      .u16(name.len() as u16)
+      .u16(20)
+      .bytes(name)
+      .u16(0x0001)
+      .u16(16)
+      .u64(entry.size)
+      .u64(entry.size);
+
+    central
+      .u32(0x02014b50)
+      .u16(UNIX | VERSION)
+      .u16(VERSION)
+      .u16(UTF8_NAMES)
+      .u16(0)
+      .u16(time)
+      .u16(date)
+      .u32(entry.crc32)
+      .u32(u32::MAX)
+      .u32(u32::MAX)
+      .u16(name.len() as u16)
+      .u16(28)
+      .u16(0)
+      .u16(0)
+      .u16(0)
+      .u32(0o100644 << 16)
+      .u32(u32::MAX)
+      .bytes(name)
+      .u16(0x0001)
+      .u16(24)
+      .u64(entry.size)
+      .u64(entry.size)
+      .u64(offset);
+
+    offset += local.0.len() as u64 + entry.size;
+    segments.push(Segment::Bytes(local.0));
+    segments.push(Segment::Object {
+      storage: entry.storage,
+      key: entry.key,
+      size: entry.size,
+    });
+  }
+
+  let central_size = central.0.len() as u64;
+  let end_offset = offset + central_size;
+  central
+    .u32(0x06064b50)
+    .u64(44)
+    .u16(UNIX | VERSION)
+    .u16(VERSION)
+    .u32(0)
+    .u32(0)
+    .u64(count)
+    .u64(count)
+    .u64(central_size)
+    .u64(offset)
+    .u32(0x07064b50)
+    .u32(0)
+    .u64(end_offset)
+    .u32(1)
+    .u32(0x06054b50)
+    .u16(0)
+    .u16(0)
+    .u16(u16::MAX)
+    .u16(u16::MAX)
+    .u32(u32::MAX)
+    .u32(u32::MAX)
+    .u16(0);
+  segments.push(Segment::Bytes(central.0));
+  segments
+}
+
+pub enum Zip {
+  Ready(Box<Download>),
+  // Headers carry each file's CRC up front, and files without one are hashed in the background.
+  Hashing { ready: usize, total: usize },
+}
+
+impl<'r> Responder<'r, 'static> for Zip {
+  fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+    match self {
+      Zip::Ready(download) => (*download).respond_to(request),
+      Zip::Hashing { ready, total } => Response::build_from(
+        format!(
+          "Hashed {} of {} files so far, try again shortly.\n",
+          ready, total
+        )
+        .respond_to(request)?,
+      )
+      .status(Status::Accepted)
+      .raw_header("Retry-After", "10")
+      .ok(),
+    }
+  }
+}
+
+// Zips up `ids`, using cached checksums for the CRCs. Only files on local storage can be included.
+fn zip(
+  db: &Database,
+  backends: &Backends,
+  checksums: &Checksums,
+  mut ids: Vec<String>,
+  range: &ByteRange,
+  archive_name: String,
+) -> Option<Zip> {
+  ids.sort();
+  ids.dedup();
+
+  let mut entries = Vec::new();
+  let (mut hashing, mut total) = (0, 0);
+  for id in ids {
+    let store_path = match db.id_to_path.get(&id) {
+      Some(store_path) if db.is_local(&id) => store_path.clone(),
+      _ => continue,
+    };
+    total += 1;
+    let digest = match checksums.get(&store_path) {
+      Some(digest) => digest,
+      None => {
+        hashing += 1;
+        continue;
+      }
+    };
+    let crc32 = match digest.crc32 {
+      Some(crc32) => crc32,
+      None => {
+        warn!("Leaving {} out of {}: no CRC-32 known", id, archive_name);
+        continue;
+      }
+    };
+    entries.push(Entry {
+      name: file_name(&id, &store_path),
+      storage: backends.for_id(db, &id),
+      key: store_path,
+      size: digest.size,
+      crc32,
+      modified: Some(UNIX_EPOCH + Duration::from_nanos(digest.mtime)),
+    });
+  }
+  if hashing > 0 {
+    return Some(Zip::Hashing {
+      ready: total - hashing,
+      total,
+    });
+  }
+  if entries.is_empty() {
+    return None;
+  }
+
+  let mut download = archive(entries).download(range);
+  download.content_type = Some(ContentType::ZIP);
+  download.headers.push(Header::new(
+    "Content-Disposition",
+    format!("attachment; filename=\"{}\"", archive_name),
+  ));
+  Some(Zip::Ready(Box::new(download)))
+}
+
+pub struct SeasonZip(u32);
+
+impl<'a> FromParam<'a> for SeasonZip {
+  type Error = &'a str;
+
+  fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+    param
+      .strip_suffix(".zip")
+      .and_then(|season| season.parse().ok())
+      .map(SeasonZip)
+      .ok_or(param)
+  }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/dr-who/season/<season>")]
+pub async fn season(
+  user: User,
+  db: Db,
+  backends: &State<Backends>,
+  checksums: &State<Checksums>,
+  throttles: &State<Throttles>,
+  traffic: Traffic,
+  range: ByteRange,
+  season: SeasonZip,
+) -> Option<Zip> {
+  let ids = db
+    .id_to_path
+    .keys()
+    .filter(|id| episode::parse(id).is_some_and(|episode| episode.season == season.0))
+    .cloned()
+    .collect();
+  let archive_name = format!("dr-who-season-{:02}.zip", season.0);
+  let mut download = match zip(&db, backends, checksums, ids, &range, archive_name)? {
+    Zip::Ready(download) => *download,
+    hashing => return Some(hashing),
+  };
+  throttles.apply(
+    &db,
+    &mut download,
+    &traffic.bulk(),
+    user.name.as_deref(),
+    None,
+  );
+  Some(Zip::Ready(Box::new(download)))
+}
+
+// `?ids=s04e01.mkv,s04e02.mkv` or `?ids=s04e01.mkv&ids=s04e02.mkv`.
+#[allow(clippy::too_many_arguments)]
+#[get("/dr-who/selection.zip?<ids>")]
+pub async fn selection(
+  user: User,
+  db: Db,
+  backends: &State<Backends>,
+  checksums: &State<Checksums>,
+  throttles: &State<Throttles>,
+  traffic: Traffic,
+  range: ByteRange,
+  ids: Vec<String>,
+) -> Option<Zip> {
+  let ids = ids
+    .iter()
+    .flat_map(|ids| ids.split(','))
+    .map(str::to_string)
+    .collect();
+  let zip = zip(
+    &db,
+    backends,
+    checksums,
+    ids,
+    &range,
+    "dr-who.zip".to_string(),
+  )?;
+  let mut download = match zip {
+    Zip::Ready(download) => *download,
+    hashing => return Some(hashing),
+  };
+  throttles.apply(
+    &db,
+    &mut download,
+    &traffic.bulk(),
+    user.name.as_deref(),
+    None,
+  );
+  Some(Zip::Ready(Box::new(download)))
+}
+
+#[cfg(test)]
+mod tests {
+  use time::macros::datetime;
+
+  use super::*;
+  use crate::storage::local::Local;
+
+  fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+  }
+
+  fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+  }
+
+  fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+  }
+
+  fn entry(name: &str, size: u64, crc32: u32) -> Entry {
+    Entry {
+      name: name.to_string(),
+      storage: Arc::new(Local),
+      key: name.to_string(),
+      size,
+      crc32,
+      modified: Some(datetime!(2023-05-06 12:34:56 UTC).into()),
+    }
+  }
+
+  // The generated headers, with the files' own bytes left out.
+  fn headers(segments: &Segments) -> Vec<&[u8]> {
+    segments
+      .0
+      .iter()
+      .filter_map(|segment| match segment {
+        Segment::Bytes(bytes) => Some(bytes.as_slice()),
+        Segment::Object { .. } => None,
+      })
+      .collect()
+  }
+
+  #[test]
+  fn converts_times_to_dos() {
+    let modified = datetime!(2023-05-06 12:34:56 UTC).into();
+    assert_eq!(
+      dos_time(Some(modified)),
+      ((12 << 11) | (34 << 5) | 28, (43 << 9) | (5 << 5) | 6)
+    );
+    let before = datetime!(1970-01-01 0:00 UTC).into();
+    assert_eq!(dos_time(Some(before)), (0, (1 << 5) | 1));
+    assert_eq!(dos_time(None), (0, (1 << 5) | 1));
+  }
+
+  #[test]
+  fn writes_zip64_local_headers() {
+    let segments = archive(vec![entry("a.txt", 5, 0xdeadbeef)]);
+    let local = headers(&segments)[0];
+    assert_eq!(local.len(), 30 + 5 + 20);
+    assert_eq!(u32_at(local, 0), 0x04034b50);
+    assert_eq!(u16_at(local, 4), 45);
+    assert_eq!(u16_at(local, 6), UTF8_NAMES);
+    assert_eq!(u16_at(local, 8), 0);
+    assert_eq!(u32_at(local, 14), 0xdeadbeef);
+    assert_eq!(u32_at(local, 18), u32::MAX);
+    assert_eq!(u32_at(local, 22), u32::MAX);
+    assert_eq!(u16_at(local, 26), 5);
+    assert_eq!(u16_at(local, 28), 20);
+    assert_eq!(&local[30..35], b"a.txt");
+    assert_eq!(u16_at(local, 35), 0x0001);
+    assert_eq!(u16_at(local, 37), 16);
+    assert_eq!(u64_at(local, 39), 5);
+    assert_eq!(u64_at(local, 47), 5);
+  }
+
+  #[test]
+  fn writes_zip64_central_directory() {
+    let size = 5 << 30;
+    let segments = archive(vec![entry("a.txt", 5, 1), entry("dir/b.mkv", size, 2)]);
+    let headers = headers(&segments);
+    let second = 30 + 5 + 20 + 5;
+    let central_offset = second + 30 + 9 + 20 + size;
+    assert_eq!(segments.size(), central_offset + headers[2].len() as u64);
+
+    let central = headers[2];
+    let first_size = 46 + 5 + 28;
+    assert_eq!(u32_at(central, 0), 0x02014b50);
+    assert_eq!(u16_at(central, 4), UNIX | VERSION);
+    assert_eq!(u32_at(central, 38), 0o100644 << 16);
+    assert_eq!(u32_at(central, 42), u32::MAX);
+    assert_eq!(u64_at(central, 46 + 5 + 4 + 16), 0);
+
+    let central = &central[first_size..];
+    assert_eq!(u32_at(central, 0), 0x02014b50);
+    assert_eq!(u32_at(central, 16), 2);
+    assert_eq!(&central[46..55], b"dir/b.mkv");
+    assert_eq!(u16_at(central, 55), 0x0001);
+    assert_eq!(u16_at(central, 57), 24);
+    assert_eq!(u64_at(central, 59), size);
+    assert_eq!(u64_at(central, 67), size);
+    assert_eq!(u64_at(central, 75), second);
+
+    let directory_size = (first_size + 46 + 9 + 28) as u64;
+    let end = &central[46 + 9 + 28..];
+    assert_eq!(u32_at(end, 0), 0x06064b50);
+    assert_eq!(u64_at(end, 4), 44);
+    assert_eq!(u64_at(end, 24), 2);
+    assert_eq!(u64_at(end, 32), 2);
+    assert_eq!(u64_at(end, 40), directory_size);
+    assert_eq!(u64_at(end, 48), central_offset);
+
+    let locator = &end[56..];
+    assert_eq!(u32_at(locator, 0), 0x07064b50);
+    assert_eq!(u64_at(locator, 8), central_offset + directory_size);
+    assert_eq!(u32_at(locator, 16), 1);
+
+    let end = &locator[20..];
+    assert_eq!(end.len(), 22);
+    assert_eq!(u32_at(end, 0), 0x06054b50);
+    assert_eq!(u16_at(end, 8), u16::MAX);
+    assert_eq!(u16_at(end, 10), u16::MAX);
+    assert_eq!(u32_at(end, 12), u32::MAX);
+    assert_eq!(u32_at(end, 16), u32::MAX);
+  }
+}
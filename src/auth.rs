@@ -0,0 +1,245 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rocket::{
+  http::{Header, RawStr, Status},
+  request::{FromRequest, Outcome, Request},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+  database::{Database, Db},
+  dlna::{on_lan, Access},
+};
+
+pub const REALM: &str = "file-share";
+
+// Only the SHA-256 of a token is kept, so `config.json` never holds one that works.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Token {
+  pub name: String,
+  pub sha256: String,
+  // Allows changing the library through WebDAV, and starting a rehash of it.
+  #[serde(default)]
+  pub write: bool,
+}
+
+pub fn hash(token: &str) -> String {
+  format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// The token from `Authorization: Bearer`, the password of `Authorization: Basic` (which is all most
+// WebDAV clients and players can send), or a `token` query parameter for plain links.
+pub fn credential(authorization: Option<&str>, query: Option<&str>) -> Option<String> {
+  if let Some(authorization) = authorization {
+    let (scheme, value) = authorization.trim().split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") {
+      return Some(value.trim().to_string());
+    }
+    if scheme.eq_ignore_ascii_case("basic") {
+      let decoded = STANDARD.decode(value.trim()).ok()?;
+      let decoded = String::from_utf8(decoded).ok()?;
+      return decoded
+        .split_once(':')
+        .map(|(_, password)| password.to_string());
+    }
+    return None;
+  }
+  query?
+    .split('&')
+    .filter_map(|pair| pair.split_once('='))
+    .find(|(key, _)| *key == "token")
+    .map(|(_, value)| RawStr::new(value).url_decode_lossy().into_owned())
+}
+
+// A token is needed and none of the configured ones was given.
+#[derive(Debug, Clone, Copy)]
+pub struct Denied;
+
+// Without any tokens configured the server is open, as it always was.
+pub fn check<'a>(db: &'a Database, credential: Option<&str>) -> Result<Option<&'a Token>, Denied> {
+  if db.tokens.is_empty() {
+    return Ok(None);
+  }
+  let sha256 = hash(credential.ok_or(Denied)?);
+  db.tokens
+    .iter()
+    .find(|token| token.sha256 == sha256)
+    .map(Some)
+    .ok_or(Denied)
+}
+
+pub fn challenge() -> Header<'static> {
+  Header::new(
+    "WWW-Authenticate",
+    format!("Basic realm=\"{}\", charset=\"UTF-8\"", REALM),
+  )
+}
+
+// The token given with `request`, `None` on an open server. The links handed to TVs count as a
+// read-only token called `dlna`, but only on the LAN they were handed out on.
+async fn token(request: &Request<'_>) -> Result<Option<Token>, Status> {
+  let db = match request.guard::<Db>().await {
+    Outcome::Success(db) => db,
+    _ => return Err(Status::InternalServerError),
+  };
+  let credential = credential(
+    request.headers().get_one("Authorization"),
+    request.uri().query().map(|query| query.as_str()),
+  );
+  match check(&db, credential.as_deref()) {
+    Ok(token) => Ok(token.cloned()),
+    Err(Denied) => match request.rocket().state::<Access>() {
+      Some(access) if on_lan(request) && access.allows(&db, credential.as_deref()) => {
+        Ok(Some(Token {
+          name: "dlna".to_string(),
+          sha256: String::new(),
+          write: false,
+        }))
+      }
+      _ => Err(Status::Unauthorized),
+    },
+  }
+}
+
+// Who is asking: the name of their token, or `None` on an open server.
+#[derive(Debug, Clone)]
+pub struct User {
+  pub name: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for User {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    match token(request).await {
+      Ok(token) => Outcome::Success(User {
+        name: token.map(|token| token.name),
+      }),
+      Err(status) => Outcome::Failure((status, ())),
+    }
+  }
+}
+
+// Someone allowed to start work on the library: anyone on an open server, as with `User`, and
+// otherwise only those whose token may write. WebDAV, which can delete files, does its own check
+// and stays read-only on an open server.
+#[derive(Debug, Clone)]
+pub struct Writer {
+  pub name: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Writer {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    match token(request).await {
+      Ok(None) => Outcome::Success(Writer { name: None }),
+      Ok(Some(token)) if token.write => Outcome::Success(Writer {
+        name: Some(token.name),
+      }),
+      Ok(Some(_)) => Outcome::Failure((Status::Forbidden, ())),
+      Err(status) => Outcome::Failure((status, ())),
+    }
+  }
+}
+
+#[derive(Responder)]
+#[response(status = 401)]
+pub struct Unauthorized {
+  body: &'static str,
+  challenge: Header<'static>,
+}
+
+#[catch(401)]
+pub fn unauthorized() -> Unauthorized {
+  Unauthorized {
+    body: "A valid token is required.\n",
+    challenge: challenge(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+
+  fn db(tokens: &[(&str, &str)]) -> Database {
+    let tokens: Vec<Token> = tokens
+      .iter()
+      .map(|(name, token)| Token {
+        name: name.to_string(),
+        sha256: hash(token),
+        write: false,
+      })
+      .collect();
+    serde_json::from_value(json!({ "id_to_path": {}, "tokens": tokens })).unwrap()
+  }
+
+  fn basic(user_and_password: &str) -> String {
+    format!("Basic {}", STANDARD.encode(user_and_password))
+  }
+
+  #[test]
+  fn reads_bearer_tokens() {
+    assert_eq!(
+      credential(Some("Bearer abc "), None).as_deref(),
+      Some("abc")
+    );
+    assert_eq!(credential(Some("bearer abc"), None).as_deref(), Some("abc"));
+    // The header wins over the query.
+    assert_eq!(
+      credential(Some("Bearer abc"), Some("token=xyz")).as_deref(),
+      Some("abc")
+    );
+  }
+
+  #[test]
+  fn reads_basic_passwords() {
+    let header = basic("alice:pass:word");
+    assert_eq!(
+      credential(Some(&header), None).as_deref(),
+      Some("pass:word")
+    );
+    let header = basic(":secret");
+    assert_eq!(credential(Some(&header), None).as_deref(), Some("secret"));
+    assert_eq!(credential(Some(&basic("no colon")), None), None);
+    assert_eq!(credential(Some("Basic !!!"), None), None);
+  }
+
+  #[test]
+  fn reads_query_tokens() {
+    assert_eq!(
+      credential(None, Some("collection=x&token=a%2Fb%20c%26")).as_deref(),
+      Some("a/b c&")
+    );
+    assert_eq!(credential(None, Some("tokens=abc")), None);
+    assert_eq!(credential(None, None), None);
+  }
+
+  #[test]
+  fn ignores_unknown_schemes() {
+    assert_eq!(credential(Some("Digest username=\"alice\""), None), None);
+    // Not even falling back to the query.
+    assert_eq!(credential(Some("Negotiate abc"), Some("token=abc")), None);
+    assert_eq!(credential(Some("Bearer"), None), None);
+  }
+
+  #[test]
+  fn checks_tokens() {
+    let open = db(&[]);
+    assert!(matches!(check(&open, None), Ok(None)));
+    assert!(matches!(check(&open, Some("anything")), Ok(None)));
+
+    let db = db(&[("alice", "secret"), ("bob", "writer")]);
+    let token = check(&db, Some("writer")).unwrap().unwrap();
+    assert_eq!(token.name, "bob");
+    assert!(matches!(check(&db, Some("wrong")), Err(Denied)));
+    assert!(matches!(check(&db, Some("")), Err(Denied)));
+    assert!(matches!(check(&db, None), Err(Denied)));
+    // Only hashes are compared, never the hash itself as a token.
+    assert!(matches!(check(&db, Some(&hash("secret"))), Err(Denied)));
+  }
+}
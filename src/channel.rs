@@ -0,0 +1,318 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use rocket::{
+  http::{ContentType, RawStr},
+  serde::json::Json,
+  State,
+};
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::{
+  auth::User,
+  database::{Channel, Database, Db},
+  episode,
+  media_info::MediaInfos,
+  xml,
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Slot {
+  pub id: String,
+  pub title: String,
+  // Unix seconds.
+  pub start: f64,
+  pub duration: f64,
+}
+
+// Ids with their titles and durations, in the order they air.
+type Programme = Vec<(String, String, f64)>;
+
+// The channel's ids with their durations. Ids whose duration can't be read are left out, as
+// nothing after them could be scheduled otherwise.
+fn programme(db: &Database, infos: &MediaInfos, channel: &Channel) -> Programme {
+  channel
+    .ids
+    .iter()
+    .filter_map(|id| {
+      let store_path = db.id_to_path.get(id)?;
+      let info = match db.is_local(id).then(|| infos.get(store_path)) {
+        Some(Ok(info)) => info,
+        _ => {
+          warn!(
+            "Leaving {} out of the schedule, its duration is unknown",
+            id
+          );
+          return None;
+        }
+      };
+      let duration = info.duration.filter(|duration| *duration > 0.0)?;
+      let title = match (
+        db.title(id).map(str::to_string).or(info.title),
+        episode::parse(id),
+      ) {
+        (Some(title), _) => title,
+        (None, Some(episode)) => episode.title(),
+        (None, None) => id.clone(),
+      };
+      Some((id.clone(), title, duration))
+    })
+    .collect()
+}
+
+// The slots airing between `from` and `until`, starting with the one airing at `from`.
+fn schedule(programme: &[(String, String, f64)], epoch: i64, from: f64, until: f64) -> Vec<Slot> {
+  let cycle: f64 = programme.iter().map(|(_, _, duration)| duration).sum();
+  if cycle <= 0.0 {
+    return Vec::new();
+  }
+  let elapsed = from - epoch as f64;
+  let mut start = from - elapsed.rem_euclid(cycle);
+  let mut slots = Vec::new();
+  for (id, title, duration) in programme.iter().cycle() {
+    if start >= until && !slots.is_empty() {
+      break;
+    }
+    if start + duration > from {
+      slots.push(Slot {
+        id: id.clone(),
+        title: title.clone(),
+        start,
+        duration: *duration,
+      });
+    }
+    start += duration;
+  }
+  slots
+}
+
+// The slot airing at `now`, how many seconds into it that is, and the slot after it.
+fn on_air(
+  programme: &[(String, String, f64)],
+  epoch: i64,
+  now: f64,
+) -> Option<(Slot, f64, Option<Slot>)> {
+  let current = schedule(programme, epoch, now, now).into_iter().next()?;
+  let ends = current.start + current.duration;
+  let next = schedule(programme, epoch, ends, ends).into_iter().next();
+  let offset = now - current.start;
+  Some((current, offset, next))
+}
+
+fn now() -> f64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|duration| duration.as_secs_f64())
+    .unwrap_or(0.0)
+}
+
+fn datetime(timestamp: f64) -> OffsetDateTime {
+  OffsetDateTime::from_unix_timestamp(timestamp as i64).unwrap_or(OffsetDateTime::UNIX_EPOCH)
+}
+
+pub fn rfc3339(timestamp: f64) -> String {
+  let time = datetime(timestamp);
+  format!(
+    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+    time.year(),
+    time.month() as u8,
+    time.day(),
+    time.hour(),
+    time.minute(),
+    time.second()
+  )
+}
+
+// XMLTV's own format, `YYYYMMDDhhmmss +0000`.
+fn xmltv_time(timestamp: f64) -> String {
+  let time = datetime(timestamp);
+  format!(
+    "{:04}{:02}{:02}{:02}{:02}{:02} +0000",
+    time.year(),
+    time.month() as u8,
+    time.day(),
+    time.hour(),
+    time.minute(),
+    time.second()
+  )
+}
+
+// The channel's programme and epoch. Reading durations can mean parsing files, so it's done off the
+// async threads, once per request.
+async fn programme_of(db: &Db, infos: &State<MediaInfos>, name: &str) -> Option<(Programme, i64)> {
+  let channel = db.channels.get(name)?.clone();
+  let db = db.0.clone();
+  let infos = infos.inner().clone();
+  rocket::tokio::task::spawn_blocking(move || (programme(&db, &infos, &channel), channel.epoch))
+    .await
+    .ok()
+}
+
+#[derive(Debug, Serialize)]
+pub struct Airing {
+  pub channel: String,
+  pub id: String,
+  pub title: String,
+  pub url: String,
+  // Seconds into the episode, for the player to seek to.
+  pub offset: f64,
+  pub started: String,
+  pub ends: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub next: Option<Slot>,
+}
+
+#[get("/channel/<name>/now")]
+pub async fn now_airing(
+  _user: User,
+  db: Db,
+  infos: &State<MediaInfos>,
+  name: String,
+) -> Option<Json<Airing>> {
+  let (programme, epoch) = programme_of(&db, infos, &name).await?;
+  let (current, offset, next) = on_air(&programme, epoch, now())?;
+  Some(Json(Airing {
+    channel: name,
+    url: format!("/dr-who/{}", RawStr::new(&current.id).percent_encode()),
+    offset,
+    started: rfc3339(current.start),
+    ends: rfc3339(current.start + current.duration),
+    id: current.id,
+    title: current.title,
+    next,
+  }))
+}
+
+// An XMLTV guide for the next `hours` (24 by default, at most a week).
+#[get("/channel/<name>/xmltv?<hours>")]
+pub async fn xmltv(
+  _user: User,
+  db: Db,
+  infos: &State<MediaInfos>,
+  name: String,
+  hours: Option<u32>,
+) -> Option<(ContentType, String)> {
+  let now = now();
+  let hours = hours.unwrap_or(24).clamp(1, 24 * 7);
+  let (programme, epoch) = programme_of(&db, infos, &name).await?;
+  let slots = schedule(&programme, epoch, now, now + hours as f64 * 3600.0);
+  let display_name = db.channels[&name]
+    .title
+    .clone()
+    .unwrap_or_else(|| name.clone());
+
+  let mut guide = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+  guide.push_str("<!DOCTYPE tv SYSTEM \"xmltv.dtd\">\n");
+  guide.push_str("<tv generator-info-name=\"file-share\">\n");
+  guide.push_str(&format!(
+    "  <channel id=\"{}\">\n    <display-name>{}</display-name>\n  </channel>\n",
+    xml::escape(&name),
+    xml::escape(&display_name)
+  ));
+  for slot in slots {
+    guide.push_str(&format!(
+      "  <programme start=\"{}\" stop=\"{}\" channel=\"{}\">\n    <title>{}</title>\n",
+      xmltv_time(slot.start),
+      xmltv_time(slot.start + slot.duration),
+      xml::escape(&name),
+      xml::escape(&slot.title)
+    ));
+    if let Some(episode) = episode::parse(&slot.id) {
+      if let Some(number) = episode.number {
+        // xmltv_ns numbers are zero based.
+        guide.push_str(&format!(
+          "    <episode-num system=\"xmltv_ns\">{}.{}.</episode-num>\n",
+          episode.season.saturating_sub(1),
+          number.saturating_sub(1)
+        ));
+      }
+      guide.push_str(&format!(
+        "    <episode-num system=\"onscreen\">{}</episode-num>\n",
+        xml::escape(&episode.title())
+      ));
+    }
+    guide.push_str("  </programme>\n");
+  }
+  guide.push_str("</tv>\n");
+  Some((ContentType::XML, guide))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // 300 seconds a cycle, starting at 1000.
+  const EPOCH: i64 = 1000;
+
+  fn programme() -> Programme {
+    [("s01e01", 100.0), ("s01e02", 50.0), ("s01e03", 150.0)]
+      .into_iter()
+      .map(|(id, duration)| (id.to_string(), id.to_uppercase(), duration))
+      .collect()
+  }
+
+  fn slots(from: f64, until: f64) -> Vec<(String, f64)> {
+    schedule(&programme(), EPOCH, from, until)
+      .into_iter()
+      .map(|slot| (slot.id, slot.start))
+      .collect()
+  }
+
+  fn slot(id: &str, start: f64) -> (String, f64) {
+    (id.to_string(), start)
+  }
+
+  #[test]
+  fn runs_back_from_an_epoch_in_the_future() {
+    // The cycle before the epoch started at 700.
+    assert_eq!(slots(900.0, 900.0), [slot("s01e03", 850.0)]);
+    assert_eq!(
+      slots(640.0, 720.0),
+      [slot("s01e03", 550.0), slot("s01e01", 700.0)]
+    );
+    assert_eq!(slots(0.0, 0.0), [slot("s01e03", -50.0)]);
+  }
+
+  #[test]
+  fn wraps_around_at_the_end_of_the_cycle() {
+    assert_eq!(
+      slots(1290.0, 1420.0),
+      [
+        slot("s01e03", 1150.0),
+        slot("s01e01", 1300.0),
+        slot("s01e02", 1400.0)
+      ]
+    );
+    // Many cycles later.
+    assert_eq!(
+      slots(1000.0 + 300.0 * 1000.0 + 120.0, 301_120.0)[0].0,
+      "s01e02"
+    );
+  }
+
+  #[test]
+  fn gives_one_slot_for_an_instant() {
+    assert_eq!(slots(1120.0, 1120.0), [slot("s01e02", 1100.0)]);
+    // On a boundary it is the slot starting then.
+    assert_eq!(slots(1100.0, 1100.0), [slot("s01e02", 1100.0)]);
+    assert!(schedule(&[], EPOCH, 1100.0, 1100.0).is_empty());
+  }
+
+  #[test]
+  fn gives_the_offset_into_what_is_airing() {
+    let (current, offset, next) = on_air(&programme(), EPOCH, 1120.5).unwrap();
+    assert_eq!(
+      (current.id.as_str(), current.title.as_str()),
+      ("s01e02", "S01E02")
+    );
+    assert_eq!(offset, 20.5);
+    assert_eq!(next.unwrap().start, 1150.0);
+
+    let (current, offset, next) = on_air(&programme(), EPOCH, 1299.0).unwrap();
+    assert_eq!((current.id.as_str(), offset), ("s01e03", 149.0));
+    let next = next.unwrap();
+    assert_eq!((next.id.as_str(), next.start), ("s01e01", 1300.0));
+    assert!(on_air(&[], EPOCH, 1299.0).is_none());
+  }
+}
@@ -0,0 +1,181 @@
+use rocket::{
+  http::ContentType,
+  response::{self, Responder},
+  serde::json::Json,
+  Request, State,
+};
+use serde::Serialize;
+
+use crate::{
+  auth::User,
+  database::{Database, Db, Span},
+  matroska::Chapter,
+  media_info::MediaInfos,
+  subtitles::{escape, timestamp},
+};
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+  File,
+  Override,
+  None,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Chapters {
+  pub id: String,
+  pub source: Source,
+  pub chapters: Vec<Chapter>,
+  pub intro: Option<Span>,
+  pub credits: Option<Span>,
+}
+
+const INTRO_TITLES: &[&str] = &[
+  "intro",
+  "opening",
+  "opening credits",
+  "title sequence",
+  "op",
+];
+const CREDITS_TITLES: &[&str] = &[
+  "credits",
+  "end credits",
+  "closing credits",
+  "ending",
+  "outro",
+  "ed",
+];
+
+// Chapters without an end run until the next one starts, or until the end of the file.
+fn fill_ends(chapters: &mut [Chapter], duration: Option<f64>) {
+  for index in 0..chapters.len() {
+    if chapters[index].end.is_none() {
+      chapters[index].end = chapters.get(index + 1).map(|next| next.start).or(duration);
+    }
+  }
+}
+
+fn find_span(chapters: &[Chapter], titles: &[&str]) -> Option<Span> {
+  chapters.iter().find_map(|chapter| {
+    let title = chapter.title.as_deref()?.trim().to_lowercase();
+    if !titles.contains(&title.as_str()) {
+      return None;
+    }
+    Some(Span {
+      start: chapter.start,
+      end: chapter.end?,
+    })
+  })
+}
+
+// Blocking, as it may have to parse the file.
+fn chapters(db: &Database, infos: &MediaInfos, id: &str) -> Option<Chapters> {
+  let store_path = db.id_to_path.get(id)?;
+  let markers = db.markers.get(id).cloned().unwrap_or_default();
+  let info = match db.is_local(id) {
+    true => infos.get(store_path).ok(),
+    false => None,
+  };
+  let duration = info.as_ref().and_then(|info| info.duration);
+
+  let (source, mut chapters) = if !markers.chapters.is_empty() {
+    (Source::Override, markers.chapters)
+  } else {
+    match info {
+      Some(info) if !info.chapters.is_empty() => (Source::File, info.chapters),
+      _ => (Source::None, Vec::new()),
+    }
+  };
+  chapters.sort_by(|a, b| a.start.total_cmp(&b.start));
+  fill_ends(&mut chapters, duration);
+
+  Some(Chapters {
+    id: id.to_string(),
+    source,
+    intro: markers.intro.or_else(|| find_span(&chapters, INTRO_TITLES)),
+    credits: markers
+      .credits
+      .or_else(|| find_span(&chapters, CREDITS_TITLES)),
+    chapters,
+  })
+}
+
+fn millis(seconds: f64) -> u64 {
+  (seconds.max(0.0) * 1000.0).round() as u64
+}
+
+// A WebVTT chapters track. The skip markers are added as extra cues with `intro` and `credits` as
+// their identifiers, so players can find them without the JSON.
+fn vtt(chapters: &Chapters) -> String {
+  let mut vtt = "WEBVTT\n\n".to_string();
+  let mut cue = |identifier: &str, start: f64, end: f64, title: &str| {
+    vtt.push_str(&format!(
+      "{}\n{} --> {}\n{}\n\n",
+      identifier,
+      timestamp(millis(start)),
+      timestamp(millis(end.max(start))),
+      escape(title)
+    ));
+  };
+  for (index, chapter) in chapters.chapters.iter().enumerate() {
+    let title = match &chapter.title {
+      Some(title) => title.clone(),
+      None => format!("Chapter {}", index + 1),
+    };
+    let end = chapter.end.unwrap_or(chapter.start);
+    cue(
+      &format!("chapter-{}", index + 1),
+      chapter.start,
+      end,
+      &title,
+    );
+  }
+  if let Some(intro) = chapters.intro {
+    cue("intro", intro.start, intro.end, "Intro");
+  }
+  if let Some(credits) = chapters.credits {
+    cue("credits", credits.start, credits.end, "Credits");
+  }
+  vtt
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromFormField)]
+pub enum Format {
+  Json,
+  Vtt,
+}
+
+pub enum ChaptersResponse {
+  Json(Json<Chapters>),
+  Vtt(String),
+}
+
+impl<'r> Responder<'r, 'static> for ChaptersResponse {
+  fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+    match self {
+      ChaptersResponse::Json(json) => json.respond_to(request),
+      ChaptersResponse::Vtt(vtt) => (ContentType::new("text", "vtt"), vtt).respond_to(request),
+    }
+  }
+}
+
+// `?format=vtt` gives a WebVTT chapters track instead of JSON.
+#[get("/dr-who/<id>/chapters?<format>", rank = 3)]
+pub async fn list(
+  _user: User,
+  db: Db,
+  infos: &State<MediaInfos>,
+  id: String,
+  format: Option<Format>,
+) -> Option<ChaptersResponse> {
+  let db = db.0.clone();
+  let infos = infos.inner().clone();
+  let chapters = rocket::tokio::task::spawn_blocking(move || chapters(&db, &infos, &id))
+    .await
+    .ok()??;
+  Some(match format.unwrap_or(Format::Json) {
+    Format::Json => ChaptersResponse::Json(Json(chapters)),
+    Format::Vtt => ChaptersResponse::Vtt(vtt(&chapters)),
+  })
+}
@@ -0,0 +1,296 @@
+use std::{
+  collections::{BTreeSet, HashMap},
+  fs::{self, File},
+  io::{self, Read},
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex, RwLock},
+  time::UNIX_EPOCH,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{info, warn};
+use rocket::{
+  fairing::AdHoc,
+  http::Header,
+  request::FromParam,
+  tokio::{select, sync::Notify},
+  State,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+  auth::User,
+  database::{data_dir, resolve, save_json, Db, Library},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileDigest {
+  pub size: u64,
+  pub mtime: u64,
+  pub sha256: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub blake3: Option<String>,
+  #[serde(default)]
+  pub crc32: Option<u32>,
+}
+
+impl FileDigest {
+  pub fn headers(&self) -> Vec<Header<'static>> {
+    let sha256 = STANDARD.encode(unhex(&self.sha256));
+    vec![
+      Header::new("Repr-Digest", format!("sha-256=:{}:", sha256)),
+      Header::new("Digest", format!("SHA-256={}", sha256)),
+    ]
+  }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn unhex(hex: &str) -> Vec<u8> {
+  (0..hex.len() / 2)
+    .filter_map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok())
+    .collect()
+}
+
+// Digests of files in the store, keyed by their `id_to_path` value and persisted in
+// `checksums.json`. An entry is only trusted while the file still has the size and mtime it had
+// when it was hashed.
+#[derive(Clone, Default)]
+pub struct Checksums {
+  cache: Arc<RwLock<HashMap<String, FileDigest>>>,
+  // Files for `fairing` to hash, for requests that won't wait for it.
+  queue: Arc<Mutex<BTreeSet<String>>>,
+  wake: Arc<Notify>,
+}
+
+fn cache_file_path() -> PathBuf {
+  data_dir().join("checksums.json")
+}
+
+pub fn stamp(path: &Path) -> io::Result<(u64, u64)> {
+  let metadata = fs::metadata(path)?;
+  let mtime = metadata
+    .modified()?
+    .duration_since(UNIX_EPOCH)
+    .map(|duration| duration.as_nanos() as u64)
+    .unwrap_or(0);
+  Ok((metadata.len(), mtime))
+}
+
+// Hashes the file, showing `each` every block on the way so that others can hash it in the same go.
+pub fn hash_file_with(
+  path: &Path,
+  size: u64,
+  mtime: u64,
+  mut each: impl FnMut(&[u8]),
+) -> io::Result<FileDigest> {
+  let mut file = File::open(path)?;
+  let mut sha256 = Sha256::new();
+  let mut crc32 = crc32fast::Hasher::new();
+  #[cfg(feature = "blake3")]
+  let mut blake3 = blake3::Hasher::new();
+
+  let mut buffer = vec![0; 1 << 20];
+  loop {
+    let read = file.read(&mut buffer)?;
+    if read == 0 {
+      break;
+    }
+    sha256.update(&buffer[..read]);
+    crc32.update(&buffer[..read]);
+    each(&buffer[..read]);
+    #[cfg(feature = "blake3")]
+    blake3.update(&buffer[..read]);
+  }
+
+  Ok(FileDigest {
+    size,
+    mtime,
+    sha256: format!("{:x}", sha256.finalize()),
+    #[cfg(feature = "blake3")]
+    blake3: Some(blake3.finalize().to_hex().to_string()),
+    #[cfg(not(feature = "blake3"))]
+    blake3: None,
+    crc32: Some(crc32.finalize()),
+  })
+}
+
+fn hash_file(path: &Path, size: u64, mtime: u64) -> io::Result<FileDigest> {
+  hash_file_with(path, size, mtime, |_| {})
+}
+
+impl Checksums {
+  pub fn load() -> Checksums {
+    let cache = File::open(cache_file_path())
+      .ok()
+      .and_then(|file| serde_json::from_reader(file).ok())
+      .unwrap_or_default();
+    Checksums {
+      cache: Arc::new(RwLock::new(cache)),
+      ..Checksums::default()
+    }
+  }
+
+  fn save(&self) {
+    let cache = self.cache.read().unwrap();
+    let result = save_json(&cache_file_path(), &*cache);
+    if let Err(error) = result {
+      warn!("Failed to save checksums: {}", error);
+    }
+  }
+
+  pub fn cached(&self, store_path: &str) -> Option<FileDigest> {
+    let (size, mtime) = stamp(&resolve(store_path)).ok()?;
+    let cache = self.cache.read().unwrap();
+    cache
+      .get(store_path)
+      .filter(|digest| digest.size == size && digest.mtime == mtime && digest.crc32.is_some())
+      .filter(|digest| cfg!(not(feature = "blake3")) || digest.blake3.is_some())
+      .cloned()
+  }
+
+  // The cached digest, or `None` after queueing the file to be hashed.
+  pub fn get(&self, store_path: &str) -> Option<FileDigest> {
+    let digest = self.cached(store_path);
+    if digest.is_none() && self.queue.lock().unwrap().insert(store_path.to_string()) {
+      self.wake.notify_one();
+    }
+    digest
+  }
+
+  fn next(&self) -> Option<String> {
+    self.queue.lock().unwrap().pop_first()
+  }
+
+  // Whatever was last worked out for `store_path`, even if the file has changed or gone since.
+  pub fn last_known(&self, store_path: &str) -> Option<FileDigest> {
+    self.cache.read().unwrap().get(store_path).cloned()
+  }
+
+  // Blocking: hashes the whole file unless an up to date digest is cached.
+  pub fn digest(&self, store_path: &str) -> io::Result<FileDigest> {
+    if let Some(digest) = self.cached(store_path) {
+      return Ok(digest);
+    }
+    let path = resolve(store_path);
+    let (size, mtime) = stamp(&path)?;
+    let digest = hash_file(&path, size, mtime)?;
+    self.record(store_path, digest.clone());
+    Ok(digest)
+  }
+
+  // For digests worked out elsewhere along with something else.
+  pub fn record(&self, store_path: &str, digest: FileDigest) {
+    self
+      .cache
+      .write()
+      .unwrap()
+      .insert(store_path.to_string(), digest);
+    self.save();
+  }
+
+  // For when a file is moved without its contents changing.
+  pub fn rename(&self, from: &str, to: &str) {
+    let mut cache = self.cache.write().unwrap();
+    if let Some(digest) = cache.remove(from) {
+      cache.insert(to.to_string(), digest);
+    }
+    drop(cache);
+    self.save();
+  }
+}
+
+// Hashes every mapped file that has no up to date digest yet, one at a time, once the server is up,
+// and then whatever is queued by `get` for as long as it runs.
+pub fn fairing() -> AdHoc {
+  AdHoc::on_liftoff("Checksums", |rocket| {
+    Box::pin(async move {
+      let db = rocket.state::<Library>().unwrap().snapshot();
+      let checksums = rocket.state::<Checksums>().unwrap().clone();
+      checksums.queue.lock().unwrap().extend(
+        db.id_to_path
+          .iter()
+          .filter(|(id, _)| db.is_local(id))
+          .map(|(_, store_path)| store_path.clone()),
+      );
+      let shutdown = rocket.shutdown();
+      rocket::tokio::spawn(async move {
+        rocket::tokio::pin!(shutdown);
+        loop {
+          while let Some(store_path) = checksums.next() {
+            if checksums.cached(&store_path).is_some() {
+              continue;
+            }
+            let (hashing, key) = (checksums.clone(), store_path.clone());
+            let result = rocket::tokio::task::spawn_blocking(move || hashing.digest(&key)).await;
+            match result.map_err(io::Error::other).and_then(|result| result) {
+              Ok(_) => info!("Hashed {}", store_path),
+              Err(error) => warn!("Failed to hash {}: {}", store_path, error),
+            }
+          }
+          select! {
+            _ = &mut shutdown => break,
+            _ = checksums.wake.notified() => {},
+          }
+        }
+      });
+    })
+  })
+}
+
+pub enum Algorithm {
+  Sha256,
+  #[cfg(feature = "blake3")]
+  Blake3,
+}
+
+pub struct ChecksumName {
+  id: String,
+  algorithm: Algorithm,
+}
+
+impl<'a> FromParam<'a> for ChecksumName {
+  type Error = &'a str;
+
+  fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+    if let Some(id) = param.strip_suffix(".sha256") {
+      return Ok(ChecksumName {
+        id: id.to_string(),
+        algorithm: Algorithm::Sha256,
+      });
+    }
+    #[cfg(feature = "blake3")]
+    if let Some(id) = param.strip_suffix(".blake3") {
+      return Ok(ChecksumName {
+        id: id.to_string(),
+        algorithm: Algorithm::Blake3,
+      });
+    }
+    Err(param)
+  }
+}
+
+// Output is in the format `sha256sum -c` / `b3sum -c` expect.
+#[get("/dr-who/<name>", rank = 1)]
+pub async fn checksum(
+  _user: User,
+  db: Db,
+  checksums: &State<Checksums>,
+  name: ChecksumName,
+) -> Option<String> {
+  let store_path = db.id_to_path.get(&name.id)?.clone();
+  let checksums = checksums.inner().clone();
+  let digest = rocket::tokio::task::spawn_blocking(move || checksums.digest(&store_path))
+    .await
+    .ok()?
+    .ok()?;
+  let hash = match name.algorithm {
+    Algorithm::Sha256 => digest.sha256,
+    #[cfg(feature = "blake3")]
+    Algorithm::Blake3 => digest.blake3?,
+  };
+  Some(format!("{}  {}\n", hash, name.id))
+}
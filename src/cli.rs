@@ -0,0 +1,396 @@
+use std::{
+  collections::HashSet,
+  fs,
+  path::{Path, PathBuf},
+};
+
+use crate::{
+  auth::{self, Token},
+  checksum::Checksums,
+  database::{file_store, resolve, Database, FileRecord, DEFAULT_COLLECTION},
+  dav::write::settle,
+  storage::local::store_files,
+  watch::{is_video, skipped},
+};
+
+pub fn usage(code: i32) -> ! {
+  let usage = "Usage: file-share [command]
+
+  serve                         Run the server, which is also what no command does
+  ls [--collection <name>]      List ids with their collection and file
+  add <id> <path> [--collection <name>] [--force]
+                                Give a file in file-store an id
+  rm <id> [--delete]            Forget an id, and with --delete its file if nothing else uses it
+  mv <id> <new-id>              Rename an id
+  scan [--add] [--collection <name>]
+                                Find files without an id and ids without a file
+  verify [--record]             Check every file against its recorded size and hash
+  dedup [--move] [--dry-run]    Hardlink duplicates, or move everything into objects/
+  import <file.json|file.csv> [--collection <name>] [--dry-run]
+                                Fill in episode details from a TVmaze or TMDB dump
+  export <dir> [--collection <name>] [--links]
+                                Copy the library out as a Jellyfin/Plex/Kodi tree
+  token ls | add <name> [--write] | rm <name>
+                                Manage access tokens";
+  match code {
+    0 => println!("{}", usage),
+    _ => eprintln!("{}", usage),
+  }
+  std::process::exit(code)
+}
+
+fn fail(message: String) -> ! {
+  eprintln!("{}", message);
+  std::process::exit(1)
+}
+
+// The value following `flag`, if given.
+pub fn option<'a>(args: &'a [String], flag: &str) -> Option<&'a String> {
+  args
+    .iter()
+    .position(|arg| arg == flag)
+    .and_then(|index| args.get(index + 1))
+}
+
+// Arguments that are neither flags nor the values of `--collection`.
+pub fn positional(args: &[String]) -> Vec<&String> {
+  args
+    .iter()
+    .enumerate()
+    .filter(|(index, arg)| {
+      !arg.starts_with("--") && (*index == 0 || args[index - 1] != "--collection")
+    })
+    .map(|(_, arg)| arg)
+    .collect()
+}
+
+pub fn collection(db: &Database, args: &[String]) -> Option<String> {
+  let collection = option(args, "--collection")?;
+  if !db.has_collection(collection) {
+    fail(format!("No collection named {}", collection));
+  }
+  Some(collection.clone())
+}
+
+// `path` relative to `file-store`, for paths given either way.
+fn store_path(path: &str) -> Option<String> {
+  let store = file_store().canonicalize().ok()?;
+  let path = match Path::new(path).is_absolute() {
+    true => PathBuf::from(path),
+    false => store.join(path),
+  };
+  let path = path.canonicalize().ok().filter(|path| path.is_file())?;
+  let relative = path
+    .strip_prefix(&store)
+    .ok()?
+    .to_string_lossy()
+    .into_owned();
+  (!skipped(&relative)).then_some(relative)
+}
+
+// `s04e01.mkv` for a file named like `Doctor.Who.S04E01.720p.mkv`, or else its own name.
+fn id_for(relative: &str) -> String {
+  let name = Path::new(relative)
+    .file_name()
+    .unwrap_or_default()
+    .to_string_lossy()
+    .into_owned();
+  let lower = name.to_lowercase();
+  let bytes = lower.as_bytes();
+  let digits = |from: usize| {
+    bytes[from..]
+      .iter()
+      .take_while(|byte| byte.is_ascii_digit())
+      .count()
+  };
+  for start in 0..bytes.len() {
+    if bytes[start] != b's' || (start > 0 && bytes[start - 1].is_ascii_alphanumeric()) {
+      continue;
+    }
+    let season = digits(start + 1);
+    let e = start + 1 + season;
+    if season == 0 || e >= bytes.len() || bytes[e] != b'e' {
+      continue;
+    }
+    let number = digits(e + 1);
+    if number == 0 {
+      continue;
+    }
+    let season: u32 = lower[start + 1..e].parse().unwrap_or(0);
+    let number: u32 = lower[e + 1..e + 1 + number].parse().unwrap_or(0);
+    return match Path::new(&name).extension() {
+      Some(extension) => format!(
+        "s{:02}e{:02}.{}",
+        season,
+        number,
+        extension.to_string_lossy()
+      ),
+      None => format!("s{:02}e{:02}", season, number),
+    };
+  }
+  name
+}
+
+// Hashes the file, which in a content addressed library also moves it into `objects/`. Done
+// before taking the lock, as it can take a while.
+fn settled(relative: &str, content_addressed: bool) -> (String, FileRecord) {
+  match settle(&Checksums::load(), relative, content_addressed) {
+    Ok((store_path, digest)) => (
+      store_path,
+      FileRecord {
+        size: Some(digest.size),
+        sha256: Some(digest.sha256),
+        added: None,
+      },
+    ),
+    Err(error) => fail(format!("Failed to hash {}: {}", relative, error)),
+  }
+}
+
+fn insert(db: &mut Database, id: &str, store_path: String, record: FileRecord, collection: &str) {
+  let added = db.added(id);
+  let previous = db.id_to_path.insert(id.to_string(), store_path);
+  db.records
+    .insert(id.to_string(), FileRecord { added, ..record });
+  if previous.is_none() || collection != db.collection_of(id) {
+    db.set_collection(id, collection);
+  }
+}
+
+pub fn add(args: &[String]) {
+  let (id, path) = match positional(args)[..] {
+    [id, path] => (id, path),
+    _ => {
+      eprintln!("Usage: file-share add <id> <path> [--collection <name>] [--force]");
+      std::process::exit(2);
+    }
+  };
+  let force = args.iter().any(|arg| arg == "--force");
+  let db = Database::load();
+  let collection = collection(&db, args);
+  if db.id_to_path.contains_key(id) && !force {
+    fail(format!("{} already exists, --force replaces it", id));
+  }
+  let relative = match store_path(path) {
+    Some(relative) => relative,
+    None => fail(format!("{} is not a file inside {:?}", path, file_store())),
+  };
+
+  let (store_path, record) = settled(&relative, db.content_addressed);
+  Database::edit(|db| {
+    let collection = collection.unwrap_or_else(|| db.collection_of(id).to_string());
+    insert(db, id, store_path.clone(), record, &collection);
+  });
+  println!("{} -> {}", id, store_path);
+}
+
+pub fn rm(args: &[String]) {
+  let id = match positional(args)[..] {
+    [id] => id,
+    _ => {
+      eprintln!("Usage: file-share rm <id> [--delete]");
+      std::process::exit(2);
+    }
+  };
+  let delete = args.iter().any(|arg| arg == "--delete");
+  let (store_path, orphaned) = Database::edit(|db| {
+    let local = db.is_local(id);
+    let store_path = db.remove(id)?;
+    let orphaned = local && !db.is_referenced(&store_path);
+    Some((store_path, orphaned))
+  })
+  .unwrap_or_else(|| fail(format!("No id {}", id)));
+  println!("Removed {} ({})", id, store_path);
+
+  if delete && orphaned {
+    match fs::remove_file(resolve(&store_path)) {
+      Ok(()) => println!("Deleted {}", store_path),
+      Err(error) => fail(format!("Failed to delete {}: {}", store_path, error)),
+    }
+  } else if delete {
+    println!(
+      "Kept {}, which is still in use or not stored locally",
+      store_path
+    );
+  }
+}
+
+pub fn ls(args: &[String]) {
+  let db = Database::load();
+  let ids = match collection(&db, args) {
+    Some(collection) => db.collection_ids(&collection),
+    None => {
+      let mut ids: Vec<String> = db.id_to_path.keys().cloned().collect();
+      ids.sort();
+      ids
+    }
+  };
+  for id in ids {
+    println!("{}\t{}\t{}", id, db.collection_of(&id), db.id_to_path[&id]);
+  }
+}
+
+pub fn mv(args: &[String]) {
+  let (from, to) = match positional(args)[..] {
+    [from, to] => (from, to),
+    _ => {
+      eprintln!("Usage: file-share mv <id> <new-id>");
+      std::process::exit(2);
+    }
+  };
+  let result = Database::edit(|db| {
+    if !db.id_to_path.contains_key(from) {
+      return Err(format!("No id {}", from));
+    }
+    if db.id_to_path.contains_key(to) {
+      return Err(format!("{} already exists", to));
+    }
+    db.rename(from, to);
+    Ok(())
+  });
+  match result {
+    Ok(()) => println!("{} -> {}", from, to),
+    Err(message) => fail(message),
+  }
+}
+
+pub fn scan(args: &[String]) {
+  let add = args.iter().any(|arg| arg == "--add");
+  let db = Database::load();
+  let collection = collection(&db, args).unwrap_or_else(|| DEFAULT_COLLECTION.to_string());
+
+  let mut missing: Vec<(&String, &String)> = db
+    .id_to_path
+    .iter()
+    .filter(|(id, store_path)| db.is_local(id) && !resolve(store_path).exists())
+    .collect();
+  missing.sort();
+  for (id, store_path) in &missing {
+    println!("missing: {} ({})", id, store_path);
+  }
+
+  let referenced: HashSet<&String> = db.id_to_path.values().collect();
+  let new: Vec<String> = store_files()
+    .into_iter()
+    .filter(|relative| !skipped(relative) && !referenced.contains(relative))
+    .collect();
+  for relative in &new {
+    println!("new: {}", relative);
+  }
+  println!("{} missing, {} new", missing.len(), new.len());
+  if !add {
+    return;
+  }
+
+  // Only videos get ids; subtitles and the like are found next to them by name.
+  let mut added = Vec::new();
+  for relative in new.iter().filter(|relative| is_video(relative)) {
+    let id = id_for(relative);
+    if db.id_to_path.contains_key(&id) || added.iter().any(|(other, _, _)| *other == id) {
+      println!("skipped: {} ({} is taken)", relative, id);
+      continue;
+    }
+    let (store_path, record) = settled(relative, db.content_addressed);
+    added.push((id, store_path, record));
+  }
+  Database::edit(|db| {
+    for (id, store_path, record) in &added {
+      // Someone may have taken the id meanwhile.
+      if !db.id_to_path.contains_key(id) {
+        insert(db, id, store_path.clone(), record.clone(), &collection);
+      }
+    }
+  });
+  for (id, store_path, _) in &added {
+    println!("added: {} -> {}", id, store_path);
+  }
+}
+
+pub fn token(args: &[String]) {
+  let name = args.get(1);
+  match (args.first().map(String::as_str), name) {
+    (Some("ls") | None, _) => {
+      let db = Database::load();
+      for token in &db.tokens {
+        let access = match token.write {
+          true => "write",
+          false => "read",
+        };
+        println!("{}\t{}", token.name, access);
+      }
+    }
+    (Some("add"), Some(name)) => {
+      let write = args.iter().any(|arg| arg == "--write");
+      let secret = format!(
+        "{:032x}{:032x}",
+        rand::random::<u128>(),
+        rand::random::<u128>()
+      );
+      let added = Database::edit(|db| {
+        if db.tokens.iter().any(|token| token.name == *name) {
+          return false;
+        }
+        db.tokens.push(Token {
+          name: name.clone(),
+          sha256: auth::hash(&secret),
+          write,
+        });
+        true
+      });
+      if !added {
+        fail(format!("A token named {} already exists", name));
+      }
+      // Only its hash is kept, so this is the one chance to see it.
+      println!("{}", secret);
+    }
+    (Some("rm"), Some(name)) => {
+      let removed = Database::edit(|db| {
+        let count = db.tokens.len();
+        db.tokens.retain(|token| token.name != *name);
+        db.tokens.len() < count
+      });
+      if !removed {
+        fail(format!("No token named {}", name));
+      }
+      println!("Removed token {}", name);
+    }
+    _ => {
+      eprintln!("Usage: file-share token ls | add <name> [--write] | rm <name>");
+      std::process::exit(2);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+  }
+
+  #[test]
+  fn finds_episode_ids_in_release_names() {
+    assert_eq!(id_for("incoming/Doctor.Who.S04E01.720p.mkv"), "s04e01.mkv");
+    assert_eq!(id_for("doctor_who_s4e12_hdtv.MP4"), "s04e12.MP4");
+    assert_eq!(id_for("Doctor Who - s10e100"), "s10e100");
+    assert_eq!(id_for("x264.s01e02.avi"), "s01e02.avi");
+  }
+
+  #[test]
+  fn keeps_other_names() {
+    assert_eq!(id_for("extras/Trailer.mkv"), "Trailer.mkv");
+    assert_eq!(id_for("Seasons1E2.mkv"), "Seasons1E2.mkv");
+    assert_eq!(id_for("DW.S04.Extras.mkv"), "DW.S04.Extras.mkv");
+    assert_eq!(id_for("s04e.mkv"), "s04e.mkv");
+  }
+
+  #[test]
+  fn separates_options_from_positionals() {
+    let args = args(&["a.mkv", "--collection", "classic", "b.mkv", "--move"]);
+    assert_eq!(option(&args, "--collection").unwrap(), "classic");
+    assert_eq!(option(&args, "--move"), None);
+    assert_eq!(option(&args, "--write"), None);
+    assert_eq!(positional(&args), ["a.mkv", "b.mkv"]);
+  }
+}
@@ -0,0 +1,642 @@
+use std::{
+  collections::HashMap,
+  fs::{self, File, OpenOptions},
+  io::{self, BufWriter},
+  ops::Deref,
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex, RwLock,
+  },
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{info, warn};
+use rocket::{
+  fairing::AdHoc,
+  http::Status,
+  request::{FromRequest, Outcome, Request},
+  tokio::{select, time},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  auth::Token,
+  dav::DavConfig,
+  dlna::DlnaConfig,
+  download::Disposition,
+  episode,
+  matroska::Chapter,
+  metalink::Mirror,
+  storage::StorageConfig,
+  throttle::ThrottleConfig,
+  watch::WatchConfig,
+  webhook::{Webhook, Webhooks},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Database {
+  pub id_to_path: HashMap<String, String>,
+  #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+  pub records: HashMap<String, FileRecord>,
+  // Store newly added files under `objects/` by their hash instead of under their own name.
+  #[serde(default)]
+  pub content_addressed: bool,
+  #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+  pub collections: HashMap<String, Collection>,
+  // Per id corrections for files with missing or wrong chapters.
+  #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+  pub markers: HashMap<String, Markers>,
+  #[serde(default)]
+  pub downloads: Downloads,
+  #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+  pub channels: HashMap<String, Channel>,
+  // Per id episode details, filled in by `file-share import`.
+  #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+  pub metadata: HashMap<String, Metadata>,
+  // When any are set, every request has to carry one of these.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub tokens: Vec<Token>,
+  #[serde(default)]
+  pub dav: DavConfig,
+  #[serde(default)]
+  pub dlna: DlnaConfig,
+  // Follows files moved or renamed inside `file-store`.
+  #[serde(default)]
+  pub watch: WatchConfig,
+  // Where to send events about the library and downloads.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub webhooks: Vec<Webhook>,
+  // Other servers with the same files, listed in Metalinks next to this one.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub mirrors: Vec<Mirror>,
+  // How fast files go out.
+  #[serde(default)]
+  pub throttle: ThrottleConfig,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub title: Option<String>,
+  // `YYYY-MM-DD`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub airdate: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub summary: Option<String>,
+  // Minutes.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub runtime: Option<u32>,
+}
+
+// A linear channel plays `ids` in order, looping forever, as if it had started at `epoch` (unix
+// seconds).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Channel {
+  pub ids: Vec<String>,
+  #[serde(default)]
+  pub epoch: i64,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub title: Option<String>,
+}
+
+// How downloads are presented to browsers. `disposition` can be overridden per request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Downloads {
+  #[serde(default)]
+  pub disposition: Disposition,
+  // Name files like `Doctor Who - S07 - The Day of the Doctor.mkv` rather than after their id.
+  #[serde(default = "default_true")]
+  pub titles: bool,
+}
+
+impl Default for Downloads {
+  fn default() -> Downloads {
+    Downloads {
+      disposition: Disposition::default(),
+      titles: true,
+    }
+  }
+}
+
+pub fn default_true() -> bool {
+  true
+}
+
+// Ids not listed in any collection belong to this one.
+pub const DEFAULT_COLLECTION: &str = "dr-who";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Collection {
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub ids: Vec<String>,
+  #[serde(default)]
+  pub storage: StorageConfig,
+  // The show's name as used in file names, e.g. `Doctor Who`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub title: Option<String>,
+}
+
+// What a file looked like when it was added, so `verify` can notice it changing underneath us.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileRecord {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub size: Option<u64>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub sha256: Option<String>,
+  // Seconds since the epoch, for the feeds of what's new.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub added: Option<u64>,
+}
+
+// Times are in seconds. Chapters given here replace the file's own, and `intro`/`credits` replace
+// whatever was guessed from the chapter titles.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Markers {
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub chapters: Vec<Chapter>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub intro: Option<Span>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub credits: Option<Span>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Span {
+  pub start: f64,
+  pub end: f64,
+}
+
+pub fn data_dir() -> PathBuf {
+  let data_dir = std::env::var("DATA_DIR")
+    .unwrap_or(concat!(env!("CARGO_MANIFEST_DIR"), "/", "data").to_string());
+  Path::new(&data_dir).to_path_buf()
+}
+
+// Writes `path` aside with `write` and renames it over `path`, so readers and a crash only ever see
+// a whole file. Each writer gets a file of its own to write aside, as caches are saved from many
+// threads.
+pub fn write_aside(path: &Path, write: impl FnOnce(File) -> io::Result<()>) -> io::Result<()> {
+  static WRITES: AtomicU64 = AtomicU64::new(0);
+  let count = WRITES.fetch_add(1, Ordering::Relaxed);
+  let mut partial = path.as_os_str().to_owned();
+  partial.push(format!(".{}.{}.partial", std::process::id(), count));
+  let partial = PathBuf::from(partial);
+  let result = File::create(&partial)
+    .and_then(write)
+    .and_then(|_| fs::rename(&partial, path));
+  if result.is_err() {
+    let _ = fs::remove_file(&partial);
+  }
+  result
+}
+
+pub fn save_json(path: &Path, value: &impl Serialize) -> io::Result<()> {
+  write_aside(path, |file| {
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, value)?;
+    writer.into_inner().map_err(|error| error.into_error())?;
+    Ok(())
+  })
+}
+
+pub fn file_store() -> PathBuf {
+  data_dir().join("file-store")
+}
+
+pub const OBJECTS_DIR: &str = "objects";
+
+// Values in `id_to_path` are either a path relative to `file-store`, or `sha256:<hex>` for files
+// kept in the content addressed object store.
+pub fn store_relative(store_path: &str) -> String {
+  match store_path.strip_prefix("sha256:") {
+    Some(hex) if hex.len() > 2 => format!("{}/{}/{}", OBJECTS_DIR, &hex[..2], hex),
+    _ => store_path.to_string(),
+  }
+}
+
+pub fn resolve(store_path: &str) -> PathBuf {
+  file_store().join(store_relative(store_path))
+}
+
+pub fn unix_now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|duration| duration.as_secs())
+    .unwrap_or(0)
+}
+
+fn config_file_path() -> PathBuf {
+  data_dir().join("config.json")
+}
+
+// Held by whoever is about to read `config.json` in order to write it back, be it the server or
+// the command line. Released when dropped.
+pub struct ConfigLock(#[allow(dead_code)] File);
+
+pub fn lock_config() -> ConfigLock {
+  let path = data_dir().join("config.lock");
+  let file = OpenOptions::new()
+    .create(true)
+    .truncate(false)
+    .write(true)
+    .open(&path)
+    .unwrap_or_else(|error| panic!("Failed to open {:?}: {}", path, error));
+  #[cfg(unix)]
+  {
+    use std::os::unix::io::AsRawFd;
+    // Every open of the file gets a lock of its own, so this keeps threads apart as well.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+      panic!(
+        "Failed to lock {:?}: {}",
+        path,
+        std::io::Error::last_os_error()
+      );
+    }
+  }
+  ConfigLock(file)
+}
+
+// Tells whether `config.json` was written by someone else since.
+fn config_stamp() -> Option<(SystemTime, u64)> {
+  let metadata = fs::metadata(config_file_path()).ok()?;
+  Some((metadata.modified().ok()?, metadata.len()))
+}
+
+impl Database {
+  pub fn load() -> Database {
+    let config_file_path = config_file_path();
+
+    if config_file_path.exists() {
+      let file = File::open(config_file_path).unwrap();
+      let db: Database = serde_json::from_reader(file).unwrap();
+      db
+    } else {
+      let db = Database {
+        id_to_path: HashMap::new(),
+        records: HashMap::new(),
+        content_addressed: false,
+        collections: HashMap::new(),
+        markers: HashMap::new(),
+        downloads: Downloads::default(),
+        channels: HashMap::new(),
+        metadata: HashMap::new(),
+        tokens: Vec::new(),
+        dav: DavConfig::default(),
+        dlna: DlnaConfig::default(),
+        watch: WatchConfig::default(),
+        webhooks: Vec::new(),
+        mirrors: Vec::new(),
+        throttle: ThrottleConfig::default(),
+      };
+      db.save();
+      db
+    }
+  }
+
+  // Written aside and renamed, so a reader never sees half of it. Whoever saves should be holding
+  // `lock_config` since before they loaded.
+  pub fn save(&self) {
+    let path = config_file_path();
+    let partial = path.with_extension("json.partial");
+    let file = File::create(&partial).unwrap();
+    serde_json::to_writer_pretty(file, self).unwrap();
+    fs::rename(partial, path).unwrap();
+  }
+
+  // Loads, changes and saves the database, with the lock held throughout. This is how the command
+  // line changes the library, with a running server picking the result up by itself.
+  pub fn edit<T>(change: impl FnOnce(&mut Database) -> T) -> T {
+    let _lock = lock_config();
+    let before = Database::load();
+    let mut db = before.clone();
+    let result = change(&mut db);
+    let now = unix_now();
+    db.date_entries(|_, _| now);
+    db.save();
+    // Queued for the server to send, should it be running.
+    Webhooks::default().entries_changed(&before, &db);
+    result
+  }
+
+  pub fn collection_of(&self, id: &str) -> &str {
+    self
+      .collections
+      .iter()
+      .find(|(_, collection)| collection.ids.iter().any(|member| member == id))
+      .map(|(name, _)| name.as_str())
+      .unwrap_or(DEFAULT_COLLECTION)
+  }
+
+  pub fn show_title(&self, collection: &str) -> String {
+    match self
+      .collections
+      .get(collection)
+      .and_then(|c| c.title.clone())
+    {
+      Some(title) => title,
+      None if collection == DEFAULT_COLLECTION => "Doctor Who".to_string(),
+      None => collection.to_string(),
+    }
+  }
+
+  pub fn title(&self, id: &str) -> Option<&str> {
+    self.metadata.get(id)?.title.as_deref()
+  }
+
+  // `S04E01 - Partners in Crime`, or as much of it as is known, for lists that already name the
+  // show.
+  pub fn episode_title(&self, id: &str) -> String {
+    match (episode::parse(id), self.title(id)) {
+      (Some(episode), Some(title)) if episode.is_special() => {
+        format!("S{:02} - {}", episode.season, title)
+      }
+      (Some(episode), Some(title)) => format!("{} - {}", episode.title(), title),
+      (Some(episode), None) => episode.title(),
+      (None, Some(title)) => title.to_string(),
+      (None, None) => id.to_string(),
+    }
+  }
+
+  pub fn has_collection(&self, name: &str) -> bool {
+    name == DEFAULT_COLLECTION || self.collections.contains_key(name)
+  }
+
+  // Every collection, including the default one, sorted.
+  pub fn collection_names(&self) -> Vec<String> {
+    let mut names: Vec<String> = self.collections.keys().cloned().collect();
+    names.push(DEFAULT_COLLECTION.to_string());
+    names.sort();
+    names.dedup();
+    names
+  }
+
+  pub fn collection_ids(&self, name: &str) -> Vec<String> {
+    let mut ids: Vec<String> = self
+      .id_to_path
+      .keys()
+      .filter(|id| self.collection_of(id) == name)
+      .cloned()
+      .collect();
+    ids.sort();
+    ids
+  }
+
+  // Whether any id still maps to `store_path`.
+  pub fn is_referenced(&self, store_path: &str) -> bool {
+    self.id_to_path.values().any(|value| value == store_path)
+  }
+
+  // Moves `id` into `collection`, which for the default one means out of every other.
+  pub fn set_collection(&mut self, id: &str, collection: &str) {
+    for members in self.collections.values_mut() {
+      members.ids.retain(|member| member != id);
+    }
+    if let Some(members) = self.collections.get_mut(collection) {
+      members.ids.push(id.to_string());
+    }
+  }
+
+  // Forgets `id` everywhere it is mentioned and returns what it mapped to. The file itself is
+  // left alone.
+  pub fn remove(&mut self, id: &str) -> Option<String> {
+    let store_path = self.id_to_path.remove(id)?;
+    self.records.remove(id);
+    self.metadata.remove(id);
+    self.markers.remove(id);
+    for collection in self.collections.values_mut() {
+      collection.ids.retain(|member| member != id);
+    }
+    for channel in self.channels.values_mut() {
+      channel.ids.retain(|member| member != id);
+    }
+    Some(store_path)
+  }
+
+  // Gives `from` a new name, keeping its file, details and place in collections and channels.
+  pub fn rename(&mut self, from: &str, to: &str) {
+    let store_path = match self.id_to_path.remove(from) {
+      Some(store_path) => store_path,
+      None => return,
+    };
+    self.id_to_path.insert(to.to_string(), store_path);
+    if let Some(record) = self.records.remove(from) {
+      self.records.insert(to.to_string(), record);
+    }
+    if let Some(metadata) = self.metadata.remove(from) {
+      self.metadata.insert(to.to_string(), metadata);
+    }
+    if let Some(markers) = self.markers.remove(from) {
+      self.markers.insert(to.to_string(), markers);
+    }
+    let ids = self
+      .collections
+      .values_mut()
+      .map(|collection| &mut collection.ids)
+      .chain(self.channels.values_mut().map(|channel| &mut channel.ids));
+    for ids in ids {
+      for member in ids.iter_mut().filter(|member| *member == from) {
+        *member = to.to_string();
+      }
+    }
+  }
+
+  // A second id for the same file, with the same details. Channels are left as they are.
+  pub fn copy(&mut self, from: &str, to: &str) {
+    let store_path = match self.id_to_path.get(from) {
+      Some(store_path) => store_path.clone(),
+      None => return,
+    };
+    self.id_to_path.insert(to.to_string(), store_path);
+    // The copy is new, and gets dated as such.
+    if let Some(record) = self.records.get(from).cloned() {
+      self.records.insert(
+        to.to_string(),
+        FileRecord {
+          added: None,
+          ..record
+        },
+      );
+    }
+    if let Some(metadata) = self.metadata.get(from).cloned() {
+      self.metadata.insert(to.to_string(), metadata);
+    }
+    if let Some(markers) = self.markers.get(from).cloned() {
+      self.markers.insert(to.to_string(), markers);
+    }
+    let collection = self.collection_of(from).to_string();
+    self.set_collection(to, &collection);
+  }
+
+  pub fn is_local(&self, id: &str) -> bool {
+    self
+      .collections
+      .get(self.collection_of(id))
+      .is_none_or(|collection| collection.storage.is_local())
+  }
+
+  pub fn added(&self, id: &str) -> Option<u64> {
+    self.records.get(id)?.added
+  }
+
+  // Dates every id that isn't yet, using `date` to pick when it was added. Returns whether any were.
+  pub fn date_entries(&mut self, date: impl Fn(&Database, &str) -> u64) -> bool {
+    let mut undated: Vec<String> = self
+      .id_to_path
+      .keys()
+      .filter(|id| self.added(id).is_none())
+      .cloned()
+      .collect();
+    undated.sort();
+    for id in &undated {
+      let added = date(self, id);
+      self.records.entry(id.clone()).or_default().added = Some(added);
+    }
+    !undated.is_empty()
+  }
+}
+
+// Ids that appeared in `config.json` while the server wasn't running are dated by their file, which
+// is the best guess there is, or failing that by when they were first seen.
+fn modified(db: &Database, id: &str) -> u64 {
+  let modified = match db.is_local(id) {
+    true => fs::metadata(resolve(&db.id_to_path[id]))
+      .and_then(|metadata| metadata.modified())
+      .ok()
+      .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+      .map(|modified| modified.as_secs()),
+    false => None,
+  };
+  modified.unwrap_or_else(unix_now)
+}
+
+// The database a running server works from. Requests work on a snapshot; changes are made to a
+// copy which is saved and then swapped in, so nobody ever sees half of one.
+#[derive(Clone)]
+pub struct Library {
+  current: Arc<RwLock<Arc<Database>>>,
+  webhooks: Webhooks,
+  // `config.json` as last loaded or saved here, to notice the command line changing it.
+  stamp: Arc<Mutex<Option<(SystemTime, u64)>>>,
+}
+
+impl Library {
+  pub fn new(webhooks: Webhooks) -> Library {
+    let _lock = lock_config();
+    let mut db = Database::load();
+    if db.date_entries(modified) {
+      db.save();
+    }
+    Library {
+      current: Arc::new(RwLock::new(Arc::new(db))),
+      webhooks,
+      stamp: Arc::new(Mutex::new(config_stamp())),
+    }
+  }
+
+  pub fn snapshot(&self) -> Arc<Database> {
+    self.current.read().unwrap().clone()
+  }
+
+  // Whatever was saved by someone else since, if anything. Needs the lock held.
+  fn changed(&self) -> Option<Database> {
+    let stamp = config_stamp();
+    let mut seen = self.stamp.lock().unwrap();
+    if stamp.is_none() || *seen == stamp {
+      return None;
+    }
+    *seen = stamp;
+    Some(Database::load())
+  }
+
+  pub fn update<T>(&self, change: impl FnOnce(&mut Database) -> T) -> T {
+    let _lock = lock_config();
+    let mut current = self.current.write().unwrap();
+    if let Some(db) = self.changed() {
+      *current = Arc::new(db);
+    }
+    let mut db = Database::clone(&current);
+    let result = change(&mut db);
+    let now = unix_now();
+    db.date_entries(|_, _| now);
+    db.save();
+    *self.stamp.lock().unwrap() = config_stamp();
+    self.webhooks.entries_changed(&current, &db);
+    *current = Arc::new(db);
+    result
+  }
+
+  // `update` for async code: waiting for the lock and writing config.json happen off the runtime.
+  pub async fn change<T: Send + 'static>(
+    &self,
+    change: impl FnOnce(&mut Database) -> T + Send + 'static,
+  ) -> T {
+    let library = self.clone();
+    rocket::tokio::task::spawn_blocking(move || library.update(change))
+      .await
+      .unwrap()
+  }
+
+  // Picks up changes made from the command line. Whoever made them already sent their events.
+  pub fn reload(&self) -> bool {
+    let _lock = lock_config();
+    let mut current = self.current.write().unwrap();
+    match self.changed() {
+      Some(db) => {
+        *current = Arc::new(db);
+        true
+      }
+      None => false,
+    }
+  }
+}
+
+// How often `config.json` is looked at for changes made behind the server's back.
+const RELOAD: Duration = Duration::from_secs(2);
+
+pub fn fairing() -> AdHoc {
+  AdHoc::on_liftoff("Config reload", |rocket| {
+    Box::pin(async move {
+      let library = rocket.state::<Library>().unwrap().clone();
+      let shutdown = rocket.shutdown();
+      rocket::tokio::spawn(async move {
+        rocket::tokio::pin!(shutdown);
+        loop {
+          select! {
+            _ = &mut shutdown => break,
+            _ = time::sleep(RELOAD) => {},
+          }
+          let library = library.clone();
+          match rocket::tokio::task::spawn_blocking(move || library.reload()).await {
+            Ok(true) => info!("Reloaded config.json"),
+            Ok(false) => {}
+            Err(error) => warn!("Failed to reload config.json: {}", error),
+          }
+        }
+      });
+    })
+  })
+}
+
+// The library as it was when the request came in.
+pub struct Db(pub Arc<Database>);
+
+impl Deref for Db {
+  type Target = Database;
+
+  fn deref(&self) -> &Database {
+    &self.0
+  }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Db {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    match request.rocket().state::<Library>() {
+      Some(library) => Outcome::Success(Db(library.snapshot())),
+      None => Outcome::Failure((Status::InternalServerError, ())),
+    }
+  }
+}
@@ -0,0 +1,175 @@
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+use hyper::{header::HeaderValue, Body, Request, Response, StatusCode};
+
+use super::{header, href, response, xml_response, Dav};
+use crate::xml;
+
+const DEFAULT_TIMEOUT: u64 = 3600;
+const MAX_TIMEOUT: u64 = 24 * 3600;
+
+struct Lock {
+  token: String,
+  expires: Instant,
+}
+
+// Exclusive write locks, kept in memory only. They are what Finder and Windows Explorer insist on
+// before writing; a lock covers its own path and nothing below it.
+#[derive(Clone, Default)]
+pub struct Locks {
+  held: Arc<Mutex<HashMap<String, Lock>>>,
+}
+
+// Every lock token mentioned in an `If` or `Lock-Token` header.
+fn tokens(value: Option<&str>) -> Vec<String> {
+  value
+    .unwrap_or("")
+    .split(['<', '>'])
+    .filter(|part| part.starts_with("opaquelocktoken:"))
+    .map(str::to_string)
+    .collect()
+}
+
+fn new_token() -> String {
+  let hex = format!("{:032x}", rand::random::<u128>());
+  format!(
+    "opaquelocktoken:{}-{}-{}-{}-{}",
+    &hex[..8],
+    &hex[8..12],
+    &hex[12..16],
+    &hex[16..20],
+    &hex[20..]
+  )
+}
+
+// `Timeout: Second-600` or `Infinite`, capped either way.
+fn timeout(value: Option<&str>) -> u64 {
+  value
+    .unwrap_or("")
+    .split(',')
+    .find_map(|part| {
+      let part = part.trim();
+      match part.eq_ignore_ascii_case("infinite") {
+        true => Some(MAX_TIMEOUT),
+        false => part.strip_prefix("Second-")?.parse().ok(),
+      }
+    })
+    .unwrap_or(DEFAULT_TIMEOUT)
+    .min(MAX_TIMEOUT)
+}
+
+pub fn path(segments: &[String]) -> String {
+  segments.join("/")
+}
+
+impl Locks {
+  // Whether a request carrying the `If` header `condition` may change `path`.
+  pub fn permits(&self, path: &str, condition: Option<&str>) -> bool {
+    let mut held = self.held.lock().unwrap();
+    held.retain(|_, lock| lock.expires > Instant::now());
+    match held.get(path) {
+      Some(lock) => tokens(condition).contains(&lock.token),
+      None => true,
+    }
+  }
+
+  pub fn forget(&self, path: &str) {
+    self.held.lock().unwrap().remove(path);
+  }
+
+  fn acquire(&self, path: &str, seconds: u64) -> Option<String> {
+    let mut held = self.held.lock().unwrap();
+    held.retain(|_, lock| lock.expires > Instant::now());
+    if held.contains_key(path) {
+      return None;
+    }
+    let token = new_token();
+    held.insert(
+      path.to_string(),
+      Lock {
+        token: token.clone(),
+        expires: Instant::now() + Duration::from_secs(seconds),
+      },
+    );
+    Some(token)
+  }
+
+  fn refresh(&self, path: &str, token: &str, seconds: u64) -> bool {
+    let mut held = self.held.lock().unwrap();
+    match held.get_mut(path) {
+      Some(lock) if lock.token == token && lock.expires > Instant::now() => {
+        lock.expires = Instant::now() + Duration::from_secs(seconds);
+        true
+      }
+      _ => false,
+    }
+  }
+
+  fn release(&self, path: &str, token: &str) -> bool {
+    let mut held = self.held.lock().unwrap();
+    match held.get(path) {
+      Some(lock) if lock.token == token => {
+        held.remove(path);
+        true
+      }
+      _ => false,
+    }
+  }
+}
+
+fn discovery(segments: &[String], token: &str, seconds: u64) -> String {
+  let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+  format!(
+    "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery><D:activelock><D:locktype><D:write/></D:locktype><D:lockscope><D:exclusive/></D:lockscope><D:depth>0</D:depth><D:timeout>Second-{}</D:timeout><D:locktoken><D:href>{}</D:href></D:locktoken><D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock></D:lockdiscovery></D:prop>\n",
+    seconds,
+    xml::escape(token),
+    xml::escape(&href(&segments, segments.len() < 2))
+  )
+}
+
+impl Dav {
+  // Paths don't need to exist to be locked; clients lock a name before uploading to it. A request
+  // without a body refreshes a lock named in its `If` header.
+  pub(super) async fn lock(&self, request: Request<Body>, segments: &[String]) -> Response<Body> {
+    let path = path(segments);
+    let seconds = timeout(header(request.headers(), "Timeout"));
+    let condition = tokens(header(request.headers(), "If"));
+    let body = hyper::body::to_bytes(request.into_body())
+      .await
+      .unwrap_or_default();
+
+    if body.is_empty() {
+      return match condition
+        .iter()
+        .find(|token| self.locks.refresh(&path, token, seconds))
+      {
+        Some(token) => xml_response(StatusCode::OK, discovery(segments, token, seconds)),
+        None => response(StatusCode::PRECONDITION_FAILED),
+      };
+    }
+    let token = match self.locks.acquire(&path, seconds) {
+      Some(token) => token,
+      None => return response(StatusCode::LOCKED),
+    };
+    let mut response = xml_response(StatusCode::OK, discovery(segments, &token, seconds));
+    if let Ok(value) = HeaderValue::from_str(&format!("<{}>", token)) {
+      response.headers_mut().insert("Lock-Token", value);
+    }
+    response
+  }
+
+  pub(super) fn unlock(&self, request: &Request<Body>, segments: &[String]) -> Response<Body> {
+    let path = path(segments);
+    let released = tokens(header(request.headers(), "Lock-Token"))
+      .iter()
+      .any(|token| self.locks.release(&path, token));
+    match released {
+      true => response(StatusCode::NO_CONTENT),
+      false => response(StatusCode::CONFLICT),
+    }
+  }
+}
@@ -0,0 +1,504 @@
+pub mod lock;
+pub mod write;
+
+use std::{
+  convert::Infallible,
+  net::{IpAddr, Ipv4Addr, SocketAddr},
+  path::Path,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{
+  header::{HeaderValue, AUTHORIZATION, RANGE},
+  server::conn::AddrStream,
+  service::{make_service_fn, service_fn},
+  Body, HeaderMap, Method, Request, Response, Server, StatusCode,
+};
+use log::{info, warn};
+use rocket::{fairing::AdHoc, http::RawStr};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio_util::io::ReaderStream;
+
+use crate::{
+  auth::{self, Denied},
+  checksum::Checksums,
+  database::{default_true, Database, Library},
+  download::{file_name, ByteRange, Unsatisfiable},
+  mime,
+  storage::Backends,
+  throttle::{Throttles, Traffic},
+  xml,
+};
+use lock::Locks;
+
+const PREFIX: &str = "/dav";
+const ALLOW: &str = "OPTIONS, GET, HEAD, PROPFIND, PUT, DELETE, MKCOL, COPY, MOVE, LOCK, UNLOCK";
+// Everything but these needs a token that may write.
+const READ_METHODS: &[&str] = &["OPTIONS", "GET", "HEAD", "PROPFIND"];
+
+// Rocket turns away methods it doesn't know, `PROPFIND` among them, so WebDAV gets a listener of
+// its own next to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DavConfig {
+  #[serde(default = "default_true")]
+  pub enabled: bool,
+  #[serde(default = "default_address")]
+  pub address: IpAddr,
+  #[serde(default = "default_port")]
+  pub port: u16,
+}
+
+impl Default for DavConfig {
+  fn default() -> DavConfig {
+    DavConfig {
+      enabled: true,
+      address: default_address(),
+      port: default_port(),
+    }
+  }
+}
+
+fn default_address() -> IpAddr {
+  IpAddr::V4(Ipv4Addr::LOCALHOST)
+}
+
+fn default_port() -> u16 {
+  8001
+}
+
+// The tree clients see: `/dav/<collection>/<file name>`, named like downloads are, whatever the
+// layout of `file-store` underneath.
+enum Resource {
+  Root,
+  Collection(String),
+  File { id: String, name: String },
+}
+
+// `(file name, id)` for every id in `collection`. Should two ids end up with the same name, the
+// first one in id order wins.
+fn files(db: &Database, collection: &str) -> Vec<(String, String)> {
+  let mut files: Vec<(String, String)> = Vec::new();
+  for id in db.collection_ids(collection) {
+    let name = file_name(&id, &db.id_to_path[&id]);
+    if !files.iter().any(|(existing, _)| *existing == name) {
+      files.push((name, id));
+    }
+  }
+  files
+}
+
+// The decoded segments of a path under `/dav`. Names that could step outside of where they are
+// put are refused.
+fn segments(path: &str) -> Option<Vec<String>> {
+  let path = path.strip_prefix(PREFIX)?;
+  if !path.is_empty() && !path.starts_with('/') {
+    return None;
+  }
+  path
+    .split('/')
+    .filter(|segment| !segment.is_empty())
+    .map(|segment| {
+      let segment = RawStr::new(segment).percent_decode_lossy().into_owned();
+      match segment.contains(['/', '\\']) || segment == "." || segment == ".." {
+        true => None,
+        false => Some(segment),
+      }
+    })
+    .collect()
+}
+
+fn locate(db: &Database, segments: &[String]) -> Option<Resource> {
+  match segments {
+    [] => Some(Resource::Root),
+    [collection] if db.has_collection(collection) => Some(Resource::Collection(collection.clone())),
+    [collection, name] if db.has_collection(collection) => files(db, collection)
+      .into_iter()
+      .find(|(file, _)| file == name)
+      .map(|(name, id)| Resource::File { id, name }),
+    _ => None,
+  }
+}
+
+fn href(segments: &[&str], collection: bool) -> String {
+  let mut href = String::from(PREFIX);
+  for segment in segments {
+    href.push('/');
+    href.push_str(RawStr::new(segment).percent_encode().as_str());
+  }
+  if collection {
+    href.push('/');
+  }
+  href
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+  headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+// RFC 7231's IMF-fixdate, `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(time: SystemTime) -> String {
+  let time = OffsetDateTime::from(time);
+  format!(
+    "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+    &time.weekday().to_string()[..3],
+    time.day(),
+    &time.month().to_string()[..3],
+    time.year(),
+    time.hour(),
+    time.minute(),
+    time.second()
+  )
+}
+
+fn etag(size: u64, modified: Option<SystemTime>) -> String {
+  let modified = modified
+    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+    .map(|modified| modified.as_secs())
+    .unwrap_or(0);
+  format!("\"{:x}-{:x}\"", size, modified)
+}
+
+struct Props {
+  href: String,
+  name: String,
+  // `None` for collections.
+  size: Option<u64>,
+  modified: Option<SystemTime>,
+  content_type: Option<String>,
+}
+
+impl Props {
+  fn collection(href: String, name: &str) -> Props {
+    Props {
+      href,
+      name: name.to_string(),
+      size: None,
+      modified: None,
+      content_type: None,
+    }
+  }
+
+  fn xml(&self) -> String {
+    let mut props = format!("<D:displayname>{}</D:displayname>", xml::escape(&self.name));
+    match self.size {
+      None => props.push_str("<D:resourcetype><D:collection/></D:resourcetype>"),
+      Some(size) => {
+        props.push_str("<D:resourcetype/>");
+        props.push_str(&format!(
+          "<D:getcontentlength>{}</D:getcontentlength>",
+          size
+        ));
+        props.push_str(&format!(
+          "<D:getetag>{}</D:getetag>",
+          xml::escape(&etag(size, self.modified))
+        ));
+      }
+    }
+    if let Some(modified) = self.modified {
+      props.push_str(&format!(
+        "<D:getlastmodified>{}</D:getlastmodified>",
+        http_date(modified)
+      ));
+    }
+    if let Some(content_type) = &self.content_type {
+      props.push_str(&format!(
+        "<D:getcontenttype>{}</D:getcontenttype>",
+        xml::escape(content_type)
+      ));
+    }
+    props.push_str(
+      "<D:supportedlock><D:lockentry><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry></D:supportedlock>",
+    );
+    format!(
+      "<D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+      xml::escape(&self.href),
+      props
+    )
+  }
+}
+
+fn response(status: StatusCode) -> Response<Body> {
+  let mut response = Response::new(Body::empty());
+  *response.status_mut() = status;
+  response
+}
+
+fn xml_response(status: StatusCode, body: String) -> Response<Body> {
+  let mut response = Response::new(Body::from(body));
+  *response.status_mut() = status;
+  response.headers_mut().insert(
+    "Content-Type",
+    HeaderValue::from_static("application/xml; charset=utf-8"),
+  );
+  response
+}
+
+#[derive(Clone)]
+struct Dav {
+  library: Library,
+  backends: Backends,
+  checksums: Checksums,
+  throttles: Throttles,
+  locks: Locks,
+}
+
+impl Dav {
+  // Files whose backend can't stat them are left out of listings.
+  async fn file_props(
+    &self,
+    db: &Database,
+    collection: &str,
+    id: &str,
+    name: &str,
+  ) -> Option<Props> {
+    let store_path = db.id_to_path.get(id)?;
+    let stat = self.backends.for_id(db, id).stat(store_path).await.ok()?;
+    let content_type = Path::new(name)
+      .extension()
+      .and_then(|extension| mime::from_extension(&extension.to_string_lossy()))
+      .map(|content_type| content_type.to_string());
+    Some(Props {
+      href: href(&[collection, name], false),
+      name: name.to_string(),
+      size: Some(stat.size),
+      modified: stat.modified,
+      content_type,
+    })
+  }
+
+  // Depth `infinity` is answered as if it were 1, the tree is only two levels deep anyway.
+  async fn propfind(
+    &self,
+    db: &Database,
+    request: &Request<Body>,
+    resource: Resource,
+  ) -> Response<Body> {
+    let children = header(request.headers(), "Depth")
+      .unwrap_or("infinity")
+      .trim()
+      != "0";
+
+    let mut responses = Vec::new();
+    match resource {
+      Resource::Root => {
+        responses.push(Props::collection(href(&[], true), "dav"));
+        if children {
+          for collection in db.collection_names() {
+            responses.push(Props::collection(href(&[&collection], true), &collection));
+          }
+        }
+      }
+      Resource::Collection(collection) => {
+        responses.push(Props::collection(href(&[&collection], true), &collection));
+        if children {
+          for (name, id) in files(db, &collection) {
+            responses.extend(self.file_props(db, &collection, &id, &name).await);
+          }
+        }
+      }
+      Resource::File { id, name } => {
+        match self.file_props(db, db.collection_of(&id), &id, &name).await {
+          Some(props) => responses.push(props),
+          None => return response(StatusCode::NOT_FOUND),
+        }
+      }
+    }
+
+    let mut body = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    body.push_str("<D:multistatus xmlns:D=\"DAV:\">\n");
+    for props in responses {
+      body.push_str(&props.xml());
+    }
+    body.push_str("</D:multistatus>\n");
+    xml_response(StatusCode::MULTI_STATUS, body)
+  }
+
+  async fn get(
+    &self,
+    db: &Database,
+    request: &Request<Body>,
+    traffic: Traffic,
+    user: Option<&str>,
+    id: &str,
+    name: &str,
+  ) -> Response<Body> {
+    let store_path = &db.id_to_path[id];
+    let storage = self.backends.for_id(db, id);
+    let stat = match storage.stat(store_path).await {
+      Ok(stat) => stat,
+      Err(_) => return response(StatusCode::NOT_FOUND),
+    };
+    let range = ByteRange::new(
+      request
+        .headers()
+        .get(RANGE)
+        .and_then(|range| range.to_str().ok()),
+    )
+    .resolve(stat.size);
+    let range = match range {
+      Ok(range) => range,
+      Err(Unsatisfiable) => {
+        let mut response = response(StatusCode::RANGE_NOT_SATISFIABLE);
+        if let Ok(value) = HeaderValue::from_str(&format!("bytes */{}", stat.size)) {
+          response.headers_mut().insert("Content-Range", value);
+        }
+        return response;
+      }
+    };
+
+    let mut builder = Response::builder()
+      .header("Accept-Ranges", "bytes")
+      .header("ETag", etag(stat.size, stat.modified));
+    if let Some(modified) = stat.modified {
+      builder = builder.header("Last-Modified", http_date(modified));
+    }
+    if let Some(content_type) = mime::resolve(storage.as_ref(), name, store_path, stat.size).await {
+      builder = builder.header("Content-Type", content_type.to_string());
+    }
+    let length = match &range {
+      Some(range) => {
+        builder = builder.status(StatusCode::PARTIAL_CONTENT).header(
+          "Content-Range",
+          format!("bytes {}-{}/{}", range.start, range.end - 1, stat.size),
+        );
+        range.end - range.start
+      }
+      None => stat.size,
+    };
+    builder = builder.header("Content-Length", length);
+
+    let body = match request.method() == Method::HEAD {
+      true => Body::empty(),
+      false => match storage.open(store_path, range).await {
+        Ok(reader) => {
+          let collection = Some(db.collection_of(id));
+          let reader = self
+            .throttles
+            .throttle(db, reader, &traffic, user, collection);
+          Body::wrap_stream(ReaderStream::new(reader))
+        }
+        Err(error) => {
+          warn!("Failed to open {} for WebDAV: {}", id, error);
+          return response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+      },
+    };
+    builder
+      .body(body)
+      .unwrap_or_else(|_| response(StatusCode::INTERNAL_SERVER_ERROR))
+  }
+
+  async fn handle(&self, request: Request<Body>, client: IpAddr) -> Response<Body> {
+    let db = self.library.snapshot();
+    let credential = auth::credential(
+      header(request.headers(), AUTHORIZATION.as_str()),
+      request.uri().query(),
+    );
+    let token = match auth::check(&db, credential.as_deref()) {
+      Ok(token) => token.cloned(),
+      Err(Denied) => {
+        let mut response = response(StatusCode::UNAUTHORIZED);
+        if let Ok(value) = HeaderValue::from_str(&auth::challenge().value) {
+          response.headers_mut().insert("WWW-Authenticate", value);
+        }
+        return response;
+      }
+    };
+
+    let segments = match segments(request.uri().path()) {
+      Some(segments) => segments,
+      None => return response(StatusCode::NOT_FOUND),
+    };
+    let method = request.method().as_str().to_string();
+    if method == "OPTIONS" {
+      let mut response = response(StatusCode::OK);
+      let headers = response.headers_mut();
+      headers.insert("DAV", HeaderValue::from_static("1, 2"));
+      headers.insert("Allow", HeaderValue::from_static(ALLOW));
+      // Windows' mini-redirector won't treat the share as WebDAV without it.
+      headers.insert("MS-Author-Via", HeaderValue::from_static("DAV"));
+      return response;
+    }
+    if !READ_METHODS.contains(&method.as_str()) {
+      // An open server, one without tokens, stays read-only.
+      if !token.is_some_and(|token| token.write) {
+        return response(StatusCode::FORBIDDEN);
+      }
+      return self.write(&db, request, &segments).await;
+    }
+
+    let resource = match locate(&db, &segments) {
+      Some(resource) => resource,
+      None => return response(StatusCode::NOT_FOUND),
+    };
+    match (method.as_str(), resource) {
+      ("PROPFIND", resource) => self.propfind(&db, &request, resource).await,
+      ("GET" | "HEAD", Resource::File { id, name }) => {
+        let headers = request.headers();
+        let traffic = Traffic::new(
+          Some(client),
+          header(headers, "Priority"),
+          headers.contains_key(RANGE),
+        );
+        let user = token.as_ref().map(|token| token.name.as_str());
+        self.get(&db, &request, traffic, user, &id, &name).await
+      }
+      _ => {
+        let mut response = response(StatusCode::METHOD_NOT_ALLOWED);
+        response
+          .headers_mut()
+          .insert("Allow", HeaderValue::from_static(ALLOW));
+        response
+      }
+    }
+  }
+}
+
+pub fn fairing() -> AdHoc {
+  AdHoc::on_liftoff("WebDAV", |rocket| {
+    Box::pin(async move {
+      let library = rocket.state::<Library>().unwrap().clone();
+      let config = library.snapshot().dav.clone();
+      if !config.enabled {
+        return;
+      }
+      let address = SocketAddr::new(config.address, config.port);
+      let dav = Dav {
+        library,
+        backends: rocket.state::<Backends>().unwrap().clone(),
+        checksums: rocket.state::<Checksums>().unwrap().clone(),
+        throttles: rocket.state::<Throttles>().unwrap().clone(),
+        locks: Locks::default(),
+      };
+      let server = match Server::try_bind(&address) {
+        Ok(server) => server,
+        Err(error) => {
+          warn!("WebDAV could not listen on {}: {}", address, error);
+          return;
+        }
+      };
+      let make_service = make_service_fn(move |connection: &AddrStream| {
+        let dav = dav.clone();
+        let client = connection.remote_addr().ip();
+        async move {
+          Ok::<_, Infallible>(service_fn(move |request| {
+            let dav = dav.clone();
+            async move { Ok::<_, Infallible>(dav.handle(request, client).await) }
+          }))
+        }
+      });
+      info!("WebDAV listening on http://{}{}/", address, PREFIX);
+      let shutdown = rocket.shutdown();
+      rocket::tokio::spawn(async move {
+        if let Err(error) = server
+          .serve(make_service)
+          .with_graceful_shutdown(shutdown)
+          .await
+        {
+          warn!("WebDAV server failed: {}", error);
+        }
+      });
+    })
+  })
+}
@@ -0,0 +1,407 @@
+use std::{fs, io};
+
+use futures_util::TryStreamExt;
+use hyper::{Body, Request, Response, StatusCode, Uri};
+use log::warn;
+use tokio_util::io::StreamReader;
+
+use super::{header, locate, lock, response, Dav, Resource};
+use crate::{
+  checksum::{Checksums, FileDigest},
+  database::{resolve, Collection, Database, FileRecord, DEFAULT_COLLECTION},
+  download::file_name,
+  storage::{Reader, Storage},
+};
+
+// Where uploads are kept in `file-store`, one directory per collection. Only files under here are
+// ever deleted through WebDAV; everything else just loses its id.
+const UPLOADS_DIR: &str = "uploads";
+
+// A free key for an upload of `name`, numbering it while the obvious one is used by an id or
+// already holds a file. Nothing is ever written over; a replaced file is discarded afterwards.
+async fn upload_key(db: &Database, storage: &dyn Storage, collection: &str, name: &str) -> String {
+  let mut key = format!("{}/{}/{}", UPLOADS_DIR, collection, name);
+  let mut number = 1;
+  while db.is_referenced(&key) || storage.stat(&key).await.is_ok() {
+    key = format!("{}/{}/{}-{}", UPLOADS_DIR, collection, number, name);
+    number += 1;
+  }
+  key
+}
+
+// Ids like `s04e02` are shown with their file's extension added, so renaming one to `s04e03.mkv`
+// gives `s04e03` rather than an id of a different kind.
+fn id_for(name: &str, id: &str, store_path: &str) -> String {
+  let shown = file_name(id, store_path);
+  let stem = shown
+    .strip_prefix(id)
+    .filter(|suffix| !suffix.is_empty())
+    .and_then(|suffix| name.strip_suffix(suffix))
+    .filter(|stem| !stem.is_empty());
+  stem.unwrap_or(name).to_string()
+}
+
+// Blocking: hashes a local upload and, in a content addressed library, moves it into the object
+// store the way `dedup --move` would.
+pub fn settle(
+  checksums: &Checksums,
+  key: &str,
+  content_addressed: bool,
+) -> io::Result<(String, FileDigest)> {
+  let digest = checksums.digest(key)?;
+  if !content_addressed {
+    return Ok((key.to_string(), digest));
+  }
+  let object = format!("sha256:{}", digest.sha256);
+  let (source, target) = (resolve(key), resolve(&object));
+  if target.exists() {
+    fs::remove_file(&source)?;
+  } else {
+    fs::create_dir_all(target.parent().unwrap())?;
+    fs::rename(&source, &target)?;
+  }
+  checksums.rename(key, &object);
+  Ok((object, digest))
+}
+
+fn same_storage(db: &Database, from: &str, to: &str) -> bool {
+  let local = |collection: &str| {
+    db.collections
+      .get(collection)
+      .is_none_or(|collection| collection.storage.is_local())
+  };
+  from == to || (local(from) && local(to))
+}
+
+impl Dav {
+  pub(super) async fn write(
+    &self,
+    db: &Database,
+    request: Request<Body>,
+    segments: &[String],
+  ) -> Response<Body> {
+    let method = request.method().as_str().to_string();
+    let condition = header(request.headers(), "If");
+    let guarded = !matches!(method.as_str(), "LOCK" | "UNLOCK" | "COPY");
+    if guarded && !self.locks.permits(&lock::path(segments), condition) {
+      return response(StatusCode::LOCKED);
+    }
+    match method.as_str() {
+      "PUT" => self.put(db, request, segments).await,
+      "MKCOL" => self.mkcol(db, segments).await,
+      "DELETE" => self.delete(db, segments).await,
+      "MOVE" => self.transfer(db, &request, segments, true).await,
+      "COPY" => self.transfer(db, &request, segments, false).await,
+      "LOCK" => self.lock(request, segments).await,
+      "UNLOCK" => self.unlock(&request, segments),
+      _ => response(StatusCode::METHOD_NOT_ALLOWED),
+    }
+  }
+
+  // Drops the bytes behind `store_path` once nothing maps to them, if they came in over WebDAV.
+  async fn discard(&self, collection: &str, store_path: &str) {
+    if !store_path.starts_with(&format!("{}/", UPLOADS_DIR))
+      || self.library.snapshot().is_referenced(store_path)
+    {
+      return;
+    }
+    let storage = self.backends.for_collection(collection);
+    if let Err(error) = storage.delete(store_path).await {
+      warn!("Failed to delete {}: {}", store_path, error);
+    }
+  }
+
+  // Uploading over an existing name replaces what its id points at; a new name becomes a new id.
+  async fn put(
+    &self,
+    db: &Database,
+    request: Request<Body>,
+    segments: &[String],
+  ) -> Response<Body> {
+    let (collection, name) = match segments {
+      [collection, name] if db.has_collection(collection) => (collection, name),
+      [] | [_] => return response(StatusCode::METHOD_NOT_ALLOWED),
+      _ => return response(StatusCode::CONFLICT),
+    };
+    let id = match locate(db, segments) {
+      Some(Resource::File { id, .. }) => id,
+      Some(_) => return response(StatusCode::METHOD_NOT_ALLOWED),
+      // Ids are shared by all collections.
+      None if db.id_to_path.contains_key(name) => return response(StatusCode::CONFLICT),
+      None => name.clone(),
+    };
+    let local = db
+      .collections
+      .get(collection)
+      .is_none_or(|collection| collection.storage.is_local());
+    // Finder streams uploads chunked and says how long they are in a header of its own.
+    let size = header(request.headers(), "Content-Length")
+      .or_else(|| header(request.headers(), "X-Expected-Entity-Length"))
+      .and_then(|size| size.parse::<u64>().ok());
+    if size.is_none() && !local {
+      return response(StatusCode::LENGTH_REQUIRED);
+    }
+
+    let storage = self.backends.for_collection(collection);
+    let key = upload_key(db, storage.as_ref(), collection, name).await;
+    let body: Reader = Box::pin(StreamReader::new(
+      request.into_body().map_err(io::Error::other),
+    ));
+    if let Err(error) = storage.put(&key, body, size.unwrap_or(0)).await {
+      warn!("Failed to store upload {}: {}", key, error);
+      return response(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    let (store_path, record) = match local {
+      true => {
+        let checksums = self.checksums.clone();
+        let content_addressed = db.content_addressed;
+        let settled =
+          rocket::tokio::task::spawn_blocking(move || settle(&checksums, &key, content_addressed))
+            .await
+            .map_err(io::Error::other)
+            .and_then(|settled| settled);
+        match settled {
+          Ok((store_path, digest)) => (
+            store_path,
+            FileRecord {
+              size: Some(digest.size),
+              sha256: Some(digest.sha256),
+              added: None,
+            },
+          ),
+          Err(error) => {
+            warn!("Failed to hash upload {}: {}", id, error);
+            return response(StatusCode::INTERNAL_SERVER_ERROR);
+          }
+        }
+      }
+      false => (
+        key,
+        FileRecord {
+          size,
+          sha256: None,
+          added: None,
+        },
+      ),
+    };
+
+    let (updated, entry) = (store_path.clone(), collection.clone());
+    let previous = self
+      .library
+      .change(move |db| {
+        let previous = db.id_to_path.insert(id.clone(), updated);
+        // Replacing a file keeps its id's place in the feeds.
+        let added = db.added(&id);
+        db.records
+          .insert(id.clone(), FileRecord { added, ..record });
+        if previous.is_none() {
+          db.set_collection(&id, &entry);
+        }
+        previous
+      })
+      .await;
+    match previous {
+      Some(previous) => {
+        if previous != store_path {
+          self.discard(collection, &previous).await;
+        }
+        response(StatusCode::NO_CONTENT)
+      }
+      None => response(StatusCode::CREATED),
+    }
+  }
+
+  // Collections can't be nested, so only `/dav/<name>/` can be made.
+  async fn mkcol(&self, db: &Database, segments: &[String]) -> Response<Body> {
+    match segments {
+      [name] if db.has_collection(name) => response(StatusCode::METHOD_NOT_ALLOWED),
+      [name] => {
+        let name = name.clone();
+        self
+          .library
+          .change(move |db| {
+            db.collections.insert(name, Collection::default());
+          })
+          .await;
+        response(StatusCode::CREATED)
+      }
+      [] => response(StatusCode::METHOD_NOT_ALLOWED),
+      _ => response(StatusCode::CONFLICT),
+    }
+  }
+
+  async fn delete(&self, db: &Database, segments: &[String]) -> Response<Body> {
+    let (collection, ids) = match locate(db, segments) {
+      None => return response(StatusCode::NOT_FOUND),
+      Some(Resource::Root) => return response(StatusCode::FORBIDDEN),
+      // Ids outside of any collection are in the default one, it can't go away.
+      Some(Resource::Collection(collection)) if collection == DEFAULT_COLLECTION => {
+        return response(StatusCode::FORBIDDEN)
+      }
+      Some(Resource::Collection(collection)) => {
+        let ids = db.collection_ids(&collection);
+        (collection, ids)
+      }
+      Some(Resource::File { id, .. }) => (db.collection_of(&id).to_string(), vec![id]),
+    };
+    let (whole, name) = (segments.len() == 1, collection.clone());
+    let removed: Vec<String> = self
+      .library
+      .change(move |db| {
+        let removed = ids.iter().filter_map(|id| db.remove(id)).collect();
+        if whole {
+          db.collections.remove(&name);
+        }
+        removed
+      })
+      .await;
+    for store_path in removed {
+      self.discard(&collection, &store_path).await;
+    }
+    self.locks.forget(&lock::path(segments));
+    response(StatusCode::NO_CONTENT)
+  }
+
+  // Only ever changes ids: a moved file keeps its bytes where they are, a copy is a second id for
+  // the same file.
+  async fn transfer(
+    &self,
+    db: &Database,
+    request: &Request<Body>,
+    segments: &[String],
+    moving: bool,
+  ) -> Response<Body> {
+    let destination = match header(request.headers(), "Destination")
+      .and_then(|destination| destination.parse::<Uri>().ok())
+      .and_then(|destination| super::segments(destination.path()))
+    {
+      Some(destination) => destination,
+      None => return response(StatusCode::BAD_REQUEST),
+    };
+    let overwrite = header(request.headers(), "Overwrite") != Some("F");
+    let condition = header(request.headers(), "If");
+    if !self.locks.permits(&lock::path(&destination), condition) {
+      return response(StatusCode::LOCKED);
+    }
+
+    let id = match locate(db, segments) {
+      None => return response(StatusCode::NOT_FOUND),
+      Some(Resource::Root) => return response(StatusCode::FORBIDDEN),
+      Some(Resource::Collection(from)) => {
+        return self
+          .rename_collection(db, &from, &destination, moving, overwrite)
+          .await
+      }
+      Some(Resource::File { id, .. }) => id,
+    };
+    let (collection, name) = match destination.as_slice() {
+      [collection, name] if db.has_collection(collection) => (collection, name),
+      _ => return response(StatusCode::CONFLICT),
+    };
+    // Ids of collections kept elsewhere, on S3 say, can't be pointed at files in this store.
+    if !same_storage(db, db.collection_of(&id), collection) {
+      return response(StatusCode::BAD_GATEWAY);
+    }
+    let new_id = id_for(name, &id, &db.id_to_path[&id]);
+    let replaced = match locate(db, &destination) {
+      Some(Resource::File { id: other, .. }) if other == id => {
+        return response(StatusCode::FORBIDDEN)
+      }
+      Some(Resource::File { .. }) if !overwrite => {
+        return response(StatusCode::PRECONDITION_FAILED)
+      }
+      Some(Resource::File { id: other, .. }) => Some(other),
+      Some(_) => return response(StatusCode::CONFLICT),
+      None => None,
+    };
+    let clash = db.id_to_path.contains_key(&new_id)
+      && replaced.as_ref() != Some(&new_id)
+      && !(moving && new_id == id);
+    if clash {
+      return response(StatusCode::CONFLICT);
+    }
+
+    let (renamed, entry) = (id.clone(), collection.clone());
+    let removed = self
+      .library
+      .change(move |db| {
+        let removed = replaced.and_then(|other| db.remove(&other));
+        match moving {
+          true => db.rename(&renamed, &new_id),
+          false => db.copy(&renamed, &new_id),
+        }
+        db.set_collection(&new_id, &entry);
+        removed
+      })
+      .await;
+    if moving {
+      self.locks.forget(&lock::path(segments));
+    }
+    match removed {
+      Some(store_path) => {
+        self.discard(collection, &store_path).await;
+        response(StatusCode::NO_CONTENT)
+      }
+      None => response(StatusCode::CREATED),
+    }
+  }
+
+  // Ids belong to a single collection, so collections can be renamed but not copied.
+  async fn rename_collection(
+    &self,
+    db: &Database,
+    from: &str,
+    destination: &[String],
+    moving: bool,
+    overwrite: bool,
+  ) -> Response<Body> {
+    let to = match destination {
+      [to] => to,
+      _ => return response(StatusCode::CONFLICT),
+    };
+    if !moving || from == DEFAULT_COLLECTION || to == DEFAULT_COLLECTION || from == to {
+      return response(StatusCode::FORBIDDEN);
+    }
+    if db.has_collection(to) {
+      return match overwrite {
+        true => response(StatusCode::FORBIDDEN),
+        false => response(StatusCode::PRECONDITION_FAILED),
+      };
+    }
+    let (from_name, to) = (from.to_string(), to.clone());
+    self
+      .library
+      .change(move |db| {
+        if let Some(collection) = db.collections.remove(&from_name) {
+          db.collections.insert(to, collection);
+        }
+      })
+      .await;
+    self.locks.forget(&lock::path(&[from.to_string()]));
+    response(StatusCode::CREATED)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn keeps_ids_of_the_same_kind() {
+    assert_eq!(
+      id_for("s04e03.mkv", "s04e02", "complete/s04e02.mkv"),
+      "s04e03"
+    );
+    assert_eq!(
+      id_for("s04e03.mp4", "s04e02", "complete/s04e02.mkv"),
+      "s04e03.mp4"
+    );
+    assert_eq!(
+      id_for("s04e03.mkv", "s04e02.mkv", "complete/s04e02.mkv"),
+      "s04e03.mkv"
+    );
+    assert_eq!(id_for("s04e03", "s04e02", "sha256:abc"), "s04e03");
+    assert_eq!(id_for(".mkv", "s04e02", "complete/s04e02.mkv"), ".mkv");
+  }
+}
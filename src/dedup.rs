@@ -0,0 +1,179 @@
+use std::{
+  collections::{HashMap, HashSet},
+  fs, io,
+  path::Path,
+};
+
+use crate::{
+  checksum::Checksums,
+  database::{file_store, resolve, Database},
+  storage::local::store_files,
+};
+
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+  use std::os::unix::fs::MetadataExt;
+  match (fs::metadata(a), fs::metadata(b)) {
+    (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+    _ => false,
+  }
+}
+
+#[cfg(not(unix))]
+fn same_file(_: &Path, _: &Path) -> bool {
+  false
+}
+
+fn human_size(bytes: u64) -> String {
+  let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let mut size = bytes as f64;
+  let mut unit = 0;
+  while size >= 1024.0 && unit < units.len() - 1 {
+    size /= 1024.0;
+    unit += 1;
+  }
+  format!("{:.1} {}", size, units[unit])
+}
+
+// Swap `duplicate` for a hardlink to `original` without ever leaving it missing.
+fn replace_with_link(original: &Path, duplicate: &Path) -> io::Result<()> {
+  let name = duplicate.file_name().unwrap_or_default().to_string_lossy();
+  let temporary = duplicate.with_file_name(format!(".{}.dedup", name));
+  fs::hard_link(original, &temporary)?;
+  fs::rename(&temporary, duplicate)
+}
+
+fn hardlink(checksums: &Checksums, dry_run: bool) -> (usize, u64) {
+  let mut by_hash: HashMap<String, Vec<String>> = HashMap::new();
+  for file in store_files() {
+    match checksums.digest(&file) {
+      Ok(digest) => by_hash.entry(digest.sha256).or_default().push(file),
+      Err(error) => println!("skipping {}: {}", file, error),
+    }
+  }
+
+  let (mut duplicates, mut reclaimed) = (0, 0);
+  for files in by_hash.values() {
+    let (original, rest) = files.split_first().unwrap();
+    let original_path = file_store().join(original);
+    for duplicate in rest {
+      let duplicate_path = file_store().join(duplicate);
+      if same_file(&original_path, &duplicate_path) {
+        continue;
+      }
+      let size = fs::metadata(&duplicate_path).map(|m| m.len()).unwrap_or(0);
+      println!("{} -> {}", duplicate, original);
+      if !dry_run {
+        if let Err(error) = replace_with_link(&original_path, &duplicate_path) {
+          println!("failed to link {}: {}", duplicate, error);
+          continue;
+        }
+      }
+      duplicates += 1;
+      reclaimed += size;
+    }
+  }
+  (duplicates, reclaimed)
+}
+
+// Moves every mapped file into the object store, deleting those whose contents are already there.
+// Returns where each store path went, for its ids to be pointed at the digest afterwards.
+fn move_into_objects(
+  db: &Database,
+  checksums: &Checksums,
+  dry_run: bool,
+) -> (usize, u64, HashMap<String, String>) {
+  let mut by_path: HashMap<String, Vec<String>> = HashMap::new();
+  for (id, store_path) in &db.id_to_path {
+    if !store_path.starts_with("sha256:") && db.is_local(id) {
+      by_path
+        .entry(store_path.clone())
+        .or_default()
+        .push(id.clone());
+    }
+  }
+  let mut store_paths: Vec<String> = by_path.keys().cloned().collect();
+  store_paths.sort();
+
+  let (mut duplicates, mut reclaimed) = (0, 0);
+  let mut planned = HashSet::new();
+  let mut moved = HashMap::new();
+  for store_path in store_paths {
+    let digest = match checksums.digest(&store_path) {
+      Ok(digest) => digest,
+      Err(error) => {
+        println!("skipping {}: {}", store_path, error);
+        continue;
+      }
+    };
+    let object = format!("sha256:{}", digest.sha256);
+    let (source, target) = (resolve(&store_path), resolve(&object));
+
+    let result = if target.exists() || planned.contains(&object) {
+      if !same_file(&source, &target) {
+        println!("{} is a duplicate of {}", store_path, object);
+        duplicates += 1;
+        reclaimed += digest.size;
+      }
+      if dry_run {
+        Ok(())
+      } else {
+        fs::remove_file(&source)
+      }
+    } else {
+      println!("{} -> {}", store_path, object);
+      planned.insert(object.clone());
+      if dry_run {
+        Ok(())
+      } else {
+        fs::create_dir_all(target.parent().unwrap()).and_then(|_| fs::rename(&source, &target))
+      }
+    };
+    if let Err(error) = result {
+      println!("failed to move {}: {}", store_path, error);
+      continue;
+    }
+
+    if !dry_run {
+      checksums.rename(&store_path, &object);
+      moved.insert(store_path, object);
+    }
+  }
+  (duplicates, reclaimed, moved)
+}
+
+// `file-share dedup [--move] [--dry-run]`. By default duplicates anywhere in `file-store` are
+// replaced by hardlinks and ids are left alone; `--move` migrates the library to content addressed
+// storage.
+pub fn run(args: &[String]) {
+  let migrate = args.iter().any(|arg| arg == "--move");
+  let dry_run = args.iter().any(|arg| arg == "--dry-run");
+  let checksums = Checksums::load();
+
+  // Hashing a whole library takes a while, so config.json is only locked to record the moves.
+  let (duplicates, reclaimed, moved) = if migrate {
+    move_into_objects(&Database::load(), &checksums, dry_run)
+  } else {
+    let (duplicates, reclaimed) = hardlink(&checksums, dry_run);
+    (duplicates, reclaimed, HashMap::new())
+  };
+  println!(
+    "{} duplicate files, {} reclaimed",
+    duplicates,
+    human_size(reclaimed)
+  );
+
+  if migrate && !dry_run {
+    Database::edit(|db| {
+      // Ids added meanwhile to a file that moved follow it too.
+      let following: Vec<(String, String)> = db
+        .id_to_path
+        .iter()
+        .filter(|(id, _)| db.is_local(id))
+        .filter_map(|(id, store_path)| Some((id.clone(), moved.get(store_path)?.clone())))
+        .collect();
+      db.id_to_path.extend(following);
+      db.content_addressed = true;
+    });
+  }
+}
@@ -0,0 +1,340 @@
+use std::{
+  collections::hash_map::DefaultHasher,
+  hash::{Hash, Hasher},
+};
+
+use rocket::{
+  http::{ContentType, RawStr, Status},
+  State,
+};
+
+use super::{argument, envelope, fault, Access, Lan, SoapAction, CONTENT_DIRECTORY};
+use crate::{
+  database::{Database, Db},
+  download::Base,
+  episode::{self, Episode},
+  media_info::MediaInfos,
+  mime,
+  storage::Backends,
+  xml,
+};
+
+// Object ids: `0` for the root, then `collection/<name>`, `season/<name>/<season>` and `item/<id>`.
+// Episodes are grouped by season, anything else sits directly in its collection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Object {
+  Root,
+  Collection(String),
+  Season(String, u32),
+  Item(String),
+}
+
+impl Object {
+  fn parse(db: &Database, object_id: &str) -> Option<Object> {
+    let object = if object_id == "0" {
+      Object::Root
+    } else if let Some(name) = object_id.strip_prefix("collection/") {
+      Object::Collection(name.to_string())
+    } else if let Some(rest) = object_id.strip_prefix("season/") {
+      let (name, season) = rest.rsplit_once('/')?;
+      Object::Season(name.to_string(), season.parse().ok()?)
+    } else {
+      Object::Item(object_id.strip_prefix("item/")?.to_string())
+    };
+    match &object {
+      Object::Root => {}
+      Object::Collection(name) if db.has_collection(name) => {}
+      Object::Season(name, season) if db.has_collection(name) => {
+        let ids = db.collection_ids(name);
+        if !ids.iter().any(|id| season_of(id) == Some(*season)) {
+          return None;
+        }
+      }
+      Object::Item(id) if db.id_to_path.contains_key(id) => {}
+      _ => return None,
+    }
+    Some(object)
+  }
+
+  fn id(&self) -> String {
+    match self {
+      Object::Root => "0".to_string(),
+      Object::Collection(name) => format!("collection/{}", name),
+      Object::Season(name, season) => format!("season/{}/{}", name, season),
+      Object::Item(id) => format!("item/{}", id),
+    }
+  }
+
+  fn parent(&self, db: &Database) -> String {
+    match self {
+      Object::Root => "-1".to_string(),
+      Object::Collection(_) => "0".to_string(),
+      Object::Season(name, _) => Object::Collection(name.clone()).id(),
+      Object::Item(id) => {
+        let collection = db.collection_of(id).to_string();
+        match season_of(id) {
+          Some(season) => Object::Season(collection, season).id(),
+          None => Object::Collection(collection).id(),
+        }
+      }
+    }
+  }
+
+  fn children(&self, db: &Database) -> Vec<Object> {
+    match self {
+      Object::Root => db
+        .collection_names()
+        .into_iter()
+        .filter(|name| !db.collection_ids(name).is_empty())
+        .map(Object::Collection)
+        .collect(),
+      Object::Collection(name) => {
+        let ids = db.collection_ids(name);
+        let mut seasons: Vec<u32> = ids.iter().filter_map(|id| season_of(id)).collect();
+        seasons.sort();
+        seasons.dedup();
+        let seasons = seasons
+          .into_iter()
+          .map(|season| Object::Season(name.clone(), season));
+        let loose = ids
+          .into_iter()
+          .filter(|id| season_of(id).is_none())
+          .map(Object::Item);
+        seasons.chain(loose).collect()
+      }
+      Object::Season(name, season) => {
+        let mut episodes: Vec<(Episode, String)> = db
+          .collection_ids(name)
+          .into_iter()
+          .filter_map(|id| Some((episode::parse(&id)?, id)))
+          .filter(|(episode, _)| episode.season == *season)
+          .collect();
+        // Numbered episodes in order, specials after them.
+        episodes
+          .sort_by_key(|(episode, id)| (episode.number.is_none(), episode.number, id.clone()));
+        episodes
+          .into_iter()
+          .map(|(_, id)| Object::Item(id))
+          .collect()
+      }
+      Object::Item(_) => Vec::new(),
+    }
+  }
+
+  fn title(&self, db: &Database) -> String {
+    match self {
+      Object::Root => db.dlna.name().to_string(),
+      Object::Collection(name) => db.show_title(name),
+      Object::Season(_, 0) => "Specials".to_string(),
+      Object::Season(_, season) => format!("Season {}", season),
+      Object::Item(id) => db.episode_title(id),
+    }
+  }
+}
+
+fn season_of(id: &str) -> Option<u32> {
+  episode::parse(id).map(|episode| episode.season)
+}
+
+// `H:MM:SS.mmm`, as DIDL-Lite wants durations.
+fn duration(seconds: f64) -> String {
+  let millis = (seconds * 1000.0).round() as u64;
+  format!(
+    "{}:{:02}:{:02}.{:03}",
+    millis / 3_600_000,
+    millis / 60_000 % 60,
+    millis / 1000 % 60,
+    millis % 1000
+  )
+}
+
+fn class(content_type: Option<&ContentType>) -> &'static str {
+  match content_type.map(|content_type| content_type.top().as_str()) {
+    Some("video") => "object.item.videoItem",
+    Some("audio") => "object.item.audioItem.musicTrack",
+    Some("image") => "object.item.imageItem.photo",
+    Some("text") => "object.item.textItem",
+    _ => "object.item",
+  }
+}
+
+struct Listing<'a> {
+  db: &'a Database,
+  base: &'a str,
+  backends: &'a Backends,
+  infos: &'a MediaInfos,
+  access: &'a Access,
+}
+
+impl Listing<'_> {
+  async fn didl(&self, object: &Object) -> String {
+    let db = self.db;
+    let title = xml::escape(&object.title(db));
+    let (object_id, parent) = (xml::escape(&object.id()), xml::escape(&object.parent(db)));
+    let id = match object {
+      Object::Item(id) => id,
+      _ => {
+        return format!(
+          "<container id=\"{}\" parentID=\"{}\" restricted=\"1\" searchable=\"0\" childCount=\"{}\"><dc:title>{}</dc:title><upnp:class>object.container.storageFolder</upnp:class></container>",
+          object_id,
+          parent,
+          object.children(db).len(),
+          title
+        );
+      }
+    };
+
+    let store_path = &db.id_to_path[id];
+    let storage = self.backends.for_id(db, id);
+    let size = storage.stat(store_path).await.ok().map(|stat| stat.size);
+    let content_type = match size {
+      Some(size) => mime::resolve(storage.as_ref(), id, store_path, size).await,
+      None => None,
+    };
+    // Only local files are parsed, fetching a remote one for its duration would take too long.
+    let seconds = match db.is_local(id) {
+      true => {
+        let (infos, store_path) = (self.infos.clone(), store_path.clone());
+        rocket::tokio::task::spawn_blocking(move || infos.get(&store_path))
+          .await
+          .ok()
+          .and_then(Result::ok)
+          .and_then(|info| info.duration)
+      }
+      false => None,
+    };
+
+    let mut url = format!(
+      "{}/dr-who/{}",
+      self.base,
+      RawStr::new(id).percent_encode().as_str()
+    );
+    if !db.tokens.is_empty() {
+      url.push_str(&format!("?token={}", self.access.secret()));
+    }
+    let mime = content_type
+      .as_ref()
+      .map(|content_type| format!("{}/{}", content_type.top(), content_type.sub()))
+      .unwrap_or_else(|| "application/octet-stream".to_string());
+
+    let mut didl = format!(
+      "<item id=\"{}\" parentID=\"{}\" restricted=\"1\"><dc:title>{}</dc:title><upnp:class>{}</upnp:class>",
+      object_id,
+      parent,
+      title,
+      class(content_type.as_ref())
+    );
+    if let Some(metadata) = db.metadata.get(id) {
+      if let Some(airdate) = &metadata.airdate {
+        didl.push_str(&format!("<dc:date>{}</dc:date>", xml::escape(airdate)));
+      }
+      if let Some(summary) = &metadata.summary {
+        didl.push_str(&format!(
+          "<dc:description>{}</dc:description>",
+          xml::escape(summary)
+        ));
+      }
+    }
+    didl.push_str(&format!(
+      "<res protocolInfo=\"http-get:*:{}:*\"",
+      xml::escape(&mime)
+    ));
+    if let Some(size) = size {
+      didl.push_str(&format!(" size=\"{}\"", size));
+    }
+    if let Some(seconds) = seconds {
+      didl.push_str(&format!(" duration=\"{}\"", duration(seconds)));
+    }
+    didl.push_str(&format!(">{}</res></item>", xml::escape(&url)));
+    didl
+  }
+
+  async fn result(&self, objects: &[Object]) -> String {
+    let mut result = String::from(
+      "<DIDL-Lite xmlns=\"urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xmlns:upnp=\"urn:schemas-upnp-org:metadata-1-0/upnp/\">",
+    );
+    for object in objects {
+      result.push_str(&self.didl(object).await);
+    }
+    result.push_str("</DIDL-Lite>");
+    result
+  }
+}
+
+// There is no change counter, so this is a hash of everything that shows up in listings; clients
+// only compare it with what they saw before.
+fn system_update_id(db: &Database) -> u32 {
+  let mut hasher = DefaultHasher::new();
+  let mut entries: Vec<(&String, &String)> = db.id_to_path.iter().collect();
+  entries.sort();
+  entries.hash(&mut hasher);
+  for name in db.collection_names() {
+    name.hash(&mut hasher);
+    db.collection_ids(&name).hash(&mut hasher);
+  }
+  hasher.finish() as u32
+}
+
+#[allow(clippy::too_many_arguments)]
+#[post("/upnp/control/content_directory", data = "<body>")]
+pub async fn control(
+  _lan: Lan,
+  db: Db,
+  base: Base,
+  backends: &State<Backends>,
+  infos: &State<MediaInfos>,
+  access: &State<Access>,
+  action: SoapAction,
+  body: String,
+) -> Option<(Status, (ContentType, String))> {
+  if !db.dlna.enabled {
+    return None;
+  }
+  let update_id = system_update_id(&db).to_string();
+  let arguments: Vec<(&str, String)> = match action.0.as_str() {
+    "GetSearchCapabilities" => vec![("SearchCaps", String::new())],
+    "GetSortCapabilities" => vec![("SortCaps", String::new())],
+    "GetSystemUpdateID" => vec![("Id", update_id)],
+    "Browse" => {
+      let object = match argument(&body, "ObjectID").and_then(|id| Object::parse(&db, &id)) {
+        Some(object) => object,
+        None => return Some(fault(701, "No such object")),
+      };
+      let objects = match argument(&body, "BrowseFlag").as_deref() {
+        Some("BrowseMetadata") => vec![object],
+        Some("BrowseDirectChildren") => object.children(&db),
+        _ => return Some(fault(402, "Invalid Args")),
+      };
+      let number = |name: &str| argument(&body, name).and_then(|value| value.trim().parse().ok());
+      let start: usize = number("StartingIndex").unwrap_or(0);
+      // A count of 0 asks for everything.
+      let count = match number("RequestedCount").unwrap_or(0) {
+        0 => usize::MAX,
+        count => count,
+      };
+      let total = objects.len();
+      let page: Vec<Object> = objects.into_iter().skip(start).take(count).collect();
+      let listing = Listing {
+        db: &db,
+        base: &base.0,
+        backends,
+        infos,
+        access,
+      };
+      vec![
+        ("Result", listing.result(&page).await),
+        ("NumberReturned", page.len().to_string()),
+        ("TotalMatches", total.to_string()),
+        ("UpdateID", update_id),
+      ]
+    }
+    _ => return Some(fault(401, "Invalid Action")),
+  };
+  Some((
+    Status::Ok,
+    (
+      ContentType::XML,
+      envelope(CONTENT_DIRECTORY, &action.0, &arguments),
+    ),
+  ))
+}
@@ -0,0 +1,549 @@
+pub mod content_directory;
+pub mod ssdp;
+
+use std::net::{IpAddr, SocketAddr};
+
+use log::info;
+use rocket::{
+  fairing::AdHoc,
+  http::{ContentType, Status},
+  request::{FromRequest, Outcome, Request},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+  database::{data_dir, Database, Db, Library},
+  xml,
+};
+
+pub const DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:MediaServer:1";
+pub const CONTENT_DIRECTORY: &str = "urn:schemas-upnp-org:service:ContentDirectory:1";
+pub const CONNECTION_MANAGER: &str = "urn:schemas-upnp-org:service:ConnectionManager:1";
+pub const DESCRIPTION_PATH: &str = "/upnp/description.xml";
+
+// A UPnP AV MediaServer for TVs that can't browse anything else. Off unless enabled, as it
+// answers anyone on the LAN without asking for a token. Nobody else gets an answer.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DlnaConfig {
+  #[serde(default)]
+  pub enabled: bool,
+  // What TVs list the server as, `file-share` by default.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub name: Option<String>,
+  // The address put in announcements, for when the one picked automatically isn't reachable.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub address: Option<IpAddr>,
+}
+
+impl DlnaConfig {
+  pub fn name(&self) -> &str {
+    self.name.as_deref().unwrap_or("file-share")
+  }
+}
+
+// TVs can't log in, so on a server with tokens the links they are given carry this instead. It is
+// made up at every start and only ever kept in memory, and only lets its holder read, and only
+// from the LAN.
+pub struct Access(String);
+
+impl Access {
+  pub fn new() -> Access {
+    Access(format!("{:032x}", rand::random::<u128>()))
+  }
+
+  pub fn secret(&self) -> &str {
+    &self.0
+  }
+
+  pub fn allows(&self, db: &Database, credential: Option<&str>) -> bool {
+    db.dlna.enabled && credential == Some(self.0.as_str())
+  }
+}
+
+impl Default for Access {
+  fn default() -> Access {
+    Access::new()
+  }
+}
+
+// Stable across restarts without being stored anywhere, and different for every data directory.
+pub fn uuid() -> String {
+  let hash = format!(
+    "{:x}",
+    Sha256::digest(format!("file-share {}", data_dir().display()).as_bytes())
+  );
+  format!(
+    "{}-{}-{}-{}-{}",
+    &hash[..8],
+    &hash[8..12],
+    &hash[12..16],
+    &hash[16..20],
+    &hash[20..32]
+  )
+}
+
+fn service(kind: &str, id: &str, name: &str) -> String {
+  format!(
+    "<service><serviceType>{}</serviceType><serviceId>urn:upnp-org:serviceId:{}</serviceId><SCPDURL>/upnp/{}.xml</SCPDURL><controlURL>/upnp/control/{}</controlURL><eventSubURL>/upnp/events/{}</eventSubURL></service>",
+    kind, id, name, name, name
+  )
+}
+
+#[get("/upnp/description.xml")]
+pub fn description(_lan: Lan, db: Db) -> Option<(ContentType, String)> {
+  if !db.dlna.enabled {
+    return None;
+  }
+  let mut description = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+  description.push_str(
+    "<root xmlns=\"urn:schemas-upnp-org:device-1-0\" xmlns:dlna=\"urn:schemas-dlna-org:device-1-0\">",
+  );
+  description.push_str("<specVersion><major>1</major><minor>0</minor></specVersion><device>");
+  description.push_str(&format!("<deviceType>{}</deviceType>", DEVICE_TYPE));
+  description.push_str(&format!(
+    "<friendlyName>{}</friendlyName>",
+    xml::escape(db.dlna.name())
+  ));
+  description.push_str("<manufacturer>file-share</manufacturer><modelName>file-share</modelName>");
+  description.push_str(&format!(
+    "<modelNumber>{}</modelNumber>",
+    env!("CARGO_PKG_VERSION")
+  ));
+  description.push_str(&format!("<UDN>uuid:{}</UDN>", uuid()));
+  description.push_str("<dlna:X_DLNADOC>DMS-1.50</dlna:X_DLNADOC><serviceList>");
+  description.push_str(&service(
+    CONTENT_DIRECTORY,
+    "ContentDirectory",
+    "content_directory",
+  ));
+  description.push_str(&service(
+    CONNECTION_MANAGER,
+    "ConnectionManager",
+    "connection_manager",
+  ));
+  description.push_str("</serviceList></device></root>\n");
+  Some((ContentType::XML, description))
+}
+
+// An argument is `(name, direction, related state variable)`, a state variable is `(name, type,
+// evented, allowed values)`.
+type Action<'a> = (&'a str, &'a [(&'a str, &'a str, &'a str)]);
+type Variable<'a> = (&'a str, &'a str, bool, &'a [&'a str]);
+
+fn scpd(actions: &[Action], variables: &[Variable]) -> String {
+  let mut scpd = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+  scpd.push_str("<scpd xmlns=\"urn:schemas-upnp-org:service-1-0\">");
+  scpd.push_str("<specVersion><major>1</major><minor>0</minor></specVersion><actionList>");
+  for (name, arguments) in actions {
+    scpd.push_str(&format!("<action><name>{}</name><argumentList>", name));
+    for (argument, direction, variable) in arguments.iter() {
+      scpd.push_str(&format!(
+        "<argument><name>{}</name><direction>{}</direction><relatedStateVariable>{}</relatedStateVariable></argument>",
+        argument, direction, variable
+      ));
+    }
+    scpd.push_str("</argumentList></action>");
+  }
+  scpd.push_str("</actionList><serviceStateTable>");
+  for (name, kind, evented, allowed) in variables {
+    scpd.push_str(&format!(
+      "<stateVariable sendEvents=\"{}\"><name>{}</name><dataType>{}</dataType>",
+      if *evented { "yes" } else { "no" },
+      name,
+      kind
+    ));
+    if !allowed.is_empty() {
+      scpd.push_str("<allowedValueList>");
+      for value in allowed.iter() {
+        scpd.push_str(&format!("<allowedValue>{}</allowedValue>", value));
+      }
+      scpd.push_str("</allowedValueList>");
+    }
+    scpd.push_str("</stateVariable>");
+  }
+  scpd.push_str("</serviceStateTable></scpd>\n");
+  scpd
+}
+
+#[get("/upnp/content_directory.xml")]
+pub fn content_directory_scpd(_lan: Lan, db: Db) -> Option<(ContentType, String)> {
+  if !db.dlna.enabled {
+    return None;
+  }
+  let browse: &[(&str, &str, &str)] = &[
+    ("ObjectID", "in", "A_ARG_TYPE_ObjectID"),
+    ("BrowseFlag", "in", "A_ARG_TYPE_BrowseFlag"),
+    ("Filter", "in", "A_ARG_TYPE_Filter"),
+    ("StartingIndex", "in", "A_ARG_TYPE_Index"),
+    ("RequestedCount", "in", "A_ARG_TYPE_Count"),
+    ("SortCriteria", "in", "A_ARG_TYPE_SortCriteria"),
+    ("Result", "out", "A_ARG_TYPE_Result"),
+    ("NumberReturned", "out", "A_ARG_TYPE_Count"),
+    ("TotalMatches", "out", "A_ARG_TYPE_Count"),
+    ("UpdateID", "out", "A_ARG_TYPE_UpdateID"),
+  ];
+  let actions: &[Action] = &[
+    ("Browse", browse),
+    (
+      "GetSearchCapabilities",
+      &[("SearchCaps", "out", "SearchCapabilities")],
+    ),
+    (
+      "GetSortCapabilities",
+      &[("SortCaps", "out", "SortCapabilities")],
+    ),
+    ("GetSystemUpdateID", &[("Id", "out", "SystemUpdateID")]),
+  ];
+  let variables: &[Variable] = &[
+    ("A_ARG_TYPE_ObjectID", "string", false, &[]),
+    (
+      "A_ARG_TYPE_BrowseFlag",
+      "string",
+      false,
+      &["BrowseMetadata", "BrowseDirectChildren"],
+    ),
+    ("A_ARG_TYPE_Filter", "string", false, &[]),
+    ("A_ARG_TYPE_Index", "ui4", false, &[]),
+    ("A_ARG_TYPE_Count", "ui4", false, &[]),
+    ("A_ARG_TYPE_SortCriteria", "string", false, &[]),
+    ("A_ARG_TYPE_Result", "string", false, &[]),
+    ("A_ARG_TYPE_UpdateID", "ui4", false, &[]),
+    ("SearchCapabilities", "string", false, &[]),
+    ("SortCapabilities", "string", false, &[]),
+    ("SystemUpdateID", "ui4", true, &[]),
+  ];
+  Some((ContentType::XML, scpd(actions, variables)))
+}
+
+#[get("/upnp/connection_manager.xml")]
+pub fn connection_manager_scpd(_lan: Lan, db: Db) -> Option<(ContentType, String)> {
+  if !db.dlna.enabled {
+    return None;
+  }
+  let connection_info: &[(&str, &str, &str)] = &[
+    ("ConnectionID", "in", "A_ARG_TYPE_ConnectionID"),
+    ("RcsID", "out", "A_ARG_TYPE_RcsID"),
+    ("AVTransportID", "out", "A_ARG_TYPE_AVTransportID"),
+    ("ProtocolInfo", "out", "A_ARG_TYPE_ProtocolInfo"),
+    (
+      "PeerConnectionManager",
+      "out",
+      "A_ARG_TYPE_ConnectionManager",
+    ),
+    ("PeerConnectionID", "out", "A_ARG_TYPE_ConnectionID"),
+    ("Direction", "out", "A_ARG_TYPE_Direction"),
+    ("Status", "out", "A_ARG_TYPE_ConnectionStatus"),
+  ];
+  let actions: &[Action] = &[
+    (
+      "GetProtocolInfo",
+      &[
+        ("Source", "out", "SourceProtocolInfo"),
+        ("Sink", "out", "SinkProtocolInfo"),
+      ],
+    ),
+    (
+      "GetCurrentConnectionIDs",
+      &[("ConnectionIDs", "out", "CurrentConnectionIDs")],
+    ),
+    ("GetCurrentConnectionInfo", connection_info),
+  ];
+  let variables: &[Variable] = &[
+    ("SourceProtocolInfo", "string", true, &[]),
+    ("SinkProtocolInfo", "string", true, &[]),
+    ("CurrentConnectionIDs", "string", true, &[]),
+    (
+      "A_ARG_TYPE_ConnectionStatus",
+      "string",
+      false,
+      &[
+        "OK",
+        "ContentFormatMismatch",
+        "InsufficientBandwidth",
+        "UnreliableChannel",
+        "Unknown",
+      ],
+    ),
+    ("A_ARG_TYPE_ConnectionManager", "string", false, &[]),
+    (
+      "A_ARG_TYPE_Direction",
+      "string",
+      false,
+      &["Input", "Output"],
+    ),
+    ("A_ARG_TYPE_ProtocolInfo", "string", false, &[]),
+    ("A_ARG_TYPE_ConnectionID", "i4", false, &[]),
+    ("A_ARG_TYPE_AVTransportID", "i4", false, &[]),
+    ("A_ARG_TYPE_RcsID", "i4", false, &[]),
+  ];
+  Some((ContentType::XML, scpd(actions, variables)))
+}
+
+// Whether `request` comes straight from the LAN. UPnP has no authentication and the links it hands
+// out carry the `Access` secret, so it is only for those who could have found the server over SSDP
+// anyway. A request a proxy passed on could come from anywhere, whatever the proxy's address.
+pub fn on_lan(request: &Request<'_>) -> bool {
+  let headers = request.headers();
+  if ["X-Forwarded-For", "X-Real-IP", "Forwarded"]
+    .iter()
+    .any(|name| headers.contains(*name))
+  {
+    return false;
+  }
+  request.remote().is_some_and(|remote| is_local(remote.ip()))
+}
+
+fn is_local(ip: IpAddr) -> bool {
+  match ip {
+    IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+    IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+      Some(ip) => is_local(IpAddr::V4(ip)),
+      // Loopback, unique local `fc00::/7` and link-local `fe80::/10`.
+      None => {
+        ip.is_loopback()
+          || ip.segments()[0] & 0xfe00 == 0xfc00
+          || ip.segments()[0] & 0xffc0 == 0xfe80
+      }
+    },
+  }
+}
+
+// Refuses everything under `/upnp` to those not on the LAN.
+pub struct Lan;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Lan {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    match on_lan(request) {
+      true => Outcome::Success(Lan),
+      false => Outcome::Failure((Status::Forbidden, ())),
+    }
+  }
+}
+
+// The action named in the `SOAPACTION` header, `"urn:...:ContentDirectory:1#Browse"`.
+pub struct SoapAction(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SoapAction {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    match request
+      .headers()
+      .get_one("SOAPACTION")
+      .and_then(|value| value.trim_matches('"').split_once('#'))
+    {
+      Some((_, action)) => Outcome::Success(SoapAction(action.to_string())),
+      None => Outcome::Failure((Status::BadRequest, ())),
+    }
+  }
+}
+
+// The text of the first element called `name`, whatever namespace prefix it was given.
+pub fn argument(body: &str, name: &str) -> Option<String> {
+  let mut rest = body;
+  while let Some(start) = rest.find('<') {
+    rest = &rest[start + 1..];
+    let tag_end = rest.find('>')?;
+    let tag = &rest[..tag_end];
+    let tag_name = tag
+      .trim_end_matches('/')
+      .split_whitespace()
+      .next()
+      .unwrap_or("");
+    let local = tag_name.rsplit(':').next().unwrap_or("");
+    if local == name && !tag.starts_with('/') {
+      if tag.ends_with('/') {
+        return Some(String::new());
+      }
+      let content = &rest[tag_end + 1..];
+      let end = content.find("</")?;
+      return Some(xml::unescape(&content[..end]));
+    }
+    rest = &rest[tag_end + 1..];
+  }
+  None
+}
+
+pub fn envelope(service: &str, action: &str, arguments: &[(&str, String)]) -> String {
+  let mut body = format!(
+    "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><u:{}Response xmlns:u=\"{}\">",
+    action, service
+  );
+  for (name, value) in arguments {
+    body.push_str(&format!("<{0}>{1}</{0}>", name, xml::escape(value)));
+  }
+  body.push_str(&format!("</u:{}Response></s:Body></s:Envelope>\n", action));
+  body
+}
+
+pub fn fault(code: u16, description: &str) -> (Status, (ContentType, String)) {
+  let body = format!(
+    "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail><UPnPError xmlns=\"urn:schemas-upnp-org:control-1-0\"><errorCode>{}</errorCode><errorDescription>{}</errorDescription></UPnPError></detail></s:Fault></s:Body></s:Envelope>\n",
+    code, description
+  );
+  (Status::InternalServerError, (ContentType::XML, body))
+}
+
+// Only ever a source of streams over HTTP; nothing is tracked per connection.
+#[post("/upnp/control/connection_manager", data = "<body>")]
+pub fn connection_manager(
+  _lan: Lan,
+  db: Db,
+  action: SoapAction,
+  body: String,
+) -> Option<(Status, (ContentType, String))> {
+  if !db.dlna.enabled {
+    return None;
+  }
+  let arguments: Vec<(&str, String)> = match action.0.as_str() {
+    "GetProtocolInfo" => vec![
+      ("Source", "http-get:*:*:*".to_string()),
+      ("Sink", String::new()),
+    ],
+    "GetCurrentConnectionIDs" => vec![("ConnectionIDs", "0".to_string())],
+    "GetCurrentConnectionInfo" if argument(&body, "ConnectionID").as_deref() == Some("0") => vec![
+      ("RcsID", "-1".to_string()),
+      ("AVTransportID", "-1".to_string()),
+      ("ProtocolInfo", String::new()),
+      ("PeerConnectionManager", String::new()),
+      ("PeerConnectionID", "-1".to_string()),
+      ("Direction", "Output".to_string()),
+      ("Status", "OK".to_string()),
+    ],
+    "GetCurrentConnectionInfo" => return Some(fault(706, "Invalid connection reference")),
+    _ => return Some(fault(401, "Invalid Action")),
+  };
+  Some((
+    Status::Ok,
+    (
+      ContentType::XML,
+      envelope(CONNECTION_MANAGER, &action.0, &arguments),
+    ),
+  ))
+}
+
+// Starts SSDP once Rocket is listening, so the announced location is known to work.
+pub fn fairing() -> AdHoc {
+  AdHoc::on_liftoff("DLNA", |rocket| {
+    Box::pin(async move {
+      let library = rocket.state::<Library>().unwrap().clone();
+      let config = library.snapshot().dlna.clone();
+      if !config.enabled {
+        return;
+      }
+      let http = SocketAddr::new(rocket.config().address, rocket.config().port);
+      info!(
+        "Announcing {} as a UPnP MediaServer, uuid:{}",
+        config.name(),
+        uuid()
+      );
+      rocket::tokio::spawn(ssdp::run(config, http, uuid(), rocket.shutdown()));
+    })
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rocket::{http::Header, local::asynchronous::Client};
+
+  const BROWSE: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+  <s:Body>
+    <u:Browse xmlns:u="urn:schemas-upnp-org:service:ContentDirectory:1">
+      <ObjectID>collection:classic &amp; new</ObjectID>
+      <BrowseFlag>BrowseDirectChildren</BrowseFlag>
+      <Filter/>
+      <StartingIndex xmlns:dt="urn:schemas-microsoft-com:datatypes" dt:dt="ui4">10</StartingIndex>
+      <u:RequestedCount>25</u:RequestedCount>
+      <SortCriteria></SortCriteria>
+    </u:Browse>
+  </s:Body>
+</s:Envelope>"#;
+
+  #[test]
+  fn reads_soap_arguments() {
+    assert_eq!(
+      argument(BROWSE, "ObjectID").as_deref(),
+      Some("collection:classic & new")
+    );
+    assert_eq!(
+      argument(BROWSE, "BrowseFlag").as_deref(),
+      Some("BrowseDirectChildren")
+    );
+    assert_eq!(argument(BROWSE, "StartingIndex").as_deref(), Some("10"));
+    assert_eq!(argument(BROWSE, "RequestedCount").as_deref(), Some("25"));
+  }
+
+  #[test]
+  fn reads_empty_soap_arguments() {
+    assert_eq!(argument(BROWSE, "Filter").as_deref(), Some(""));
+    assert_eq!(argument(BROWSE, "SortCriteria").as_deref(), Some(""));
+    assert_eq!(argument(BROWSE, "ConnectionID"), None);
+    assert_eq!(argument("<Broken", "Broken"), None);
+  }
+
+  #[test]
+  fn wraps_responses_in_envelopes() {
+    let body = envelope(
+      CONNECTION_MANAGER,
+      "GetCurrentConnectionIDs",
+      &[
+        ("ConnectionIDs", "0".to_string()),
+        ("Note", "<&>".to_string()),
+      ],
+    );
+    assert!(body.contains(
+      "<u:GetCurrentConnectionIDsResponse xmlns:u=\"urn:schemas-upnp-org:service:ConnectionManager:1\">"
+    ));
+    assert!(body.contains("<ConnectionIDs>0</ConnectionIDs><Note>&lt;&amp;&gt;</Note>"));
+    assert_eq!(argument(&body, "Note").as_deref(), Some("<&>"));
+  }
+
+  async fn lan(remote: &str, forwarded: bool) -> bool {
+    let client = Client::untracked(rocket::build()).await.unwrap();
+    let mut request = client
+      .post("/upnp/control/content_directory")
+      .remote(remote.parse().unwrap())
+      .header(Header::new(
+        "SOAPACTION",
+        format!("\"{}#Browse\"", CONTENT_DIRECTORY),
+      ))
+      .body(BROWSE);
+    if forwarded {
+      request.add_header(Header::new("X-Forwarded-For", "192.168.1.20"));
+    }
+    matches!(Lan::from_request(&request).await, Outcome::Success(_))
+  }
+
+  #[rocket::async_test]
+  async fn refuses_browsing_from_outside_the_lan() {
+    assert!(!lan("203.0.113.7:50000", false).await);
+    assert!(!lan("[2001:db8::1]:50000", false).await);
+    assert!(!lan("[::ffff:8.8.8.8]:50000", false).await);
+    // Through a proxy on the same machine.
+    assert!(!lan("127.0.0.1:50000", true).await);
+  }
+
+  #[rocket::async_test]
+  async fn answers_the_lan() {
+    for remote in [
+      "127.0.0.1:50000",
+      "192.168.1.20:50000",
+      "10.0.0.5:50000",
+      "172.16.4.4:50000",
+      "169.254.10.1:50000",
+      "[::1]:50000",
+      "[fd12:3456::1]:50000",
+      "[fe80::1]:50000",
+      "[::ffff:192.168.1.20]:50000",
+    ] {
+      assert!(lan(remote, false).await, "{}", remote);
+    }
+  }
+}
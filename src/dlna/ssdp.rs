@@ -0,0 +1,213 @@
+use std::{
+  io,
+  net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket as StdUdpSocket},
+  time::{Duration, SystemTime},
+};
+
+use log::{info, warn};
+use rocket::{
+  tokio::{net::UdpSocket, select, time},
+  Shutdown,
+};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use super::{DlnaConfig, CONNECTION_MANAGER, CONTENT_DIRECTORY, DESCRIPTION_PATH, DEVICE_TYPE};
+use crate::dav::http_date;
+
+const GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const PORT: u16 = 1900;
+const MAX_AGE: u64 = 1800;
+// Well within `MAX_AGE`, so a missed announcement or two doesn't make the server disappear.
+const ANNOUNCE_EVERY: Duration = Duration::from_secs(600);
+
+// Port 1900 is shared with every other UPnP program on the machine, hence the address reuse.
+fn bind() -> io::Result<UdpSocket> {
+  let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+  socket.set_reuse_address(true)?;
+  socket.set_nonblocking(true)?;
+  socket.bind(&SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, PORT)).into())?;
+  socket.set_multicast_loop_v4(true)?;
+  // Joining on loopback as well lets clients on the same machine find us without a network.
+  for interface in [Ipv4Addr::UNSPECIFIED, Ipv4Addr::LOCALHOST] {
+    if let Err(error) = socket.join_multicast_v4(&GROUP, &interface) {
+      if interface.is_unspecified() {
+        warn!("Failed to join the SSDP group: {}", error);
+      }
+    }
+  }
+  UdpSocket::from_std(socket.into())
+}
+
+struct Announcer {
+  config: DlnaConfig,
+  http: SocketAddr,
+  uuid: String,
+}
+
+impl Announcer {
+  // What the device and its services are known as, each announced on its own.
+  fn targets(&self) -> Vec<String> {
+    vec![
+      "upnp:rootdevice".to_string(),
+      format!("uuid:{}", self.uuid),
+      DEVICE_TYPE.to_string(),
+      CONTENT_DIRECTORY.to_string(),
+      CONNECTION_MANAGER.to_string(),
+    ]
+  }
+
+  fn usn(&self, target: &str) -> String {
+    match target.starts_with("uuid:") {
+      true => target.to_string(),
+      false => format!("uuid:{}::{}", self.uuid, target),
+    }
+  }
+
+  // The address `peer` should fetch the description from. Rocket bound to all interfaces doesn't
+  // say which one that is, so ask the routing table.
+  fn location(&self, peer: SocketAddr) -> String {
+    let ip = match (self.config.address, self.http.ip()) {
+      (Some(address), _) => address,
+      (None, ip) if !ip.is_unspecified() => ip,
+      (None, _) => StdUdpSocket::bind("0.0.0.0:0")
+        .and_then(|socket| {
+          socket.connect(peer)?;
+          socket.local_addr()
+        })
+        .map(|local| local.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+    };
+    format!(
+      "http://{}{}",
+      SocketAddr::new(ip, self.http.port()),
+      DESCRIPTION_PATH
+    )
+  }
+
+  fn server() -> String {
+    format!(
+      "{}/{} UPnP/1.0 file-share/{}",
+      std::env::consts::OS,
+      std::env::consts::ARCH,
+      env!("CARGO_PKG_VERSION")
+    )
+  }
+
+  fn notify(&self, target: &str, alive: bool) -> String {
+    let group = SocketAddr::V4(SocketAddrV4::new(GROUP, PORT));
+    let mut message = format!(
+      "NOTIFY * HTTP/1.1\r\nHOST: {}\r\nNT: {}\r\nUSN: {}\r\n",
+      group,
+      target,
+      self.usn(target)
+    );
+    match alive {
+      true => message.push_str(&format!(
+        "NTS: ssdp:alive\r\nCACHE-CONTROL: max-age={}\r\nLOCATION: {}\r\nSERVER: {}\r\n",
+        MAX_AGE,
+        self.location(group),
+        Announcer::server()
+      )),
+      false => message.push_str("NTS: ssdp:byebye\r\n"),
+    }
+    message.push_str("\r\n");
+    message
+  }
+
+  fn reply(&self, target: &str, peer: SocketAddr) -> String {
+    format!(
+      "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age={}\r\nDATE: {}\r\nEXT:\r\nLOCATION: {}\r\nSERVER: {}\r\nST: {}\r\nUSN: {}\r\nContent-Length: 0\r\n\r\n",
+      MAX_AGE,
+      http_date(SystemTime::now()),
+      self.location(peer),
+      Announcer::server(),
+      target,
+      self.usn(target)
+    )
+  }
+
+  async fn announce(&self, socket: &UdpSocket, alive: bool) {
+    let group = SocketAddr::V4(SocketAddrV4::new(GROUP, PORT));
+    for target in self.targets() {
+      if let Err(error) = socket
+        .send_to(self.notify(&target, alive).as_bytes(), group)
+        .await
+      {
+        warn!("Failed to send an SSDP announcement: {}", error);
+        return;
+      }
+    }
+  }
+
+  // Answers `M-SEARCH` requests for anything we are; everything else on the group is ignored.
+  async fn answer(&self, socket: &UdpSocket, message: &[u8], peer: SocketAddr) {
+    let message = String::from_utf8_lossy(message);
+    let mut lines = message.lines();
+    if !lines
+      .next()
+      .is_some_and(|line| line.starts_with("M-SEARCH * "))
+    {
+      return;
+    }
+    let header = |name: &str| {
+      message.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key
+          .trim()
+          .eq_ignore_ascii_case(name)
+          .then(|| value.trim().trim_matches('"').to_string())
+      })
+    };
+    if header("MAN").as_deref() != Some("ssdp:discover") {
+      return;
+    }
+    let search = match header("ST") {
+      Some(search) => search,
+      None => return,
+    };
+    let targets: Vec<String> = match search.as_str() {
+      "ssdp:all" => self.targets(),
+      _ => self
+        .targets()
+        .into_iter()
+        .filter(|target| *target == search)
+        .collect(),
+    };
+    for target in targets {
+      if let Err(error) = socket
+        .send_to(self.reply(&target, peer).as_bytes(), peer)
+        .await
+      {
+        warn!("Failed to answer an SSDP search from {}: {}", peer, error);
+        return;
+      }
+    }
+  }
+}
+
+// Announces the server until Rocket shuts down, then says goodbye so it vanishes from TVs at once.
+pub async fn run(config: DlnaConfig, http: SocketAddr, uuid: String, shutdown: Shutdown) {
+  let socket = match bind() {
+    Ok(socket) => socket,
+    Err(error) => {
+      warn!("Failed to listen for SSDP on port {}: {}", PORT, error);
+      return;
+    }
+  };
+  info!("Listening for SSDP searches on port {}", PORT);
+  let announcer = Announcer { config, http, uuid };
+  let mut ticker = time::interval(ANNOUNCE_EVERY);
+  let mut buffer = [0; 2048];
+  rocket::tokio::pin!(shutdown);
+  loop {
+    select! {
+      _ = &mut shutdown => break,
+      _ = ticker.tick() => announcer.announce(&socket, true).await,
+      received = socket.recv_from(&mut buffer) => match received {
+        Ok((length, peer)) => announcer.answer(&socket, &buffer[..length], peer).await,
+        Err(error) => warn!("Failed to receive SSDP: {}", error),
+      },
+    }
+  }
+  announcer.announce(&socket, false).await;
+}
@@ -0,0 +1,467 @@
+use std::{
+  io,
+  ops::Range,
+  path::Path,
+  pin::Pin,
+  task::{Context, Poll},
+};
+
+use rocket::{
+  http::{ContentType, Header, Status},
+  request::{FromRequest, Outcome, Request},
+  response::{self, Responder, Response},
+  tokio::io::{AsyncRead, AsyncSeek, ReadBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  database::Database,
+  episode::{self, humanize},
+  mime,
+  storage::Storage,
+};
+
+pub type Body = Pin<Box<dyn AsyncRead + Send>>;
+
+// The name to save `id` under, borrowing the stored file's extension when the id has none.
+pub fn file_name(id: &str, store_path: &str) -> String {
+  if Path::new(id).extension().is_some() || store_path.starts_with("sha256:") {
+    return id.to_string();
+  }
+  match Path::new(store_path).extension() {
+    Some(extension) => format!("{}.{}", id, extension.to_string_lossy()),
+    None => id.to_string(),
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, FromFormField)]
+#[serde(rename_all = "lowercase")]
+pub enum Disposition {
+  // Play in the browser, but still suggest a name for "Save as".
+  #[default]
+  Inline,
+  Attachment,
+}
+
+// Drops the characters Windows and most file managers refuse in names.
+pub fn sanitize(name: &str) -> String {
+  name
+    .chars()
+    .filter(|c| {
+      !matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') && !c.is_control()
+    })
+    .collect()
+}
+
+// What the browser should save `id` as. With titles enabled, episodes get the show's name and their
+// imported or readable title; the extension comes from the stored file or its sniffed type.
+pub fn download_name(
+  db: &Database,
+  id: &str,
+  store_path: &str,
+  content_type: Option<&ContentType>,
+) -> String {
+  let name = file_name(id, store_path);
+  let path = Path::new(&name);
+  let extension = match path.extension() {
+    Some(extension) => Some(extension.to_string_lossy().into_owned()),
+    None => content_type.and_then(mime::extension).map(str::to_string),
+  };
+  let stem = match episode::parse(id) {
+    Some(episode) if db.downloads.titles => {
+      let show = db.show_title(db.collection_of(id));
+      match (episode.number, db.title(id)) {
+        (Some(_), Some(title)) => format!("{} - {} - {}", show, episode.title(), title),
+        (None, Some(title)) => format!("{} - S{:02} - {}", show, episode.season, title),
+        (_, None) => format!("{} - {}", show, episode.title()),
+      }
+    }
+    _ if db.downloads.titles && path.extension().is_none() => humanize(id),
+    _ => path
+      .file_stem()
+      .map(|stem| stem.to_string_lossy().into_owned())
+      .unwrap_or_else(|| id.to_string()),
+  };
+  let stem = sanitize(&stem);
+  match extension {
+    Some(extension) => format!("{}.{}", stem, extension),
+    None => stem,
+  }
+}
+
+// RFC 6266: a plain ASCII `filename` for old clients plus the exact name as UTF-8 in `filename*`.
+pub fn content_disposition(disposition: Disposition, name: &str) -> Header<'static> {
+  let fallback: String = name
+    .chars()
+    .map(|c| match c {
+      ' '..='~' if c != '"' && c != '\\' => c,
+      _ => '_',
+    })
+    .collect();
+  let mut encoded = String::new();
+  for byte in name.bytes() {
+    match byte {
+      b'A'..=b'Z'
+      | b'a'..=b'z'
+      | b'0'..=b'9'
+      | b'!'
+      | b'#'
+      | b'$'
+      | b'&'
+      | b'+'
+      | b'-'
+      | b'.'
+      | b'^'
+      | b'_'
+      | b'`'
+      | b'|'
+      | b'~' => encoded.push(byte as char),
+      _ => encoded.push_str(&format!("%{:02X}", byte)),
+    }
+  }
+  let kind = match disposition {
+    Disposition::Inline => "inline",
+    Disposition::Attachment => "attachment",
+  };
+  Header::new(
+    "Content-Disposition",
+    format!(
+      "{}; filename=\"{}\"; filename*=UTF-8''{}",
+      kind, fallback, encoded
+    ),
+  )
+}
+
+// The raw `Range` request header. Only single `bytes=` ranges are honoured, anything else gets
+// the whole representation.
+pub struct ByteRange(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ByteRange {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    Outcome::Success(ByteRange(
+      request.headers().get_one("Range").map(str::to_string),
+    ))
+  }
+}
+
+// `http://host:port` as the client reached this server, for links that have to be absolute. A proxy
+// in front terminating TLS says so with `X-Forwarded-Proto`.
+pub struct Base(pub String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Base {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    let scheme = match request.headers().get_one("X-Forwarded-Proto") {
+      Some("https") => "https",
+      _ => "http",
+    };
+    match request.headers().get_one("Host") {
+      Some(host) => Outcome::Success(Base(format!("{}://{}", scheme, host))),
+      None => Outcome::Failure((Status::BadRequest, ())),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Unsatisfiable;
+
+impl ByteRange {
+  pub fn new(header: Option<&str>) -> ByteRange {
+    ByteRange(header.map(str::to_string))
+  }
+
+  // `Ok(None)` means the full body should be sent.
+  pub fn resolve(&self, size: u64) -> Result<Option<Range<u64>>, Unsatisfiable> {
+    let spec = match self
+      .0
+      .as_deref()
+      .and_then(|value| value.strip_prefix("bytes="))
+    {
+      Some(spec) if !spec.contains(',') => spec.trim(),
+      _ => return Ok(None),
+    };
+    let (start, end) = match spec.split_once('-') {
+      Some(bounds) => bounds,
+      None => return Ok(None),
+    };
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+      (Ok(start), Ok(end)) if start <= end => start..end.saturating_add(1).min(size),
+      (Ok(start), Err(_)) if end.is_empty() => start..size,
+      (Err(_), Ok(suffix)) if start.is_empty() => size.saturating_sub(suffix)..size,
+      _ => return Ok(None),
+    };
+    if range.start >= size || range.is_empty() {
+      return Err(Unsatisfiable);
+    }
+    Ok(Some(range))
+  }
+}
+
+// Calls `done` once the body has been read to its end, which a client hanging up never gets to.
+struct Finished {
+  body: Body,
+  done: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl AsyncRead for Finished {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<()>> {
+    let before = buf.filled().len();
+    let poll = self.body.as_mut().poll_read(cx, buf);
+    if let Poll::Ready(Ok(())) = poll {
+      if buf.filled().len() == before && buf.remaining() > 0 {
+        if let Some(done) = self.done.take() {
+          done();
+        }
+      }
+    }
+    poll
+  }
+}
+
+// Lets a body of known length go through `sized_body`, which wants something seekable even when
+// the size is given up front.
+struct Sized(Body);
+
+impl AsyncRead for Sized {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<()>> {
+    self.0.as_mut().poll_read(cx, buf)
+  }
+}
+
+impl AsyncSeek for Sized {
+  fn start_seek(self: Pin<&mut Self>, _: io::SeekFrom) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+  }
+
+  fn poll_complete(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<u64>> {
+    Poll::Ready(Err(io::ErrorKind::Unsupported.into()))
+  }
+}
+
+pub struct Download {
+  body: Body,
+  // `None` for bodies generated on the fly, which can not be resumed.
+  size: Option<u64>,
+  range: Result<Option<Range<u64>>, Unsatisfiable>,
+  pub content_type: Option<ContentType>,
+  pub headers: Vec<Header<'static>>,
+}
+
+impl Download {
+  // `open` is only called when there is something to send.
+  pub fn new(
+    size: u64,
+    range: &ByteRange,
+    open: impl FnOnce(Option<Range<u64>>) -> Body,
+  ) -> Download {
+    let range = range.resolve(size);
+    let body = match &range {
+      Ok(range) => open(range.clone()),
+      Err(Unsatisfiable) => Box::pin(rocket::tokio::io::empty()),
+    };
+    Download {
+      body,
+      size: Some(size),
+      range,
+      content_type: None,
+      headers: Vec::new(),
+    }
+  }
+
+  pub fn streamed(body: Body) -> Download {
+    Download {
+      body,
+      size: None,
+      range: Ok(None),
+      content_type: None,
+      headers: Vec::new(),
+    }
+  }
+
+  pub async fn open(
+    storage: &dyn Storage,
+    key: &str,
+    size: u64,
+    range: &ByteRange,
+  ) -> io::Result<Download> {
+    let range = range.resolve(size);
+    let body: Body = match &range {
+      Ok(range) => storage.open(key, range.clone()).await?,
+      Err(Unsatisfiable) => Box::pin(rocket::tokio::io::empty()),
+    };
+    Ok(Download {
+      body,
+      size: Some(size),
+      range,
+      content_type: None,
+      headers: Vec::new(),
+    })
+  }
+}
+
+impl Download {
+  // Whether the whole file is being sent, rather than the part a player seeking asked for.
+  pub fn is_whole(&self) -> bool {
+    match (&self.range, self.size) {
+      (Ok(None), Some(_)) => true,
+      (Ok(Some(range)), Some(size)) => range.start == 0 && range.end == size,
+      _ => false,
+    }
+  }
+
+  // Puts something between the body and the client.
+  pub fn wrap(&mut self, wrap: impl FnOnce(Body) -> Body) {
+    let body = std::mem::replace(&mut self.body, Box::pin(rocket::tokio::io::empty()));
+    self.body = wrap(body);
+  }
+
+  pub fn on_finish(&mut self, done: impl FnOnce() + Send + 'static) {
+    self.wrap(|body| {
+      Box::pin(Finished {
+        body,
+        done: Some(Box::new(done)),
+      })
+    });
+  }
+}
+
+impl<'r> Responder<'r, 'static> for Download {
+  fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+    let mut response = Response::build();
+    match (self.size, self.range) {
+      (None, _) => {
+        response.streamed_body(self.body);
+      }
+      (Some(size), Err(Unsatisfiable)) => {
+        return response
+          .status(Status::RangeNotSatisfiable)
+          .raw_header("Content-Range", format!("bytes */{}", size))
+          .ok();
+      }
+      (Some(size), Ok(Some(range))) => {
+        response
+          .status(Status::PartialContent)
+          .raw_header("Accept-Ranges", "bytes")
+          .raw_header(
+            "Content-Range",
+            format!("bytes {}-{}/{}", range.start, range.end - 1, size),
+          )
+          .sized_body((range.end - range.start) as usize, Sized(self.body));
+      }
+      (Some(size), Ok(None)) => {
+        response
+          .raw_header("Accept-Ranges", "bytes")
+          .sized_body(size as usize, Sized(self.body));
+      }
+    }
+    if let Some(content_type) = self.content_type {
+      response.header(content_type);
+    }
+    for header in self.headers {
+      response.header(header);
+    }
+    response.ok()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn resolve(header: &str, size: u64) -> Result<Option<Range<u64>>, Unsatisfiable> {
+    ByteRange::new(Some(header)).resolve(size)
+  }
+
+  fn disposition(disposition: Disposition, name: &str) -> String {
+    content_disposition(disposition, name).value().to_string()
+  }
+
+  #[test]
+  fn quotes_plain_names() {
+    assert_eq!(
+      disposition(Disposition::Attachment, "Doctor Who - S04E01.mkv"),
+      "attachment; filename=\"Doctor Who - S04E01.mkv\"; \
+       filename*=UTF-8''Doctor%20Who%20-%20S04E01.mkv"
+    );
+  }
+
+  #[test]
+  fn encodes_names_outside_ascii() {
+    assert_eq!(
+      disposition(Disposition::Inline, "Amélie \"2001\".mkv"),
+      "inline; filename=\"Am_lie _2001_.mkv\"; \
+       filename*=UTF-8''Am%C3%A9lie%20%222001%22.mkv"
+    );
+    assert_eq!(
+      disposition(Disposition::Inline, "a\\b;c%d"),
+      "inline; filename=\"a_b;c%d\"; filename*=UTF-8''a%5Cb%3Bc%25d"
+    );
+  }
+
+  #[test]
+  fn borrows_the_stored_extension() {
+    assert_eq!(file_name("s04e01", "complete/s04e01.mkv"), "s04e01.mkv");
+    assert_eq!(file_name("s04e01.mp4", "complete/s04e01.mkv"), "s04e01.mp4");
+    assert_eq!(file_name("s04e01", "sha256:abc"), "s04e01");
+    assert_eq!(file_name("s04e01", "complete/s04e01"), "s04e01");
+  }
+
+  #[test]
+  fn sanitizes_names() {
+    assert_eq!(
+      sanitize("S04 - Who? / What: \"Doctor\"*"),
+      "S04 - Who  What Doctor"
+    );
+    assert_eq!(sanitize("tab\there\n"), "tabhere");
+  }
+
+  #[test]
+  fn resolves_single_ranges() {
+    assert_eq!(resolve("bytes=0-99", 1000), Ok(Some(0..100)));
+    assert_eq!(resolve("bytes=500-", 1000), Ok(Some(500..1000)));
+    assert_eq!(resolve("bytes=-200", 1000), Ok(Some(800..1000)));
+    assert_eq!(resolve("bytes= 10-19 ", 1000), Ok(Some(10..20)));
+  }
+
+  #[test]
+  fn clamps_ranges_to_the_size() {
+    assert_eq!(resolve("bytes=900-1999", 1000), Ok(Some(900..1000)));
+    assert_eq!(resolve("bytes=-5000", 1000), Ok(Some(0..1000)));
+    assert_eq!(
+      resolve("bytes=0-18446744073709551615", 1000),
+      Ok(Some(0..1000))
+    );
+  }
+
+  #[test]
+  fn refuses_ranges_past_the_end() {
+    assert_eq!(resolve("bytes=1000-", 1000), Err(Unsatisfiable));
+    assert_eq!(resolve("bytes=1000-1099", 1000), Err(Unsatisfiable));
+    assert_eq!(resolve("bytes=-0", 1000), Err(Unsatisfiable));
+    assert_eq!(resolve("bytes=0-", 0), Err(Unsatisfiable));
+  }
+
+  #[test]
+  fn sends_everything_for_what_it_does_not_understand() {
+    assert_eq!(ByteRange::new(None).resolve(1000), Ok(None));
+    assert_eq!(resolve("bytes=0-9,20-29", 1000), Ok(None));
+    assert_eq!(resolve("items=0-9", 1000), Ok(None));
+    assert_eq!(resolve("bytes=9-0", 1000), Ok(None));
+    assert_eq!(resolve("bytes=-", 1000), Ok(None));
+    assert_eq!(resolve("bytes=ten", 1000), Ok(None));
+  }
+}
@@ -0,0 +1,134 @@
+// Ids look like `s04e01.mkv`, `s02eSpecial.mkv` or `s07eTheDayOfTheDoctor`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Episode {
+  pub season: u32,
+  pub number: Option<u32>,
+  pub label: String,
+}
+
+impl Episode {
+  pub fn is_special(&self) -> bool {
+    self.number.is_none()
+  }
+}
+
+pub fn parse(id: &str) -> Option<Episode> {
+  let stem = id.split('.').next()?;
+  let rest = stem.strip_prefix(['s', 'S'])?;
+  let (season, label) = rest.split_once(['e', 'E'])?;
+  Some(Episode {
+    season: season.parse().ok()?,
+    number: label.parse().ok(),
+    label: label.to_string(),
+  })
+}
+
+const SMALL_WORDS: &[&str] = &[
+  "a", "an", "and", "at", "by", "for", "in", "of", "on", "or", "the", "to",
+];
+
+// `TheDayOfTheDoctor` -> `The Day of the Doctor`. Separators are turned into spaces as well, so
+// `the_day-of.the.doctor` comes out the same.
+pub fn humanize(label: &str) -> String {
+  let mut words: Vec<String> = Vec::new();
+  let mut word = String::new();
+  let mut previous: Option<char> = None;
+  for c in label.chars() {
+    let boundary = match previous {
+      Some(previous) => {
+        (c.is_uppercase() && !previous.is_uppercase())
+          || (c.is_ascii_digit() != previous.is_ascii_digit())
+      }
+      None => false,
+    };
+    let separator = c == '_' || c == '-' || c == '.' || c.is_whitespace();
+    if (separator || boundary) && !word.is_empty() {
+      words.push(std::mem::take(&mut word));
+    }
+    if c.is_alphanumeric() || c == '\'' {
+      word.push(c);
+    }
+    previous = Some(c);
+  }
+  if !word.is_empty() {
+    words.push(word);
+  }
+
+  words
+    .iter()
+    .enumerate()
+    .map(|(index, word)| {
+      let lower = word.to_lowercase();
+      if index > 0 && SMALL_WORDS.contains(&lower.as_str()) {
+        return lower;
+      }
+      let mut chars = word.chars();
+      match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+      }
+    })
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+impl Episode {
+  // `S04E01` for numbered episodes, `S07 - The Day of the Doctor` for specials.
+  pub fn title(&self) -> String {
+    match self.number {
+      Some(number) => format!("S{:02}E{:02}", self.season, number),
+      None => format!("S{:02} - {}", self.season, humanize(&self.label)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_numbered_episodes() {
+    let episode = parse("s04e01.mkv").unwrap();
+    assert_eq!(episode.season, 4);
+    assert_eq!(episode.number, Some(1));
+    assert_eq!(episode.label, "01");
+    assert!(!episode.is_special());
+    assert_eq!(parse("S10E12").unwrap().number, Some(12));
+  }
+
+  #[test]
+  fn parses_specials() {
+    let episode = parse("s07eTheDayOfTheDoctor.mkv").unwrap();
+    assert_eq!(episode.season, 7);
+    assert_eq!(episode.number, None);
+    assert_eq!(episode.label, "TheDayOfTheDoctor");
+    assert!(episode.is_special());
+    assert_eq!(parse("s02eSpecial").unwrap().label, "Special");
+  }
+
+  #[test]
+  fn ignores_other_ids() {
+    assert_eq!(parse("trailer.mkv"), None);
+    assert_eq!(parse("sXe01.mkv"), None);
+    assert_eq!(parse("s04.mkv"), None);
+    assert_eq!(parse(""), None);
+  }
+
+  #[test]
+  fn titles_episodes() {
+    assert_eq!(parse("s04e01.mkv").unwrap().title(), "S04E01");
+    assert_eq!(
+      parse("s07eTheDayOfTheDoctor.mkv").unwrap().title(),
+      "S07 - The Day of the Doctor"
+    );
+    assert_eq!(parse("s02eSpecial").unwrap().title(), "S02 - Special");
+  }
+
+  #[test]
+  fn humanizes_labels() {
+    assert_eq!(humanize("TheDayOfTheDoctor"), "The Day of the Doctor");
+    assert_eq!(humanize("the_day-of.the.doctor"), "The Day of the Doctor");
+    assert_eq!(humanize("Series2Trailer"), "Series 2 Trailer");
+    assert_eq!(humanize("DoctorWho's"), "Doctor Who's");
+  }
+}
@@ -0,0 +1,204 @@
+use std::{
+  fs::{self, File},
+  io::{self, Read},
+  path::{Path, PathBuf},
+};
+
+use crate::{
+  cli::{collection, positional},
+  database::{resolve, Database},
+  download::{file_name, sanitize},
+  episode::{self, Episode},
+  mime, xml,
+};
+
+struct Item {
+  id: String,
+  episode: Episode,
+  // Where Kodi should file it. Specials have no number in their id, so they go to season 0 and are
+  // numbered in air date order, keeping their real season in `displayseason`.
+  season: u32,
+  number: u32,
+}
+
+fn extension(id: &str, store_path: &str) -> Option<String> {
+  if let Some(extension) = Path::new(&file_name(id, store_path)).extension() {
+    return Some(extension.to_string_lossy().into_owned());
+  }
+  let mut header = Vec::new();
+  File::open(resolve(store_path))
+    .ok()?
+    .take(mime::SNIFF_LENGTH)
+    .read_to_end(&mut header)
+    .ok()?;
+  mime::extension(&mime::sniff(&header)?).map(str::to_string)
+}
+
+fn items(db: &Database, collection: &str) -> Vec<Item> {
+  let mut episodes: Vec<(String, Episode)> = db
+    .collection_ids(collection)
+    .into_iter()
+    .filter_map(|id| Some((id.clone(), episode::parse(&id)?)))
+    .collect();
+  let airdate = |id: &str| {
+    db.metadata
+      .get(id)
+      .and_then(|metadata| metadata.airdate.clone())
+      .unwrap_or_default()
+  };
+  episodes.sort_by_key(|(id, episode)| (episode.is_special(), airdate(id), id.clone()));
+
+  let mut specials = 0;
+  episodes
+    .into_iter()
+    .map(|(id, episode)| {
+      let (season, number) = match episode.number {
+        Some(number) => (episode.season, number),
+        None => {
+          specials += 1;
+          (0, specials)
+        }
+      };
+      Item {
+        id,
+        episode,
+        season,
+        number,
+      }
+    })
+    .collect()
+}
+
+fn write(path: &Path, contents: &str) -> io::Result<()> {
+  let partial = PathBuf::from(format!("{}.part", path.display()));
+  fs::write(&partial, contents)?;
+  fs::rename(&partial, path)
+}
+
+fn tag(name: &str, value: impl ToString) -> String {
+  format!("  <{0}>{1}</{0}>\n", name, xml::escape(&value.to_string()))
+}
+
+fn episode_nfo(db: &Database, show: &str, item: &Item) -> String {
+  let metadata = db.metadata.get(&item.id).cloned().unwrap_or_default();
+  let title = match metadata.title.clone() {
+    Some(title) => title,
+    None => item.episode.title(),
+  };
+  let mut nfo = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n");
+  nfo.push_str("<episodedetails>\n");
+  nfo.push_str(&tag("title", title));
+  nfo.push_str(&tag("showtitle", show));
+  nfo.push_str(&tag("season", item.season));
+  nfo.push_str(&tag("episode", item.number));
+  if item.episode.is_special() {
+    nfo.push_str(&tag("displayseason", item.episode.season));
+  }
+  if let Some(airdate) = metadata.airdate {
+    nfo.push_str(&tag("aired", airdate));
+  }
+  if let Some(summary) = metadata.summary {
+    nfo.push_str(&tag("plot", summary));
+  }
+  if let Some(runtime) = metadata.runtime {
+    nfo.push_str(&tag("runtime", runtime));
+  }
+  nfo.push_str(&tag("uniqueid", &item.id));
+  nfo.push_str("</episodedetails>\n");
+  nfo
+}
+
+// Replaces whatever is at `link` unless it already points at `target`.
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+  if fs::read_link(link).is_ok_and(|existing| existing == target) {
+    return Ok(());
+  }
+  if fs::symlink_metadata(link).is_ok() {
+    fs::remove_file(link)?;
+  }
+  #[cfg(unix)]
+  return std::os::unix::fs::symlink(target, link);
+  #[cfg(windows)]
+  return std::os::windows::fs::symlink_file(target, link);
+}
+
+fn export_collection(
+  db: &Database,
+  collection: &str,
+  root: &Path,
+  links: bool,
+) -> io::Result<usize> {
+  let show = db.show_title(collection);
+  let show_dir = root.join(sanitize(&show));
+  fs::create_dir_all(&show_dir)?;
+  write(
+    &show_dir.join("tvshow.nfo"),
+    &format!(
+      "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n<tvshow>\n{}</tvshow>\n",
+      tag("title", &show)
+    ),
+  )?;
+
+  let items = items(db, collection);
+  for item in &items {
+    let store_path = &db.id_to_path[&item.id];
+    let title = match db.title(&item.id) {
+      Some(title) => title.to_string(),
+      None if item.episode.is_special() => episode::humanize(&item.episode.label),
+      None => String::new(),
+    };
+    let mut stem = format!("{} - S{:02}E{:02}", show, item.season, item.number);
+    if !title.is_empty() {
+      stem = format!("{} - {}", stem, title);
+    }
+    let season_dir = show_dir.join(format!("Season {:02}", item.season));
+    fs::create_dir_all(&season_dir)?;
+    let base: PathBuf = season_dir.join(sanitize(&stem));
+
+    // Not `with_extension`, titles may well contain dots.
+    let nfo = PathBuf::from(format!("{}.nfo", base.display()));
+    write(&nfo, &episode_nfo(db, &show, item))?;
+    if links && db.is_local(&item.id) {
+      let target = fs::canonicalize(resolve(store_path)).unwrap_or_else(|_| resolve(store_path));
+      let link = match extension(&item.id, store_path) {
+        Some(extension) => PathBuf::from(format!("{}.{}", base.display(), extension)),
+        None => base.clone(),
+      };
+      symlink(&target, &link)?;
+    }
+    println!("{} -> {}", item.id, base.display());
+  }
+  Ok(items.len())
+}
+
+// `file-share export <dir> [--collection <name>] [--links]`. Writes `tvshow.nfo` and one `.nfo`
+// per episode in a Kodi/Jellyfin style `Show/Season 01/` tree; with `--links` the episodes
+// themselves are symlinked in next to their `.nfo`.
+pub fn run(args: &[String]) {
+  let links = args.iter().any(|arg| arg == "--links");
+  let root = match positional(args).first() {
+    Some(root) => PathBuf::from(root),
+    None => {
+      eprintln!("Usage: file-share export <dir> [--collection <name>] [--links]");
+      std::process::exit(2);
+    }
+  };
+
+  let db = Database::load();
+  let collections: Vec<String> = match collection(&db, args) {
+    Some(collection) => vec![collection],
+    None => db.collection_names(),
+  };
+
+  let mut exported = 0;
+  for collection in collections {
+    match export_collection(&db, &collection, &root, links) {
+      Ok(count) => exported += count,
+      Err(error) => {
+        eprintln!("Failed to export {}: {}", collection, error);
+        std::process::exit(1);
+      }
+    }
+  }
+  println!("{} episodes exported to {}", exported, root.display());
+}
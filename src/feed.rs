@@ -0,0 +1,272 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use rocket::{
+  http::{ContentType, RawStr},
+  State,
+};
+
+use crate::{
+  auth::User,
+  channel::rfc3339,
+  database::{Database, Db},
+  dav::http_date,
+  download::Base,
+  media_info::MediaInfos,
+  mime,
+  storage::Backends,
+  xml,
+};
+
+// Feed readers only ever look at the top of a feed.
+const LENGTH: usize = 50;
+
+struct Entry {
+  id: String,
+  title: String,
+  summary: Option<String>,
+  added: u64,
+  url: String,
+  size: Option<u64>,
+  mime: String,
+  duration: Option<f64>,
+}
+
+struct Feed {
+  title: String,
+  // Where the feed itself lives, which is also what identifies it.
+  link: String,
+  updated: u64,
+  entries: Vec<Entry>,
+}
+
+// Podcast apps fetch enclosures as plain links, so those carry the token the feed was fetched with.
+pub fn with_token(url: String, token: Option<&str>) -> String {
+  match token {
+    Some(token) => format!("{}?token={}", url, RawStr::new(token).percent_encode()),
+    None => url,
+  }
+}
+
+// The newest ids of `collection`, or of the whole library, with everything a podcast app needs to
+// download them.
+async fn feed(
+  db: &Database,
+  base: &str,
+  path: &str,
+  collection: Option<&str>,
+  token: Option<&str>,
+  backends: &Backends,
+  infos: &MediaInfos,
+) -> Option<Feed> {
+  let (title, ids) = match collection {
+    Some(collection) if !db.has_collection(collection) => return None,
+    Some(collection) => (db.show_title(collection), db.collection_ids(collection)),
+    None => {
+      let mut ids: Vec<String> = db.id_to_path.keys().cloned().collect();
+      ids.sort();
+      ("file-share".to_string(), ids)
+    }
+  };
+  let mut ids: Vec<(u64, String)> = ids
+    .into_iter()
+    .map(|id| (db.added(&id).unwrap_or(0), id))
+    .collect();
+  ids.sort_by(|a, b| b.cmp(a));
+  ids.truncate(LENGTH);
+
+  let mut entries = Vec::new();
+  for (added, id) in ids {
+    let store_path = &db.id_to_path[&id];
+    let storage = backends.for_id(db, &id);
+    let size = storage.stat(store_path).await.ok().map(|stat| stat.size);
+    let content_type = match size {
+      Some(size) => mime::resolve(storage.as_ref(), &id, store_path, size).await,
+      None => None,
+    };
+    let duration = match db.is_local(&id) {
+      true => {
+        let (infos, store_path) = (infos.clone(), store_path.clone());
+        rocket::tokio::task::spawn_blocking(move || infos.get(&store_path))
+          .await
+          .ok()
+          .and_then(Result::ok)
+          .and_then(|info| info.duration)
+      }
+      false => None,
+    };
+    // The global feed mixes shows, so its entries name theirs.
+    let title = match collection {
+      Some(_) => db.episode_title(&id),
+      None => format!(
+        "{} - {}",
+        db.show_title(db.collection_of(&id)),
+        db.episode_title(&id)
+      ),
+    };
+    entries.push(Entry {
+      title,
+      summary: db
+        .metadata
+        .get(&id)
+        .and_then(|metadata| metadata.summary.clone()),
+      added,
+      url: with_token(
+        format!("{}/dr-who/{}", base, RawStr::new(&id).percent_encode()),
+        token,
+      ),
+      size,
+      mime: content_type
+        .map(|content_type| format!("{}/{}", content_type.top(), content_type.sub()))
+        .unwrap_or_else(|| "application/octet-stream".to_string()),
+      duration,
+      id,
+    });
+  }
+
+  let mut link = format!("{}{}", base, path);
+  if let Some(collection) = collection {
+    link.push_str(&format!(
+      "?collection={}",
+      RawStr::new(collection).percent_encode()
+    ));
+  }
+  Some(Feed {
+    title,
+    link,
+    updated: entries.iter().map(|entry| entry.added).max().unwrap_or(0),
+    entries,
+  })
+}
+
+fn rss_date(timestamp: u64) -> String {
+  http_date(UNIX_EPOCH + Duration::from_secs(timestamp))
+}
+
+// `H:MM:SS`, which is what iTunes wants.
+fn itunes_duration(seconds: f64) -> String {
+  let seconds = seconds.round() as u64;
+  format!(
+    "{}:{:02}:{:02}",
+    seconds / 3600,
+    seconds / 60 % 60,
+    seconds % 60
+  )
+}
+
+#[get("/feed.atom?<collection>&<token>")]
+pub async fn atom(
+  _user: User,
+  db: Db,
+  base: Base,
+  backends: &State<Backends>,
+  infos: &State<MediaInfos>,
+  collection: Option<String>,
+  token: Option<String>,
+) -> Option<(ContentType, String)> {
+  let feed = feed(
+    &db,
+    &base.0,
+    "/feed.atom",
+    collection.as_deref(),
+    token.as_deref(),
+    backends,
+    infos,
+  )
+  .await?;
+
+  let mut atom = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+  atom.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+  atom.push_str(&format!(
+    "  <id>{0}</id>\n  <title>{1}</title>\n  <updated>{2}</updated>\n  <link rel=\"self\" href=\"{0}\"/>\n  <generator>file-share</generator>\n",
+    xml::escape(&feed.link),
+    xml::escape(&feed.title),
+    rfc3339(feed.updated as f64)
+  ));
+  for entry in &feed.entries {
+    atom.push_str(&format!(
+      "  <entry>\n    <id>{}</id>\n    <title>{}</title>\n    <published>{2}</published>\n    <updated>{2}</updated>\n    <author><name>file-share</name></author>\n",
+      xml::escape(&format!("{}#{}", feed.link, entry.id)),
+      xml::escape(&entry.title),
+      rfc3339(entry.added as f64)
+    ));
+    if let Some(summary) = &entry.summary {
+      atom.push_str(&format!(
+        "    <summary>{}</summary>\n",
+        xml::escape(summary)
+      ));
+    }
+    // Entries without `<content>` need an alternate link; the id's URL is the entry itself.
+    atom.push_str(&format!(
+      "    <link rel=\"alternate\" href=\"{0}\"/>\n    <link rel=\"enclosure\" href=\"{0}\" type=\"{1}\"",
+      xml::escape(&entry.url),
+      xml::escape(&entry.mime)
+    ));
+    if let Some(size) = entry.size {
+      atom.push_str(&format!(" length=\"{}\"", size));
+    }
+    atom.push_str("/>\n  </entry>\n");
+  }
+  atom.push_str("</feed>\n");
+  Some((ContentType::new("application", "atom+xml"), atom))
+}
+
+#[get("/feed.rss?<collection>&<token>")]
+pub async fn rss(
+  _user: User,
+  db: Db,
+  base: Base,
+  backends: &State<Backends>,
+  infos: &State<MediaInfos>,
+  collection: Option<String>,
+  token: Option<String>,
+) -> Option<(ContentType, String)> {
+  let feed = feed(
+    &db,
+    &base.0,
+    "/feed.rss",
+    collection.as_deref(),
+    token.as_deref(),
+    backends,
+    infos,
+  )
+  .await?;
+
+  let mut rss = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+  rss.push_str("<rss version=\"2.0\" xmlns:itunes=\"http://www.itunes.com/dtds/podcast-1.0.dtd\" xmlns:atom=\"http://www.w3.org/2005/Atom\">\n<channel>\n");
+  rss.push_str(&format!(
+    "  <title>{0}</title>\n  <link>{1}</link>\n  <description>{0}</description>\n  <atom:link rel=\"self\" href=\"{1}\" type=\"application/rss+xml\"/>\n  <lastBuildDate>{2}</lastBuildDate>\n  <generator>file-share</generator>\n  <itunes:type>episodic</itunes:type>\n",
+    xml::escape(&feed.title),
+    xml::escape(&feed.link),
+    rss_date(feed.updated)
+  ));
+  for entry in &feed.entries {
+    rss.push_str(&format!(
+      "  <item>\n    <title>{}</title>\n    <guid isPermaLink=\"false\">{}</guid>\n    <pubDate>{}</pubDate>\n",
+      xml::escape(&entry.title),
+      xml::escape(&format!("{}#{}", feed.link, entry.id)),
+      rss_date(entry.added)
+    ));
+    if let Some(summary) = &entry.summary {
+      rss.push_str(&format!(
+        "    <description>{}</description>\n",
+        xml::escape(summary)
+      ));
+    }
+    // RSS insists on a length, 0 being the agreed way of saying it isn't known.
+    rss.push_str(&format!(
+      "    <enclosure url=\"{}\" length=\"{}\" type=\"{}\"/>\n",
+      xml::escape(&entry.url),
+      entry.size.unwrap_or(0),
+      xml::escape(&entry.mime)
+    ));
+    if let Some(duration) = entry.duration {
+      rss.push_str(&format!(
+        "    <itunes:duration>{}</itunes:duration>\n",
+        itunes_duration(duration)
+      ));
+    }
+    rss.push_str("  </item>\n");
+  }
+  rss.push_str("</channel>\n</rss>\n");
+  Some((ContentType::new("application", "rss+xml"), rss))
+}
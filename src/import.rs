@@ -0,0 +1,403 @@
+use std::{fs::File, io, path::Path};
+
+use serde_json::Value;
+
+use crate::{
+  cli::{collection, positional},
+  database::{Database, Metadata},
+  episode::{self, humanize},
+};
+
+// An episode from the dump, before it is matched to an id.
+#[derive(Debug)]
+struct Record {
+  season: u32,
+  number: Option<u32>,
+  metadata: Metadata,
+}
+
+// TVmaze summaries are HTML fragments, TMDB overviews are plain text; both end up as plain text.
+fn strip_html(html: &str) -> Option<String> {
+  let mut text = String::new();
+  let mut tag: Option<String> = None;
+  for c in html.chars() {
+    match (&mut tag, c) {
+      (None, '<') => tag = Some(String::new()),
+      (Some(name), '>') => {
+        // Block level tags separate words, inline ones like `<b>` don't.
+        let name = name
+          .trim_start_matches('/')
+          .split_whitespace()
+          .next()
+          .unwrap_or("");
+        if matches!(
+          name.to_ascii_lowercase().as_str(),
+          "p" | "br" | "br/" | "div" | "li"
+        ) {
+          text.push(' ');
+        }
+        tag = None;
+      }
+      (Some(name), c) => name.push(c),
+      (None, c) => text.push(c),
+    }
+  }
+  let text = text
+    .replace("&nbsp;", " ")
+    .replace("&quot;", "\"")
+    .replace("&#39;", "'")
+    .replace("&lt;", "<")
+    .replace("&gt;", ">")
+    .replace("&amp;", "&");
+  let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+  (!text.is_empty()).then_some(text)
+}
+
+fn non_empty(value: Option<&str>) -> Option<String> {
+  value
+    .map(str::trim)
+    .filter(|value| !value.is_empty())
+    .map(str::to_string)
+}
+
+fn field<'a>(item: &'a Value, names: &[&str]) -> Option<&'a Value> {
+  names
+    .iter()
+    .find_map(|name| item.get(name))
+    .filter(|value| !value.is_null())
+}
+
+fn json_record(item: &Value) -> Option<Record> {
+  let number = |names: &[&str]| {
+    let value = field(item, names)?;
+    value
+      .as_u64()
+      .or_else(|| value.as_str()?.trim().parse().ok())
+      .map(|number| number as u32)
+  };
+  let text = |names: &[&str]| non_empty(field(item, names)?.as_str());
+  Some(Record {
+    season: number(&["season", "season_number"])?,
+    number: number(&["number", "episode_number", "episode"]),
+    metadata: Metadata {
+      title: text(&["name", "title"]),
+      airdate: text(&["airdate", "air_date"]),
+      summary: text(&["summary", "overview"]).and_then(|summary| strip_html(&summary)),
+      runtime: number(&["runtime"]),
+    },
+  })
+}
+
+// Accepts a bare list of episodes, a TVmaze show with `_embedded.episodes`, a TMDB season with
+// `episodes`, or a TMDB show whose `seasons` each carry their `episodes`, or that has them appended
+// as `season/0`, `season/1` and so on.
+fn from_json(value: &Value) -> Vec<Record> {
+  let mut items: Vec<&Value> = Vec::new();
+  let mut lists = vec![value, &value["episodes"], &value["_embedded"]["episodes"]];
+  if let Some(seasons) = value["seasons"].as_array() {
+    lists.extend(seasons.iter().map(|season| &season["episodes"]));
+  }
+  if let Some(fields) = value.as_object() {
+    lists.extend(
+      fields
+        .iter()
+        .filter(|(key, _)| key.starts_with("season/"))
+        .map(|(_, season)| &season["episodes"]),
+    );
+  }
+  for list in lists {
+    if let Some(list) = list.as_array() {
+      items.extend(list);
+    }
+  }
+  items.into_iter().filter_map(json_record).collect()
+}
+
+// A header row is required; column names follow the JSON field names.
+fn from_csv(path: &Path) -> io::Result<Vec<Record>> {
+  let mut reader = csv::Reader::from_path(path).map_err(io::Error::other)?;
+  let headers: Vec<String> = reader
+    .headers()
+    .map_err(io::Error::other)?
+    .iter()
+    .map(|header| header.trim().to_ascii_lowercase())
+    .collect();
+  let mut records = Vec::new();
+  for row in reader.records() {
+    let row = row.map_err(io::Error::other)?;
+    let item: serde_json::Map<String, Value> = headers
+      .iter()
+      .zip(row.iter())
+      .map(|(header, value)| (header.clone(), Value::String(value.to_string())))
+      .collect();
+    records.extend(json_record(&Value::Object(item)));
+  }
+  Ok(records)
+}
+
+fn load(path: &Path) -> io::Result<Vec<Record>> {
+  let is_csv = path
+    .extension()
+    .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+  if is_csv {
+    return from_csv(path);
+  }
+  let value: Value = serde_json::from_reader(File::open(path)?)?;
+  Ok(from_json(&value))
+}
+
+fn normalize(title: &str) -> String {
+  title
+    .chars()
+    .filter(|c| c.is_alphanumeric())
+    .flat_map(char::to_lowercase)
+    .collect()
+}
+
+// Numbered episodes match on season and number. Specials have no number in their id, so they are
+// matched on their label against the titles of the season's episodes, then of season 0, where
+// TVmaze and TMDB put specials, then of any season.
+fn find<'a>(records: &'a [Record], id: &str) -> Option<&'a Record> {
+  let episode = episode::parse(id)?;
+  if let Some(number) = episode.number {
+    return records
+      .iter()
+      .find(|record| record.season == episode.season && record.number == Some(number));
+  }
+  let label = normalize(&humanize(&episode.label));
+  let titled = |record: &&Record| title(record).is_some_and(|title| title == label);
+  let in_season = |season: u32| move |record: &&Record| record.season == season;
+  records
+    .iter()
+    .filter(in_season(episode.season))
+    .find(titled)
+    .or_else(|| records.iter().filter(in_season(0)).find(titled))
+    .or_else(|| records.iter().find(titled))
+}
+
+fn title(record: &Record) -> Option<String> {
+  record.metadata.title.as_deref().map(normalize)
+}
+
+// Specials in season 0 are often titled with more than the label, like `Doctor Who: The Day of the
+// Doctor`, so failing an exact match the one season 0 title containing the label will do. Should
+// there be more than one, as `Special` would find, there is no telling which.
+fn find_loosely<'a>(records: &'a [Record], id: &str) -> Option<&'a Record> {
+  let episode = episode::parse(id).filter(|episode| episode.is_special())?;
+  let label = normalize(&humanize(&episode.label));
+  let mut found = records.iter().filter(|record| {
+    record.season == 0 && title(record).is_some_and(|title| title.contains(&label))
+  });
+  let record = found.next()?;
+  found.next().is_none().then_some(record)
+}
+
+// What each of `ids` matches. A loose match only counts if no other id matched the same record,
+// or ids with the same label, like `s02eSpecial` and `s03eSpecial`, would all get one special.
+fn find_all<'a>(records: &'a [Record], ids: &[String]) -> Vec<Option<&'a Record>> {
+  let exact: Vec<Option<&Record>> = ids.iter().map(|id| find(records, id)).collect();
+  let loose: Vec<Option<&Record>> = ids
+    .iter()
+    .zip(&exact)
+    .map(|(id, exact)| match exact {
+      Some(_) => None,
+      None => find_loosely(records, id),
+    })
+    .collect();
+  let claims = |record: &Record| {
+    exact
+      .iter()
+      .chain(&loose)
+      .flatten()
+      .filter(|other| std::ptr::eq(**other, record))
+      .count()
+  };
+  exact
+    .iter()
+    .zip(&loose)
+    .map(|(exact, loose)| exact.or(loose.filter(|record| claims(record) == 1)))
+    .collect()
+}
+
+// Fields missing from the dump keep whatever was imported before.
+fn merge(old: Option<&Metadata>, new: &Metadata) -> Metadata {
+  let old = old.cloned().unwrap_or_default();
+  Metadata {
+    title: new.title.clone().or(old.title),
+    airdate: new.airdate.clone().or(old.airdate),
+    summary: new.summary.clone().or(old.summary),
+    runtime: new.runtime.or(old.runtime),
+  }
+}
+
+// `file-share import <dump.json|dump.csv> [--collection <name>] [--dry-run]`
+pub fn run(args: &[String]) {
+  let dry_run = args.iter().any(|arg| arg == "--dry-run");
+  let file = match positional(args).first() {
+    Some(file) => *file,
+    None => {
+      eprintln!("Usage: file-share import <file.json|file.csv> [--collection <name>] [--dry-run]");
+      std::process::exit(2);
+    }
+  };
+
+  let records = match load(Path::new(file)) {
+    Ok(records) => records,
+    Err(error) => {
+      eprintln!("Failed to read {}: {}", file, error);
+      std::process::exit(1);
+    }
+  };
+
+  match dry_run {
+    true => import(&mut Database::load(), &records, args),
+    false => Database::edit(|db| import(db, &records, args)),
+  }
+}
+
+fn import(db: &mut Database, records: &[Record], args: &[String]) {
+  let collection = collection(db, args);
+  let mut ids: Vec<String> = db
+    .id_to_path
+    .keys()
+    .filter(|id| {
+      collection
+        .as_ref()
+        .is_none_or(|collection| db.collection_of(id) == collection)
+    })
+    .cloned()
+    .collect();
+  ids.sort();
+
+  let (mut matched, mut updated, mut unmatched) = (0, 0, Vec::new());
+  for (id, record) in ids.iter().zip(find_all(records, &ids)) {
+    let record = match record {
+      Some(record) => record,
+      None => {
+        unmatched.push(id);
+        continue;
+      }
+    };
+    matched += 1;
+    let metadata = merge(db.metadata.get(id), &record.metadata);
+    if db.metadata.get(id) != Some(&metadata) {
+      println!(
+        "{}: {}",
+        id,
+        metadata.title.as_deref().unwrap_or("(untitled)")
+      );
+      db.metadata.insert(id.clone(), metadata);
+      updated += 1;
+    }
+  }
+  for id in &unmatched {
+    println!("no match: {}", id);
+  }
+  println!(
+    "{} records, {} of {} ids matched, {} updated",
+    records.len(),
+    matched,
+    ids.len(),
+    updated
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn titles(records: &[Record], ids: &[&str]) -> Vec<Option<String>> {
+    let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+    find_all(records, &ids)
+      .into_iter()
+      .map(|record| record?.metadata.title.clone())
+      .collect()
+  }
+
+  #[test]
+  fn matches_numbered_episodes() {
+    let records = from_json(&json!({"_embedded": {"episodes": [
+      {"season": 4, "number": 1, "name": "Partners in Crime", "summary": "<p>Donna <b>returns</b>.</p>"},
+      {"season": 4, "number": 2, "name": "The Fires of Pompeii"},
+    ]}}));
+    assert_eq!(
+      titles(&records, &["s04e02.mkv", "s04e03.mkv", "trailer.mkv"]),
+      [Some("The Fires of Pompeii".to_string()), None, None]
+    );
+    let summary = find(&records, "s04e01.mkv")
+      .unwrap()
+      .metadata
+      .summary
+      .clone();
+    assert_eq!(summary.as_deref(), Some("Donna returns."));
+  }
+
+  #[test]
+  fn matches_specials_in_season_zero() {
+    let records = from_json(&json!({
+      "season/0": {"episodes": [
+        {"season_number": 0, "episode_number": 1, "name": "Doctor Who: The Day of the Doctor"},
+        {"season_number": 0, "episode_number": 2, "name": "The Christmas Invasion"},
+      ]},
+      "season/2": {"episodes": [
+        {"season_number": 2, "episode_number": 1, "name": "New Earth"},
+      ]},
+    }));
+    assert_eq!(records.len(), 3);
+    assert_eq!(
+      titles(
+        &records,
+        &[
+          "s07eTheDayOfTheDoctor",
+          "s02eTheChristmasInvasion.mkv",
+          "s02e01.mkv",
+          "s00e02.mkv"
+        ]
+      ),
+      [
+        Some("Doctor Who: The Day of the Doctor".to_string()),
+        Some("The Christmas Invasion".to_string()),
+        Some("New Earth".to_string()),
+        Some("The Christmas Invasion".to_string()),
+      ]
+    );
+  }
+
+  #[test]
+  fn prefers_specials_in_their_own_season() {
+    let records = from_json(&json!([
+      {"season": 0, "number": 3, "name": "Voyage of the Damned"},
+      {"season": 4, "name": "Voyage of the Damned", "runtime": 71},
+    ]));
+    let record = find(&records, "s04eVoyageOfTheDamned").unwrap();
+    assert_eq!(record.season, 4);
+    assert_eq!(record.metadata.runtime, Some(71));
+  }
+
+  #[test]
+  fn leaves_generic_specials_alone() {
+    let records = from_json(&json!([
+      {"season": 0, "number": 1, "name": "Doctor Who: Christmas Special"},
+      {"season": 2, "number": 1, "name": "New Earth"},
+    ]));
+    let special = Some("Doctor Who: Christmas Special".to_string());
+    assert_eq!(titles(&records, &["s02eSpecial.mkv"]), [special]);
+    // Either could be the one.
+    assert_eq!(
+      titles(&records, &["s02eSpecial.mkv", "s03eSpecial.mkv"]),
+      [None, None]
+    );
+    // Taken by an id that matches it exactly.
+    assert_eq!(
+      titles(&records, &["s00e01.mkv", "s06eSpecial.mkv"]),
+      [Some("Doctor Who: Christmas Special".to_string()), None]
+    );
+
+    let records = from_json(&json!([
+      {"season": 0, "number": 1, "name": "Christmas Special"},
+      {"season": 0, "number": 2, "name": "Easter Special"},
+    ]));
+    assert_eq!(titles(&records, &["s02eSpecial.mkv"]), [None]);
+  }
+}
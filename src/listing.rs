@@ -0,0 +1,77 @@
+use rocket::{http::RawStr, serde::json::Json, State};
+use serde::Serialize;
+
+use crate::{
+  auth::User,
+  database::{Db, Metadata, DEFAULT_COLLECTION},
+  download::Base,
+  episode,
+  torrent::{self, Torrents},
+};
+
+#[derive(Debug, Serialize)]
+pub struct Entry {
+  pub id: String,
+  pub url: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub season: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub episode: Option<u32>,
+  #[serde(flatten)]
+  pub metadata: Metadata,
+  // Once the file's pieces are hashed, which asking for the listing gets started.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub magnet: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CollectionEntry {
+  pub name: String,
+  pub title: String,
+  pub count: usize,
+}
+
+#[get("/collections")]
+pub async fn collections(_user: User, db: Db) -> Json<Vec<CollectionEntry>> {
+  let collections = db
+    .collection_names()
+    .into_iter()
+    .map(|name| CollectionEntry {
+      title: db.show_title(&name),
+      count: db.collection_ids(&name).len(),
+      name,
+    })
+    .collect();
+  Json(collections)
+}
+
+// Ids are unique across collections, so every one is fetched from under `/dr-who` whichever
+// collection it is listed in.
+// `token` ends up in the magnet links, for the clients they are handed to.
+#[get("/dr-who?<collection>&<token>")]
+pub async fn list(
+  _user: User,
+  db: Db,
+  base: Base,
+  torrents: &State<Torrents>,
+  collection: Option<String>,
+  token: Option<String>,
+) -> Option<Json<Vec<Entry>>> {
+  let collection = collection.unwrap_or_else(|| DEFAULT_COLLECTION.to_string());
+  if !db.has_collection(&collection) {
+    return None;
+  }
+  let mut entries = Vec::new();
+  for id in db.collection_ids(&collection) {
+    let episode = episode::parse(&id);
+    entries.push(Entry {
+      url: format!("/dr-who/{}", RawStr::new(&id).percent_encode()),
+      season: episode.as_ref().map(|episode| episode.season),
+      episode: episode.and_then(|episode| episode.number),
+      metadata: db.metadata.get(&id).cloned().unwrap_or_default(),
+      magnet: torrent::magnet_for(&db, torrents, &base.0, &id, token.as_deref()).await,
+      id,
+    });
+  }
+  Some(Json(entries))
+}
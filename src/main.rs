@@ -1,70 +1,216 @@
 #[macro_use]
 extern crate rocket;
 
-use std::{
-  collections::HashMap,
-  fs::File,
-  path::{Path, PathBuf},
-};
+pub mod archive;
+pub mod auth;
+pub mod channel;
+pub mod chapters;
+pub mod checksum;
+pub mod cli;
+pub mod database;
+pub mod dav;
+pub mod dedup;
+pub mod dlna;
+pub mod download;
+pub mod episode;
+pub mod export;
+pub mod feed;
+pub mod import;
+pub mod listing;
+pub mod matroska;
+pub mod media_info;
+pub mod metalink;
+pub mod mime;
+pub mod pieces;
+pub mod shuffle;
+pub mod storage;
+pub mod subtitles;
+pub mod throttle;
+pub mod torrent;
+pub mod verify;
+pub mod watch;
+pub mod webhook;
+pub mod xml;
 
-use log::trace;
-use rocket::{fs::NamedFile, get, launch, routes, State};
-use serde::{Deserialize, Serialize};
+use log::{debug, trace};
+use rocket::{
+  http::{Header, Method, RawStr},
+  State,
+};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Database {
-  id_to_path: HashMap<String, String>,
-}
-fn data_dir() -> PathBuf {
-  let data_dir = std::env::var("DATA_DIR")
-    .unwrap_or(concat!(env!("CARGO_MANIFEST_DIR"), "/", "data").to_string());
-  Path::new(&data_dir).to_path_buf()
-}
+use auth::User;
+use checksum::Checksums;
+use database::{Db, Library};
+use download::{content_disposition, download_name, ByteRange, Disposition, Download};
+use media_info::MediaInfos;
+use pieces::Pieces;
+use serde_json::json;
+use shuffle::Recent;
+use storage::Backends;
+use throttle::{Throttles, Traffic};
+use torrent::Torrents;
+use watch::Watcher;
+use webhook::Webhooks;
 
-#[launch]
-async fn rocket() -> _ {
+#[rocket::main]
+async fn main() {
   pretty_env_logger::init();
   trace!("Initialized logger");
 
-  let config_file_path = Path::new(&data_dir()).join("config.json");
-
-  let db = if config_file_path.exists() {
-    let file = File::open(config_file_path).unwrap();
-    let db: Database = serde_json::from_reader(file).unwrap();
-    db
-  } else {
-    let db = Database {
-      id_to_path: HashMap::new(),
-    };
-    let file = File::create(config_file_path).unwrap();
-    serde_json::to_writer(file, &db).unwrap();
-    db
-  };
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  match args.first().map(String::as_str) {
+    None | Some("serve") => {
+      if let Err(error) = rocket().launch().await {
+        eprintln!("Rocket failed: {}", error);
+        std::process::exit(1);
+      }
+    }
+    Some("add") => cli::add(&args[1..]),
+    Some("rm") => cli::rm(&args[1..]),
+    Some("ls") => cli::ls(&args[1..]),
+    Some("mv") => cli::mv(&args[1..]),
+    Some("scan") => cli::scan(&args[1..]),
+    Some("token") => cli::token(&args[1..]),
+    Some("verify") => verify::run(&args[1..]),
+    Some("dedup") => dedup::run(&args[1..]),
+    Some("import") => import::run(&args[1..]),
+    Some("export") => export::run(&args[1..]),
+    Some("help" | "--help" | "-h") => cli::usage(0),
+    Some(command) => {
+      eprintln!("Unknown command: {}", command);
+      cli::usage(2)
+    }
+  }
+}
+
+fn rocket() -> rocket::Rocket<rocket::Build> {
+  let webhooks = Webhooks::default();
+  let library = Library::new(webhooks.clone());
+  let backends = Backends::new(library.clone());
 
   rocket::build()
-    .manage(db)
-    .mount("/", routes![home, retrieve])
+    .manage(library)
+    .manage(webhooks)
+    .manage(backends)
+    .manage(Checksums::load())
+    .manage(MediaInfos::load())
+    .manage(Pieces::load())
+    .manage(Torrents::load())
+    .manage(Recent::default())
+    .manage(Watcher::load())
+    .manage(Throttles::default())
+    .manage(dlna::Access::new())
+    .attach(database::fairing())
+    .attach(checksum::fairing())
+    .attach(dav::fairing())
+    .attach(dlna::fairing())
+    .attach(webhook::fairing())
+    .attach(watch::fairing())
+    .attach(pieces::fairing())
+    .attach(torrent::fairing())
+    .register("/", catchers![auth::unauthorized])
+    .mount(
+      "/",
+      routes![
+        home,
+        retrieve,
+        listing::list,
+        listing::collections,
+        checksum::checksum,
+        metalink::metalink,
+        torrent::id,
+        torrent::season,
+        torrent::seed,
+        media_info::info,
+        chapters::list,
+        shuffle::random,
+        shuffle::shuffle,
+        channel::now_airing,
+        channel::xmltv,
+        feed::atom,
+        feed::rss,
+        subtitles::list,
+        subtitles::vtt,
+        archive::zip::season,
+        archive::zip::selection,
+        archive::tar::tar,
+        dlna::description,
+        dlna::content_directory_scpd,
+        dlna::connection_manager_scpd,
+        dlna::connection_manager,
+        dlna::content_directory::control
+      ],
+    )
+    .mount("/admin", routes![verify::report, watch::findings])
 }
 
 #[get("/")]
-fn home() -> String {
+fn home(_user: User) -> String {
   "Hello, world!".to_string()
 }
 
-#[get("/dr-who/<id>")]
-async fn retrieve(db: &State<Database>, id: String) -> Option<NamedFile> {
-  println!("ID: {}", id);
-  let value = db.id_to_path.get(&id);
-  println!("Value: {:?}", value);
-  if value.is_none() {
-    println!("No file found for id: {}", id);
-    return None;
+#[allow(clippy::too_many_arguments)]
+#[get("/dr-who/<id>?<disposition>", rank = 3)]
+async fn retrieve(
+  user: User,
+  db: Db,
+  method: Method,
+  backends: &State<Backends>,
+  checksums: &State<Checksums>,
+  webhooks: &State<Webhooks>,
+  throttles: &State<Throttles>,
+  traffic: Traffic,
+  range: ByteRange,
+  id: String,
+  disposition: Option<Disposition>,
+) -> Option<Download> {
+  let file_name = match db.id_to_path.get(&id) {
+    Some(store_path) => store_path.clone(),
+    None => {
+      debug!("No file found for id: {}", id);
+      return None;
+    }
+  };
+  let storage = backends.for_id(&db, &id);
+  let stat = storage.stat(&file_name).await.ok()?;
+  let mut download = Download::open(storage.as_ref(), &file_name, stat.size, &range)
+    .await
+    .ok()?;
+  download.content_type = mime::resolve(storage.as_ref(), &id, &file_name, stat.size).await;
+  let name = download_name(&db, &id, &file_name, download.content_type.as_ref());
+  download.headers.push(content_disposition(
+    disposition.unwrap_or(db.downloads.disposition),
+    &name,
+  ));
+  if let Some(digest) = checksums.cached(&file_name) {
+    download.headers.extend(digest.headers());
+  }
+  // Lets Metalink aware downloaders (RFC 6249) find the pieces and mirrors.
+  if db.is_local(&id) {
+    download.headers.push(Header::new(
+      "Link",
+      format!(
+        "</dr-who/{}.meta4>; rel=describedby; type=\"application/metalink4+xml\"",
+        RawStr::new(&id).percent_encode()
+      ),
+    ));
+  }
+  // Only whole files count as downloads; players fetch ranges all the time.
+  if method == Method::Get && download.is_whole() {
+    let data = json!({ "id": id, "user": user.name, "size": stat.size });
+    webhooks.emit(&db, webhook::DOWNLOAD_STARTED, data.clone());
+    let (webhooks, db) = (webhooks.inner().clone(), db.0.clone());
+    download.on_finish(move || webhooks.emit(&db, webhook::DOWNLOAD_COMPLETED, data));
   }
-  let file_name: String = value.unwrap().to_string();
-  println!("File name: {}", file_name);
-  let path = Path::new(&data_dir()).join("file-store").join(file_name);
-  println!("Path: {:?}", path);
-  NamedFile::open(path).await.ok()
+  let collection = db.collection_of(&id);
+  throttles.apply(
+    &db,
+    &mut download,
+    &traffic,
+    user.name.as_deref(),
+    Some(collection),
+  );
+  Some(download)
 }
 /*
 "s01e01.mkv": "completed/Doctor.Who.2005.S01.1080p.BluRay.x264-SHORTBREHD[rartv]/doctor.who.2005.s01e01.1080p.bluray.x264-shortbrehd.mkv",
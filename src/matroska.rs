@@ -0,0 +1,1029 @@
+use std::{
+  collections::{HashMap, HashSet},
+  fs::File,
+  io::{self, BufReader, Read, Seek},
+  path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+type Reader = BufReader<File>;
+
+const EBML: u32 = 0x1A45DFA3;
+const SEGMENT: u32 = 0x18538067;
+const SEEK_HEAD: u32 = 0x114D9B74;
+const SEEK: u32 = 0x4DBB;
+const SEEK_ID: u32 = 0x53AB;
+const SEEK_POSITION: u32 = 0x53AC;
+const INFO: u32 = 0x1549A966;
+const TIMESTAMP_SCALE: u32 = 0x2AD7B1;
+const DURATION: u32 = 0x4489;
+const TITLE: u32 = 0x7BA9;
+const TRACKS: u32 = 0x1654AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const FLAG_DEFAULT: u32 = 0x88;
+const FLAG_FORCED: u32 = 0x55AA;
+const CODEC_ID: u32 = 0x86;
+const LANGUAGE: u32 = 0x22B59C;
+const LANGUAGE_BCP47: u32 = 0x22B59D;
+const NAME: u32 = 0x536E;
+const VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+const AUDIO: u32 = 0xE1;
+const SAMPLING_FREQUENCY: u32 = 0xB5;
+const CHANNELS: u32 = 0x9F;
+const CONTENT_ENCODINGS: u32 = 0x6D80;
+const CONTENT_ENCODING: u32 = 0x6240;
+const CONTENT_COMPRESSION: u32 = 0x5034;
+const CONTENT_COMP_ALGO: u32 = 0x4254;
+const CONTENT_COMP_SETTINGS: u32 = 0x4255;
+const CHAPTERS: u32 = 0x1043A770;
+const EDITION_ENTRY: u32 = 0x45B9;
+const EDITION_FLAG_HIDDEN: u32 = 0x45BD;
+const EDITION_FLAG_DEFAULT: u32 = 0x45DB;
+const CHAPTER_ATOM: u32 = 0xB6;
+const CHAPTER_TIME_START: u32 = 0x91;
+const CHAPTER_TIME_END: u32 = 0x92;
+const CHAPTER_FLAG_HIDDEN: u32 = 0x98;
+const CHAPTER_DISPLAY: u32 = 0x80;
+const CHAP_STRING: u32 = 0x85;
+const CHAP_LANGUAGE: u32 = 0x437C;
+const TAGS: u32 = 0x1254C367;
+const TAG: u32 = 0x7373;
+const TARGETS: u32 = 0x63C0;
+const TARGET_TYPE_VALUE: u32 = 0x68CA;
+const TAG_TRACK_UID: u32 = 0x63C5;
+const SIMPLE_TAG: u32 = 0x67C8;
+const TAG_NAME: u32 = 0x45A3;
+const TAG_STRING: u32 = 0x4487;
+const CLUSTER: u32 = 0x1F43B675;
+const CLUSTER_TIMESTAMP: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+const BLOCK_GROUP: u32 = 0xA0;
+const BLOCK: u32 = 0xA1;
+const BLOCK_DURATION: u32 = 0x9B;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MediaInfo {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub title: Option<String>,
+  // Seconds.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub duration: Option<f64>,
+  pub tracks: Vec<Track>,
+  #[serde(default)]
+  pub chapters: Vec<Chapter>,
+  #[serde(default)]
+  pub tags: Vec<Tag>,
+  // Nanoseconds per block timestamp tick.
+  #[serde(default)]
+  pub timestamp_scale: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackKind {
+  Video,
+  Audio,
+  Subtitle,
+  Other,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Track {
+  pub number: u64,
+  #[serde(default)]
+  pub uid: u64,
+  pub kind: TrackKind,
+  pub codec: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub language: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub name: Option<String>,
+  pub default: bool,
+  pub forced: bool,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub width: Option<u64>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub height: Option<u64>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub channels: Option<u64>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub sampling_frequency: Option<f64>,
+  // How the track's frames are compressed, needed to read them.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub compression: Option<Compression>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+  Zlib,
+  // The given bytes were stripped from the start of every frame.
+  HeaderStripping(Vec<u8>),
+  Unsupported,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chapter {
+  // Seconds.
+  pub start: f64,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub end: Option<f64>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub title: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub language: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tag {
+  pub name: String,
+  pub value: String,
+  // Tags without a track are about the whole file (or the episode, show, etc. it belongs to).
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub track: Option<u64>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub target: Option<u64>,
+}
+
+// Where an element's data starts and how long it is. `size` is `None` for elements written with
+// an unknown size, which only clusters and segments of live streams do.
+#[derive(Debug, Clone, Copy)]
+struct Element {
+  id: u32,
+  start: u64,
+  size: Option<u64>,
+}
+
+impl Element {
+  fn end(&self) -> u64 {
+    self.size.map(|size| self.start + size).unwrap_or(u64::MAX)
+  }
+}
+
+fn invalid(message: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Variable length integers: the number of leading zero bits in the first byte says how many
+// bytes follow. Ids keep their length marker, sizes don't.
+fn read_vint(r: &mut Reader, keep_marker: bool) -> io::Result<(u64, usize)> {
+  let mut first = [0];
+  r.read_exact(&mut first)?;
+  let length = first[0].leading_zeros() as usize + 1;
+  if length > 8 {
+    return Err(invalid("invalid EBML variable length integer"));
+  }
+  let mut value = if keep_marker {
+    first[0] as u64
+  } else {
+    (first[0] & (0x7F >> (length - 1))) as u64
+  };
+  let mut rest = [0; 7];
+  r.read_exact(&mut rest[..length - 1])?;
+  for byte in &rest[..length - 1] {
+    value = (value << 8) | *byte as u64;
+  }
+  Ok((value, length))
+}
+
+fn read_element(r: &mut Reader) -> io::Result<Element> {
+  let (id, id_length) = read_vint(r, true)?;
+  if id_length > 4 {
+    return Err(invalid("invalid EBML element id"));
+  }
+  let (size, size_length) = read_vint(r, false)?;
+  let unknown = size == (1 << (7 * size_length)) - 1;
+  Ok(Element {
+    id: id as u32,
+    start: r.stream_position()?,
+    size: (!unknown).then_some(size),
+  })
+}
+
+fn goto(r: &mut Reader, position: u64) -> io::Result<()> {
+  let current = r.stream_position()?;
+  r.seek_relative(position as i64 - current as i64)
+}
+
+fn read_bytes(r: &mut Reader, element: &Element) -> io::Result<Vec<u8>> {
+  let size = element.size.ok_or_else(|| invalid("unsized value"))?;
+  if size > 16 << 20 {
+    return Err(invalid("oversized value"));
+  }
+  let mut data = vec![0; size as usize];
+  r.read_exact(&mut data)?;
+  Ok(data)
+}
+
+fn read_uint(r: &mut Reader, element: &Element) -> io::Result<u64> {
+  let data = read_bytes(r, element)?;
+  if data.len() > 8 {
+    return Err(invalid("oversized integer"));
+  }
+  Ok(
+    data
+      .iter()
+      .fold(0, |value, byte| (value << 8) | *byte as u64),
+  )
+}
+
+fn read_float(r: &mut Reader, element: &Element) -> io::Result<f64> {
+  let data = read_bytes(r, element)?;
+  match data.len() {
+    0 => Ok(0.0),
+    4 => Ok(f32::from_be_bytes(data.try_into().unwrap()) as f64),
+    8 => Ok(f64::from_be_bytes(data.try_into().unwrap())),
+    _ => Err(invalid("invalid float")),
+  }
+}
+
+fn read_string(r: &mut Reader, element: &Element) -> io::Result<String> {
+  let data = read_bytes(r, element)?;
+  let data = match data.iter().position(|byte| *byte == 0) {
+    Some(end) => &data[..end],
+    None => &data[..],
+  };
+  Ok(String::from_utf8_lossy(data).into_owned())
+}
+
+// Calls `f` for every child of `parent`, skipping whatever `f` left unread.
+fn children(
+  r: &mut Reader,
+  parent: &Element,
+  mut f: impl FnMut(&mut Reader, &Element) -> io::Result<()>,
+) -> io::Result<()> {
+  goto(r, parent.start)?;
+  while r.stream_position()? < parent.end() {
+    let child = match read_element(r) {
+      Ok(child) => child,
+      Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+      Err(error) => return Err(error),
+    };
+    let size = child.size.ok_or_else(|| invalid("unsized child element"))?;
+    f(r, &child)?;
+    goto(r, child.start + size)?;
+  }
+  Ok(())
+}
+
+fn parse_seek_head(r: &mut Reader, element: &Element, segment: u64) -> io::Result<Vec<(u32, u64)>> {
+  let mut seeks = Vec::new();
+  children(r, element, |r, seek| {
+    if seek.id != SEEK {
+      return Ok(());
+    }
+    let (mut id, mut position) = (None, None);
+    children(r, seek, |r, child| {
+      match child.id {
+        SEEK_ID => id = Some(read_uint(r, child)? as u32),
+        SEEK_POSITION => position = Some(read_uint(r, child)?),
+        _ => {}
+      }
+      Ok(())
+    })?;
+    if let (Some(id), Some(position)) = (id, position) {
+      seeks.push((id, segment + position));
+    }
+    Ok(())
+  })?;
+  Ok(seeks)
+}
+
+fn parse_info(r: &mut Reader, element: &Element, info: &mut MediaInfo) -> io::Result<()> {
+  let mut duration = None;
+  children(r, element, |r, child| {
+    match child.id {
+      TIMESTAMP_SCALE => info.timestamp_scale = read_uint(r, child)?,
+      DURATION => duration = Some(read_float(r, child)?),
+      TITLE => info.title = Some(read_string(r, child)?),
+      _ => {}
+    }
+    Ok(())
+  })?;
+  info.duration = duration.map(|ticks| ticks * info.timestamp_scale as f64 / 1e9);
+  Ok(())
+}
+
+fn parse_track(r: &mut Reader, element: &Element) -> io::Result<Track> {
+  let mut track = Track {
+    number: 0,
+    uid: 0,
+    kind: TrackKind::Other,
+    codec: String::new(),
+    // The spec's default when no language is written.
+    language: Some("eng".to_string()),
+    name: None,
+    default: true,
+    forced: false,
+    width: None,
+    height: None,
+    channels: None,
+    sampling_frequency: None,
+    compression: None,
+  };
+  let mut bcp47 = None;
+  children(r, element, |r, child| {
+    match child.id {
+      TRACK_NUMBER => track.number = read_uint(r, child)?,
+      TRACK_UID => track.uid = read_uint(r, child)?,
+      TRACK_TYPE => {
+        track.kind = match read_uint(r, child)? {
+          1 => TrackKind::Video,
+          2 => TrackKind::Audio,
+          17 => TrackKind::Subtitle,
+          _ => TrackKind::Other,
+        }
+      }
+      FLAG_DEFAULT => track.default = read_uint(r, child)? != 0,
+      FLAG_FORCED => track.forced = read_uint(r, child)? != 0,
+      CODEC_ID => track.codec = read_string(r, child)?,
+      LANGUAGE => track.language = Some(read_string(r, child)?),
+      LANGUAGE_BCP47 => bcp47 = Some(read_string(r, child)?),
+      NAME => track.name = Some(read_string(r, child)?),
+      VIDEO => children(r, child, |r, video| {
+        match video.id {
+          PIXEL_WIDTH => track.width = Some(read_uint(r, video)?),
+          PIXEL_HEIGHT => track.height = Some(read_uint(r, video)?),
+          _ => {}
+        }
+        Ok(())
+      })?,
+      AUDIO => children(r, child, |r, audio| {
+        match audio.id {
+          SAMPLING_FREQUENCY => track.sampling_frequency = Some(read_float(r, audio)?),
+          CHANNELS => track.channels = Some(read_uint(r, audio)?),
+          _ => {}
+        }
+        Ok(())
+      })?,
+      CONTENT_ENCODINGS => track.compression = parse_encodings(r, child)?,
+      _ => {}
+    }
+    Ok(())
+  })?;
+  if bcp47.is_some() {
+    track.language = bcp47;
+  }
+  Ok(track)
+}
+
+fn parse_encodings(r: &mut Reader, element: &Element) -> io::Result<Option<Compression>> {
+  let mut compression = None;
+  children(r, element, |r, encoding| {
+    if encoding.id != CONTENT_ENCODING {
+      return Ok(());
+    }
+    children(r, encoding, |r, child| {
+      if child.id != CONTENT_COMPRESSION {
+        return Ok(());
+      }
+      let (mut algorithm, mut settings) = (0, Vec::new());
+      children(r, child, |r, field| {
+        match field.id {
+          CONTENT_COMP_ALGO => algorithm = read_uint(r, field)?,
+          CONTENT_COMP_SETTINGS => settings = read_bytes(r, field)?,
+          _ => {}
+        }
+        Ok(())
+      })?;
+      compression = Some(match algorithm {
+        0 => Compression::Zlib,
+        3 => Compression::HeaderStripping(settings),
+        _ => Compression::Unsupported,
+      });
+      Ok(())
+    })
+  })?;
+  Ok(compression)
+}
+
+fn parse_chapter_atom(
+  r: &mut Reader,
+  element: &Element,
+  chapters: &mut Vec<Chapter>,
+) -> io::Result<()> {
+  let (mut start, mut end, mut hidden) = (0, None, false);
+  let (mut title, mut language) = (None, None);
+  let mut nested = Vec::new();
+  children(r, element, |r, child| {
+    match child.id {
+      CHAPTER_TIME_START => start = read_uint(r, child)?,
+      CHAPTER_TIME_END => end = Some(read_uint(r, child)?),
+      CHAPTER_FLAG_HIDDEN => hidden = read_uint(r, child)? != 0,
+      // Only the first display is used, which is normally the main language's.
+      CHAPTER_DISPLAY if title.is_none() => children(r, child, |r, display| {
+        match display.id {
+          CHAP_STRING => title = Some(read_string(r, display)?),
+          CHAP_LANGUAGE => language = Some(read_string(r, display)?),
+          _ => {}
+        }
+        Ok(())
+      })?,
+      CHAPTER_ATOM => parse_chapter_atom(r, child, &mut nested)?,
+      _ => {}
+    }
+    Ok(())
+  })?;
+  if !hidden {
+    chapters.push(Chapter {
+      start: start as f64 / 1e9,
+      end: end.map(|end| end as f64 / 1e9),
+      title,
+      language,
+    });
+    chapters.append(&mut nested);
+  }
+  Ok(())
+}
+
+// Chapters of the default edition, or of the first visible one if none is marked default.
+fn parse_chapters(r: &mut Reader, element: &Element) -> io::Result<Vec<Chapter>> {
+  let mut editions = Vec::new();
+  children(r, element, |r, edition| {
+    if edition.id != EDITION_ENTRY {
+      return Ok(());
+    }
+    let (mut default, mut hidden, mut chapters) = (false, false, Vec::new());
+    children(r, edition, |r, child| {
+      match child.id {
+        EDITION_FLAG_DEFAULT => default = read_uint(r, child)? != 0,
+        EDITION_FLAG_HIDDEN => hidden = read_uint(r, child)? != 0,
+        CHAPTER_ATOM => parse_chapter_atom(r, child, &mut chapters)?,
+        _ => {}
+      }
+      Ok(())
+    })?;
+    if !hidden {
+      editions.push((default, chapters));
+    }
+    Ok(())
+  })?;
+  let index = editions
+    .iter()
+    .position(|(default, _)| *default)
+    .unwrap_or(0);
+  let mut chapters = match editions.into_iter().nth(index) {
+    Some((_, chapters)) => chapters,
+    None => Vec::new(),
+  };
+  chapters.sort_by(|a, b| a.start.total_cmp(&b.start));
+  Ok(chapters)
+}
+
+fn parse_tags(r: &mut Reader, element: &Element) -> io::Result<Vec<Tag>> {
+  let mut tags = Vec::new();
+  children(r, element, |r, tag| {
+    if tag.id != TAG {
+      return Ok(());
+    }
+    let (mut track, mut target) = (None, None);
+    let mut simple = Vec::new();
+    children(r, tag, |r, child| {
+      match child.id {
+        TARGETS => children(r, child, |r, field| {
+          match field.id {
+            TARGET_TYPE_VALUE => target = Some(read_uint(r, field)?),
+            TAG_TRACK_UID => track = Some(read_uint(r, field)?).filter(|uid| *uid != 0),
+            _ => {}
+          }
+          Ok(())
+        })?,
+        SIMPLE_TAG => {
+          let (mut name, mut value) = (None, None);
+          children(r, child, |r, field| {
+            match field.id {
+              TAG_NAME => name = Some(read_string(r, field)?),
+              TAG_STRING => value = Some(read_string(r, field)?),
+              _ => {}
+            }
+            Ok(())
+          })?;
+          if let (Some(name), Some(value)) = (name, value) {
+            simple.push((name, value));
+          }
+        }
+        _ => {}
+      }
+      Ok(())
+    })?;
+    for (name, value) in simple {
+      tags.push(Tag {
+        name,
+        value,
+        track,
+        target,
+      });
+    }
+    Ok(())
+  })?;
+  Ok(tags)
+}
+
+// Opens a Matroska/WebM file and returns a reader positioned in its segment.
+fn open_segment(path: &Path) -> io::Result<(Reader, Element)> {
+  let mut r = BufReader::new(File::open(path)?);
+  let header = read_element(&mut r)?;
+  if header.id != EBML {
+    return Err(invalid("not a Matroska file"));
+  }
+  goto(&mut r, header.end())?;
+  let segment = read_element(&mut r)?;
+  if segment.id != SEGMENT {
+    return Err(invalid("no Matroska segment"));
+  }
+  Ok((r, segment))
+}
+
+// Reads the segment's top level elements up to the first cluster, then follows the seek head to
+// any metadata written after the clusters, so the media data itself is never scanned.
+pub fn parse(path: &Path) -> io::Result<MediaInfo> {
+  let (mut r, segment) = open_segment(path)?;
+  let mut info = MediaInfo {
+    timestamp_scale: 1_000_000,
+    ..MediaInfo::default()
+  };
+  let mut pending: Vec<(u32, u64)> = Vec::new();
+  let mut seen: HashSet<u64> = HashSet::new();
+
+  let mut position = segment.start;
+  loop {
+    goto(&mut r, position)?;
+    if position >= segment.end() {
+      break;
+    }
+    let element = match read_element(&mut r) {
+      Ok(element) => element,
+      Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+      Err(error) => return Err(error),
+    };
+    if element.id == CLUSTER || element.size.is_none() {
+      break;
+    }
+    seen.insert(position);
+    read_top_level(&mut r, &element, &mut info, segment.start, &mut pending)?;
+    position = element.end();
+  }
+
+  while let Some((id, position)) = pending.pop() {
+    if !seen.insert(position) {
+      continue;
+    }
+    goto(&mut r, position)?;
+    let element = read_element(&mut r)?;
+    if element.id != id || element.size.is_none() {
+      continue;
+    }
+    read_top_level(&mut r, &element, &mut info, segment.start, &mut pending)?;
+  }
+  Ok(info)
+}
+
+fn read_top_level(
+  r: &mut Reader,
+  element: &Element,
+  info: &mut MediaInfo,
+  segment: u64,
+  pending: &mut Vec<(u32, u64)>,
+) -> io::Result<()> {
+  match element.id {
+    SEEK_HEAD => {
+      let seeks = parse_seek_head(r, element, segment)?;
+      pending.extend(
+        seeks
+          .into_iter()
+          .filter(|(id, _)| matches!(*id, SEEK_HEAD | INFO | TRACKS | CHAPTERS | TAGS)),
+      );
+    }
+    INFO => parse_info(r, element, info)?,
+    TRACKS => {
+      info.tracks.clear();
+      children(r, element, |r, child| {
+        if child.id == TRACK_ENTRY {
+          info.tracks.push(parse_track(r, child)?);
+        }
+        Ok(())
+      })?;
+    }
+    CHAPTERS => info.chapters = parse_chapters(r, element)?,
+    TAGS => info.tags.extend(parse_tags(r, element)?),
+    _ => {}
+  }
+  Ok(())
+}
+
+// A frame of one of the tracks asked for in `frames`. Times are in nanoseconds.
+pub struct Frame {
+  pub track: u64,
+  pub start: u64,
+  pub duration: Option<u64>,
+  pub data: Vec<u8>,
+}
+
+// Splits a (Simple)Block into its track number, relative timestamp and payload. Laced blocks are
+// skipped, they are only used for audio in practice.
+fn parse_block(data: &[u8]) -> Option<(u64, i16, &[u8])> {
+  let length = data.first()?.leading_zeros() as usize + 1;
+  if length > 8 || data.len() < length + 3 {
+    return None;
+  }
+  let track = data[1..length]
+    .iter()
+    .fold((data[0] & (0x7F >> (length - 1))) as u64, |track, byte| {
+      (track << 8) | *byte as u64
+    });
+  let timestamp = i16::from_be_bytes([data[length], data[length + 1]]);
+  if data[length + 2] & 0x06 != 0 {
+    return None;
+  }
+  Some((track, timestamp, &data[length + 3..]))
+}
+
+// Reads a block if it belongs to one of `tracks`, looking only at its header otherwise.
+fn read_block(
+  r: &mut Reader,
+  element: &Element,
+  tracks: &HashSet<u64>,
+) -> io::Result<Option<(u64, i16, Vec<u8>)>> {
+  let mut header = [0; 12];
+  let length = (element.size.unwrap_or(0) as usize).min(header.len());
+  r.read_exact(&mut header[..length])?;
+  match parse_block(&header[..length]) {
+    Some((track, _, _)) if tracks.contains(&track) => {}
+    _ => return Ok(None),
+  }
+  goto(r, element.start)?;
+  let data = read_bytes(r, element)?;
+  Ok(parse_block(&data).map(|(track, timestamp, payload)| (track, timestamp, payload.to_vec())))
+}
+
+fn decompress(compression: Option<&Compression>, data: Vec<u8>) -> Option<Vec<u8>> {
+  match compression {
+    None => Some(data),
+    Some(Compression::HeaderStripping(header)) => Some([header.clone(), data].concat()),
+    Some(Compression::Zlib) => {
+      let mut inflated = Vec::new();
+      flate2::read::ZlibDecoder::new(&data[..])
+        .read_to_end(&mut inflated)
+        .ok()?;
+      Some(inflated)
+    }
+    Some(Compression::Unsupported) => None,
+  }
+}
+
+// Scans every cluster for frames of `tracks`. Blocks of other tracks are skipped unread, but this
+// still walks the whole file, so callers should cache whatever they build from the frames.
+pub fn frames(path: &Path, info: &MediaInfo, tracks: &HashSet<u64>) -> io::Result<Vec<Frame>> {
+  let (mut r, segment) = open_segment(path)?;
+  let scale = info.timestamp_scale.max(1);
+  let compression: HashMap<u64, &Compression> = info
+    .tracks
+    .iter()
+    .filter_map(|track| Some((track.number, track.compression.as_ref()?)))
+    .collect();
+  let mut frames = Vec::new();
+  let mut cluster: Option<Element> = None;
+  let mut cluster_timestamp = 0;
+
+  let mut position = segment.start;
+  while position < segment.end() {
+    goto(&mut r, position)?;
+    let element = match read_element(&mut r) {
+      Ok(element) => element,
+      Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+      Err(error) => return Err(error),
+    };
+    // Clusters of unknown size end where the next top level element, which all have four byte
+    // ids, starts.
+    if cluster.is_some_and(|cluster| position >= cluster.end() || element.id > 0xFFFFFF) {
+      cluster = None;
+    }
+    if element.id == CLUSTER {
+      cluster = Some(element);
+      cluster_timestamp = 0;
+      position = element.start;
+      continue;
+    }
+    let size = match element.size {
+      Some(size) => size,
+      None => break,
+    };
+    position = element.start + size;
+    if cluster.is_none() {
+      continue;
+    }
+
+    let (mut block, mut duration) = (None, None);
+    match element.id {
+      CLUSTER_TIMESTAMP => cluster_timestamp = read_uint(&mut r, &element)?,
+      SIMPLE_BLOCK => block = read_block(&mut r, &element, tracks)?,
+      BLOCK_GROUP => children(&mut r, &element, |r, child| {
+        match child.id {
+          BLOCK => block = read_block(r, child, tracks)?,
+          BLOCK_DURATION => duration = Some(read_uint(r, child)?),
+          _ => {}
+        }
+        Ok(())
+      })?,
+      _ => {}
+    }
+    if let Some((track, timestamp, data)) = block {
+      let start = (cluster_timestamp as i64 + timestamp as i64).max(0) as u64;
+      if let Some(data) = decompress(compression.get(&track).copied(), data) {
+        frames.push(Frame {
+          track,
+          start: start * scale,
+          duration: duration.map(|duration| duration * scale),
+          data,
+        });
+      }
+    }
+  }
+  Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{fs, io::Write, path::PathBuf};
+
+  use flate2::{write::ZlibEncoder, Compression as Level};
+
+  use super::*;
+
+  const DOC_TYPE: u32 = 0x4282;
+  const CUES: u32 = 0x1C53BB6B;
+
+  fn scratch(name: &str, bytes: &[u8]) -> PathBuf {
+    let directory =
+      std::env::temp_dir().join(format!("file-share-matroska-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let path = directory.join(name);
+    fs::write(&path, bytes).unwrap();
+    path
+  }
+
+  fn reader(name: &str, bytes: &[u8]) -> Reader {
+    BufReader::new(File::open(scratch(name, bytes)).unwrap())
+  }
+
+  fn id(id: u32) -> Vec<u8> {
+    id.to_be_bytes()
+      .into_iter()
+      .skip_while(|byte| *byte == 0)
+      .collect()
+  }
+
+  fn element(element: u32, data: &[u8]) -> Vec<u8> {
+    let mut bytes = id(element);
+    match data.len() {
+      length if length < 0x7F => bytes.push(0x80 | length as u8),
+      length => bytes.extend((0x4000 | length as u16).to_be_bytes()),
+    }
+    bytes.extend(data);
+    bytes
+  }
+
+  fn uint(id: u32, value: u64) -> Vec<u8> {
+    element(id, &value.to_be_bytes())
+  }
+
+  fn string(id: u32, value: &str) -> Vec<u8> {
+    element(id, value.as_bytes())
+  }
+
+  fn compression(algorithm: u64, settings: &[u8]) -> Vec<u8> {
+    let mut fields = uint(CONTENT_COMP_ALGO, algorithm);
+    fields.extend(element(CONTENT_COMP_SETTINGS, settings));
+    let compression = element(CONTENT_COMPRESSION, &fields);
+    element(CONTENT_ENCODINGS, &element(CONTENT_ENCODING, &compression))
+  }
+
+  fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Level::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+  }
+
+  fn block(track: u8, timestamp: i16, flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut block = vec![0x80 | track];
+    block.extend(timestamp.to_be_bytes());
+    block.push(flags);
+    block.extend(payload);
+    block
+  }
+
+  // Three subtitle and audio tracks, a cluster of unknown size ended by cues, and a sized one.
+  fn file() -> Vec<u8> {
+    let mut bytes = element(EBML, &string(DOC_TYPE, "matroska"));
+    // A segment of unknown size, as live streams write.
+    bytes.extend(id(SEGMENT));
+    bytes.push(0xFF);
+
+    let mut info = uint(TIMESTAMP_SCALE, 1_000_000);
+    info.extend(element(DURATION, &2500f64.to_be_bytes()));
+    info.extend(string(TITLE, "Pilot"));
+    bytes.extend(element(INFO, &info));
+
+    let mut stripped = uint(TRACK_NUMBER, 1);
+    stripped.extend(uint(TRACK_TYPE, 17));
+    stripped.extend(string(CODEC_ID, "S_TEXT/UTF8"));
+    stripped.extend(string(LANGUAGE, "ger"));
+    stripped.extend(compression(3, b"Hel"));
+    let mut zlibbed = uint(TRACK_NUMBER, 2);
+    zlibbed.extend(uint(TRACK_TYPE, 17));
+    zlibbed.extend(string(CODEC_ID, "S_TEXT/ASS"));
+    zlibbed.extend(compression(0, b""));
+    let mut audio = uint(TRACK_NUMBER, 3);
+    audio.extend(uint(TRACK_TYPE, 2));
+    audio.extend(string(LANGUAGE, "eng"));
+    audio.extend(string(LANGUAGE_BCP47, "en-GB"));
+    let mut settings = element(SAMPLING_FREQUENCY, &48000f32.to_be_bytes());
+    settings.extend(uint(CHANNELS, 2));
+    audio.extend(element(AUDIO, &settings));
+    let mut tracks = element(TRACK_ENTRY, &stripped);
+    tracks.extend(element(TRACK_ENTRY, &zlibbed));
+    tracks.extend(element(TRACK_ENTRY, &audio));
+    bytes.extend(element(TRACKS, &tracks));
+
+    bytes.extend(id(CLUSTER));
+    bytes.push(0xFF);
+    bytes.extend(uint(CLUSTER_TIMESTAMP, 100));
+    bytes.extend(element(SIMPLE_BLOCK, &block(1, 5, 0x80, b"lo")));
+    bytes.extend(element(SIMPLE_BLOCK, &block(2, 7, 0x80, &zlib(b"Hi"))));
+    bytes.extend(element(SIMPLE_BLOCK, &block(3, 0, 0x80, b"audio")));
+    // Laced.
+    bytes.extend(element(SIMPLE_BLOCK, &block(1, 9, 0x82, b"\x01lace")));
+
+    // Ends the cluster above, so this block belongs to none.
+    bytes.extend(element(CUES, b""));
+    bytes.extend(element(SIMPLE_BLOCK, &block(1, 0, 0x80, b"stray")));
+
+    let mut group = element(BLOCK, &block(1, -10, 0, b"p"));
+    group.extend(uint(BLOCK_DURATION, 40));
+    let mut cluster = uint(CLUSTER_TIMESTAMP, 200);
+    cluster.extend(element(BLOCK_GROUP, &group));
+    bytes.extend(element(CLUSTER, &cluster));
+    bytes
+  }
+
+  #[test]
+  fn reads_vints_of_every_length() {
+    let mut bytes = Vec::new();
+    for length in 1..=8 {
+      let mut vint = vec![0; length];
+      vint[0] = 0x80 >> (length - 1);
+      vint[length - 1] |= 5;
+      bytes.extend(vint);
+    }
+    let mut r = reader("vints", &bytes);
+    for length in 1..=8 {
+      assert_eq!(read_vint(&mut r, false).unwrap(), (5, length));
+    }
+
+    let mut r = reader("ids", &[0x1A, 0x45, 0xDF, 0xA3, 0x81]);
+    assert_eq!(read_vint(&mut r, true).unwrap(), (EBML as u64, 4));
+    assert_eq!(read_vint(&mut r, true).unwrap(), (0x81, 1));
+
+    let mut r = reader("no marker", &[0x00, 0x81]);
+    let error = read_vint(&mut r, false).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    // Ids are at most four bytes long.
+    let mut r = reader("long id", &[0x08, 0, 0, 0, 1, 0x80]);
+    assert!(read_element(&mut r).is_err());
+  }
+
+  #[test]
+  fn reads_unknown_sizes() {
+    let mut bytes = vec![0xA3, 0xFF];
+    bytes.extend([0xA3, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+    bytes.extend([0xA3, 0x40, 0x7F]);
+    let mut r = reader("sizes", &bytes);
+    assert_eq!(read_element(&mut r).unwrap().size, None);
+    let element = read_element(&mut r).unwrap();
+    assert_eq!(element.size, None);
+    assert_eq!(element.end(), u64::MAX);
+    // All ones only means unknown for the length it is written in.
+    assert_eq!(read_element(&mut r).unwrap().size, Some(0x7F));
+  }
+
+  #[test]
+  fn reads_floats_of_either_width() {
+    let mut bytes = element(DURATION, b"");
+    bytes.extend(element(DURATION, &1.5f32.to_be_bytes()));
+    bytes.extend(element(DURATION, &2.25f64.to_be_bytes()));
+    bytes.extend(element(DURATION, &[0; 3]));
+    let mut r = reader("floats", &bytes);
+    for expected in [0.0, 1.5, 2.25] {
+      let element = read_element(&mut r).unwrap();
+      assert_eq!(read_float(&mut r, &element).unwrap(), expected);
+    }
+    let element = read_element(&mut r).unwrap();
+    assert!(read_float(&mut r, &element).is_err());
+  }
+
+  #[test]
+  fn splits_blocks() {
+    assert_eq!(
+      parse_block(&block(1, 5, 0x80, b"x")),
+      Some((1, 5, &b"x"[..]))
+    );
+    assert_eq!(
+      parse_block(&[0x40, 0x82, 0xFF, 0xFE, 0x00, b'y']),
+      Some((130, -2, &b"y"[..]))
+    );
+    // Xiph, fixed size and EBML lacing.
+    for flags in [0x02, 0x04, 0x06] {
+      assert_eq!(parse_block(&block(1, 0, flags, b"\x01ab")), None);
+    }
+    assert_eq!(parse_block(&[0x81, 0x00]), None);
+    assert_eq!(parse_block(&[]), None);
+    assert_eq!(parse_block(&[0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]), None);
+  }
+
+  #[test]
+  fn decompresses_frames() {
+    assert_eq!(decompress(None, b"as is".to_vec()), Some(b"as is".to_vec()));
+    let stripped = Compression::HeaderStripping(b"\x00\x00\x01".to_vec());
+    assert_eq!(
+      decompress(Some(&stripped), b"\x09".to_vec()),
+      Some(b"\x00\x00\x01\x09".to_vec())
+    );
+    let zlibbed = zlib(b"Dialogue: 0,0:00:01.00");
+    assert_eq!(
+      decompress(Some(&Compression::Zlib), zlibbed),
+      Some(b"Dialogue: 0,0:00:01.00".to_vec())
+    );
+    assert_eq!(decompress(Some(&Compression::Zlib), b"junk".to_vec()), None);
+    assert_eq!(
+      decompress(Some(&Compression::Unsupported), b"lzo".to_vec()),
+      None
+    );
+  }
+
+  #[test]
+  fn parses_info_and_tracks() {
+    let info = parse(&scratch("info.mkv", &file())).unwrap();
+    assert_eq!(info.title.as_deref(), Some("Pilot"));
+    assert_eq!(info.duration, Some(2.5));
+    assert_eq!(info.timestamp_scale, 1_000_000);
+    let numbers: Vec<u64> = info.tracks.iter().map(|track| track.number).collect();
+    assert_eq!(numbers, [1, 2, 3]);
+
+    let (stripped, zlibbed, audio) = (&info.tracks[0], &info.tracks[1], &info.tracks[2]);
+    assert_eq!(stripped.kind, TrackKind::Subtitle);
+    assert_eq!(stripped.language.as_deref(), Some("ger"));
+    assert!(matches!(
+      &stripped.compression,
+      Some(Compression::HeaderStripping(header)) if header == b"Hel"
+    ));
+    assert_eq!(zlibbed.codec, "S_TEXT/ASS");
+    // The spec's default language.
+    assert_eq!(zlibbed.language.as_deref(), Some("eng"));
+    assert!(matches!(zlibbed.compression, Some(Compression::Zlib)));
+    assert_eq!(audio.kind, TrackKind::Audio);
+    assert_eq!(audio.language.as_deref(), Some("en-GB"));
+    assert_eq!(audio.sampling_frequency, Some(48000.0));
+    assert_eq!(audio.channels, Some(2));
+    assert!(audio.compression.is_none());
+  }
+
+  #[test]
+  fn reads_frames_of_unknown_size_clusters() {
+    let path = scratch("frames.mkv", &file());
+    let info = parse(&path).unwrap();
+    let frames = frames(&path, &info, &HashSet::from([1, 2])).unwrap();
+    let frames: Vec<(u64, u64, Option<u64>, &[u8])> = frames
+      .iter()
+      .map(|frame| (frame.track, frame.start, frame.duration, &frame.data[..]))
+      .collect();
+    assert_eq!(
+      frames,
+      [
+        (1, 105_000_000, None, &b"Hello"[..]),
+        (2, 107_000_000, None, &b"Hi"[..]),
+        (1, 190_000_000, Some(40_000_000), &b"Help"[..]),
+      ]
+    );
+  }
+
+  #[test]
+  fn fails_on_truncated_files() {
+    let bytes = file();
+    let info = parse(&scratch("whole.mkv", &bytes)).unwrap();
+    for length in 0..bytes.len() {
+      let path = scratch("truncated.mkv", &bytes[..length]);
+      let _ = parse(&path);
+      let _ = frames(&path, &info, &HashSet::from([1, 2, 3]));
+    }
+    assert!(parse(&scratch("header.mkv", &bytes[..3])).is_err());
+    // Cut off inside the tracks.
+    let tracks = bytes.len() / 3;
+    assert!(parse(&scratch("tracks.mkv", &bytes[..tracks])).is_err());
+  }
+}
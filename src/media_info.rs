@@ -0,0 +1,120 @@
+use std::{
+  collections::HashMap,
+  fs::File,
+  io,
+  path::PathBuf,
+  sync::{Arc, RwLock},
+};
+
+use log::warn;
+use rocket::{serde::json::Json, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  auth::User,
+  checksum::stamp,
+  database::{data_dir, resolve, save_json, Db, Metadata},
+  matroska::{self, MediaInfo},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+  size: u64,
+  mtime: u64,
+  info: MediaInfo,
+}
+
+// Parsed container metadata of files in the store, keyed by their `id_to_path` value and persisted
+// in `media-info.json`. Like checksums, entries are dropped once the file's size or mtime changes.
+#[derive(Clone, Default)]
+pub struct MediaInfos {
+  cache: Arc<RwLock<HashMap<String, Entry>>>,
+}
+
+fn cache_file_path() -> PathBuf {
+  data_dir().join("media-info.json")
+}
+
+impl MediaInfos {
+  pub fn load() -> MediaInfos {
+    let cache = File::open(cache_file_path())
+      .ok()
+      .and_then(|file| serde_json::from_reader(file).ok())
+      .unwrap_or_default();
+    MediaInfos {
+      cache: Arc::new(RwLock::new(cache)),
+    }
+  }
+
+  fn save(&self) {
+    let cache = self.cache.read().unwrap();
+    let result = save_json(&cache_file_path(), &*cache);
+    if let Err(error) = result {
+      warn!("Failed to save media info: {}", error);
+    }
+  }
+
+  // Blocking: parses the file unless up to date info is cached.
+  pub fn get(&self, store_path: &str) -> io::Result<MediaInfo> {
+    let path = resolve(store_path);
+    let (size, mtime) = stamp(&path)?;
+    if let Some(entry) = self.cache.read().unwrap().get(store_path) {
+      if entry.size == size && entry.mtime == mtime {
+        return Ok(entry.info.clone());
+      }
+    }
+    let info = matroska::parse(&path)?;
+    self.cache.write().unwrap().insert(
+      store_path.to_string(),
+      Entry {
+        size,
+        mtime,
+        info: info.clone(),
+      },
+    );
+    self.save();
+    Ok(info)
+  }
+}
+
+#[derive(Serialize)]
+pub struct Info {
+  id: String,
+  // Kept apart from the container's own fields, which have a `title` of their own.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  metadata: Option<Metadata>,
+  #[serde(flatten)]
+  media: Option<MediaInfo>,
+}
+
+// Container details are only read from Matroska/WebM files on local storage; ids with neither
+// those nor imported metadata are a 404.
+#[get("/dr-who/<id>/info", rank = 3)]
+pub async fn info(
+  _user: User,
+  db: Db,
+  infos: &State<MediaInfos>,
+  id: String,
+) -> Option<Json<Info>> {
+  let store_path = db.id_to_path.get(&id)?.clone();
+  let metadata = db.metadata.get(&id).cloned();
+  let media = match db.is_local(&id) {
+    true => {
+      let infos = infos.inner().clone();
+      rocket::tokio::task::spawn_blocking(move || infos.get(&store_path))
+        .await
+        .ok()?
+        .map_err(|error| warn!("Failed to read media info of {}: {}", id, error))
+        .ok()
+    }
+    false => None,
+  };
+  if media.is_none() && metadata.is_none() {
+    return None;
+  }
+  Some(Json(Info {
+    id,
+    metadata,
+    media,
+  }))
+}
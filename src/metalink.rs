@@ -0,0 +1,155 @@
+use rocket::{
+  http::{ContentType, RawStr, Status},
+  request::FromParam,
+  response::{self, Responder},
+  Request, Response, State,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  auth::User,
+  channel::rfc3339,
+  checksum::Checksums,
+  database::Db,
+  download::{download_name, Base},
+  feed::with_token,
+  mime,
+  pieces::Pieces,
+  storage::Backends,
+  xml,
+};
+
+// Another server with the same files, `url` being what an id is appended to, e.g.
+// `https://mirror.example.org/dr-who/`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mirror {
+  pub url: String,
+  // ISO 3166-1 country code, for downloaders preferring nearby mirrors.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub location: Option<String>,
+  // 1 is tried first; this server is 1.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub priority: Option<u32>,
+}
+
+pub struct Meta4Name(String);
+
+impl<'a> FromParam<'a> for Meta4Name {
+  type Error = &'a str;
+
+  fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+    match param.strip_suffix(".meta4") {
+      Some(id) => Ok(Meta4Name(id.to_string())),
+      None => Err(param),
+    }
+  }
+}
+
+pub enum Meta4 {
+  Ready(String),
+  // Hashing takes a while for big files, and happens in the background.
+  Hashing,
+}
+
+impl<'r> Responder<'r, 'static> for Meta4 {
+  fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+    match self {
+      Meta4::Ready(meta4) => Response::build_from(meta4.respond_to(request)?)
+        .header(ContentType::new("application", "metalink4+xml"))
+        .ok(),
+      Meta4::Hashing => {
+        Response::build_from("Hashing pieces, try again shortly.\n".respond_to(request)?)
+          .status(Status::Accepted)
+          .raw_header("Retry-After", "10")
+          .ok()
+      }
+    }
+  }
+}
+
+// A Metalink 4 (RFC 5854) document for `id`, so that aria2 and the like can fetch pieces from
+// this server and its mirrors side by side, checking each one as it arrives. Only files on local
+// storage have their pieces hashed.
+#[allow(clippy::too_many_arguments)]
+#[get("/dr-who/<name>?<token>", rank = 2)]
+pub async fn metalink(
+  _user: User,
+  db: Db,
+  base: Base,
+  backends: &State<Backends>,
+  checksums: &State<Checksums>,
+  pieces: &State<Pieces>,
+  name: Meta4Name,
+  token: Option<String>,
+) -> Option<Meta4> {
+  let id = name.0;
+  let store_path = db.id_to_path.get(&id)?.clone();
+  if !db.is_local(&id) {
+    return None;
+  }
+  let storage = backends.for_id(&db, &id);
+  let content_type = mime::resolve(
+    storage.as_ref(),
+    &id,
+    &store_path,
+    storage.stat(&store_path).await.ok()?.size,
+  )
+  .await;
+  let (digest, pieces) = match pieces.get(checksums, &store_path) {
+    Some(hashed) => hashed,
+    None => return Some(Meta4::Hashing),
+  };
+
+  let name = download_name(&db, &id, &db.id_to_path[&id], content_type.as_ref());
+  let mut meta4 = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+  meta4.push_str("<metalink xmlns=\"urn:ietf:params:xml:ns:metalink\">\n");
+  meta4.push_str("  <generator>file-share</generator>\n");
+  if let Some(added) = db.added(&id) {
+    meta4.push_str(&format!(
+      "  <published>{}</published>\n",
+      rfc3339(added as f64)
+    ));
+  }
+  meta4.push_str(&format!(
+    "  <file name=\"{}\">\n    <size>{}</size>\n    <hash type=\"sha-256\">{}</hash>\n",
+    xml::escape(&name),
+    digest.size,
+    digest.sha256
+  ));
+  if let Some(title) = db.title(&id) {
+    meta4.push_str(&format!(
+      "    <description>{}</description>\n",
+      xml::escape(title)
+    ));
+  }
+  meta4.push_str(&format!(
+    "    <pieces length=\"{}\" type=\"sha-256\">\n",
+    pieces.length
+  ));
+  for hash in &pieces.sha256 {
+    meta4.push_str(&format!("      <hash>{}</hash>\n", hash));
+  }
+  meta4.push_str("    </pieces>\n");
+
+  let encoded = RawStr::new(&id).percent_encode().to_string();
+  meta4.push_str(&format!(
+    "    <url priority=\"1\">{}</url>\n",
+    xml::escape(&with_token(
+      format!("{}/dr-who/{}", base.0, encoded),
+      token.as_deref()
+    ))
+  ));
+  // Mirrors are other people's servers, so they don't get this one's token.
+  for mirror in &db.mirrors {
+    meta4.push_str("    <url");
+    if let Some(location) = &mirror.location {
+      meta4.push_str(&format!(" location=\"{}\"", xml::escape(location)));
+    }
+    if let Some(priority) = mirror.priority {
+      meta4.push_str(&format!(" priority=\"{}\"", priority));
+    }
+    meta4.push_str(&format!(">{}{}</url>\n", xml::escape(&mirror.url), encoded));
+  }
+  meta4.push_str("  </file>\n</metalink>\n");
+  Some(Meta4::Ready(meta4))
+}
@@ -0,0 +1,244 @@
+use std::path::Path;
+
+use rocket::{http::ContentType, tokio::io::AsyncReadExt};
+
+use crate::{download::file_name, storage::Storage};
+
+// Rocket's own table misses most video containers and subtitle formats. The first extension
+// listed for a type is the one used when a name has to be made up for it.
+const TYPES: &[(&str, &str, &str)] = &[
+  ("mkv", "video", "x-matroska"),
+  ("mk3d", "video", "x-matroska"),
+  ("mka", "audio", "x-matroska"),
+  ("webm", "video", "webm"),
+  ("mp4", "video", "mp4"),
+  ("m4v", "video", "mp4"),
+  ("m4a", "audio", "mp4"),
+  ("mov", "video", "quicktime"),
+  ("avi", "video", "x-msvideo"),
+  ("ts", "video", "mp2t"),
+  ("m2ts", "video", "mp2t"),
+  ("mpg", "video", "mpeg"),
+  ("mpeg", "video", "mpeg"),
+  ("ogv", "video", "ogg"),
+  ("ogg", "audio", "ogg"),
+  ("opus", "audio", "ogg"),
+  ("mp3", "audio", "mpeg"),
+  ("flac", "audio", "flac"),
+  ("wav", "audio", "wav"),
+  ("srt", "application", "x-subrip"),
+  ("vtt", "text", "vtt"),
+  ("ass", "text", "x-ssa"),
+  ("ssa", "text", "x-ssa"),
+  ("nfo", "text", "plain"),
+  ("txt", "text", "plain"),
+  ("jpg", "image", "jpeg"),
+  ("jpeg", "image", "jpeg"),
+  ("png", "image", "png"),
+  ("gif", "image", "gif"),
+  ("pdf", "application", "pdf"),
+  ("zip", "application", "zip"),
+  ("tar", "application", "x-tar"),
+  ("zst", "application", "zstd"),
+];
+
+pub fn from_extension(extension: &str) -> Option<ContentType> {
+  let extension = extension.to_ascii_lowercase();
+  TYPES
+    .iter()
+    .find(|(known, _, _)| *known == extension)
+    .map(|(_, top, sub)| ContentType::new(*top, *sub))
+    .or_else(|| ContentType::from_extension(&extension))
+}
+
+pub fn extension(content_type: &ContentType) -> Option<&'static str> {
+  TYPES
+    .iter()
+    .find(|(_, top, sub)| content_type.top() == *top && content_type.sub() == *sub)
+    .map(|(extension, _, _)| *extension)
+}
+
+// Enough for every signature below; MPEG-TS needs a second sync byte 188 bytes in.
+pub const SNIFF_LENGTH: u64 = 512;
+
+pub fn sniff(header: &[u8]) -> Option<ContentType> {
+  let at = |offset: usize, magic: &[u8]| header.get(offset..offset + magic.len()) == Some(magic);
+  let (top, sub) = if at(0, &[0x1A, 0x45, 0xDF, 0xA3]) {
+    // The DocType sits in the EBML header, a few bytes in.
+    match header.windows(4).take(64).any(|window| window == b"webm") {
+      true => ("video", "webm"),
+      false => ("video", "x-matroska"),
+    }
+  } else if at(4, b"ftyp") {
+    match header.get(8..12) {
+      Some(b"qt  ") => ("video", "quicktime"),
+      Some(b"M4A ") => ("audio", "mp4"),
+      _ => ("video", "mp4"),
+    }
+  } else if at(0, b"RIFF") && at(8, b"AVI ") {
+    ("video", "x-msvideo")
+  } else if at(0, b"RIFF") && at(8, b"WAVE") {
+    ("audio", "wav")
+  } else if at(0, &[0x47]) && at(188, &[0x47]) {
+    ("video", "mp2t")
+  } else if at(0, &[0x00, 0x00, 0x01, 0xBA]) {
+    ("video", "mpeg")
+  } else if at(0, b"OggS") {
+    ("audio", "ogg")
+  } else if at(0, b"fLaC") {
+    ("audio", "flac")
+  } else if at(0, b"ID3") || (header.len() > 1 && header[0] == 0xFF && header[1] & 0xE0 == 0xE0) {
+    ("audio", "mpeg")
+  } else if at(0, b"\x89PNG") {
+    ("image", "png")
+  } else if at(0, &[0xFF, 0xD8, 0xFF]) {
+    ("image", "jpeg")
+  } else if at(0, b"GIF8") {
+    ("image", "gif")
+  } else if at(0, b"%PDF") {
+    ("application", "pdf")
+  } else if at(0, b"PK\x03\x04") {
+    ("application", "zip")
+  } else if at(257, b"ustar") {
+    ("application", "x-tar")
+  } else if at(0, &[0x28, 0xB5, 0x2F, 0xFD]) {
+    ("application", "zstd")
+  } else if at(0, b"WEBVTT") || at(3, b"WEBVTT") {
+    ("text", "vtt")
+  } else {
+    return None;
+  };
+  Some(ContentType::new(top, sub))
+}
+
+// By the extension of the id or of the stored file, and failing that by looking at the first bytes
+// of the file, which is what content addressed files and ids like `s07eTheDayOfTheDoctor` need.
+pub async fn resolve(
+  storage: &dyn Storage,
+  id: &str,
+  store_path: &str,
+  size: u64,
+) -> Option<ContentType> {
+  let named = Path::new(&file_name(id, store_path))
+    .extension()
+    .and_then(|extension| from_extension(&extension.to_string_lossy()));
+  if named.is_some() || size == 0 {
+    return named;
+  }
+  let mut reader = storage
+    .open(store_path, Some(0..size.min(SNIFF_LENGTH)))
+    .await
+    .ok()?;
+  let mut header = Vec::new();
+  reader.read_to_end(&mut header).await.ok()?;
+  sniff(&header)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{fs, path::PathBuf};
+
+  use super::*;
+  use crate::storage::local::Local;
+
+  // An EBML header for `doc_type`, as Matroska and WebM files start.
+  fn ebml(doc_type: &str) -> Vec<u8> {
+    let mut header = vec![0x1A, 0x45, 0xDF, 0xA3, 0x80 | (doc_type.len() as u8 + 3)];
+    header.extend([0x42, 0x82, 0x80 | doc_type.len() as u8]);
+    header.extend(doc_type.as_bytes());
+    header
+  }
+
+  fn sniffed(header: &[u8]) -> Option<String> {
+    sniff(header).map(|content_type| format!("{}/{}", content_type.top(), content_type.sub()))
+  }
+
+  // Absolute paths resolve to themselves, so nothing in the data directory is touched.
+  fn scratch(name: &str, bytes: &[u8]) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("file-share-mime-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let path = directory.join(name);
+    fs::write(&path, bytes).unwrap();
+    path
+  }
+
+  #[test]
+  fn sniffs_containers() {
+    let mut ts = vec![0; 189];
+    (ts[0], ts[188]) = (0x47, 0x47);
+    let mut tar = vec![0; 512];
+    tar[257..262].copy_from_slice(b"ustar");
+    let cases: &[(&[u8], &str)] = &[
+      (&ebml("matroska"), "video/x-matroska"),
+      (&ebml("webm"), "video/webm"),
+      (b"\x00\x00\x00\x20ftypisom", "video/mp4"),
+      (b"\x00\x00\x00\x14ftypqt  ", "video/quicktime"),
+      (b"\x00\x00\x00\x20ftypM4A ", "audio/mp4"),
+      (b"RIFF\x00\x00\x00\x00AVI LIST", "video/x-msvideo"),
+      (b"RIFF\x00\x00\x00\x00WAVEfmt ", "audio/wav"),
+      (&ts, "video/mp2t"),
+      (b"\x00\x00\x01\xBA\x44", "video/mpeg"),
+      (b"OggS\x00\x02", "audio/ogg"),
+      (b"fLaC\x00", "audio/flac"),
+      (b"ID3\x04\x00", "audio/mpeg"),
+      (b"\xFF\xFB\x90\x00", "audio/mpeg"),
+      (b"\x89PNG\r\n\x1A\n", "image/png"),
+      (b"\xFF\xD8\xFF\xE0", "image/jpeg"),
+      (b"GIF89a", "image/gif"),
+      (b"%PDF-1.7", "application/pdf"),
+      (b"PK\x03\x04\x14\x00", "application/zip"),
+      (&tar, "application/x-tar"),
+      (b"\x28\xB5\x2F\xFD\x00", "application/zstd"),
+      (b"WEBVTT\n\n", "text/vtt"),
+      (b"\xEF\xBB\xBFWEBVTT\n", "text/vtt"),
+    ];
+    for (header, expected) in cases {
+      assert_eq!(sniffed(header).as_deref(), Some(*expected), "{:?}", header);
+    }
+  }
+
+  #[test]
+  fn sniffs_nothing_from_too_little() {
+    assert_eq!(sniffed(b""), None);
+    assert_eq!(sniffed(b"plain text"), None);
+    // A sync byte, but not a second one a packet later.
+    assert_eq!(sniffed(&[0x47; 100]), None);
+    assert_eq!(
+      sniffed(b"\x00\x00\x00\x20ftyp"),
+      Some("video/mp4".to_string())
+    );
+    assert_eq!(sniffed(b"RIFF"), None);
+  }
+
+  #[rocket::async_test]
+  async fn resolves_ids_without_an_extension() {
+    let header = ebml("matroska");
+    let path = scratch("noext", &header);
+    let path = path.to_str().unwrap();
+    let size = header.len() as u64;
+    let resolved = resolve(&Local, "s07eTheDayOfTheDoctor", path, size).await;
+    assert_eq!(resolved, Some(ContentType::new("video", "x-matroska")));
+
+    let webm = ebml("webm");
+    let trailer = scratch("trailer", &webm);
+    let size = webm.len() as u64;
+    let resolved = resolve(&Local, "trailer", trailer.to_str().unwrap(), size).await;
+    assert_eq!(resolved, Some(ContentType::new("video", "webm")));
+
+    // Named types win over whatever the file starts with.
+    let resolved = resolve(&Local, "s04e01.srt", path, size).await;
+    assert_eq!(resolved, Some(ContentType::new("application", "x-subrip")));
+    let subtitles = scratch("subtitles.vtt", b"");
+    let resolved = resolve(&Local, "s04e01", subtitles.to_str().unwrap(), 0).await;
+    assert_eq!(resolved, Some(ContentType::new("text", "vtt")));
+
+    let unknown = scratch("unknown", b"neither here nor there");
+    let resolved = resolve(&Local, "s04e02", unknown.to_str().unwrap(), 22).await;
+    assert_eq!(resolved, None);
+    let empty = scratch("empty", b"");
+    assert_eq!(
+      resolve(&Local, "s04e03", empty.to_str().unwrap(), 0).await,
+      None
+    );
+  }
+}
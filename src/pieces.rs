@@ -0,0 +1,177 @@
+use std::{
+  collections::{BTreeSet, HashMap},
+  fs::File,
+  io,
+  sync::{Arc, Mutex, RwLock},
+};
+
+use log::{info, warn};
+use rocket::{
+  fairing::AdHoc,
+  tokio::{select, sync::Notify},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+  checksum::{hash_file_with, stamp, Checksums, FileDigest},
+  database::{data_dir, resolve, save_json},
+};
+
+const MIN_PIECE: u64 = 256 * 1024;
+const MAX_PIECE: u64 = 16 * 1024 * 1024;
+// Pieces get longer with the file, to keep the list of their hashes short.
+const MAX_PIECES: u64 = 2048;
+
+pub fn piece_length(size: u64) -> u64 {
+  let mut length = MIN_PIECE;
+  while length < MAX_PIECE && size.div_ceil(length) > MAX_PIECES {
+    length *= 2;
+  }
+  length
+}
+
+// Hashes of a file's consecutive pieces, for downloaders to check each as it arrives.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PieceHashes {
+  pub size: u64,
+  pub mtime: u64,
+  pub length: u64,
+  pub sha256: Vec<String>,
+}
+
+// One pass over the file for both its digest and its pieces, as metalinks need the two together.
+fn hash_pieces(store_path: &str, size: u64, mtime: u64) -> io::Result<(FileDigest, PieceHashes)> {
+  let length = piece_length(size);
+  let (mut piece, mut in_piece, mut sha256) = (Sha256::new(), 0, Vec::new());
+  let digest = hash_file_with(&resolve(store_path), size, mtime, |mut block| {
+    while !block.is_empty() {
+      let take = block.len().min((length - in_piece) as usize);
+      piece.update(&block[..take]);
+      in_piece += take as u64;
+      block = &block[take..];
+      if in_piece == length {
+        sha256.push(format!("{:x}", std::mem::take(&mut piece).finalize()));
+        in_piece = 0;
+      }
+    }
+  })?;
+  if in_piece > 0 {
+    sha256.push(format!("{:x}", piece.finalize()));
+  }
+  if digest.size != size {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      "changed while being hashed",
+    ));
+  }
+  let pieces = PieceHashes {
+    size,
+    mtime,
+    length,
+    sha256,
+  };
+  Ok((digest, pieces))
+}
+
+// Piece hashes of files in the store, keyed like `Checksums` and persisted in `pieces.json`. Like
+// torrent hashes, they are worked out by `fairing` in the background rather than while a request
+// waits.
+#[derive(Clone, Default)]
+pub struct Pieces {
+  cache: Arc<RwLock<HashMap<String, PieceHashes>>>,
+  queue: Arc<Mutex<BTreeSet<String>>>,
+  wake: Arc<Notify>,
+}
+
+impl Pieces {
+  pub fn load() -> Pieces {
+    let cache = File::open(data_dir().join("pieces.json"))
+      .ok()
+      .and_then(|file| serde_json::from_reader(file).ok())
+      .unwrap_or_default();
+    Pieces {
+      cache: Arc::new(RwLock::new(cache)),
+      ..Pieces::default()
+    }
+  }
+
+  fn save(&self) {
+    let cache = self.cache.read().unwrap();
+    let result = save_json(&data_dir().join("pieces.json"), &*cache);
+    if let Err(error) = result {
+      warn!("Failed to save piece hashes: {}", error);
+    }
+  }
+
+  fn cached(&self, store_path: &str) -> Option<PieceHashes> {
+    let (size, mtime) = stamp(&resolve(store_path)).ok()?;
+    self
+      .cache
+      .read()
+      .unwrap()
+      .get(store_path)
+      .filter(|pieces| pieces.size == size && pieces.mtime == mtime)
+      .cloned()
+  }
+
+  // The file's digest and pieces once both are known, or `None` after queueing it to be hashed.
+  pub fn get(&self, checksums: &Checksums, store_path: &str) -> Option<(FileDigest, PieceHashes)> {
+    let hashed = checksums.cached(store_path).zip(self.cached(store_path));
+    if hashed.is_none() && self.queue.lock().unwrap().insert(store_path.to_string()) {
+      self.wake.notify_one();
+    }
+    hashed
+  }
+
+  // Blocking.
+  fn hash(&self, checksums: &Checksums, store_path: &str) -> io::Result<()> {
+    if checksums.cached(store_path).is_some() && self.cached(store_path).is_some() {
+      return Ok(());
+    }
+    let (size, mtime) = stamp(&resolve(store_path))?;
+    let (digest, pieces) = hash_pieces(store_path, size, mtime)?;
+    checksums.record(store_path, digest);
+    self
+      .cache
+      .write()
+      .unwrap()
+      .insert(store_path.to_string(), pieces);
+    self.save();
+    Ok(())
+  }
+
+  fn next(&self) -> Option<String> {
+    self.queue.lock().unwrap().pop_first()
+  }
+}
+
+// Works through the queue of files to hash for as long as the server runs.
+pub fn fairing() -> AdHoc {
+  AdHoc::on_liftoff("Piece hashes", |rocket| {
+    Box::pin(async move {
+      let pieces = rocket.state::<Pieces>().unwrap().clone();
+      let checksums = rocket.state::<Checksums>().unwrap().clone();
+      let shutdown = rocket.shutdown();
+      rocket::tokio::spawn(async move {
+        rocket::tokio::pin!(shutdown);
+        loop {
+          while let Some(store_path) = pieces.next() {
+            let (hashing, checksums) = (pieces.clone(), checksums.clone());
+            let key = store_path.clone();
+            let result =
+              rocket::tokio::task::spawn_blocking(move || hashing.hash(&checksums, &key)).await;
+            match result.map_err(io::Error::other).and_then(|result| result) {
+              Ok(()) => info!("Hashed pieces of {}", store_path),
+              Err(error) => warn!("Failed to hash pieces of {}: {}", store_path, error),
+            }
+          }
+          select! {
+            _ = &mut shutdown => break,
+            _ = pieces.wake.notified() => {},
+          }
+        }
+      });
+    })
+  })
+}
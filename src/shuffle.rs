@@ -0,0 +1,234 @@
+use std::{
+  collections::{HashMap, VecDeque},
+  net::IpAddr,
+  sync::{Arc, Mutex},
+};
+
+use rand::{seq::SliceRandom, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rocket::{
+  http::{ContentType, Header, RawStr},
+  response::Redirect,
+  State,
+};
+
+use crate::{
+  auth::User,
+  database::{Database, Db, DEFAULT_COLLECTION},
+  episode,
+  feed::with_token,
+  media_info::MediaInfos,
+};
+
+// How many picks per caller `fresh` remembers.
+const RECENT_LENGTH: usize = 50;
+// How many callers it remembers them for, forgetting whoever asked longest ago first.
+const RECENT_CLIENTS: usize = 1024;
+
+// Ids recently handed out by `random`, per client address. Only kept in memory.
+#[derive(Clone, Default)]
+pub struct Recent {
+  served: Arc<Mutex<Served>>,
+}
+
+#[derive(Default)]
+struct Served {
+  // Counts every pick, so that each client's last one tells who asked longest ago.
+  picks: u64,
+  clients: HashMap<IpAddr, (u64, VecDeque<String>)>,
+}
+
+impl Served {
+  fn history(&mut self, client: IpAddr) -> &mut VecDeque<String> {
+    if !self.clients.contains_key(&client) && self.clients.len() >= RECENT_CLIENTS {
+      let oldest = self
+        .clients
+        .iter()
+        .min_by_key(|(_, (last, _))| *last)
+        .map(|(client, _)| *client);
+      if let Some(oldest) = oldest {
+        self.clients.remove(&oldest);
+      }
+    }
+    self.picks += 1;
+    let (last, history) = self.clients.entry(client).or_default();
+    *last = self.picks;
+    history
+  }
+}
+
+#[derive(Debug)]
+pub struct Filter {
+  season: Option<u32>,
+  // `specials=false` leaves out unnumbered episodes.
+  specials: Option<bool>,
+}
+
+impl Filter {
+  // Ids that aren't episodes at all only match when nothing is filtered on.
+  fn matches(&self, id: &str) -> bool {
+    if self.season.is_none() && self.specials != Some(false) {
+      return true;
+    }
+    match episode::parse(id) {
+      Some(episode) => {
+        self.season.is_none_or(|season| season == episode.season)
+          && (self.specials != Some(false) || !episode.is_special())
+      }
+      None => false,
+    }
+  }
+}
+
+fn candidates(db: &Database, filter: &Filter) -> Vec<String> {
+  db.collection_ids(DEFAULT_COLLECTION)
+    .into_iter()
+    .filter(|id| filter.matches(id))
+    .collect()
+}
+
+// With `fresh`, ids recently served to the same address are skipped until there is nothing else
+// left to pick from. The redirect keeps `token`, for players that can only send it in the URL.
+#[allow(clippy::too_many_arguments)]
+#[get("/dr-who/random?<season>&<specials>&<fresh>&<token>")]
+pub async fn random(
+  _user: User,
+  db: Db,
+  recent: &State<Recent>,
+  client: Option<IpAddr>,
+  season: Option<u32>,
+  specials: Option<bool>,
+  fresh: Option<bool>,
+  token: Option<String>,
+) -> Option<Redirect> {
+  let filter = Filter { season, specials };
+  let candidates = candidates(&db, &filter);
+  let mut rng = rand::thread_rng();
+  let mut served = recent.served.lock().unwrap();
+  let history = client.map(|client| served.history(client));
+
+  let unseen: Vec<&String> = match &history {
+    Some(history) if fresh.unwrap_or(false) => candidates
+      .iter()
+      .filter(|id| !history.contains(id))
+      .collect(),
+    _ => Vec::new(),
+  };
+  let id = match unseen.choose(&mut rng) {
+    Some(id) => (*id).clone(),
+    None => candidates.choose(&mut rng)?.clone(),
+  };
+  if let Some(history) = history {
+    history.retain(|served| *served != id);
+    history.push_back(id.clone());
+    if history.len() > RECENT_LENGTH {
+      history.pop_front();
+    }
+  }
+  Some(Redirect::temporary(with_token(
+    format!("/dr-who/{}", RawStr::new(&id).percent_encode()),
+    token.as_deref(),
+  )))
+}
+
+#[derive(Responder)]
+pub struct Playlist {
+  body: String,
+  seed: Header<'static>,
+}
+
+// The same seed and filter always give the same order, so a playlist can be shared or resumed.
+// Without a seed a new one is picked and sent back in `X-Shuffle-Seed`.
+// Players fetch entries as plain links, so those carry `token` the way feed enclosures do.
+#[get("/dr-who/shuffle.m3u8?<season>&<specials>&<seed>&<token>")]
+pub async fn shuffle(
+  _user: User,
+  db: Db,
+  infos: &State<MediaInfos>,
+  season: Option<u32>,
+  specials: Option<bool>,
+  seed: Option<u64>,
+  token: Option<String>,
+) -> (ContentType, Playlist) {
+  let filter = Filter { season, specials };
+  let seed = seed.unwrap_or_else(|| rand::thread_rng().gen());
+  let mut ids = candidates(&db, &filter);
+  ids.shuffle(&mut ChaCha8Rng::seed_from_u64(seed));
+
+  // Durations are only known for local Matroska files, players accept -1 for the rest.
+  let entries: Vec<(String, Option<String>, Option<String>)> = ids
+    .into_iter()
+    .map(|id| {
+      let store_path = match db.is_local(&id) {
+        true => db.id_to_path.get(&id).cloned(),
+        false => None,
+      };
+      let title = db.title(&id).map(str::to_string);
+      (id, store_path, title)
+    })
+    .collect();
+  let infos = infos.inner().clone();
+  let playlist = rocket::tokio::task::spawn_blocking(move || {
+    let mut playlist = format!("#EXTM3U\n#PLAYLIST:Shuffle {}\n", seed);
+    for (id, store_path, title) in entries {
+      let info = store_path.and_then(|store_path| infos.get(&store_path).ok());
+      let duration = info
+        .as_ref()
+        .and_then(|info| info.duration)
+        .map(|duration| duration.round() as i64)
+        .unwrap_or(-1);
+      let title = match (
+        title.or(info.and_then(|info| info.title)),
+        episode::parse(&id),
+      ) {
+        (Some(title), _) => title,
+        (None, Some(episode)) => episode.title(),
+        (None, None) => id.clone(),
+      };
+      // A line break in a title would start a line of the playlist's own.
+      let title = title.replace(['\r', '\n'], " ");
+      let url = with_token(
+        format!("/dr-who/{}", RawStr::new(&id).percent_encode()),
+        token.as_deref(),
+      );
+      playlist.push_str(&format!("#EXTINF:{},{}\n{}\n", duration, title, url));
+    }
+    playlist
+  })
+  .await
+  .unwrap_or_default();
+
+  (
+    ContentType::new("audio", "x-mpegurl"),
+    Playlist {
+      body: playlist,
+      seed: Header::new("X-Shuffle-Seed", seed.to_string()),
+    },
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::Ipv4Addr;
+
+  use super::*;
+
+  fn client(number: usize) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::from(number as u32))
+  }
+
+  #[test]
+  fn forgets_the_longest_idle_client() {
+    let mut served = Served::default();
+    for number in 0..RECENT_CLIENTS {
+      served.history(client(number)).push_back(number.to_string());
+    }
+    // Asking again keeps the first client around.
+    assert_eq!(served.history(client(0)).len(), 1);
+    served.history(client(RECENT_CLIENTS));
+    assert_eq!(served.clients.len(), RECENT_CLIENTS);
+    assert!(served.clients.contains_key(&client(0)));
+    assert!(!served.clients.contains_key(&client(1)));
+    assert!(served.clients.contains_key(&client(RECENT_CLIENTS)));
+  }
+}
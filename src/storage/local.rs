@@ -0,0 +1,96 @@
+use std::{
+  fs, io,
+  io::SeekFrom,
+  ops::Range,
+  path::{Path, PathBuf},
+};
+
+use log::warn;
+use rocket::tokio::{
+  self,
+  io::{AsyncReadExt, AsyncSeekExt},
+};
+
+use super::{Reader, Stat, Storage};
+use crate::database::{file_store, resolve};
+
+pub struct Local;
+
+fn walk(dir: &Path, root: &Path, files: &mut Vec<String>) -> io::Result<()> {
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    if path.is_dir() {
+      walk(&path, root, files)?;
+    } else if let Ok(relative) = path.strip_prefix(root) {
+      files.push(relative.to_string_lossy().to_string());
+    }
+  }
+  Ok(())
+}
+
+// Every file under `file-store`, relative to it.
+pub fn store_files() -> Vec<String> {
+  let store = file_store();
+  let mut files = Vec::new();
+  if let Err(error) = walk(&store, &store, &mut files) {
+    warn!("Failed to walk {:?}: {}", store, error);
+  }
+  files.sort();
+  files
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+  let name = path.file_name().unwrap_or_default().to_string_lossy();
+  path.with_file_name(format!(".{}.part", name))
+}
+
+#[rocket::async_trait]
+impl Storage for Local {
+  async fn open(&self, key: &str, range: Option<Range<u64>>) -> io::Result<Reader> {
+    let mut file = tokio::fs::File::open(resolve(key)).await?;
+    match range {
+      Some(range) => {
+        file.seek(SeekFrom::Start(range.start)).await?;
+        Ok(Box::pin(file.take(range.end - range.start)))
+      }
+      None => Ok(Box::pin(file)),
+    }
+  }
+
+  async fn stat(&self, key: &str) -> io::Result<Stat> {
+    let metadata = tokio::fs::metadata(resolve(key)).await?;
+    if !metadata.is_file() {
+      return Err(io::Error::other("not a regular file"));
+    }
+    Ok(Stat {
+      size: metadata.len(),
+      modified: metadata.modified().ok(),
+    })
+  }
+
+  async fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+    let files = tokio::task::spawn_blocking(store_files).await?;
+    Ok(
+      files
+        .into_iter()
+        .filter(|file| file.starts_with(prefix))
+        .collect(),
+    )
+  }
+
+  async fn put(&self, key: &str, mut body: Reader, _size: u64) -> io::Result<()> {
+    let path = resolve(key);
+    if let Some(parent) = path.parent() {
+      tokio::fs::create_dir_all(parent).await?;
+    }
+    let partial = partial_path(&path);
+    let mut file = tokio::fs::File::create(&partial).await?;
+    tokio::io::copy(&mut body, &mut file).await?;
+    file.sync_all().await?;
+    tokio::fs::rename(&partial, &path).await
+  }
+
+  async fn delete(&self, key: &str) -> io::Result<()> {
+    tokio::fs::remove_file(resolve(key)).await
+  }
+}
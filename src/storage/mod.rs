@@ -0,0 +1,90 @@
+pub mod local;
+pub mod s3;
+
+use std::{
+  collections::HashMap,
+  io,
+  ops::Range,
+  pin::Pin,
+  sync::{Arc, Mutex},
+  time::SystemTime,
+};
+
+use rocket::tokio::io::AsyncRead;
+use serde::{Deserialize, Serialize};
+
+use crate::database::{Database, Library};
+
+pub type Reader = Pin<Box<dyn AsyncRead + Send + Sync>>;
+
+pub struct Stat {
+  pub size: u64,
+  pub modified: Option<SystemTime>,
+}
+
+// Keys are `id_to_path` values; each backend decides where they live.
+#[rocket::async_trait]
+pub trait Storage: Send + Sync {
+  async fn open(&self, key: &str, range: Option<Range<u64>>) -> io::Result<Reader>;
+  async fn stat(&self, key: &str) -> io::Result<Stat>;
+  async fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+  async fn put(&self, key: &str, body: Reader, size: u64) -> io::Result<()>;
+  async fn delete(&self, key: &str) -> io::Result<()>;
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StorageConfig {
+  #[default]
+  Local,
+  S3(s3::S3Config),
+}
+
+impl StorageConfig {
+  pub fn is_local(&self) -> bool {
+    matches!(self, StorageConfig::Local)
+  }
+}
+
+// A client for a remote backend and the settings it was made with.
+type Remote = (s3::S3Config, Arc<dyn Storage>);
+
+// Storage for each collection as config.json currently has it. Clients for remote backends are
+// kept, and replaced once their settings change.
+#[derive(Clone)]
+pub struct Backends {
+  library: Library,
+  local: Arc<dyn Storage>,
+  remote: Arc<Mutex<HashMap<String, Remote>>>,
+}
+
+impl Backends {
+  pub fn new(library: Library) -> Backends {
+    Backends {
+      library,
+      local: Arc::new(local::Local),
+      remote: Arc::default(),
+    }
+  }
+
+  pub fn for_collection(&self, collection: &str) -> Arc<dyn Storage> {
+    let db = self.library.snapshot();
+    let config = match db.collections.get(collection).map(|c| &c.storage) {
+      Some(StorageConfig::S3(config)) => config,
+      _ => return self.local.clone(),
+    };
+    let mut remote = self.remote.lock().unwrap();
+    match remote.get(collection) {
+      Some((built, storage)) if built == config => storage.clone(),
+      _ => {
+        let storage: Arc<dyn Storage> = Arc::new(s3::S3::new(config.clone()));
+        remote.insert(collection.to_string(), (config.clone(), storage.clone()));
+        storage
+      }
+    }
+  }
+
+  pub fn for_id(&self, db: &Database, id: &str) -> Arc<dyn Storage> {
+    self.for_collection(db.collection_of(id))
+  }
+}
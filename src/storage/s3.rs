@@ -0,0 +1,365 @@
+use std::{io, ops::Range};
+
+use futures_util::TryStreamExt;
+use hmac::{Hmac, Mac};
+use reqwest::{header::HeaderMap, Client, Method, Response, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use super::{Reader, Stat, Storage};
+use crate::{checksum::hex, xml};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct S3Config {
+  // e.g. `https://s3.eu-west-2.amazonaws.com`, or `http://localhost:9000` for MinIO.
+  pub endpoint: String,
+  pub bucket: String,
+  #[serde(default = "default_region")]
+  pub region: String,
+  pub access_key: String,
+  pub secret_key: String,
+  #[serde(default)]
+  pub prefix: String,
+}
+
+fn default_region() -> String {
+  "us-east-1".to_string()
+}
+
+// Talks to S3 compatible object stores using path style addressing and SigV4 signed requests.
+pub struct S3 {
+  config: S3Config,
+  client: Client,
+}
+
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+  let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+  mac.update(data.as_bytes());
+  mac.finalize().into_bytes().to_vec()
+}
+
+fn uri_encode(value: &str, keep_slash: bool) -> String {
+  let mut encoded = String::new();
+  for byte in value.bytes() {
+    match byte {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+        encoded.push(byte as char)
+      }
+      b'/' if keep_slash => encoded.push('/'),
+      _ => encoded.push_str(&format!("%{:02X}", byte)),
+    }
+  }
+  encoded
+}
+
+async fn check(response: Response) -> io::Result<Response> {
+  match response.status() {
+    status if status.is_success() => Ok(response),
+    StatusCode::NOT_FOUND => Err(io::ErrorKind::NotFound.into()),
+    StatusCode::FORBIDDEN => Err(io::ErrorKind::PermissionDenied.into()),
+    status => Err(io::Error::other(format!("S3 responded with {}", status))),
+  }
+}
+
+impl S3 {
+  pub fn new(config: S3Config) -> S3 {
+    S3 {
+      config,
+      client: Client::new(),
+    }
+  }
+
+  fn object_path(&self, key: &str) -> String {
+    format!(
+      "/{}/{}",
+      self.config.bucket,
+      uri_encode(&format!("{}{}", self.config.prefix, key), true)
+    )
+  }
+
+  fn signed(
+    &self,
+    method: Method,
+    path: &str,
+    query: &[(&str, &str)],
+  ) -> io::Result<reqwest::RequestBuilder> {
+    self.signed_at(method, path, query, OffsetDateTime::now_utc())
+  }
+
+  // `query` must already be sorted by name, as SigV4 requires.
+  fn signed_at(
+    &self,
+    method: Method,
+    path: &str,
+    query: &[(&str, &str)],
+    now: OffsetDateTime,
+  ) -> io::Result<reqwest::RequestBuilder> {
+    let canonical_query = query
+      .iter()
+      .map(|(name, value)| format!("{}={}", uri_encode(name, false), uri_encode(value, false)))
+      .collect::<Vec<_>>()
+      .join("&");
+    let mut url = Url::parse(&self.config.endpoint).map_err(io::Error::other)?;
+    url.set_path(path);
+    url.set_query(
+      Some(&canonical_query)
+        .filter(|query| !query.is_empty())
+        .map(|q| q.as_str()),
+    );
+    let host = match (url.host_str(), url.port()) {
+      (Some(host), Some(port)) => format!("{}:{}", host, port),
+      (Some(host), None) => host.to_string(),
+      _ => return Err(io::Error::other("S3 endpoint has no host")),
+    };
+
+    let date = format!("{:04}{:02}{:02}", now.year(), now.month() as u8, now.day());
+    let timestamp = format!(
+      "{}T{:02}{:02}{:02}Z",
+      date,
+      now.hour(),
+      now.minute(),
+      now.second()
+    );
+    let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+    let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+    let canonical_request = format!(
+      "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+      method,
+      path,
+      canonical_query,
+      host,
+      UNSIGNED_PAYLOAD,
+      timestamp,
+      signed_headers,
+      UNSIGNED_PAYLOAD
+    );
+    let string_to_sign = format!(
+      "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+      timestamp,
+      scope,
+      Sha256::digest(canonical_request.as_bytes())
+    );
+
+    let mut key = hmac(format!("AWS4{}", self.config.secret_key).as_bytes(), &date);
+    for part in [self.config.region.as_str(), "s3", "aws4_request"] {
+      key = hmac(&key, part);
+    }
+    let signature = hex(&hmac(&key, &string_to_sign));
+
+    let mut headers = HeaderMap::new();
+    headers.insert("x-amz-content-sha256", UNSIGNED_PAYLOAD.parse().unwrap());
+    headers.insert("x-amz-date", timestamp.parse().unwrap());
+    headers.insert(
+      "authorization",
+      format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        self.config.access_key, scope, signed_headers, signature
+      )
+      .parse()
+      .map_err(io::Error::other)?,
+    );
+    Ok(self.client.request(method, url).headers(headers))
+  }
+}
+
+#[rocket::async_trait]
+impl Storage for S3 {
+  async fn open(&self, key: &str, range: Option<Range<u64>>) -> io::Result<Reader> {
+    let mut request = self.signed(Method::GET, &self.object_path(key), &[])?;
+    if let Some(range) = &range {
+      request = request.header("range", format!("bytes={}-{}", range.start, range.end - 1));
+    }
+    let response = check(request.send().await.map_err(io::Error::other)?).await?;
+    // Endpoints that ignore ranges send the whole object, which must not go out as a slice of it.
+    if range.is_some() && response.status() != StatusCode::PARTIAL_CONTENT {
+      return Err(io::Error::other(format!(
+        "S3 answered a range request with {}",
+        response.status()
+      )));
+    }
+    let stream = response.bytes_stream().map_err(io::Error::other);
+    Ok(Box::pin(StreamReader::new(stream)))
+  }
+
+  async fn stat(&self, key: &str) -> io::Result<Stat> {
+    let request = self.signed(Method::HEAD, &self.object_path(key), &[])?;
+    let response = check(request.send().await.map_err(io::Error::other)?).await?;
+    let size = response
+      .headers()
+      .get("content-length")
+      .and_then(|value| value.to_str().ok()?.parse().ok())
+      .ok_or_else(|| io::Error::other("S3 did not send a length"))?;
+    Ok(Stat {
+      size,
+      modified: None,
+    })
+  }
+
+  async fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+    let path = format!("/{}", self.config.bucket);
+    let full_prefix = format!("{}{}", self.config.prefix, prefix);
+    let mut keys = Vec::new();
+    let mut token: Option<String> = None;
+    loop {
+      let mut query = vec![];
+      if let Some(token) = &token {
+        query.push(("continuation-token", token.as_str()));
+      }
+      query.push(("list-type", "2"));
+      query.push(("prefix", full_prefix.as_str()));
+
+      let response = check(
+        self
+          .signed(Method::GET, &path, &query)?
+          .send()
+          .await
+          .map_err(io::Error::other)?,
+      )
+      .await?;
+      let xml = response.text().await.map_err(io::Error::other)?;
+      for key in xml::values(&xml, "Key") {
+        let key = xml::unescape(key);
+        if let Some(key) = key.strip_prefix(&self.config.prefix) {
+          keys.push(key.to_string());
+        }
+      }
+      token = xml::values(&xml, "NextContinuationToken")
+        .first()
+        .map(|token| xml::unescape(token));
+      if token.is_none() || xml::values(&xml, "IsTruncated").first() != Some(&"true") {
+        return Ok(keys);
+      }
+    }
+  }
+
+  async fn put(&self, key: &str, body: Reader, size: u64) -> io::Result<()> {
+    let request = self
+      .signed(Method::PUT, &self.object_path(key), &[])?
+      .header("content-length", size)
+      .body(reqwest::Body::wrap_stream(ReaderStream::new(body)));
+    check(request.send().await.map_err(io::Error::other)?).await?;
+    Ok(())
+  }
+
+  async fn delete(&self, key: &str) -> io::Result<()> {
+    let request = self.signed(Method::DELETE, &self.object_path(key), &[])?;
+    check(request.send().await.map_err(io::Error::other)?).await?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use rocket::tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    task::JoinHandle,
+  };
+  use time::macros::datetime;
+
+  use super::*;
+
+  fn s3(endpoint: &str) -> S3 {
+    S3::new(S3Config {
+      endpoint: endpoint.to_string(),
+      bucket: "library".to_string(),
+      region: "eu-west-2".to_string(),
+      access_key: "AKIDEXAMPLE".to_string(),
+      secret_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+      prefix: "media/".to_string(),
+    })
+  }
+
+  // Answers a single request with `response`, handing back what was asked.
+  async fn endpoint(response: &'static str) -> (String, JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let served = rocket::tokio::spawn(async move {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let mut request = Vec::new();
+      let mut buffer = [0; 1024];
+      while !request.ends_with(b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).await.unwrap();
+        request.extend_from_slice(&buffer[..read]);
+      }
+      stream.write_all(response.as_bytes()).await.unwrap();
+      String::from_utf8(request).unwrap()
+    });
+    (format!("http://{}", address), served)
+  }
+
+  async fn read(reader: io::Result<Reader>) -> io::Result<String> {
+    let mut body = String::new();
+    reader?.read_to_string(&mut body).await?;
+    Ok(body)
+  }
+
+  #[test]
+  fn signs_requests_with_sigv4() {
+    let request = s3("http://localhost:9000")
+      .signed_at(
+        Method::GET,
+        "/library",
+        &[("list-type", "2"), ("prefix", "media/s 1")],
+        datetime!(2024-03-01 12:30:45 UTC),
+      )
+      .unwrap()
+      .build()
+      .unwrap();
+    assert_eq!(
+      request.url().as_str(),
+      "http://localhost:9000/library?list-type=2&prefix=media%2Fs%201"
+    );
+    let headers = request.headers();
+    assert_eq!(headers["x-amz-date"], "20240301T123045Z");
+    assert_eq!(headers["x-amz-content-sha256"], UNSIGNED_PAYLOAD);
+    assert_eq!(
+      headers["authorization"],
+      "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20240301/eu-west-2/s3/aws4_request, \
+       SignedHeaders=host;x-amz-content-sha256;x-amz-date, \
+       Signature=2b0cc03fce62344ab13f484bc0b74752246ac321f34141f2209afe4bc639bf60"
+    );
+  }
+
+  #[test]
+  fn encodes_keys_like_s3() {
+    assert_eq!(uri_encode("a b/c+d~é", true), "a%20b/c%2Bd~%C3%A9");
+    assert_eq!(uri_encode("a/b", false), "a%2Fb");
+  }
+
+  #[rocket::async_test]
+  async fn reads_ranges() {
+    let (url, served) =
+      endpoint("HTTP/1.1 206 Partial Content\r\nContent-Length: 3\r\n\r\nbcd").await;
+    let body = read(s3(&url).open("s01 e01.mkv", Some(1..4)).await).await;
+    assert_eq!(body.unwrap(), "bcd");
+    let request = served.await.unwrap();
+    assert!(request.starts_with("GET /library/media/s01%20e01.mkv HTTP/1.1\r\n"));
+    assert!(request.contains("range: bytes=1-3\r\n"));
+    assert!(request.contains("authorization: AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/"));
+  }
+
+  #[rocket::async_test]
+  async fn refuses_whole_objects_for_ranges() {
+    let (url, _) = endpoint("HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nabcdef").await;
+    assert!(s3(&url).open("s01e01.mkv", Some(1..4)).await.is_err());
+  }
+
+  #[rocket::async_test]
+  async fn reads_whole_objects() {
+    let (url, _) = endpoint("HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nabcdef").await;
+    let body = read(s3(&url).open("s01e01.mkv", None).await).await;
+    assert_eq!(body.unwrap(), "abcdef");
+  }
+
+  #[rocket::async_test]
+  async fn maps_missing_objects() {
+    let (url, _) = endpoint("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n").await;
+    let error = s3(&url).stat("gone.mkv").await.err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
+  }
+}
@@ -0,0 +1,607 @@
+use std::{
+  collections::HashSet,
+  fs,
+  io::{self, Write},
+  path::{Path, PathBuf},
+};
+
+use log::warn;
+use rocket::{
+  http::{ContentType, RawStr},
+  request::FromParam,
+  serde::json::Json,
+  State,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+  auth::User,
+  checksum::stamp,
+  database::{data_dir, resolve, write_aside, Db},
+  matroska::{self, TrackKind},
+  media_info::MediaInfos,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+  Srt,
+  // Also covers SSA, which only differs in ways that don't matter for plain text.
+  Ass,
+  Vtt,
+}
+
+impl Format {
+  fn from_extension(extension: &str) -> Option<Format> {
+    match extension.to_ascii_lowercase().as_str() {
+      "srt" => Some(Format::Srt),
+      "ass" | "ssa" => Some(Format::Ass),
+      "vtt" => Some(Format::Vtt),
+      _ => None,
+    }
+  }
+
+  fn from_codec(codec: &str) -> Option<Format> {
+    match codec {
+      "S_TEXT/UTF8" | "S_TEXT/ASCII" => Some(Format::Srt),
+      "S_TEXT/ASS" | "S_TEXT/SSA" | "S_ASS" | "S_SSA" => Some(Format::Ass),
+      "S_TEXT/WEBVTT" => Some(Format::Vtt),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+  Embedded,
+  Sidecar,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Subtitle {
+  // The Matroska track number for embedded tracks, the file name for sidecar files.
+  pub track: String,
+  pub source: Source,
+  pub format: Format,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub language: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub name: Option<String>,
+  pub default: bool,
+  pub forced: bool,
+  pub url: String,
+}
+
+// Text subtitle tracks of a Matroska file. Image based ones (PGS, VobSub) can't be converted and
+// are left out.
+fn embedded(infos: &MediaInfos, store_path: &str) -> Vec<Subtitle> {
+  let info = match infos.get(store_path) {
+    Ok(info) => info,
+    Err(_) => return Vec::new(),
+  };
+  info
+    .tracks
+    .into_iter()
+    .filter(|track| track.kind == TrackKind::Subtitle)
+    .filter_map(|track| {
+      Some(Subtitle {
+        track: track.number.to_string(),
+        source: Source::Embedded,
+        format: Format::from_codec(&track.codec)?,
+        language: track.language,
+        name: track.name,
+        default: track.default,
+        forced: track.forced,
+        url: String::new(),
+      })
+    })
+    .collect()
+}
+
+// Subtitle files next to the target whose name starts with the target's, e.g.
+// `episode.en.srt` or `episode.eng.forced.ass` for `episode.mkv`.
+fn sidecars(store_path: &str) -> Vec<(Subtitle, PathBuf)> {
+  if store_path.starts_with("sha256:") {
+    return Vec::new();
+  }
+  let path = resolve(store_path);
+  let (directory, stem) = match (path.parent(), path.file_stem()) {
+    (Some(directory), Some(stem)) => (directory, stem.to_string_lossy().into_owned()),
+    _ => return Vec::new(),
+  };
+  let entries = match fs::read_dir(directory) {
+    Ok(entries) => entries,
+    Err(_) => return Vec::new(),
+  };
+
+  let mut sidecars: Vec<(Subtitle, PathBuf)> = entries
+    .filter_map(|entry| {
+      let path = entry.ok()?.path();
+      let file_name = path.file_name()?.to_string_lossy().into_owned();
+      let format = Format::from_extension(&path.extension()?.to_string_lossy())?;
+      let middle = file_name
+        .strip_prefix(&stem)?
+        .rsplit_once('.')
+        .map(|(middle, _)| middle.to_string())?;
+      if !(middle.is_empty() || middle.starts_with('.')) || !path.is_file() {
+        return None;
+      }
+      let parts: Vec<&str> = middle.split('.').filter(|part| !part.is_empty()).collect();
+      let language = parts
+        .iter()
+        .find(|part| (2..=3).contains(&part.len()) && part.chars().all(|c| c.is_ascii_alphabetic()))
+        .map(|part| part.to_ascii_lowercase());
+      let subtitle = Subtitle {
+        track: file_name,
+        source: Source::Sidecar,
+        format,
+        language,
+        name: None,
+        default: false,
+        forced: parts.iter().any(|part| part.eq_ignore_ascii_case("forced")),
+        url: String::new(),
+      };
+      Some((subtitle, path))
+    })
+    .collect();
+  sidecars.sort_by(|a, b| a.0.track.cmp(&b.0.track));
+  sidecars
+}
+
+fn list_subtitles(infos: &MediaInfos, id: &str, store_path: &str) -> Vec<Subtitle> {
+  let mut subtitles = embedded(infos, store_path);
+  subtitles.extend(
+    sidecars(store_path)
+      .into_iter()
+      .map(|(subtitle, _)| subtitle),
+  );
+  for subtitle in &mut subtitles {
+    subtitle.url = format!(
+      "/dr-who/{}/subtitles/{}.vtt",
+      RawStr::new(id).percent_encode(),
+      RawStr::new(&subtitle.track).percent_encode()
+    );
+  }
+  subtitles
+}
+
+// Times are in milliseconds, `text` is already valid WebVTT cue text.
+struct Cue {
+  start: u64,
+  end: u64,
+  text: String,
+}
+
+pub fn timestamp(ms: u64) -> String {
+  format!(
+    "{:02}:{:02}:{:02}.{:03}",
+    ms / 3_600_000,
+    ms / 60_000 % 60,
+    ms / 1000 % 60,
+    ms % 1000
+  )
+}
+
+fn write_vtt(mut cues: Vec<Cue>) -> String {
+  cues.sort_by_key(|cue| cue.start);
+  let mut vtt = "WEBVTT\n\n".to_string();
+  for cue in cues.into_iter().filter(|cue| !cue.text.is_empty()) {
+    vtt.push_str(&format!(
+      "{} --> {}\n{}\n\n",
+      timestamp(cue.start),
+      timestamp(cue.end.max(cue.start)),
+      cue.text
+    ));
+  }
+  vtt
+}
+
+pub fn escape(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+}
+
+// A blank line would end the cue early.
+fn join_lines<'a>(lines: impl Iterator<Item = &'a str>) -> String {
+  lines
+    .map(str::trim_end)
+    .filter(|line| !line.is_empty())
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+// SRT markup: keeps italic, bold and underline, drops `<font>` and `{\an8}` style positioning.
+fn srt_text(text: &str) -> String {
+  let mut out = String::new();
+  let mut rest = text;
+  while let Some(index) = rest.find(['<', '{']) {
+    out.push_str(&escape(&rest[..index]));
+    rest = &rest[index..];
+    let close = if rest.starts_with('<') { '>' } else { '}' };
+    let end = match rest.find(close) {
+      Some(end) => end,
+      None => break,
+    };
+    let tag = rest[1..end].trim().to_ascii_lowercase();
+    if matches!(tag.as_str(), "i" | "b" | "u" | "/i" | "/b" | "/u") && close == '>' {
+      out.push_str(&format!("<{}>", tag));
+    }
+    rest = &rest[end + 1..];
+  }
+  out.push_str(&escape(rest));
+  join_lines(out.lines())
+}
+
+// `HH:MM:SS,mmm` in SRT, `H:MM:SS.cc` in ASS, `MM:SS.mmm` in WebVTT.
+fn parse_time(value: &str) -> Option<u64> {
+  let value = value.trim();
+  let (clock, fraction) = value.split_once([',', '.']).unwrap_or((value, "0"));
+  let mut seconds = 0;
+  for part in clock.split(':') {
+    seconds = seconds * 60 + part.trim().parse::<u64>().ok()?;
+  }
+  let digits: String = fraction.chars().take_while(char::is_ascii_digit).collect();
+  let ms = format!("{:0<3}", &digits[..digits.len().min(3)])
+    .parse::<u64>()
+    .ok()?;
+  Some(seconds * 1000 + ms)
+}
+
+fn parse_srt(text: &str) -> Vec<Cue> {
+  let text = text.replace("\r\n", "\n");
+  let mut cues = Vec::new();
+  for block in text.split("\n\n") {
+    let mut lines = block.lines().skip_while(|line| !line.contains("-->"));
+    let timing = match lines.next() {
+      Some(timing) => timing,
+      None => continue,
+    };
+    let (start, end) = timing.split_once("-->").unwrap();
+    // Some files put coordinates after the end time.
+    let end = end.split_whitespace().next().unwrap_or("");
+    if let (Some(start), Some(end)) = (parse_time(start), parse_time(end)) {
+      let text = lines.collect::<Vec<_>>().join("\n");
+      cues.push(Cue {
+        start,
+        end,
+        text: srt_text(&text),
+      });
+    }
+  }
+  cues
+}
+
+// Override blocks like `{\i1}` become WebVTT tags where there is one, everything else in them is
+// dropped. Tags left open are closed at the end of the cue.
+fn ass_text(text: &str) -> String {
+  let mut out = String::new();
+  let mut open: Vec<char> = Vec::new();
+  let mut rest = text;
+  loop {
+    let (plain, block) = match rest.find('{') {
+      Some(index) => (&rest[..index], Some(&rest[index + 1..])),
+      None => (rest, None),
+    };
+    out.push_str(
+      &escape(plain)
+        .replace("\\N", "\n")
+        .replace("\\n", "\n")
+        .replace("\\h", "\u{a0}"),
+    );
+    let block = match block {
+      Some(block) => block,
+      None => break,
+    };
+    let end = block.find('}').unwrap_or(block.len());
+    for code in block[..end].split('\\') {
+      let mut chars = code.chars();
+      let tag = match chars.next() {
+        Some(tag @ ('i' | 'b' | 'u')) => tag,
+        _ => continue,
+      };
+      let value: String = chars.collect();
+      if !value.chars().all(|c| c.is_ascii_digit()) || value.is_empty() {
+        continue;
+      }
+      let on = value != "0";
+      if on && !open.contains(&tag) {
+        out.push_str(&format!("<{}>", tag));
+        open.push(tag);
+      } else if !on && open.contains(&tag) {
+        // Close everything opened after it too, so the tags stay nested.
+        while let Some(last) = open.pop() {
+          out.push_str(&format!("</{}>", last));
+          if last == tag {
+            break;
+          }
+        }
+      }
+    }
+    rest = block.get(end + 1..).unwrap_or("");
+  }
+  while let Some(last) = open.pop() {
+    out.push_str(&format!("</{}>", last));
+  }
+  join_lines(out.lines())
+}
+
+fn parse_ass(text: &str) -> Vec<Cue> {
+  let mut cues = Vec::new();
+  let mut in_events = false;
+  let mut format: Vec<String> = Vec::new();
+  for line in text.lines() {
+    let line = line.trim();
+    if line.starts_with('[') {
+      in_events = line.eq_ignore_ascii_case("[events]");
+      continue;
+    }
+    if !in_events {
+      continue;
+    }
+    if let Some(fields) = line.strip_prefix("Format:") {
+      format = fields
+        .split(',')
+        .map(|field| field.trim().to_ascii_lowercase())
+        .collect();
+      continue;
+    }
+    let dialogue = match line.strip_prefix("Dialogue:") {
+      Some(dialogue) if !format.is_empty() => dialogue,
+      _ => continue,
+    };
+    // The text is always last and may itself contain commas.
+    let values: Vec<&str> = dialogue.splitn(format.len(), ',').collect();
+    let field = |name: &str| {
+      let index = format.iter().position(|field| field == name)?;
+      values.get(index).copied()
+    };
+    if let (Some(start), Some(end), Some(text)) = (
+      field("start").and_then(parse_time),
+      field("end").and_then(parse_time),
+      field("text"),
+    ) {
+      cues.push(Cue {
+        start,
+        end,
+        text: ass_text(text),
+      });
+    }
+  }
+  cues
+}
+
+fn read_text(path: &Path) -> io::Result<String> {
+  let data = fs::read(path)?;
+  let text = String::from_utf8_lossy(&data);
+  Ok(text.trim_start_matches('\u{feff}').to_string())
+}
+
+// Embedded tracks store one cue per frame, with the timing in the block rather than the text.
+// ASS frames hold a Dialogue line without its times: `ReadOrder, Layer, Style, Name, MarginL,
+// MarginR, MarginV, Effect, Text`.
+fn convert_embedded(store_path: &str, infos: &MediaInfos, number: u64) -> io::Result<String> {
+  let info = infos.get(store_path)?;
+  let format = info
+    .tracks
+    .iter()
+    .find(|track| track.number == number && track.kind == TrackKind::Subtitle)
+    .and_then(|track| Format::from_codec(&track.codec))
+    .ok_or(io::ErrorKind::NotFound)?;
+  let frames = matroska::frames(&resolve(store_path), &info, &HashSet::from([number]))?;
+
+  let mut cues = Vec::new();
+  for (index, frame) in frames.iter().enumerate() {
+    let text = String::from_utf8_lossy(&frame.data);
+    let start = frame.start / 1_000_000;
+    let end = match frame.duration {
+      Some(duration) => (frame.start + duration) / 1_000_000,
+      None => frames
+        .get(index + 1)
+        .map(|next| next.start / 1_000_000)
+        .unwrap_or(start + 5000),
+    };
+    let text = match format {
+      Format::Srt => srt_text(&text),
+      Format::Ass => ass_text(text.splitn(9, ',').nth(8).unwrap_or("")),
+      Format::Vtt => join_lines(text.lines()),
+    };
+    cues.push(Cue { start, end, text });
+  }
+  Ok(write_vtt(cues))
+}
+
+fn convert_sidecar(path: &Path, format: Format) -> io::Result<String> {
+  let text = read_text(path)?;
+  Ok(match format {
+    Format::Srt => write_vtt(parse_srt(&text)),
+    Format::Ass => write_vtt(parse_ass(&text)),
+    Format::Vtt => text,
+  })
+}
+
+fn cache_dir() -> PathBuf {
+  data_dir().join("subtitles")
+}
+
+// Converted tracks are kept under `subtitles/`, named after the source file's path, size and mtime
+// so a replaced file gets converted again.
+fn convert(infos: &MediaInfos, store_path: &str, track: &str) -> io::Result<String> {
+  let sidecar = sidecars(store_path)
+    .into_iter()
+    .find(|(subtitle, _)| subtitle.track == track);
+  let source = match &sidecar {
+    Some((_, path)) => path.clone(),
+    None => resolve(store_path),
+  };
+  let (size, mtime) = stamp(&source)?;
+  let key = Sha256::digest(format!("{}\0{}\0{}\0{}", store_path, track, size, mtime).as_bytes());
+  let cached = cache_dir().join(format!("{:x}.vtt", key));
+  if let Ok(vtt) = fs::read_to_string(&cached) {
+    return Ok(vtt);
+  }
+
+  let vtt = match sidecar {
+    Some((subtitle, path)) => convert_sidecar(&path, subtitle.format)?,
+    None => {
+      let number = track.parse().map_err(|_| io::ErrorKind::NotFound)?;
+      convert_embedded(store_path, infos, number)?
+    }
+  };
+  let stored = fs::create_dir_all(cache_dir())
+    .and_then(|_| write_aside(&cached, |mut file| file.write_all(vtt.as_bytes())));
+  if let Err(error) = stored {
+    warn!("Failed to cache subtitles of {}: {}", store_path, error);
+  }
+  Ok(vtt)
+}
+
+#[get("/dr-who/<id>/subtitles", rank = 3)]
+pub async fn list(
+  _user: User,
+  db: Db,
+  infos: &State<MediaInfos>,
+  id: String,
+) -> Option<Json<Vec<Subtitle>>> {
+  if !db.is_local(&id) {
+    return None;
+  }
+  let store_path = db.id_to_path.get(&id)?.clone();
+  let infos = infos.inner().clone();
+  let subtitles =
+    rocket::tokio::task::spawn_blocking(move || list_subtitles(&infos, &id, &store_path))
+      .await
+      .ok()?;
+  Some(Json(subtitles))
+}
+
+pub struct VttName(String);
+
+impl<'a> FromParam<'a> for VttName {
+  type Error = &'a str;
+
+  fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+    match param.strip_suffix(".vtt") {
+      Some(track) if !track.is_empty() && !track.contains('/') => Ok(VttName(track.to_string())),
+      _ => Err(param),
+    }
+  }
+}
+
+#[get("/dr-who/<id>/subtitles/<name>", rank = 3)]
+pub async fn vtt(
+  _user: User,
+  db: Db,
+  infos: &State<MediaInfos>,
+  id: String,
+  name: VttName,
+) -> Option<(ContentType, String)> {
+  if !db.is_local(&id) {
+    return None;
+  }
+  let store_path = db.id_to_path.get(&id)?.clone();
+  let infos = infos.inner().clone();
+  let vtt = rocket::tokio::task::spawn_blocking(move || convert(&infos, &store_path, &name.0))
+    .await
+    .ok()?;
+  match vtt {
+    Ok(vtt) => Some((ContentType::new("text", "vtt"), vtt)),
+    Err(error) => {
+      if error.kind() != io::ErrorKind::NotFound {
+        warn!("Failed to convert subtitles of {}: {}", id, error);
+      }
+      None
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_times() {
+    assert_eq!(parse_time("00:01:02,003"), Some(62_003));
+    assert_eq!(parse_time(" 1:02:03.04 "), Some(3_723_040));
+    assert_eq!(parse_time("02:03.5"), Some(123_500));
+    assert_eq!(parse_time("00:00:01.23456"), Some(1_234));
+    assert_eq!(parse_time("5"), Some(5_000));
+    assert_eq!(parse_time("one"), None);
+    assert_eq!(timestamp(3_723_040), "01:02:03.040");
+  }
+
+  #[test]
+  fn converts_srt() {
+    let srt = "1\r\n\
+      00:00:01,500 --> 00:00:03,000\r\n\
+      <i>Hello</i> & {\\an8}<font color=\"red\">world</font>\r\n\
+      \r\n\
+      2\r\n\
+      00:01:02,003 --> 00:01:04,000 X1:10 X2:20\r\n\
+      Line one\r\n\
+      Line two\r\n\
+      \r\n\
+      3\r\n\
+      00:00:00,500 --> 00:00:01,000\r\n\
+      1 < 2\r\n";
+    assert_eq!(
+      write_vtt(parse_srt(srt)),
+      "WEBVTT\n\n\
+       00:00:00.500 --> 00:00:01.000\n1 &lt; 2\n\n\
+       00:00:01.500 --> 00:00:03.000\n<i>Hello</i> &amp; world\n\n\
+       00:01:02.003 --> 00:01:04.000\nLine one\nLine two\n\n"
+    );
+  }
+
+  #[test]
+  fn converts_ass() {
+    let ass = "[Script Info]\n\
+      Title: Test\n\
+      \n\
+      [V4+ Styles]\n\
+      Format: Name, Fontname\n\
+      Style: Default,Arial\n\
+      \n\
+      [Events]\n\
+      Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n\
+      Dialogue: 0,0:00:01.50,0:00:03.00,Default,,0,0,0,,{\\i1}Hello, {\\b1}there{\\i0} friend\\Nnext\n\
+      Comment: 0,0:00:02.00,0:00:04.00,Default,,0,0,0,,Not shown\n\
+      Dialogue: 0,1:02:03.04,1:02:05.00,Default,,0,0,0,,{\\pos(10,10)}Positioned\\hspace\n";
+    assert_eq!(
+      write_vtt(parse_ass(ass)),
+      "WEBVTT\n\n\
+       00:00:01.500 --> 00:00:03.000\n<i>Hello, <b>there</b></i> friend\nnext\n\n\
+       01:02:03.040 --> 01:02:05.000\nPositioned\u{a0}space\n\n"
+    );
+  }
+
+  #[test]
+  fn closes_ass_tags_left_open() {
+    assert_eq!(ass_text("{\\u1}open {\\b1}bold"), "<u>open <b>bold</b></u>");
+    assert_eq!(ass_text("{\\i0}never opened"), "never opened");
+    assert_eq!(ass_text("a < b {unclosed"), "a &lt; b");
+  }
+
+  #[test]
+  fn drops_empty_cues_and_blank_lines() {
+    let cues = vec![
+      Cue {
+        start: 2000,
+        end: 1000,
+        text: join_lines("one\n\n  \ntwo  ".lines()),
+      },
+      Cue {
+        start: 0,
+        end: 500,
+        text: String::new(),
+      },
+    ];
+    assert_eq!(
+      write_vtt(cues),
+      "WEBVTT\n\n00:00:02.000 --> 00:00:02.000\none\ntwo\n\n"
+    );
+  }
+}
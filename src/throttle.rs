@@ -0,0 +1,381 @@
+use std::{
+  collections::HashMap,
+  future::Future,
+  io,
+  net::IpAddr,
+  pin::Pin,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+  task::{ready, Context, Poll},
+  time::{Duration, Instant},
+};
+
+use rocket::{
+  request::{FromRequest, Outcome, Request},
+  tokio::{
+    io::{AsyncRead, ReadBuf},
+    time::{sleep, Sleep},
+  },
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  database::Database,
+  download::{Body, Download},
+};
+
+fn default_bulk_share() -> f64 {
+  0.25
+}
+
+// Rates are in bytes per second, and unlimited when unset.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThrottleConfig {
+  // Everything the server sends, together.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub global: Option<u64>,
+  // Each response on its own.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub per_connection: Option<u64>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub per_ip: Option<u64>,
+  // Each token, unless it has a rate of its own in `users`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub per_user: Option<u64>,
+  #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+  pub users: HashMap<String, u64>,
+  #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+  pub collections: HashMap<String, u64>,
+  // How much of `global` bulk downloads get while anything is being played.
+  #[serde(default = "default_bulk_share")]
+  pub bulk_share: f64,
+}
+
+impl Default for ThrottleConfig {
+  fn default() -> ThrottleConfig {
+    ThrottleConfig {
+      global: None,
+      per_connection: None,
+      per_ip: None,
+      per_user: None,
+      users: HashMap::new(),
+      collections: HashMap::new(),
+      bulk_share: default_bulk_share(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Class {
+  // Someone watching, who notices every stall.
+  Interactive,
+  // Whole files and archives, which can wait.
+  Bulk,
+}
+
+// Who a response goes to and how urgent it is. An RFC 9218 `Priority` header decides, urgencies
+// 0 to 3 being interactive; without one, players are told apart by asking for ranges.
+pub struct Traffic {
+  pub ip: Option<IpAddr>,
+  pub class: Class,
+}
+
+fn urgency(priority: &str) -> Option<u8> {
+  priority
+    .split(',')
+    .filter_map(|parameter| parameter.trim().strip_prefix("u="))
+    .find_map(|urgency| urgency.trim().parse().ok())
+}
+
+impl Traffic {
+  pub fn new(ip: Option<IpAddr>, priority: Option<&str>, ranged: bool) -> Traffic {
+    let class = match priority.and_then(urgency) {
+      Some(urgency) if urgency <= 3 => Class::Interactive,
+      Some(_) => Class::Bulk,
+      None if ranged => Class::Interactive,
+      None => Class::Bulk,
+    };
+    Traffic { ip, class }
+  }
+
+  // For what is never watched as it comes in, whatever the client says.
+  pub fn bulk(self) -> Traffic {
+    Traffic {
+      class: Class::Bulk,
+      ..self
+    }
+  }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Traffic {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    let headers = request.headers();
+    Outcome::Success(Traffic::new(
+      request.client_ip(),
+      headers.get_one("Priority"),
+      headers.contains("Range"),
+    ))
+  }
+}
+
+// Holds up to a second's worth of bytes. Reads are charged after the fact, and whoever takes
+// the bucket below empty waits for it to come back up to zero.
+struct Bucket {
+  rate: f64,
+  tokens: f64,
+  last: Instant,
+}
+
+// A rate of 0 would never refill, so a byte a second is as slow as it gets.
+fn per_second(rate: u64) -> f64 {
+  rate.max(1) as f64
+}
+
+impl Bucket {
+  fn new(rate: u64) -> Bucket {
+    Bucket {
+      rate: per_second(rate),
+      tokens: per_second(rate),
+      last: Instant::now(),
+    }
+  }
+
+  fn refill(&mut self, now: Instant) {
+    let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+    self.last = now;
+  }
+
+  fn charge(&mut self, bytes: usize, now: Instant) -> Duration {
+    self.refill(now);
+    self.tokens -= bytes as f64;
+    match self.tokens < 0.0 {
+      true => Duration::from_secs_f64(-self.tokens / self.rate),
+      false => Duration::ZERO,
+    }
+  }
+
+  fn is_full(&mut self, now: Instant) -> bool {
+    self.refill(now);
+    self.tokens >= self.rate
+  }
+}
+
+type Shared = Arc<Mutex<Bucket>>;
+
+// The buckets shared between responses, kept for as long as one is using them or still owes.
+#[derive(Clone, Default)]
+pub struct Throttles {
+  buckets: Arc<Mutex<HashMap<String, Shared>>>,
+  // Interactive responses being sent right now.
+  playing: Arc<AtomicUsize>,
+}
+
+impl Throttles {
+  fn bucket(&self, key: String, rate: u64) -> Shared {
+    let mut buckets = self.buckets.lock().unwrap();
+    let now = Instant::now();
+    buckets
+      .retain(|_, bucket| Arc::strong_count(bucket) > 1 || !bucket.lock().unwrap().is_full(now));
+    let bucket = buckets
+      .entry(key)
+      .or_insert_with(|| Arc::new(Mutex::new(Bucket::new(rate))));
+    // The config may have changed since.
+    bucket.lock().unwrap().rate = per_second(rate);
+    bucket.clone()
+  }
+
+  // Slows the body of `download` down to whatever applies to it.
+  pub fn apply(
+    &self,
+    db: &Database,
+    download: &mut Download,
+    traffic: &Traffic,
+    user: Option<&str>,
+    collection: Option<&str>,
+  ) {
+    download.wrap(|body| self.throttle(db, body, traffic, user, collection));
+  }
+
+  // The same for a body sent some other way, as WebDAV does.
+  pub fn throttle(
+    &self,
+    db: &Database,
+    body: Body,
+    traffic: &Traffic,
+    user: Option<&str>,
+    collection: Option<&str>,
+  ) -> Body {
+    let (config, class) = (&db.throttle, traffic.class);
+    let mut buckets = Vec::new();
+    if let Some(rate) = config.global {
+      buckets.push(self.bucket("global".to_string(), rate));
+    }
+    if let Some(rate) = config.per_connection {
+      buckets.push(Arc::new(Mutex::new(Bucket::new(rate))));
+    }
+    if let (Some(rate), Some(ip)) = (config.per_ip, traffic.ip) {
+      buckets.push(self.bucket(format!("ip:{}", ip), rate));
+    }
+    if let Some(user) = user {
+      if let Some(rate) = config.users.get(user).copied().or(config.per_user) {
+        buckets.push(self.bucket(format!("user:{}", user), rate));
+      }
+    }
+    if let Some(collection) = collection {
+      if let Some(rate) = config.collections.get(collection) {
+        buckets.push(self.bucket(format!("collection:{}", collection), *rate));
+      }
+    }
+    let bulk = match (class, config.global) {
+      (Class::Bulk, Some(rate)) => {
+        let rate = (rate as f64 * config.bulk_share.clamp(0.0, 1.0)) as u64;
+        Some(self.bucket("bulk".to_string(), rate))
+      }
+      _ => None,
+    };
+    if buckets.is_empty() && bulk.is_none() && class == Class::Bulk {
+      return body;
+    }
+    let playing = (class == Class::Interactive).then(|| Playing::new(self.playing.clone()));
+    Box::pin(Throttled {
+      body,
+      buckets,
+      bulk,
+      playing_count: self.playing.clone(),
+      _playing: playing,
+      sleep: None,
+    })
+  }
+}
+
+// Counts an interactive response for as long as its body is alive.
+struct Playing(Arc<AtomicUsize>);
+
+impl Playing {
+  fn new(count: Arc<AtomicUsize>) -> Playing {
+    count.fetch_add(1, Ordering::Relaxed);
+    Playing(count)
+  }
+}
+
+impl Drop for Playing {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+struct Throttled {
+  body: Body,
+  buckets: Vec<Shared>,
+  // Only holds bulk responses back while something interactive is being sent.
+  bulk: Option<Shared>,
+  playing_count: Arc<AtomicUsize>,
+  _playing: Option<Playing>,
+  sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Throttled {
+  fn charge(&self, bytes: usize) -> Duration {
+    let now = Instant::now();
+    let mut wait = Duration::ZERO;
+    for bucket in &self.buckets {
+      wait = wait.max(bucket.lock().unwrap().charge(bytes, now));
+    }
+    // Charging it all along would leave bulk downloads owing whatever they sent at full speed.
+    if let (Some(bulk), true) = (&self.bulk, self.playing_count.load(Ordering::Relaxed) > 0) {
+      wait = wait.max(bulk.lock().unwrap().charge(bytes, now));
+    }
+    wait
+  }
+}
+
+impl AsyncRead for Throttled {
+  fn poll_read(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<()>> {
+    if let Some(sleep) = self.sleep.as_mut() {
+      ready!(sleep.as_mut().poll(cx));
+      self.sleep = None;
+    }
+    let before = buf.filled().len();
+    ready!(self.body.as_mut().poll_read(cx, buf))?;
+    let read = buf.filled().len() - before;
+    if read > 0 {
+      let wait = self.charge(read);
+      if !wait.is_zero() {
+        self.sleep = Some(Box::pin(sleep(wait)));
+      }
+    }
+    Poll::Ready(Ok(()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn lets_a_second_through_at_once() {
+    let start = Instant::now();
+    let mut bucket = Bucket::new(1000);
+    bucket.last = start;
+    assert_eq!(bucket.charge(600, start), Duration::ZERO);
+    assert_eq!(bucket.charge(400, start), Duration::ZERO);
+    assert!(!bucket.is_full(start));
+  }
+
+  #[test]
+  fn waits_off_what_is_owed() {
+    let start = Instant::now();
+    let mut bucket = Bucket::new(1000);
+    bucket.last = start;
+    assert_eq!(bucket.charge(1500, start), Duration::from_millis(500));
+    // Half a second later it is back at zero, and the next read waits its own share.
+    let later = start + Duration::from_millis(500);
+    assert_eq!(bucket.charge(250, later), Duration::from_millis(250));
+  }
+
+  #[test]
+  fn refills_up_to_a_second() {
+    let start = Instant::now();
+    let mut bucket = Bucket::new(1000);
+    bucket.last = start;
+    bucket.charge(1000, start);
+    assert!(!bucket.is_full(start + Duration::from_millis(999)));
+    let later = start + Duration::from_secs(60);
+    assert!(bucket.is_full(later));
+    assert_eq!(bucket.charge(1000, later), Duration::ZERO);
+    assert_eq!(bucket.charge(1000, later), Duration::from_secs(1));
+  }
+
+  #[test]
+  fn treats_a_rate_of_0_as_a_byte_a_second() {
+    let start = Instant::now();
+    let mut bucket = Bucket::new(0);
+    bucket.last = start;
+    assert_eq!(bucket.charge(3, start), Duration::from_secs(2));
+    // Also once the config changes a shared bucket to 0.
+    let throttles = Throttles::default();
+    let _before = throttles.bucket("global".to_string(), 1000);
+    let shared = throttles.bucket("global".to_string(), 0);
+    let mut bucket = shared.lock().unwrap();
+    bucket.last = start;
+    assert_eq!(bucket.charge(2, start), Duration::from_secs(1));
+  }
+
+  #[test]
+  fn reads_urgencies() {
+    assert_eq!(urgency("u=0"), Some(0));
+    assert_eq!(urgency("i, u=5"), Some(5));
+    assert_eq!(urgency("u=x, u=2"), Some(2));
+    assert_eq!(urgency("i"), None);
+  }
+}
@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+
+// Dictionaries are kept sorted by their raw keys, as bencoding requires.
+#[derive(Debug, Clone)]
+pub enum Value {
+  Int(i64),
+  Bytes(Vec<u8>),
+  List(Vec<Value>),
+  Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+  pub fn dict<'a>(entries: impl IntoIterator<Item = (&'a str, Value)>) -> Value {
+    Value::Dict(
+      entries
+        .into_iter()
+        .map(|(key, value)| (key.as_bytes().to_vec(), value))
+        .collect(),
+    )
+  }
+
+  pub fn string(value: &str) -> Value {
+    Value::Bytes(value.as_bytes().to_vec())
+  }
+
+  pub fn encode(&self) -> Vec<u8> {
+    let mut out = Vec::new();
+    self.write(&mut out);
+    out
+  }
+
+  fn write(&self, out: &mut Vec<u8>) {
+    match self {
+      Value::Int(value) => out.extend_from_slice(format!("i{}e", value).as_bytes()),
+      Value::Bytes(bytes) => {
+        out.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+        out.extend_from_slice(bytes);
+      }
+      Value::List(values) => {
+        out.push(b'l');
+        for value in values {
+          value.write(out);
+        }
+        out.push(b'e');
+      }
+      Value::Dict(entries) => {
+        out.push(b'd');
+        for (key, value) in entries {
+          Value::Bytes(key.clone()).write(out);
+          value.write(out);
+        }
+        out.push(b'e');
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn encodes_scalars() {
+    assert_eq!(Value::Int(42).encode(), b"i42e");
+    assert_eq!(Value::Int(-3).encode(), b"i-3e");
+    assert_eq!(Value::Int(0).encode(), b"i0e");
+    assert_eq!(Value::string("spam").encode(), b"4:spam");
+    assert_eq!(Value::string("").encode(), b"0:");
+    assert_eq!(Value::Bytes(vec![0, 255]).encode(), b"2:\x00\xff");
+  }
+
+  #[test]
+  fn encodes_lists_and_dicts() {
+    let list = Value::List(vec![Value::string("spam"), Value::Int(7)]);
+    assert_eq!(list.encode(), b"l4:spami7ee");
+    assert_eq!(Value::List(Vec::new()).encode(), b"le");
+    let dict = Value::dict([("spam", list), ("cow", Value::string("moo"))]);
+    assert_eq!(dict.encode(), b"d3:cow3:moo4:spaml4:spami7eee");
+  }
+
+  #[test]
+  fn sorts_keys_by_their_bytes() {
+    let dict = Value::dict([
+      ("piece length", Value::Int(1)),
+      ("Name", Value::Int(2)),
+      ("name", Value::Int(3)),
+      ("", Value::Int(4)),
+    ]);
+    assert_eq!(
+      dict.encode(),
+      b"d0:i4e4:Namei2e4:namei3e12:piece lengthi1ee".to_vec()
+    );
+  }
+}
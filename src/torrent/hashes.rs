@@ -0,0 +1,356 @@
+use std::{
+  collections::{BTreeSet, HashMap},
+  fs::File,
+  io::{self, Read},
+  sync::{Arc, Mutex, RwLock},
+};
+
+use log::{info, warn};
+use rocket::{
+  fairing::AdHoc,
+  tokio::{select, sync::Notify},
+};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::{
+  checksum::{hex, stamp, unhex},
+  database::{data_dir, resolve, save_json},
+};
+
+// BitTorrent v2 hashes files in blocks of this size, the leaves of each file's merkle tree.
+pub const BLOCK: usize = 16 * 1024;
+
+// Everything a torrent needs of one file at one piece length. Files always start on a piece of
+// their own, with v1 pieces padded with zeros to the next one as BEP 47 pad files would be.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileHashes {
+  pub size: u64,
+  pub mtime: u64,
+  pub length: u64,
+  // v1 piece hashes, the last one over the tail of the file and the padding after it.
+  pub sha1: Vec<String>,
+  // The last v1 piece without padding, for when the file is the last in the torrent. `None` when
+  // the file fills its last piece anyway.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub sha1_tail: Option<String>,
+  // v2: the root of the file's merkle tree, and the hashes of its pieces' subtrees for files
+  // longer than one piece.
+  pub pieces_root: String,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub piece_layer: Vec<String>,
+}
+
+impl FileHashes {
+  // v1 pieces as they follow each other in a torrent.
+  pub fn v1_pieces(&self, last: bool) -> Vec<u8> {
+    let mut pieces: Vec<u8> = self.sha1.iter().flat_map(|hash| unhex(hash)).collect();
+    if let (true, Some(tail)) = (last, &self.sha1_tail) {
+      pieces.truncate(pieces.len() - 20);
+      pieces.extend(unhex(tail));
+    }
+    pieces
+  }
+
+  // How many zero bytes pad the file to its last piece's end.
+  pub fn padding(&self) -> u64 {
+    (self.length - self.size % self.length) % self.length
+  }
+}
+
+fn sha256_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+  let mut hasher = Sha256::new();
+  hasher.update(left);
+  hasher.update(right);
+  hasher.finalize().into()
+}
+
+// The root of a tree over `layer`, filled up with `pad` to `width` nodes, a power of two.
+fn merkle_root(mut layer: Vec<[u8; 32]>, width: usize, pad: [u8; 32]) -> [u8; 32] {
+  layer.resize(width.max(1), pad);
+  while layer.len() > 1 {
+    layer = layer
+      .chunks(2)
+      .map(|pair| sha256_pair(&pair[0], &pair[1]))
+      .collect();
+  }
+  layer[0]
+}
+
+fn hash_file(store_path: &str, size: u64, mtime: u64, length: u64) -> io::Result<FileHashes> {
+  hash_reader(File::open(resolve(store_path))?, size, mtime, length)
+}
+
+fn hash_reader(mut file: impl Read, size: u64, mtime: u64, length: u64) -> io::Result<FileHashes> {
+  let mut block = vec![0; BLOCK];
+  let (mut leaves, mut sha1) = (Vec::new(), Vec::new());
+  let (mut piece, mut in_piece, mut total) = (Sha1::default(), 0, 0);
+  loop {
+    let mut filled = 0;
+    while filled < BLOCK {
+      match file.read(&mut block[filled..])? {
+        0 => break,
+        read => filled += read,
+      }
+    }
+    if filled == 0 {
+      break;
+    }
+    total += filled as u64;
+    leaves.push(Sha256::digest(&block[..filled]).into());
+    piece.update(&block[..filled]);
+    in_piece += filled as u64;
+    if in_piece == length {
+      sha1.push(hex(&std::mem::take(&mut piece).finalize()));
+      in_piece = 0;
+    }
+    if filled < BLOCK {
+      break;
+    }
+  }
+  if total != size || size == 0 {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      "empty, or changed while being hashed",
+    ));
+  }
+  let mut sha1_tail = None;
+  if in_piece > 0 {
+    sha1_tail = Some(hex(&piece.clone().finalize()));
+    piece.update(vec![0; (length - in_piece) as usize]);
+    sha1.push(hex(&piece.finalize()));
+  }
+
+  let per_piece = (length as usize) / BLOCK;
+  let zero = [0; 32];
+  let (pieces_root, piece_layer) = match leaves.len() > per_piece {
+    true => {
+      let layer: Vec<[u8; 32]> = leaves
+        .chunks(per_piece)
+        .map(|leaves| merkle_root(leaves.to_vec(), per_piece, zero))
+        .collect();
+      let pad = merkle_root(Vec::new(), per_piece, zero);
+      let root = merkle_root(layer.clone(), layer.len().next_power_of_two(), pad);
+      (root, layer)
+    }
+    false => {
+      let width = leaves.len().next_power_of_two();
+      (merkle_root(leaves, width, zero), Vec::new())
+    }
+  };
+  Ok(FileHashes {
+    size,
+    mtime,
+    length,
+    sha1,
+    sha1_tail,
+    pieces_root: hex(&pieces_root),
+    piece_layer: piece_layer.iter().map(|hash| hex(hash)).collect(),
+  })
+}
+
+// Torrent hashes of files in the store by piece length, persisted in `torrents.json`. Nothing is
+// hashed while a request waits; files are queued and hashed one at a time by `fairing`.
+#[derive(Clone, Default)]
+pub struct Torrents {
+  cache: Arc<RwLock<HashMap<String, Vec<FileHashes>>>>,
+  queue: Arc<Mutex<BTreeSet<(String, u64)>>>,
+  wake: Arc<Notify>,
+}
+
+impl Torrents {
+  pub fn load() -> Torrents {
+    let cache = File::open(data_dir().join("torrents.json"))
+      .ok()
+      .and_then(|file| serde_json::from_reader(file).ok())
+      .unwrap_or_default();
+    Torrents {
+      cache: Arc::new(RwLock::new(cache)),
+      ..Torrents::default()
+    }
+  }
+
+  fn save(&self) {
+    let cache = self.cache.read().unwrap();
+    let result = save_json(&data_dir().join("torrents.json"), &*cache);
+    if let Err(error) = result {
+      warn!("Failed to save torrent hashes: {}", error);
+    }
+  }
+
+  pub fn cached(&self, store_path: &str, length: u64) -> Option<FileHashes> {
+    let (size, mtime) = stamp(&resolve(store_path)).ok()?;
+    let cache = self.cache.read().unwrap();
+    cache
+      .get(store_path)?
+      .iter()
+      .find(|hashes| hashes.length == length && hashes.size == size && hashes.mtime == mtime)
+      .cloned()
+  }
+
+  // The cached hashes, or `None` after queueing the file to be hashed.
+  pub fn get(&self, store_path: &str, length: u64) -> Option<FileHashes> {
+    let hashes = self.cached(store_path, length);
+    if hashes.is_none()
+      && self
+        .queue
+        .lock()
+        .unwrap()
+        .insert((store_path.to_string(), length))
+    {
+      self.wake.notify_one();
+    }
+    hashes
+  }
+
+  // Blocking.
+  fn hash(&self, store_path: &str, length: u64) -> io::Result<()> {
+    if self.cached(store_path, length).is_some() {
+      return Ok(());
+    }
+    let (size, mtime) = stamp(&resolve(store_path))?;
+    let hashes = hash_file(store_path, size, mtime, length)?;
+    let mut cache = self.cache.write().unwrap();
+    let entries = cache.entry(store_path.to_string()).or_default();
+    entries.retain(|entry| entry.length != length);
+    entries.push(hashes);
+    drop(cache);
+    self.save();
+    Ok(())
+  }
+
+  fn next(&self) -> Option<(String, u64)> {
+    self.queue.lock().unwrap().pop_first()
+  }
+}
+
+// Works through the queue of files to hash for as long as the server runs.
+pub fn fairing() -> AdHoc {
+  AdHoc::on_liftoff("Torrent hashes", |rocket| {
+    Box::pin(async move {
+      let torrents = rocket.state::<Torrents>().unwrap().clone();
+      let shutdown = rocket.shutdown();
+      rocket::tokio::spawn(async move {
+        rocket::tokio::pin!(shutdown);
+        loop {
+          while let Some((store_path, length)) = torrents.next() {
+            let hashing = torrents.clone();
+            let key = store_path.clone();
+            let result =
+              rocket::tokio::task::spawn_blocking(move || hashing.hash(&key, length)).await;
+            match result.map_err(io::Error::other).and_then(|result| result) {
+              Ok(()) => info!("Hashed pieces of {}", store_path),
+              Err(error) => warn!("Failed to hash pieces of {}: {}", store_path, error),
+            }
+          }
+          select! {
+            _ = &mut shutdown => break,
+            _ = torrents.wake.notified() => {},
+          }
+        }
+      });
+    })
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+
+  use super::*;
+
+  // A pattern that doesn't repeat from one piece to the next.
+  fn data(size: usize) -> Vec<u8> {
+    (0..size).map(|i| ((i * i + i / 7) % 251) as u8).collect()
+  }
+
+  fn hashed(data: &[u8]) -> FileHashes {
+    hash_reader(Cursor::new(data), data.len() as u64, 0, 32768).unwrap()
+  }
+
+  // The vectors were worked out independently, following BEP 3, 47 and 52 to the letter.
+  #[test]
+  fn hashes_files_spanning_pieces() {
+    let hashes = hashed(&data(100_000));
+    assert_eq!(
+      hashes.pieces_root,
+      "5c4432153647bdec677d1dbc84ca55d9a3876adf6313e89833b5c83d816059c3"
+    );
+    assert_eq!(
+      hashes.piece_layer,
+      [
+        "fafa7f6f4b982b0c5cbe5290a354e3167a21e8b44a9704806ffc7bed5ff998ab",
+        "df7fba2ee23d735b5dd9b3d89ab9714c21fb816fb0c2f62eeb7e10d884b4f1c8",
+        "5b343cbbd8769abd95c9797d9b217111e2f0ccbaa607f20318d8659f38e67d40",
+        "2c7608d57f59244c5535051b30893237e73f248b01d9a0eda394b71b068a10ac",
+      ]
+    );
+    assert_eq!(
+      hashes.sha1,
+      [
+        "7f546dcafa841d0bdaf80084d91dc4640211f10f",
+        "4b6708524f997383ea326111172d58f1741fc0e3",
+        "b6c7d27ff8a0d54fa5a7dbab295199555885ec11",
+        "d39ccd03554b157e2cddfbabdeca04929405b84b",
+      ]
+    );
+    assert_eq!(
+      hashes.sha1_tail.as_deref(),
+      Some("eff5187ac2ebc9426f4e658e8943aadbd6bb2ac6")
+    );
+  }
+
+  #[test]
+  fn pads_piece_layers_to_a_power_of_two() {
+    let hashes = hashed(&data(80_000));
+    assert_eq!(hashes.piece_layer.len(), 3);
+    assert_eq!(
+      hashes.pieces_root,
+      "0ce30501e1c05b943d836097c1b058076e7be76c137d2de34679e87d46369f8c"
+    );
+  }
+
+  #[test]
+  fn hashes_files_within_a_piece() {
+    let hashes = hashed(&data(20_000));
+    assert!(hashes.piece_layer.is_empty());
+    assert_eq!(
+      hashes.pieces_root,
+      "84310f9a3d2d6f4e94f11e1d2a99d5e933521d09ca8ce315d9d45f70b203baa8"
+    );
+    // A single block is its own root.
+    let block = data(10_000);
+    assert_eq!(hashes_of(&block).pieces_root, hex(&Sha256::digest(&block)));
+  }
+
+  fn hashes_of(block: &[u8]) -> FileHashes {
+    hash_reader(Cursor::new(block), block.len() as u64, 0, 32768).unwrap()
+  }
+
+  #[test]
+  fn pads_files_to_their_last_piece() {
+    let hashes = hashed(&data(100_000));
+    assert_eq!(hashes.padding(), 32768 - 100_000 % 32768);
+    let pieces = hashes.v1_pieces(false);
+    assert_eq!(pieces.len(), 4 * 20);
+    assert_eq!(hex(&pieces[60..]), hashes.sha1[3]);
+    // The last file in a torrent isn't padded.
+    let pieces = hashes.v1_pieces(true);
+    assert_eq!(
+      hex(&pieces[60..]),
+      "eff5187ac2ebc9426f4e658e8943aadbd6bb2ac6"
+    );
+
+    let whole = hashed(&data(65_536));
+    assert_eq!(whole.padding(), 0);
+    assert_eq!(whole.sha1_tail, None);
+    assert_eq!(whole.v1_pieces(true), whole.v1_pieces(false));
+  }
+
+  #[test]
+  fn refuses_files_that_changed() {
+    assert!(hash_reader(Cursor::new(data(1000)), 2000, 0, 32768).is_err());
+    assert!(hash_reader(Cursor::new(Vec::new()), 0, 0, 32768).is_err());
+  }
+}
@@ -0,0 +1,588 @@
+use std::collections::BTreeMap;
+
+use rocket::{
+  http::{ContentType, Header, Method, RawStr, Status},
+  response::{self, Responder},
+  Request, Response, State,
+};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::{
+  auth::{self, User},
+  checksum::{hex, unhex, Checksums},
+  database::{Database, Db, DEFAULT_COLLECTION},
+  download::{file_name, Base, ByteRange, Download},
+  episode,
+  feed::with_token,
+  pieces::piece_length,
+  storage::{local::Local, Backends, Storage},
+  throttle::{Throttles, Traffic},
+  webhook::Webhooks,
+};
+use bencode::Value;
+use hashes::FileHashes;
+
+pub mod bencode;
+pub mod hashes;
+
+pub use hashes::{fairing, Torrents};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Version {
+  V1,
+  V2,
+  // Both at once (BEP 52), which is what clients get unless they ask otherwise.
+  Hybrid,
+}
+
+impl Version {
+  fn from_param(version: Option<u8>) -> Option<Version> {
+    match version {
+      None => Some(Version::Hybrid),
+      Some(1) => Some(Version::V1),
+      Some(2) => Some(Version::V2),
+      Some(_) => None,
+    }
+  }
+
+  fn v1(self) -> bool {
+    self != Version::V2
+  }
+
+  fn v2(self) -> bool {
+    self != Version::V1
+  }
+}
+
+struct TorrentFile {
+  name: String,
+  store_path: String,
+  size: u64,
+}
+
+impl TorrentFile {
+  // Only files on local storage can be hashed; empty ones make no sense to share.
+  async fn of(db: &Database, id: &str) -> Option<TorrentFile> {
+    let store_path = db.id_to_path.get(id)?;
+    if !db.is_local(id) {
+      return None;
+    }
+    let size = Local.stat(store_path).await.ok()?.size;
+    (size > 0).then(|| TorrentFile {
+      name: file_name(id, store_path),
+      store_path: store_path.clone(),
+      size,
+    })
+  }
+}
+
+// A metainfo file and its info dictionary, or how many of its files are hashed so far.
+fn build(
+  torrents: &Torrents,
+  name: &str,
+  mut files: Vec<TorrentFile>,
+  version: Version,
+  web_seed: &str,
+  created: u64,
+) -> Result<(Vec<u8>, Vec<u8>), (usize, usize)> {
+  // v2 keeps files sorted by name, and a hybrid's v1 list has to follow the same order.
+  files.sort_by(|a, b| a.name.cmp(&b.name));
+  let length = piece_length(files.iter().map(|file| file.size).sum());
+  let hashes: Vec<Option<FileHashes>> = files
+    .iter()
+    .map(|file| torrents.get(&file.store_path, length))
+    .collect();
+  let ready = hashes.iter().filter(|hashes| hashes.is_some()).count();
+  if ready < files.len() {
+    return Err((ready, files.len()));
+  }
+  let hashes: Vec<FileHashes> = hashes.into_iter().flatten().collect();
+  Ok(encode(
+    name, length, &files, &hashes, version, web_seed, created,
+  ))
+}
+
+// `files` sorted by name, each with its hashes at piece length `length`.
+fn encode(
+  name: &str,
+  length: u64,
+  files: &[TorrentFile],
+  hashes: &[FileHashes],
+  version: Version,
+  web_seed: &str,
+  created: u64,
+) -> (Vec<u8>, Vec<u8>) {
+  let mut info = vec![
+    ("name", Value::string(name)),
+    ("piece length", Value::Int(length as i64)),
+  ];
+  let single = files.len() == 1;
+  if version.v1() {
+    let mut pieces = Vec::new();
+    let mut list = Vec::new();
+    for (index, (file, hashes)) in files.iter().zip(hashes).enumerate() {
+      let last = index + 1 == files.len();
+      pieces.extend(hashes.v1_pieces(last));
+      list.push(Value::dict([
+        ("length", Value::Int(file.size as i64)),
+        ("path", Value::List(vec![Value::string(&file.name)])),
+      ]));
+      // BEP 47 pad files start every file on a piece of its own, as v2 does.
+      if !last && hashes.padding() > 0 {
+        list.push(Value::dict([
+          ("attr", Value::string("p")),
+          ("length", Value::Int(hashes.padding() as i64)),
+          (
+            "path",
+            Value::List(vec![
+              Value::string(".pad"),
+              Value::string(&hashes.padding().to_string()),
+            ]),
+          ),
+        ]));
+      }
+    }
+    match single {
+      true => info.push(("length", Value::Int(files[0].size as i64))),
+      false => info.push(("files", Value::List(list))),
+    }
+    info.push(("pieces", Value::Bytes(pieces)));
+  }
+  let mut layers = BTreeMap::new();
+  if version.v2() {
+    let tree: BTreeMap<Vec<u8>, Value> = files
+      .iter()
+      .zip(hashes)
+      .map(|(file, hashes)| {
+        let entry = Value::dict([
+          ("length", Value::Int(file.size as i64)),
+          ("pieces root", Value::Bytes(unhex(&hashes.pieces_root))),
+        ]);
+        (file.name.as_bytes().to_vec(), Value::dict([("", entry)]))
+      })
+      .collect();
+    info.push(("file tree", Value::Dict(tree)));
+    info.push(("meta version", Value::Int(2)));
+    for hashes in hashes
+      .iter()
+      .filter(|hashes| !hashes.piece_layer.is_empty())
+    {
+      let layer: Vec<u8> = hashes
+        .piece_layer
+        .iter()
+        .flat_map(|hash| unhex(hash))
+        .collect();
+      layers.insert(unhex(&hashes.pieces_root), Value::Bytes(layer));
+    }
+  }
+  let info = Value::dict(info);
+
+  let mut metainfo = vec![
+    ("created by", Value::string("file-share")),
+    ("creation date", Value::Int(created as i64)),
+    ("info", info.clone()),
+    ("url-list", Value::string(web_seed)),
+  ];
+  if version.v2() {
+    metainfo.push(("piece layers", Value::Dict(layers)));
+  }
+  (Value::dict(metainfo).encode(), info.encode())
+}
+
+fn magnet(info: &[u8], version: Version, name: &str, web_seed: &str, torrent: &str) -> String {
+  let mut magnet = String::from("magnet:?");
+  if version.v1() {
+    magnet.push_str(&format!("xt=urn:btih:{}&", hex(&Sha1::digest(info))));
+  }
+  if version.v2() {
+    // A multihash: 0x12 for SHA-256, 0x20 for its length.
+    magnet.push_str(&format!("xt=urn:btmh:1220{}&", hex(&Sha256::digest(info))));
+  }
+  magnet.push_str(&format!(
+    "dn={}&ws={}&xs={}",
+    RawStr::new(name).percent_encode(),
+    RawStr::new(web_seed).percent_encode(),
+    RawStr::new(torrent).percent_encode()
+  ));
+  magnet
+}
+
+fn created(db: &Database, ids: &[String]) -> u64 {
+  ids.iter().filter_map(|id| db.added(id)).max().unwrap_or(0)
+}
+
+// Web seeds (BEP 19) for one file are its own URL.
+fn id_seed(base: &str, id: &str, token: Option<&str>) -> String {
+  with_token(
+    format!("{}/dr-who/{}", base, RawStr::new(id).percent_encode()),
+    token,
+  )
+}
+
+// For several, clients append the torrent's name and the file's to this, which `seed` serves.
+// The token is part of the path, as nothing can be appended to a query.
+fn season_seed(base: &str, token: Option<&str>) -> String {
+  let token = token.unwrap_or("-");
+  format!("{}/seed/{}/", base, RawStr::new(token).percent_encode())
+}
+
+// Seasons are only numbered in the default collection, as with shuffling.
+fn season_name(season: u32) -> String {
+  format!("{}-season-{:02}", DEFAULT_COLLECTION, season)
+}
+
+fn season_of(name: &str) -> Option<u32> {
+  name
+    .strip_prefix(DEFAULT_COLLECTION)?
+    .strip_prefix("-season-")?
+    .parse()
+    .ok()
+}
+
+fn season_ids(db: &Database, season: u32) -> Vec<String> {
+  db.collection_ids(DEFAULT_COLLECTION)
+    .into_iter()
+    .filter(|id| episode::parse(id).is_some_and(|episode| episode.season == season))
+    .collect()
+}
+
+// The magnet link of `id`'s torrent, once its pieces are hashed.
+pub async fn magnet_for(
+  db: &Database,
+  torrents: &Torrents,
+  base: &str,
+  id: &str,
+  token: Option<&str>,
+) -> Option<String> {
+  let file = TorrentFile::of(db, id).await?;
+  let name = file.name.clone();
+  let web_seed = id_seed(base, id, token);
+  let (_, info) = build(torrents, &name, vec![file], Version::Hybrid, &web_seed, 0).ok()?;
+  let torrent = with_token(
+    format!(
+      "{}/dr-who/{}/torrent",
+      base,
+      RawStr::new(id).percent_encode()
+    ),
+    token,
+  );
+  Some(magnet(&info, Version::Hybrid, &name, &web_seed, &torrent))
+}
+
+pub enum Metainfo {
+  Ready { name: String, metainfo: Vec<u8> },
+  Hashing { ready: usize, total: usize },
+}
+
+impl<'r> Responder<'r, 'static> for Metainfo {
+  fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+    match self {
+      Metainfo::Ready { name, metainfo } => Response::build_from(metainfo.respond_to(request)?)
+        .header(ContentType::new("application", "x-bittorrent"))
+        .header(Header::new(
+          "Content-Disposition",
+          format!("attachment; filename=\"{}.torrent\"", name.replace('"', "")),
+        ))
+        .ok(),
+      // Hashing takes a while for big files, and happens in the background.
+      Metainfo::Hashing { ready, total } => Response::build_from(
+        format!(
+          "Hashed {} of {} files so far, try again shortly.\n",
+          ready, total
+        )
+        .respond_to(request)?,
+      )
+      .status(Status::Accepted)
+      .raw_header("Retry-After", "10")
+      .ok(),
+    }
+  }
+}
+
+fn respond(
+  torrents: &Torrents,
+  name: String,
+  files: Vec<TorrentFile>,
+  version: Version,
+  web_seed: &str,
+  created: u64,
+) -> Metainfo {
+  match build(torrents, &name, files, version, web_seed, created) {
+    Ok((metainfo, _)) => Metainfo::Ready { name, metainfo },
+    Err((ready, total)) => Metainfo::Hashing { ready, total },
+  }
+}
+
+// `?version=1` or `?version=2` for a torrent of only that version.
+#[get("/dr-who/<id>/torrent?<version>&<token>", rank = 3)]
+pub async fn id(
+  _user: User,
+  db: Db,
+  base: Base,
+  torrents: &State<Torrents>,
+  id: String,
+  version: Option<u8>,
+  token: Option<String>,
+) -> Option<Metainfo> {
+  let version = Version::from_param(version)?;
+  let file = TorrentFile::of(&db, &id).await?;
+  let web_seed = id_seed(&base.0, &id, token.as_deref());
+  Some(respond(
+    torrents,
+    file.name.clone(),
+    vec![file],
+    version,
+    &web_seed,
+    created(&db, std::slice::from_ref(&id)),
+  ))
+}
+
+#[get("/dr-who/season/<season>/torrent?<version>&<token>")]
+pub async fn season(
+  _user: User,
+  db: Db,
+  base: Base,
+  torrents: &State<Torrents>,
+  season: u32,
+  version: Option<u8>,
+  token: Option<String>,
+) -> Option<Metainfo> {
+  let version = Version::from_param(version)?;
+  let ids = season_ids(&db, season);
+  let mut files = Vec::new();
+  for id in &ids {
+    files.extend(TorrentFile::of(&db, id).await);
+  }
+  if files.is_empty() {
+    return None;
+  }
+  let web_seed = season_seed(&base.0, token.as_deref());
+  Some(respond(
+    torrents,
+    season_name(season),
+    files,
+    version,
+    &web_seed,
+    created(&db, &ids),
+  ))
+}
+
+// Where clients fetch a season torrent's files from this server, handed on to `retrieve`. Peers
+// fetch whole pieces, ranges or not, so none of it counts as playback.
+#[allow(clippy::too_many_arguments)]
+#[get("/seed/<token>/<torrent>/<file>")]
+pub async fn seed(
+  db: Db,
+  method: Method,
+  backends: &State<Backends>,
+  checksums: &State<Checksums>,
+  webhooks: &State<Webhooks>,
+  throttles: &State<Throttles>,
+  traffic: Traffic,
+  range: ByteRange,
+  token: &str,
+  torrent: &str,
+  file: &str,
+) -> Result<Option<Download>, Status> {
+  let credential = (token != "-").then_some(token);
+  let user = match auth::check(&db, credential) {
+    Ok(token) => User {
+      name: token.map(|token| token.name.clone()),
+    },
+    Err(auth::Denied) => return Err(Status::Unauthorized),
+  };
+  let id = season_of(torrent)
+    .map(|season| season_ids(&db, season))
+    .unwrap_or_default()
+    .into_iter()
+    .find(|id| file_name(id, &db.id_to_path[id]) == file);
+  let id = match id {
+    Some(id) => id,
+    None => return Ok(None),
+  };
+  Ok(
+    crate::retrieve(
+      user,
+      db,
+      method,
+      backends,
+      checksums,
+      webhooks,
+      throttles,
+      traffic.bulk(),
+      range,
+      id,
+      None,
+    )
+    .await,
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const LENGTH: u64 = 32768;
+
+  fn file(name: &str, size: u64) -> TorrentFile {
+    TorrentFile {
+      name: name.to_string(),
+      store_path: format!("complete/{}", name),
+      size,
+    }
+  }
+
+  fn hashes(size: u64, sha1: &[&str], tail: &str, root: &str, layer: &[&str]) -> FileHashes {
+    FileHashes {
+      size,
+      mtime: 0,
+      length: LENGTH,
+      sha1: sha1.iter().map(|byte| byte.repeat(20)).collect(),
+      sha1_tail: Some(tail.repeat(20)),
+      pieces_root: root.repeat(32),
+      piece_layer: layer.iter().map(|byte| byte.repeat(32)).collect(),
+    }
+  }
+
+  fn bytes(byte: u8, count: usize) -> Vec<u8> {
+    vec![byte; count]
+  }
+
+  fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+      .windows(needle.len())
+      .any(|window| window == needle)
+  }
+
+  // Two pieces with 25536 bytes of padding, and a file within one piece.
+  fn two_files(version: Version) -> (Vec<u8>, Vec<u8>) {
+    let files = [file("a.mkv", 40000), file("b.mkv", 5)];
+    let hashes = [
+      hashes(40000, &["11", "22"], "33", "aa", &["a1", "a2"]),
+      hashes(5, &["44"], "55", "bb", &[]),
+    ];
+    encode("s04", LENGTH, &files, &hashes, version, "http://seed/", 7)
+  }
+
+  #[test]
+  fn names_seasons_of_the_default_collection() {
+    assert_eq!(season_name(3), "dr-who-season-03");
+    assert_eq!(season_of(&season_name(3)), Some(3));
+    assert_eq!(season_of("dr-who-season-x"), None);
+    assert_eq!(season_of("torchwood-season-01"), None);
+
+    let db: Database = serde_json::from_value(serde_json::json!({
+      "id_to_path": {
+        "s01e01.mkv": "complete/a.mkv",
+        "s01e02.mkv": "complete/b.mkv",
+        "s02e01.mkv": "complete/c.mkv",
+        "s01e01-torchwood.mkv": "complete/d.mkv",
+      },
+      "collections": { "torchwood": { "ids": ["s01e01-torchwood.mkv"] } },
+    }))
+    .unwrap();
+    assert_eq!(season_ids(&db, 1), ["s01e01.mkv", "s01e02.mkv"]);
+  }
+
+  #[test]
+  fn reads_versions() {
+    assert_eq!(Version::from_param(None), Some(Version::Hybrid));
+    assert_eq!(Version::from_param(Some(1)), Some(Version::V1));
+    assert_eq!(Version::from_param(Some(2)), Some(Version::V2));
+    assert_eq!(Version::from_param(Some(3)), None);
+    assert!(Version::Hybrid.v1() && Version::Hybrid.v2());
+    assert!(Version::V1.v1() && !Version::V1.v2());
+    assert!(!Version::V2.v1() && Version::V2.v2());
+  }
+
+  #[test]
+  fn encodes_single_file_v1_torrents() {
+    let (metainfo, info) = encode(
+      "b.mkv",
+      LENGTH,
+      &[file("b.mkv", 5)],
+      &[hashes(5, &["44"], "55", "bb", &[])],
+      Version::V1,
+      "http://seed/b.mkv",
+      7,
+    );
+    let mut expected = b"d6:lengthi5e4:name5:b.mkv12:piece lengthi32768e6:pieces20:".to_vec();
+    expected.extend(bytes(0x55, 20));
+    expected.push(b'e');
+    assert_eq!(info, expected);
+
+    let mut expected = b"d10:created by10:file-share13:creation datei7e4:info".to_vec();
+    expected.extend(&info);
+    expected.extend(b"8:url-list17:http://seed/b.mkve");
+    assert_eq!(metainfo, expected);
+  }
+
+  #[test]
+  fn pads_files_in_hybrid_torrents() {
+    let (metainfo, info) = two_files(Version::Hybrid);
+    assert!(contains(
+      &info,
+      b"5:filesld6:lengthi40000e4:pathl5:a.mkveed4:attr1:p6:lengthi25536e4:pathl4:.pad5:25536eed6:lengthi5e4:pathl5:b.mkveee"
+    ));
+    // Only the last file's piece goes without its padding.
+    let mut pieces = b"6:pieces60:".to_vec();
+    pieces.extend(bytes(0x11, 20));
+    pieces.extend(bytes(0x22, 20));
+    pieces.extend(bytes(0x55, 20));
+    assert!(contains(&info, &pieces));
+
+    let mut tree = b"9:file treed5:a.mkvd0:d6:lengthi40000e11:pieces root32:".to_vec();
+    tree.extend(bytes(0xaa, 32));
+    tree.extend(b"ee5:b.mkvd0:d6:lengthi5e11:pieces root32:");
+    tree.extend(bytes(0xbb, 32));
+    tree.extend(b"eee5:files");
+    assert!(contains(&info, &tree));
+    assert!(contains(&info, b"12:meta versioni2e4:name3:s04"));
+
+    // Files within a piece have no layer of their own.
+    let mut layers = b"12:piece layersd32:".to_vec();
+    layers.extend(bytes(0xaa, 32));
+    layers.extend(b"64:");
+    layers.extend(bytes(0xa1, 32));
+    layers.extend(bytes(0xa2, 32));
+    layers.extend(b"e8:url-list12:http://seed/e");
+    assert!(metainfo.ends_with(&layers));
+  }
+
+  #[test]
+  fn leaves_v1_out_of_v2_torrents() {
+    let (metainfo, info) = two_files(Version::V2);
+    assert!(!contains(&info, b"5:files"));
+    assert!(!contains(&info, b"6:pieces"));
+    assert!(contains(&info, b"9:file tree"));
+    assert!(contains(&metainfo, b"12:piece layers"));
+
+    let (metainfo, info) = two_files(Version::V1);
+    assert!(contains(&info, b"5:files"));
+    assert!(!contains(&info, b"9:file tree"));
+    assert!(!contains(&metainfo, b"12:piece layers"));
+  }
+
+  #[test]
+  fn links_magnets_to_both_info_hashes() {
+    let (_, info) = two_files(Version::Hybrid);
+    let link = magnet(
+      &info,
+      Version::Hybrid,
+      "Season 4",
+      "http://seed/",
+      "http://host/t",
+    );
+    assert_eq!(
+      link,
+      format!(
+        "magnet:?xt=urn:btih:{}&xt=urn:btmh:1220{}&dn=Season%204&ws=http:%2F%2Fseed%2F&xs=http:%2F%2Fhost%2Ft",
+        hex(&Sha1::digest(&info)),
+        hex(&Sha256::digest(&info))
+      )
+    );
+    let link = magnet(&info, Version::V1, "s04", "ws", "xs");
+    assert!(link.starts_with("magnet:?xt=urn:btih:") && !link.contains("btmh"));
+  }
+}
@@ -0,0 +1,321 @@
+use std::{
+  collections::{HashMap, HashSet},
+  fmt,
+  fs::{self, File},
+  io::{self, Read},
+};
+
+use rocket::{serde::json::Json, State};
+use serde::Serialize;
+
+use crate::{
+  auth::Writer,
+  checksum::Checksums,
+  database::{resolve, store_relative, Database, Db, FileRecord},
+  storage::local::store_files,
+  webhook::{self, Webhooks},
+};
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+  pub checked: usize,
+  pub healthy: usize,
+  pub problems: Vec<Problem>,
+  pub orphans: Vec<String>,
+  #[serde(skip)]
+  pub measured: HashMap<String, FileRecord>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Problem {
+  pub id: String,
+  pub path: String,
+  #[serde(flatten)]
+  pub issue: Issue,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "issue", rename_all = "snake_case")]
+pub enum Issue {
+  Missing,
+  Unreadable { error: String },
+  Empty,
+  SizeMismatch { expected: u64, actual: u64 },
+  HashMismatch { expected: String, actual: String },
+}
+
+impl fmt::Display for Issue {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Issue::Missing => write!(f, "missing"),
+      Issue::Unreadable { error } => write!(f, "unreadable: {}", error),
+      Issue::Empty => write!(f, "empty"),
+      Issue::SizeMismatch { expected, actual } => {
+        write!(f, "size is {} bytes, expected {}", actual, expected)
+      }
+      Issue::HashMismatch { expected, actual } => {
+        write!(f, "sha256 is {}, expected {}", actual, expected)
+      }
+    }
+  }
+}
+
+fn unreadable(error: io::Error) -> Issue {
+  Issue::Unreadable {
+    error: error.to_string(),
+  }
+}
+
+// Hashing is only done when a hash was recorded for the entry, or when `hash_all` is set.
+fn check(
+  store_path: &str,
+  record: Option<&FileRecord>,
+  checksums: &Checksums,
+  hash_all: bool,
+) -> Result<FileRecord, Issue> {
+  let path = resolve(store_path);
+  let metadata = match fs::metadata(&path) {
+    Ok(metadata) => metadata,
+    Err(error) if error.kind() == io::ErrorKind::NotFound => return Err(Issue::Missing),
+    Err(error) => return Err(unreadable(error)),
+  };
+  if !metadata.is_file() {
+    return Err(Issue::Unreadable {
+      error: "not a regular file".to_string(),
+    });
+  }
+  File::open(&path)
+    .and_then(|mut file| file.read(&mut [0; 1]))
+    .map_err(unreadable)?;
+
+  let size = metadata.len();
+  if size == 0 {
+    return Err(Issue::Empty);
+  }
+  if let Some(expected) = record.and_then(|record| record.size) {
+    if expected != size {
+      return Err(Issue::SizeMismatch {
+        expected,
+        actual: size,
+      });
+    }
+  }
+
+  let expected_hash = record
+    .and_then(|record| record.sha256.clone())
+    .or_else(|| store_path.strip_prefix("sha256:").map(str::to_string));
+  let sha256 = if hash_all || expected_hash.is_some() {
+    Some(checksums.digest(store_path).map_err(unreadable)?.sha256)
+  } else {
+    None
+  };
+  if let (Some(expected), Some(actual)) = (expected_hash, &sha256) {
+    if !expected.eq_ignore_ascii_case(actual) {
+      return Err(Issue::HashMismatch {
+        expected,
+        actual: actual.clone(),
+      });
+    }
+  }
+
+  Ok(FileRecord {
+    size: Some(size),
+    sha256,
+    added: record.and_then(|record| record.added),
+  })
+}
+
+// Only ids kept on local storage are checked; remote backends look after their own integrity.
+pub fn verify_library(db: &Database, checksums: &Checksums, hash_all: bool) -> Report {
+  let mut ids: Vec<&String> = db.id_to_path.keys().filter(|id| db.is_local(id)).collect();
+  ids.sort();
+
+  let mut report = Report {
+    checked: ids.len(),
+    healthy: 0,
+    problems: Vec::new(),
+    orphans: Vec::new(),
+    measured: HashMap::new(),
+  };
+  for id in ids {
+    let path = &db.id_to_path[id];
+    match check(path, db.records.get(id), checksums, hash_all) {
+      Ok(record) => {
+        report.healthy += 1;
+        report.measured.insert(id.clone(), record);
+      }
+      Err(issue) => report.problems.push(Problem {
+        id: id.clone(),
+        path: path.clone(),
+        issue,
+      }),
+    }
+  }
+
+  let referenced: HashSet<String> = db
+    .id_to_path
+    .values()
+    .map(|path| store_relative(path))
+    .collect();
+  report.orphans = store_files()
+    .into_iter()
+    .filter(|file| !referenced.contains(file))
+    .collect();
+  report
+}
+
+// A `verify.failed` event for every problem found.
+fn notify(db: &Database, webhooks: &Webhooks, report: &Report) {
+  for problem in &report.problems {
+    if let Ok(data) = serde_json::to_value(problem) {
+      webhooks.emit(db, webhook::VERIFY_FAILED, data);
+    }
+  }
+}
+
+// Hashes whatever has a recorded hash and can set off webhooks, so on a server with tokens it
+// takes one that may write.
+#[get("/verify")]
+pub async fn report(
+  _writer: Writer,
+  db: Db,
+  checksums: &State<Checksums>,
+  webhooks: &State<Webhooks>,
+) -> Json<Report> {
+  let db = db.0.clone();
+  let checksums = checksums.inner().clone();
+  let snapshot = db.clone();
+  let report =
+    rocket::tokio::task::spawn_blocking(move || verify_library(&snapshot, &checksums, false))
+      .await
+      .unwrap();
+  notify(&db, webhooks, &report);
+  Json(report)
+}
+
+// `file-share verify [--record]`. With `--record` the current size and hash of every healthy entry
+// is written back to the config, so later runs can detect files being replaced or truncated.
+pub fn run(args: &[String]) {
+  let record = args.iter().any(|arg| arg == "--record");
+  let db = Database::load();
+  let report = verify_library(&db, &Checksums::load(), record);
+  // Queued for the server to send, should it be running.
+  notify(&db, &Webhooks::default(), &report);
+
+  for problem in &report.problems {
+    println!("{}: {} ({})", problem.id, problem.issue, problem.path);
+  }
+  for orphan in &report.orphans {
+    println!("orphan: {}", orphan);
+  }
+  println!(
+    "{} checked, {} healthy, {} problems, {} orphans",
+    report.checked,
+    report.healthy,
+    report.problems.len(),
+    report.orphans.len()
+  );
+
+  if record {
+    Database::edit(|db| db.records.extend(report.measured));
+  }
+  if !report.problems.is_empty() {
+    std::process::exit(1);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::{Path, PathBuf};
+
+  use super::*;
+
+  // Absolute paths resolve to themselves, so nothing in the data directory is touched. Nothing
+  // is hashed either, as that would record digests there.
+  fn scratch(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("file-share-verify-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    directory.join(name)
+  }
+
+  fn checked(path: &Path, record: Option<FileRecord>) -> Result<FileRecord, Issue> {
+    let record = record.as_ref();
+    check(path.to_str().unwrap(), record, &Checksums::default(), false)
+  }
+
+  #[test]
+  fn passes_files_as_recorded() {
+    let path = scratch("healthy.mkv");
+    fs::write(&path, b"episode").unwrap();
+    let record = FileRecord {
+      size: Some(7),
+      sha256: None,
+      added: Some(1700000000),
+    };
+    let measured = checked(&path, Some(record)).unwrap();
+    assert_eq!(measured.size, Some(7));
+    assert_eq!(measured.sha256, None);
+    assert_eq!(measured.added, Some(1700000000));
+    assert_eq!(checked(&path, None).unwrap().size, Some(7));
+  }
+
+  #[test]
+  fn finds_broken_files() {
+    let missing = scratch("missing.mkv");
+    assert!(matches!(checked(&missing, None), Err(Issue::Missing)));
+
+    let empty = scratch("empty.mkv");
+    fs::write(&empty, b"").unwrap();
+    assert!(matches!(checked(&empty, None), Err(Issue::Empty)));
+
+    let directory = scratch("directory.mkv");
+    fs::create_dir_all(&directory).unwrap();
+    assert!(matches!(
+      checked(&directory, None),
+      Err(Issue::Unreadable { .. })
+    ));
+  }
+
+  #[test]
+  fn finds_files_that_changed_size() {
+    let path = scratch("truncated.mkv");
+    fs::write(&path, b"epis").unwrap();
+    let record = FileRecord {
+      size: Some(7),
+      ..FileRecord::default()
+    };
+    let issue = checked(&path, Some(record)).unwrap_err();
+    assert!(matches!(
+      issue,
+      Issue::SizeMismatch {
+        expected: 7,
+        actual: 4
+      }
+    ));
+    assert_eq!(issue.to_string(), "size is 4 bytes, expected 7");
+  }
+
+  #[test]
+  fn reports_issues_by_kind() {
+    let problem = Problem {
+      id: "s04e01.mkv".to_string(),
+      path: "complete/s04e01.mkv".to_string(),
+      issue: Issue::HashMismatch {
+        expected: "aa".to_string(),
+        actual: "bb".to_string(),
+      },
+    };
+    assert_eq!(
+      serde_json::to_value(&problem).unwrap(),
+      serde_json::json!({
+        "id": "s04e01.mkv",
+        "path": "complete/s04e01.mkv",
+        "issue": "hash_mismatch",
+        "expected": "aa",
+        "actual": "bb",
+      })
+    );
+    assert_eq!(problem.issue.to_string(), "sha256 is bb, expected aa");
+    assert_eq!(Issue::Missing.to_string(), "missing");
+  }
+}
@@ -0,0 +1,177 @@
+use std::{
+  collections::{BTreeSet, HashMap},
+  ffi::CString,
+  fs, io,
+  os::unix::ffi::OsStrExt,
+};
+
+use log::warn;
+
+use super::skipped;
+use crate::database::file_store;
+
+const MASK: u32 = libc::IN_CREATE
+  | libc::IN_CLOSE_WRITE
+  | libc::IN_MOVED_FROM
+  | libc::IN_MOVED_TO
+  | libc::IN_DELETE
+  | libc::IN_ONLYDIR;
+
+pub struct Event {
+  pub path: String,
+  pub mask: u32,
+  pub cookie: u32,
+}
+
+impl Event {
+  pub fn is(&self, flag: u32) -> bool {
+    self.mask & flag != 0
+  }
+}
+
+// Watches over every directory of `file-store`. Paths are relative to it, the store itself being
+// the empty one.
+pub struct Inotify {
+  fd: libc::c_int,
+  dirs: HashMap<libc::c_int, String>,
+}
+
+fn join(dir: &str, name: &str) -> String {
+  match dir.is_empty() {
+    true => name.to_string(),
+    false => format!("{}/{}", dir, name),
+  }
+}
+
+impl Inotify {
+  pub fn new() -> io::Result<Inotify> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+    if fd < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(Inotify {
+      fd,
+      dirs: HashMap::new(),
+    })
+  }
+
+  // Watches `dir` and everything below it, returning the files found there.
+  pub fn add(&mut self, dir: &str) -> BTreeSet<String> {
+    let mut files = BTreeSet::new();
+    let path = file_store().join(dir);
+    let watched = CString::new(path.as_os_str().as_bytes())
+      .map_err(io::Error::other)
+      .and_then(
+        |path| match unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), MASK) } {
+          wd if wd < 0 => Err(io::Error::last_os_error()),
+          wd => Ok(wd),
+        },
+      );
+    match watched {
+      Ok(wd) => {
+        self.dirs.insert(wd, dir.to_string());
+      }
+      Err(error) => {
+        warn!("Failed to watch {:?}: {}", path, error);
+        return files;
+      }
+    }
+    for entry in fs::read_dir(&path).into_iter().flatten().flatten() {
+      let name = entry.file_name().to_string_lossy().into_owned();
+      let relative = join(dir, &name);
+      if skipped(&relative) {
+        continue;
+      }
+      match entry.file_type() {
+        Ok(kind) if kind.is_dir() => files.extend(self.add(&relative)),
+        Ok(kind) if kind.is_file() => {
+          files.insert(relative);
+        }
+        _ => {}
+      }
+    }
+    files
+  }
+
+  // A directory was renamed within the store; its watches stay, only their paths change.
+  pub fn moved(&mut self, from: &str, to: &str) {
+    for dir in self.dirs.values_mut() {
+      if dir == from {
+        *dir = to.to_string();
+      } else if let Some(rest) = dir.strip_prefix(&format!("{}/", from)) {
+        *dir = format!("{}/{}", to, rest);
+      }
+    }
+  }
+
+  // A directory left the store, but inotify would keep following it wherever it went.
+  pub fn forget(&mut self, dir: &str) {
+    let prefix = format!("{}/", dir);
+    let gone: Vec<libc::c_int> = self
+      .dirs
+      .iter()
+      .filter(|(_, watched)| *watched == dir || watched.starts_with(&prefix))
+      .map(|(wd, _)| *wd)
+      .collect();
+    for wd in gone {
+      unsafe { libc::inotify_rm_watch(self.fd, wd) };
+      self.dirs.remove(&wd);
+    }
+  }
+
+  // Waits up to `timeout` milliseconds for events.
+  pub fn read(&mut self, timeout: i32) -> io::Result<Vec<Event>> {
+    let mut poll = libc::pollfd {
+      fd: self.fd,
+      events: libc::POLLIN,
+      revents: 0,
+    };
+    if unsafe { libc::poll(&mut poll, 1, timeout) } <= 0 {
+      return Ok(Vec::new());
+    }
+    let mut buffer = vec![0u8; 64 * 1024];
+    let length = unsafe { libc::read(self.fd, buffer.as_mut_ptr().cast(), buffer.len()) };
+    if length < 0 {
+      let error = io::Error::last_os_error();
+      return match error.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(Vec::new()),
+        _ => Err(error),
+      };
+    }
+
+    let header = std::mem::size_of::<libc::inotify_event>();
+    let mut events = Vec::new();
+    let mut offset = 0;
+    while offset + header <= length as usize {
+      let event: libc::inotify_event =
+        unsafe { std::ptr::read_unaligned(buffer[offset..].as_ptr().cast()) };
+      let name = &buffer[offset + header..offset + header + event.len as usize];
+      let name = name.split(|byte| *byte == 0).next().unwrap_or(&[]);
+      let name = String::from_utf8_lossy(name).into_owned();
+      offset += header + event.len as usize;
+
+      if event.mask & libc::IN_IGNORED != 0 {
+        self.dirs.remove(&event.wd);
+        continue;
+      }
+      let path = match self.dirs.get(&event.wd) {
+        Some(dir) => join(dir, &name),
+        // Only an overflow comes without a watch.
+        None if event.mask & libc::IN_Q_OVERFLOW != 0 => String::new(),
+        None => continue,
+      };
+      events.push(Event {
+        path,
+        mask: event.mask,
+        cookie: event.cookie,
+      });
+    }
+    Ok(events)
+  }
+}
+
+impl Drop for Inotify {
+  fn drop(&mut self) {
+    unsafe { libc::close(self.fd) };
+  }
+}
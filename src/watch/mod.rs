@@ -0,0 +1,504 @@
+#[cfg(target_os = "linux")]
+mod inotify;
+
+use std::{
+  collections::{BTreeMap, BTreeSet, HashMap},
+  fs::{self, File},
+  os::unix::fs::MetadataExt,
+  path::{Path, PathBuf},
+  sync::{Arc, RwLock},
+};
+
+use log::{info, warn};
+use rocket::{fairing::AdHoc, serde::json::Json, State};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  auth::User,
+  checksum::Checksums,
+  database::{
+    data_dir, default_true, file_store, save_json, unix_now, Database, Library, OBJECTS_DIR,
+  },
+  mime,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WatchConfig {
+  #[serde(default = "default_true")]
+  pub enabled: bool,
+}
+
+impl Default for WatchConfig {
+  fn default() -> WatchConfig {
+    WatchConfig { enabled: true }
+  }
+}
+
+// The object store is named by content and never renamed by hand, and dot files are partial
+// uploads and the like.
+pub fn skipped(relative: &str) -> bool {
+  relative == OBJECTS_DIR
+    || relative.starts_with(&format!("{}/", OBJECTS_DIR))
+    || relative.split('/').any(|part| part.starts_with('.'))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Unclassified {
+  pub path: String,
+  pub size: u64,
+  pub found: u64,
+}
+
+// What the watcher couldn't fix by itself, persisted in `watch.json`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Findings {
+  // Ids whose file went away without turning up anywhere else, with where it used to be.
+  pub missing: BTreeMap<String, String>,
+  // Videos nobody maps to yet, waiting for the classifier to give them ids.
+  pub unclassified: Vec<Unclassified>,
+}
+
+#[derive(Clone, Default)]
+pub struct Watcher {
+  findings: Arc<RwLock<Findings>>,
+}
+
+fn findings_file_path() -> PathBuf {
+  data_dir().join("watch.json")
+}
+
+impl Watcher {
+  pub fn load() -> Watcher {
+    let findings = File::open(findings_file_path())
+      .ok()
+      .and_then(|file| serde_json::from_reader(file).ok())
+      .unwrap_or_default();
+    Watcher {
+      findings: Arc::new(RwLock::new(findings)),
+    }
+  }
+
+  fn save(&self) {
+    let findings = self.findings.read().unwrap();
+    let result = save_json(&findings_file_path(), &*findings);
+    if let Err(error) = result {
+      warn!("Failed to save watch findings: {}", error);
+    }
+  }
+
+  pub fn findings(&self) -> Findings {
+    self.findings.read().unwrap().clone()
+  }
+}
+
+// Which file a path is, so a rename can be told apart from a new file with the same contents. Inodes
+// of deleted files get reused, but renaming one leaves its size and mtime alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Identity {
+  device: u64,
+  inode: u64,
+  size: u64,
+  modified: (i64, i64),
+}
+
+fn identity(relative: &str) -> Option<Identity> {
+  let metadata = fs::metadata(file_store().join(relative)).ok()?;
+  metadata.is_file().then(|| Identity {
+    device: metadata.dev(),
+    inode: metadata.ino(),
+    size: metadata.len(),
+    modified: (metadata.mtime(), metadata.mtime_nsec()),
+  })
+}
+
+fn exists(relative: &str) -> bool {
+  Path::new(&file_store().join(relative)).exists()
+}
+
+pub fn is_video(relative: &str) -> bool {
+  Path::new(relative)
+    .extension()
+    .and_then(|extension| mime::from_extension(&extension.to_string_lossy()))
+    .is_some_and(|content_type| content_type.top() == "video")
+}
+
+// Ids kept as plain paths in the local store, which are all a rename inside it can break.
+fn watched_ids(db: &Database) -> Vec<(String, String)> {
+  let mut ids: Vec<(String, String)> = db
+    .id_to_path
+    .iter()
+    .filter(|(id, store_path)| db.is_local(id) && !store_path.starts_with("sha256:"))
+    .map(|(id, store_path)| (id.clone(), store_path.clone()))
+    .collect();
+  ids.sort();
+  ids
+}
+
+// `path` as it is after `from` was renamed to `to`, if it was affected.
+fn renamed(path: &str, from: &str, to: &str) -> Option<String> {
+  match path.strip_prefix(from) {
+    Some("") => Some(to.to_string()),
+    Some(rest) if rest.starts_with('/') => Some(format!("{}{}", to, rest)),
+    _ => None,
+  }
+}
+
+// `path` after each of `renames` in turn, so a file in a folder renamed twice, or renamed along
+// with the folder it is in, ends up where it is now.
+fn follow(path: &str, renames: &[(String, String)]) -> String {
+  renames.iter().fold(path.to_string(), |path, (from, to)| {
+    renamed(&path, from, to).unwrap_or(path)
+  })
+}
+
+// Where in `pool` the very file `known` was is, whatever it is called now.
+fn same_file(pool: &[(String, Identity)], known: &Identity) -> Option<usize> {
+  pool
+    .iter()
+    .position(|(_, identity)| identity.device == known.device && identity.inode == known.inode)
+}
+
+// Remembered identities of the files ids point at, for finding them again once they are moved.
+type Index = HashMap<String, Identity>;
+
+fn index(db: &Database) -> Index {
+  watched_ids(db)
+    .into_iter()
+    .filter_map(|(_, store_path)| Some((store_path.clone(), identity(&store_path)?)))
+    .collect()
+}
+
+struct Tracker {
+  library: Library,
+  checksums: Checksums,
+  watcher: Watcher,
+  index: Index,
+}
+
+impl Tracker {
+  // Blocking: brings `id_to_path` in line with renames seen as such, then looks for files that went
+  // missing among `candidates`, by inode first and failing that by size and hash.
+  fn reconcile(&mut self, renames: &[(String, String)], candidates: &BTreeSet<String>) {
+    let db = self.library.snapshot();
+    let ids = watched_ids(&db);
+    let mut moves: BTreeMap<String, String> = BTreeMap::new();
+    for (id, store_path) in &ids {
+      let current = follow(store_path, renames);
+      if current != *store_path {
+        moves.insert(id.clone(), current);
+      }
+    }
+    let current = |id: &str, store_path: &str, moves: &BTreeMap<String, String>| {
+      moves.get(id).cloned().unwrap_or(store_path.to_string())
+    };
+
+    let mut claimed: BTreeSet<String> = ids
+      .iter()
+      .map(|(id, store_path)| current(id, store_path, &moves))
+      .collect();
+    claimed.extend(db.id_to_path.values().cloned());
+    let mut pool: Vec<(String, Identity)> = candidates
+      .iter()
+      .filter(|path| !claimed.contains(*path))
+      .filter_map(|path| Some((path.clone(), identity(path)?)))
+      .collect();
+
+    // Several ids can share a file, and all of them follow it.
+    let mut found: HashMap<String, Option<String>> = HashMap::new();
+    for (id, store_path) in &ids {
+      if moves.contains_key(id) || exists(store_path) {
+        continue;
+      }
+      let target = found
+        .entry(store_path.clone())
+        .or_insert_with(|| self.find(&db, id, store_path, &mut pool))
+        .clone();
+      if let Some(target) = target {
+        moves.insert(id.clone(), target);
+      }
+    }
+
+    if !moves.is_empty() {
+      for (id, target) in &moves {
+        let store_path = &db.id_to_path[id];
+        info!("{} moved from {} to {}", id, store_path, target);
+        self.checksums.rename(store_path, target);
+      }
+      self.library.update(|db| {
+        for (id, target) in moves {
+          if let Some(store_path) = db.id_to_path.get_mut(&id) {
+            *store_path = target;
+          }
+        }
+      });
+    }
+
+    let db = self.library.snapshot();
+    self.index = index(&db);
+    let missing: BTreeMap<String, String> = watched_ids(&db)
+      .into_iter()
+      .filter(|(_, store_path)| !exists(store_path))
+      .collect();
+    let mut findings = self.watcher.findings.write().unwrap();
+    for (id, store_path) in &missing {
+      if !findings.missing.contains_key(id) {
+        warn!("{} is missing, {} is gone", id, store_path);
+      }
+    }
+    findings.missing = missing;
+    findings
+      .unclassified
+      .retain(|file| exists(&file.path) && !db.is_referenced(&file.path));
+    for (path, identity) in pool {
+      let queued = findings.unclassified.iter().any(|file| file.path == path);
+      if !queued && is_video(&path) && !db.is_referenced(&path) {
+        info!("Queued {} for classification", path);
+        findings.unclassified.push(Unclassified {
+          path,
+          size: identity.size,
+          found: unix_now(),
+        });
+      }
+    }
+    drop(findings);
+    self.watcher.save();
+  }
+
+  // Takes the file that used to be at `store_path` out of `pool`, if it is there.
+  fn find(
+    &self,
+    db: &Database,
+    id: &str,
+    store_path: &str,
+    pool: &mut Vec<(String, Identity)>,
+  ) -> Option<String> {
+    let known = self.index.get(store_path).copied();
+    let mut position = known.and_then(|known| same_file(pool, &known));
+    if position.is_none() {
+      let record = db.records.get(id);
+      let digest = self.checksums.last_known(store_path);
+      let sha256 = record
+        .and_then(|record| record.sha256.clone())
+        .or_else(|| digest.as_ref().map(|digest| digest.sha256.clone()));
+      let size = known
+        .map(|known| known.size)
+        .or_else(|| record.and_then(|record| record.size))
+        .or_else(|| digest.as_ref().map(|digest| digest.size));
+      if let (Some(sha256), Some(size)) = (sha256, size) {
+        position = pool.iter().position(|(path, identity)| {
+          identity.size == size
+            && self
+              .checksums
+              .digest(path)
+              .is_ok_and(|digest| digest.sha256.eq_ignore_ascii_case(&sha256))
+        });
+      }
+    }
+    position.map(|position| pool.remove(position).0)
+  }
+}
+
+#[cfg(target_os = "linux")]
+fn run(mut tracker: Tracker, stop: Arc<std::sync::atomic::AtomicBool>) {
+  use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+  };
+
+  use inotify::Inotify;
+
+  // Moves arrive as two events, and release folders are unpacked a file at a time, so changes are
+  // only acted on once things have been quiet for a bit.
+  const QUIET: Duration = Duration::from_secs(2);
+
+  let mut inotify = match Inotify::new() {
+    Ok(inotify) => inotify,
+    Err(error) => {
+      warn!("Failed to set up inotify: {}", error);
+      return;
+    }
+  };
+  // Anything that moved while the server wasn't running can only be found by its hash.
+  let files = inotify.add("");
+  tracker.reconcile(&[], &files);
+  info!("Watching {:?} for moved files", file_store());
+
+  let mut from: HashMap<u32, (String, bool)> = HashMap::new();
+  let mut renames: Vec<(String, String)> = Vec::new();
+  let mut candidates: BTreeSet<String> = BTreeSet::new();
+  let (mut dirty, mut overflowed) = (false, false);
+  let mut last = Instant::now();
+  while !stop.load(Ordering::Relaxed) {
+    let events = match inotify.read(1000) {
+      Ok(events) => events,
+      Err(error) => {
+        warn!("Stopped watching {:?}: {}", file_store(), error);
+        return;
+      }
+    };
+    for event in &events {
+      if event.is(libc::IN_Q_OVERFLOW) {
+        overflowed = true;
+        continue;
+      }
+      if skipped(&event.path) {
+        continue;
+      }
+      let directory = event.is(libc::IN_ISDIR);
+      if event.is(libc::IN_MOVED_FROM) {
+        from.insert(event.cookie, (event.path.clone(), directory));
+      } else if event.is(libc::IN_MOVED_TO) {
+        match from.remove(&event.cookie) {
+          Some((source, _)) => {
+            if directory {
+              inotify.moved(&source, &event.path);
+            }
+            renames.push((source, event.path.clone()));
+          }
+          None if directory => candidates.extend(inotify.add(&event.path)),
+          None => {
+            candidates.insert(event.path.clone());
+          }
+        }
+      } else if event.is(libc::IN_CREATE) && directory {
+        candidates.extend(inotify.add(&event.path));
+      } else if event.is(libc::IN_CLOSE_WRITE) {
+        candidates.insert(event.path.clone());
+      }
+    }
+    if !events.is_empty() {
+      dirty = true;
+      last = Instant::now();
+      continue;
+    }
+    if !dirty || last.elapsed() < QUIET {
+      continue;
+    }
+
+    // Whatever was moved out of the store is gone as far as we are concerned.
+    for (path, directory) in from.drain().map(|(_, moved)| moved) {
+      if directory {
+        inotify.forget(&path);
+      }
+    }
+    if overflowed {
+      warn!("Missed changes to {:?}, rescanning it", file_store());
+      inotify = match Inotify::new() {
+        Ok(inotify) => inotify,
+        Err(error) => {
+          warn!("Failed to set up inotify: {}", error);
+          return;
+        }
+      };
+      candidates = inotify.add("");
+    }
+    tracker.reconcile(&renames, &candidates);
+    renames.clear();
+    candidates.clear();
+    (dirty, overflowed) = (false, false);
+  }
+}
+
+// Follows files around `file-store` for as long as the server runs.
+pub fn fairing() -> AdHoc {
+  AdHoc::on_liftoff("Watcher", |rocket| {
+    Box::pin(async move {
+      let library = rocket.state::<Library>().unwrap().clone();
+      if !library.snapshot().watch.enabled {
+        return;
+      }
+      let tracker = Tracker {
+        index: index(&library.snapshot()),
+        library,
+        checksums: rocket.state::<Checksums>().unwrap().clone(),
+        watcher: rocket.state::<Watcher>().unwrap().clone(),
+      };
+
+      #[cfg(target_os = "linux")]
+      {
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let shutdown = rocket.shutdown();
+        let stopping = stop.clone();
+        rocket::tokio::spawn(async move {
+          shutdown.await;
+          stopping.store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+        std::thread::spawn(move || run(tracker, stop));
+      }
+      #[cfg(not(target_os = "linux"))]
+      {
+        drop(tracker);
+        warn!("Watching file-store for moved files needs inotify, which is Linux only");
+      }
+    })
+  })
+}
+
+#[get("/watch")]
+pub fn findings(_user: User, watcher: &State<Watcher>) -> Json<Findings> {
+  Json(watcher.findings())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn renames(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+      .iter()
+      .map(|(from, to)| (from.to_string(), to.to_string()))
+      .collect()
+  }
+
+  #[test]
+  fn renames_paths_and_what_is_under_them() {
+    let cases = [
+      ("a/b", "a/b", "a/c", Some("a/c")),
+      ("a/b/s01e01.mkv", "a/b", "a/c", Some("a/c/s01e01.mkv")),
+      ("a/b/c/d.mkv", "a", "z", Some("z/b/c/d.mkv")),
+      // Only whole names count.
+      ("a/bc", "a/b", "a/x", None),
+      ("a/bc/d.mkv", "a/b", "a/x", None),
+      ("a/b.mkv", "a/b", "a/x", None),
+      ("xa/b", "a/b", "a/x", None),
+      ("a", "a/b", "a/x", None),
+    ];
+    for (path, from, to, expected) in cases {
+      assert_eq!(renamed(path, from, to).as_deref(), expected, "{}", path);
+    }
+  }
+
+  #[test]
+  fn follows_nested_renames() {
+    let moves = renames(&[("shows/who", "shows/doctor"), ("shows", "tv")]);
+    assert_eq!(
+      follow("shows/who/s01e01.mkv", &moves),
+      "tv/doctor/s01e01.mkv"
+    );
+    assert_eq!(follow("shows/whoever.mkv", &moves), "tv/whoever.mkv");
+    assert_eq!(follow("films/who.mkv", &moves), "films/who.mkv");
+    // Renamed back and forth.
+    let moves = renames(&[("a/b", "a/tmp"), ("a/c", "a/b"), ("a/tmp", "a/c")]);
+    assert_eq!(follow("a/b/x.mkv", &moves), "a/c/x.mkv");
+    assert_eq!(follow("a/c/y.mkv", &moves), "a/b/y.mkv");
+    assert_eq!(follow("a/bb/z.mkv", &moves), "a/bb/z.mkv");
+  }
+
+  #[test]
+  fn finds_the_same_file_by_inode() {
+    let identity = |device, inode, size| Identity {
+      device,
+      inode,
+      size,
+      modified: (0, 0),
+    };
+    let pool = vec![
+      ("copy.mkv".to_string(), identity(1, 10, 100)),
+      ("other-disk.mkv".to_string(), identity(2, 20, 100)),
+      ("moved.mkv".to_string(), identity(1, 20, 100)),
+    ];
+    assert_eq!(same_file(&pool, &identity(1, 20, 100)), Some(2));
+    assert_eq!(same_file(&pool, &identity(3, 20, 100)), None);
+    assert_eq!(same_file(&[], &identity(1, 20, 100)), None);
+  }
+}
@@ -0,0 +1,379 @@
+use std::{
+  fs::{self, File, OpenOptions},
+  io::{self, Write},
+  path::PathBuf,
+  sync::Arc,
+  time::Duration,
+};
+
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use rocket::{
+  fairing::AdHoc,
+  tokio::{select, sync::Notify, time},
+  Shutdown,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+use crate::{
+  auth,
+  channel::rfc3339,
+  checksum::hex,
+  database::{data_dir, unix_now, Database, Library},
+};
+
+pub const ENTRY_ADDED: &str = "entry.added";
+pub const ENTRY_REMOVED: &str = "entry.removed";
+pub const VERIFY_FAILED: &str = "verify.failed";
+pub const DOWNLOAD_STARTED: &str = "download.started";
+pub const DOWNLOAD_COMPLETED: &str = "download.completed";
+
+// How often the queue is looked at when nothing wakes the sender up, which also picks up events
+// queued by `file-share verify` while the server runs.
+const POLL: Duration = Duration::from_secs(30);
+const TIMEOUT: Duration = Duration::from_secs(10);
+// Retries back off from 30 seconds to 6 hours, giving up after about 15 hours.
+const MAX_ATTEMPTS: u32 = 12;
+const MAX_BACKOFF: u64 = 6 * 3600;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+  pub url: String,
+  // Every body is signed with this, see `signature`.
+  pub secret: String,
+  // Which events to send, all of them when empty.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub events: Vec<String>,
+}
+
+impl Webhook {
+  fn wants(&self, event: &str) -> bool {
+    self.events.is_empty() || self.events.iter().any(|wanted| wanted == event)
+  }
+}
+
+// One event on its way to one URL. Each is a file of its own in the queue, so a `verify` run can
+// add to it while the server is sending.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Delivery {
+  id: String,
+  event: String,
+  url: String,
+  // Kept as sent, so every retry carries the same body and signature.
+  body: String,
+  attempts: u32,
+  due: u64,
+}
+
+fn webhooks_dir() -> PathBuf {
+  data_dir().join("webhooks")
+}
+
+fn queue_dir() -> PathBuf {
+  webhooks_dir().join("queue")
+}
+
+fn log_path() -> PathBuf {
+  webhooks_dir().join("deliveries.jsonl")
+}
+
+impl Delivery {
+  fn path(&self) -> PathBuf {
+    queue_dir().join(format!("{}-{}.json", self.id, &auth::hash(&self.url)[..12]))
+  }
+
+  // Written aside and renamed, so the sender never reads half of one.
+  fn save(&self) -> io::Result<()> {
+    let path = self.path();
+    let partial = path.with_extension("partial");
+    fs::create_dir_all(queue_dir())?;
+    serde_json::to_writer(File::create(&partial)?, self)?;
+    fs::rename(partial, path)
+  }
+
+  fn forget(&self) {
+    if let Err(error) = fs::remove_file(self.path()) {
+      warn!("Failed to remove webhook delivery {}: {}", self.id, error);
+    }
+  }
+
+  // A line per attempt in `deliveries.jsonl`.
+  fn log(&self, outcome: &str, status: Option<u16>, error: Option<&str>) {
+    let line = json!({
+      "at": rfc3339(unix_now() as f64),
+      "id": self.id,
+      "event": self.event,
+      "url": self.url,
+      "attempt": self.attempts,
+      "status": status,
+      "error": error,
+      "outcome": outcome,
+    });
+    let result = fs::create_dir_all(webhooks_dir()).and_then(|_| {
+      let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path())?;
+      writeln!(log, "{}", line)
+    });
+    if let Err(error) = result {
+      warn!("Failed to log webhook delivery {}: {}", self.id, error);
+    }
+  }
+}
+
+// Deliveries waiting to be sent, oldest first.
+fn pending() -> Vec<Delivery> {
+  let mut pending: Vec<Delivery> = fs::read_dir(queue_dir())
+    .into_iter()
+    .flatten()
+    .flatten()
+    .filter(|entry| {
+      entry
+        .path()
+        .extension()
+        .is_some_and(|extension| extension == "json")
+    })
+    .filter_map(|entry| File::open(entry.path()).ok())
+    .filter_map(|file| serde_json::from_reader(file).ok())
+    .collect();
+  pending.sort_by_key(|delivery| delivery.due);
+  pending
+}
+
+// `sha256=<hex>` of the HMAC-SHA256 of the body, keyed with the webhook's secret.
+pub fn signature(secret: &str, body: &str) -> String {
+  let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+  mac.update(body.as_bytes());
+  format!("sha256={}", hex(&mac.finalize().into_bytes()))
+}
+
+fn backoff(attempts: u32) -> u64 {
+  (30u64 << attempts.saturating_sub(1).min(16)).min(MAX_BACKOFF)
+}
+
+// Queues events for the configured webhooks; sending them is up to the server's `fairing`.
+#[derive(Clone, Default)]
+pub struct Webhooks {
+  wake: Arc<Notify>,
+}
+
+impl Webhooks {
+  pub fn emit(&self, db: &Database, event: &str, data: Value) {
+    let hooks: Vec<&Webhook> = db
+      .webhooks
+      .iter()
+      .filter(|hook| hook.wants(event))
+      .collect();
+    if hooks.is_empty() {
+      return;
+    }
+    let id = format!("{:032x}", rand::random::<u128>());
+    let body = json!({
+      "id": id,
+      "event": event,
+      "at": rfc3339(unix_now() as f64),
+      "data": data,
+    })
+    .to_string();
+    for hook in hooks {
+      let delivery = Delivery {
+        id: id.clone(),
+        event: event.to_string(),
+        url: hook.url.clone(),
+        body: body.clone(),
+        attempts: 0,
+        due: 0,
+      };
+      if let Err(error) = delivery.save() {
+        warn!("Failed to queue {} for {}: {}", event, hook.url, error);
+      }
+    }
+    self.wake.notify_one();
+  }
+
+  // `entry.added` and `entry.removed` for whatever a change to the library did. A renamed id is
+  // both.
+  pub fn entries_changed(&self, before: &Database, after: &Database) {
+    let mut added: Vec<&String> = after
+      .id_to_path
+      .keys()
+      .filter(|id| !before.id_to_path.contains_key(*id))
+      .collect();
+    added.sort();
+    for id in added {
+      let data = json!({
+        "id": id,
+        "collection": after.collection_of(id),
+        "title": after.episode_title(id),
+      });
+      self.emit(after, ENTRY_ADDED, data);
+    }
+    let mut removed: Vec<&String> = before
+      .id_to_path
+      .keys()
+      .filter(|id| !after.id_to_path.contains_key(*id))
+      .collect();
+    removed.sort();
+    for id in removed {
+      let data = json!({
+        "id": id,
+        "collection": before.collection_of(id),
+        "title": before.episode_title(id),
+      });
+      self.emit(after, ENTRY_REMOVED, data);
+    }
+  }
+}
+
+// The status of the response, and for failures why they failed.
+async fn send(
+  client: &reqwest::Client,
+  hook: &Webhook,
+  delivery: &Delivery,
+) -> Result<u16, (Option<u16>, String)> {
+  let response = client
+    .post(&delivery.url)
+    .header("Content-Type", "application/json")
+    .header(
+      "User-Agent",
+      concat!("file-share/", env!("CARGO_PKG_VERSION")),
+    )
+    .header("X-Webhook-Event", &delivery.event)
+    .header("X-Webhook-Id", &delivery.id)
+    .header(
+      "X-Webhook-Signature",
+      signature(&hook.secret, &delivery.body),
+    )
+    .body(delivery.body.clone())
+    .send()
+    .await
+    .map_err(|error| (None, error.to_string()))?;
+  let status = response.status();
+  match status.is_success() {
+    true => Ok(status.as_u16()),
+    false => Err((Some(status.as_u16()), status.to_string())),
+  }
+}
+
+async fn attempt(client: &reqwest::Client, db: &Database, mut delivery: Delivery) {
+  // Deliveries to webhooks that have since been removed from the config are dropped.
+  let hook = match db.webhooks.iter().find(|hook| hook.url == delivery.url) {
+    Some(hook) => hook,
+    None => {
+      delivery.log("dropped", None, Some("no longer configured"));
+      delivery.forget();
+      return;
+    }
+  };
+  delivery.attempts += 1;
+  let (status, error) = match send(client, hook, &delivery).await {
+    Ok(status) => {
+      delivery.log("delivered", Some(status), None);
+      delivery.forget();
+      return;
+    }
+    Err(failure) => failure,
+  };
+  if delivery.attempts >= MAX_ATTEMPTS {
+    warn!(
+      "Giving up on {} {} for {}: {}",
+      delivery.event, delivery.id, delivery.url, error
+    );
+    delivery.log("failed", status, Some(&error));
+    delivery.forget();
+    return;
+  }
+  delivery.due = unix_now() + backoff(delivery.attempts);
+  delivery.log("retrying", status, Some(&error));
+  if let Err(error) = delivery.save() {
+    warn!(
+      "Failed to requeue webhook delivery {}: {}",
+      delivery.id, error
+    );
+  }
+}
+
+async fn run(library: Library, webhooks: Webhooks, shutdown: Shutdown) {
+  let client = match reqwest::Client::builder().timeout(TIMEOUT).build() {
+    Ok(client) => client,
+    Err(error) => {
+      warn!("Failed to set up webhooks: {}", error);
+      return;
+    }
+  };
+  rocket::tokio::pin!(shutdown);
+  loop {
+    let now = unix_now();
+    let due: Vec<Delivery> = rocket::tokio::task::spawn_blocking(pending)
+      .await
+      .unwrap_or_default()
+      .into_iter()
+      .filter(|delivery| delivery.due <= now)
+      .collect();
+    let db = library.snapshot();
+    for delivery in due {
+      attempt(&client, &db, delivery).await;
+    }
+    select! {
+      _ = &mut shutdown => break,
+      _ = webhooks.wake.notified() => {},
+      _ = time::sleep(POLL) => {},
+    }
+  }
+}
+
+// Sends queued events for as long as the server runs; whatever is left stays queued for next time.
+pub fn fairing() -> AdHoc {
+  AdHoc::on_liftoff("Webhooks", |rocket| {
+    Box::pin(async move {
+      let library = rocket.state::<Library>().unwrap().clone();
+      let webhooks = rocket.state::<Webhooks>().unwrap().clone();
+      let count = library.snapshot().webhooks.len();
+      if count > 0 {
+        info!("Sending events to {} webhooks", count);
+      }
+      rocket::tokio::spawn(run(library, webhooks, rocket.shutdown()));
+    })
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn signs_bodies_with_hmac_sha256() {
+    // RFC 4231, test case 2.
+    assert_eq!(
+      signature("Jefe", "what do ya want for nothing?"),
+      "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+  }
+
+  #[test]
+  fn doubles_the_backoff_up_to_six_hours() {
+    let backoffs: Vec<u64> = (1..MAX_ATTEMPTS).map(backoff).collect();
+    assert_eq!(
+      backoffs,
+      [30, 60, 120, 240, 480, 960, 1920, 3840, 7680, 15360, 21600]
+    );
+    assert_eq!(backoff(0), 30);
+    assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+  }
+
+  #[test]
+  fn filters_events() {
+    let mut hook = Webhook {
+      url: "http://localhost/hook".to_string(),
+      secret: "secret".to_string(),
+      events: Vec::new(),
+    };
+    assert!(hook.wants(VERIFY_FAILED));
+    hook.events = vec![ENTRY_ADDED.to_string()];
+    assert!(hook.wants(ENTRY_ADDED));
+    assert!(!hook.wants(ENTRY_REMOVED));
+  }
+}
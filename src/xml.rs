@@ -0,0 +1,79 @@
+// For text and attribute values alike.
+pub fn escape(value: &str) -> String {
+  let mut escaped = String::with_capacity(value.len());
+  for c in value.chars() {
+    match c {
+      '&' => escaped.push_str("&amp;"),
+      '<' => escaped.push_str("&lt;"),
+      '>' => escaped.push_str("&gt;"),
+      '"' => escaped.push_str("&quot;"),
+      '\'' => escaped.push_str("&apos;"),
+      // Control characters other than tab and newlines are not allowed in XML 1.0 at all.
+      c if c.is_control() && !matches!(c, '\t' | '\n' | '\r') => {}
+      c => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+// The five predefined entities and numeric references, which is all SOAP arguments ever carry.
+pub fn unescape(value: &str) -> String {
+  let mut unescaped = String::with_capacity(value.len());
+  let mut rest = value;
+  while let Some(start) = rest.find('&') {
+    unescaped.push_str(&rest[..start]);
+    rest = &rest[start..];
+    let end = match rest.find(';') {
+      Some(end) => end,
+      None => break,
+    };
+    let entity = &rest[1..end];
+    let c = match entity {
+      "amp" => Some('&'),
+      "lt" => Some('<'),
+      "gt" => Some('>'),
+      "quot" => Some('"'),
+      "apos" => Some('\''),
+      _ => match entity
+        .strip_prefix("#x")
+        .or_else(|| entity.strip_prefix("#X"))
+      {
+        Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+        None => entity
+          .strip_prefix('#')
+          .and_then(|decimal| decimal.parse().ok())
+          .and_then(char::from_u32),
+      },
+    };
+    match c {
+      Some(c) => {
+        unescaped.push(c);
+        rest = &rest[end + 1..];
+      }
+      None => {
+        unescaped.push('&');
+        rest = &rest[1..];
+      }
+    }
+  }
+  unescaped.push_str(rest);
+  unescaped
+}
+
+// The contents of every `<tag>` in `xml`, still escaped. Enough for the flat documents S3 sends.
+pub fn values<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+  let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+  let mut values = Vec::new();
+  let mut rest = xml;
+  while let Some(start) = rest.find(&open) {
+    rest = &rest[start + open.len()..];
+    match rest.find(&close) {
+      Some(end) => {
+        values.push(&rest[..end]);
+        rest = &rest[end + close.len()..];
+      }
+      None => break,
+    }
+  }
+  values
+}